@@ -0,0 +1,232 @@
+use chrono::{DateTime, Duration, Utc};
+use ndarray::Array2;
+use crate::lbm::InputField;
+use crate::lattice::{Lattice, D2Q9};
+use crate::model::Normalization;
+use serde::{Deserialize, Serialize};
+use crate::repo::{MeteorologicalData, MeteorologicalType};
+
+// 境界条件のうち、lbmのstreamの外側にあるもの
+// 1. 流入境界(Boundary::Inflow)で領域の外側を埋める値をrepoのデータから作る
+//    データの時刻の間は前後の時刻から線形補間し、InputField(平衡分布)にして返す
+// 2. 領域の端の条件(Zou–He、流出) StreamingWeight::set_edges()で与える
+//    fはD2Q9のq成分(q = (dr+1) * 3 + (dc+1))を並べたもので、法線(nr, nc)は領域の内向き
+
+// 端のセルで与える条件 値は格子単位
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EdgeCondition {
+    // 速度を与える
+    Velocity { u_vert: f64, u_hori: f64 },
+    // 密度(圧力)を与える 接線方向の速度は0
+    Pressure { rho: f64 },
+    // 流出(勾配0) 外から入ってくる成分を1つ内側のセルからコピーする
+    Outflow,
+}
+
+// north:上端(最初の行)  south:下端  west:左端(最初の列)  east:右端  Noneの端はstreamの結果のまま
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Edges {
+    pub north: Option<EdgeCondition>,
+    pub south: Option<EdgeCondition>,
+    pub west: Option<EdgeCondition>,
+    pub east: Option<EdgeCondition>,
+}
+
+// 端の条件を適用する f_innerは法線方向に1つ内側のセルのf(Outflowのときだけ使う)
+pub fn apply_edge(f: &mut [f64], f_inner: &[f64], normal: (i32, i32), condition: EdgeCondition) {
+    match condition {
+        EdgeCondition::Outflow => zero_gradient(f, f_inner, normal),
+        _ => zou_he(f, normal, condition),
+    }
+}
+
+// 外から入ってくる(e・n > 0)成分を内側のセルと同じにする
+pub fn zero_gradient(f: &mut [f64], f_inner: &[f64], normal: (i32, i32)) {
+    for (q, [dr, dc]) in D2Q9::VELOCITIES.into_iter().enumerate() {
+        if dr * normal.0 + dc * normal.1 > 0 {
+            f[q] = f_inner[q];
+        }
+    }
+}
+
+// Zou–He 外から入ってくる(e・n > 0)成分を、条件と残りの成分から決める
+// 法線方向の成分は f_e = f_-e + 2/3 rho u_n
+// 斜めの成分(e = n + s t)は f_e = f_-e - s/2 Σ(e'・t)f_e' + 1/6 rho u_n + s/2 rho u_t (Σは e'・n = 0 の成分について)
+pub fn zou_he(f: &mut [f64], normal: (i32, i32), condition: EdgeCondition) {
+    let (nr, nc) = normal;
+    let (tr, tc) = (nc.abs(), nr.abs()); // 接線方向
+    let (mut sum_tangent, mut sum_outgoing, mut tangent) = (0.0, 0.0, 0.0);
+    for (f_e, [dr, dc]) in f.iter().zip(D2Q9::VELOCITIES) {
+        match dr * nr + dc * nc {
+            0 => {
+                sum_tangent += f_e;
+                tangent += (dr * tr + dc * tc) as f64 * f_e;
+            }
+            n if n < 0 => sum_outgoing += f_e,
+            _ => {}
+        }
+    }
+    let (rho, u_n, u_t) = match condition {
+        EdgeCondition::Velocity { u_vert, u_hori } => {
+            let u_n = u_vert * nr as f64 + u_hori * nc as f64;
+            ((sum_tangent + 2.0 * sum_outgoing) / (1.0 - u_n), u_n, u_vert * tr as f64 + u_hori * tc as f64)
+        }
+        EdgeCondition::Pressure { rho } => (rho, 1.0 - (sum_tangent + 2.0 * sum_outgoing) / rho, 0.0),
+        EdgeCondition::Outflow => panic!("panicked at line {} in {}", line!(), file!()),
+    };
+    for s in -1..=1_i32 {
+        let (dr, dc) = (nr + s * tr, nc + s * tc);
+        let q = ((dr + 1) * 3 + (dc + 1)) as usize;
+        let opposite = f[D2Q9.opposite(q)];
+        f[q] = if s == 0 {
+            opposite + 2.0 / 3.0 * rho * u_n
+        } else {
+            opposite - s as f64 / 2.0 * tangent + rho * u_n / 6.0 + s as f64 / 2.0 * rho * u_t
+        };
+    }
+}
+
+// apply_edge()はf, f_innerについて1次式(f -> A f + B f_inner + b)なので、その(A, B)
+// A[i][j] = d(f後)_i / d(f前)_j、B[i][j] = d(f後)_i / d(f_inner)_j  添字はD2Q9のq
+pub fn edge_jacobian(normal: (i32, i32), condition: EdgeCondition) -> ([[f64; 9]; 9], [[f64; 9]; 9]) {
+    // j < 9はfの、9 <= jはf_innerの成分を1にする
+    let apply = |j: Option<usize>| {
+        let mut f = [0.0; 9];
+        let mut f_inner = [0.0; 9];
+        match j {
+            Some(j) if j < 9 => f[j] = 1.0,
+            Some(j) => f_inner[j - 9] = 1.0,
+            None => {}
+        }
+        apply_edge(&mut f, &f_inner, normal, condition);
+        f
+    };
+    let offset = apply(None);
+    let (mut jacobian, mut jacobian_inner) = ([[0.0; 9]; 9], [[0.0; 9]; 9]);
+    for j in 0..18 {
+        let f = apply(Some(j));
+        let target = if j < 9 { &mut jacobian } else { &mut jacobian_inner };
+        for (i, row) in target.iter_mut().enumerate() {
+            row[j % 9] = f[i] - offset[i];
+        }
+    }
+    (jacobian, jacobian_inner)
+}
+
+// datetimeでの格子単位の(u_vert, u_hori, rho) データの時刻の範囲外や、前後の時刻のデータが欠けているときはNone
+pub fn interpolate(data: &MeteorologicalData, normalization: Normalization, datetime: DateTime<Utc>) -> Option<(Array2<f64>, Array2<f64>, Array2<f64>)> {
+    let datetimes = data.datetimes();
+    let before = *datetimes.iter().rev().find(|t| **t <= datetime)?;
+    let after = *datetimes.iter().find(|t| **t >= datetime)?;
+    let lattice = |t: DateTime<Utc>| -> Option<(Array2<f64>, Array2<f64>, Array2<f64>)> {
+        let (u_vert, u_hori) = data.lattice_velocity(t)?;
+        let pressure = data.get(t, MeteorologicalType::Pressure)?;
+        Some(normalization.to_lattice(&u_vert, &u_hori, pressure))
+    };
+
+    let (u_vert_before, u_hori_before, rho_before) = lattice(before)?;
+    if before == after {
+        return Some((u_vert_before, u_hori_before, rho_before));
+    }
+    let (u_vert_after, u_hori_after, rho_after) = lattice(after)?;
+    let s = (datetime - before).num_seconds() as f64 / (after - before).num_seconds() as f64;
+    let lerp = |a: Array2<f64>, b: Array2<f64>| a * (1.0 - s) + b * s;
+    Some((lerp(u_vert_before, u_vert_after), lerp(u_hori_before, u_hori_after), lerp(rho_before, rho_after)))
+}
+
+// startからendまでをsteps回のstreamに分けたときの境界 k番目はk回目のstreamの直後(start + (k + 1) / steps * (end - start))
+// Model::forward_with_inflow()にそのまま渡せる
+pub fn inflow_boundaries(data: &MeteorologicalData, normalization: Normalization, start: DateTime<Utc>, end: DateTime<Utc>, steps: usize) -> Option<Vec<InputField>> {
+    let (row, col) = data.grid().shape();
+    let span = (end - start).num_seconds();
+    (1..=steps).map(|k| {
+        let datetime = start + Duration::seconds(span * k as i64 / steps as i64);
+        let (u_vert, u_hori, rho) = interpolate(data, normalization, datetime)?;
+        let mut boundary = InputField::new(row, col);
+        boundary.set(u_vert, u_hori, rho);
+        Some(boundary)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use crate::geo::GeoGrid;
+    use crate::model::Model;
+    use crate::repo::{self, Coarsening, Region};
+    use crate::lbm::Boundary;
+    use crate::synthetic::{SyntheticFlow, write_synthetic_data};
+    use super::*;
+
+    fn synthetic_data(name: &str, flow: &SyntheticFlow, grid: &GeoGrid, datetimes: &[DateTime<Utc>]) -> MeteorologicalData {
+        let dir = std::env::temp_dir().join(name);
+        let data_dir = dir.to_str().unwrap().to_string() + "/";
+        write_synthetic_data(&data_dir, flow, grid, datetimes).unwrap();
+        let data = repo::get_meteorological_data_from_dir(&data_dir, datetimes.to_vec(), grid, &Region::new(None, Coarsening::None)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        data
+    }
+
+    fn macroscopic(f: &[f64; 9]) -> (f64, f64, f64) {
+        let rho: f64 = f.iter().sum();
+        let u_vert = f.iter().zip(D2Q9::VELOCITIES).map(|(f, [dr, _])| dr as f64 * f).sum::<f64>() / rho;
+        let u_hori = f.iter().zip(D2Q9::VELOCITIES).map(|(f, [_, dc])| dc as f64 * f).sum::<f64>() / rho;
+        (rho, u_vert, u_hori)
+    }
+
+    #[test]
+    fn test_zou_he() {
+        let f_streamed = [0.03, 0.11, 0.02, 0.12, 0.45, 0.10, 0.025, 0.09, 0.03];
+        for normal in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let mut f = f_streamed;
+            zou_he(&mut f, normal, EdgeCondition::Velocity { u_vert: 0.02, u_hori: -0.05 });
+            let (_, u_vert, u_hori) = macroscopic(&f);
+            assert!((u_vert - 0.02).abs() < 0.000000001, "{:?}", normal);
+            assert!((u_hori + 0.05).abs() < 0.000000001, "{:?}", normal);
+
+            let mut f = f_streamed;
+            zou_he(&mut f, normal, EdgeCondition::Pressure { rho: 1.02 });
+            let (rho, u_vert, u_hori) = macroscopic(&f);
+            assert!((rho - 1.02).abs() < 0.000000001, "{:?}", normal);
+            assert!((u_vert * normal.1 as f64 + u_hori * normal.0 as f64).abs() < 0.000000001, "{:?}", normal);
+        }
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let grid = GeoGrid::new(35.0, 135.0, -0.05, 0.0625, 6, 8);
+        let datetimes = vec![Utc.with_ymd_and_hms(2020, 3, 20, 0, 0, 0).unwrap(), Utc.with_ymd_and_hms(2020, 3, 20, 1, 0, 0).unwrap()];
+        let flow = SyntheticFlow::TaylorGreen { u0: 10.0, nu: 1.0 };
+        let data = synthetic_data("lbm_rust_test_boundary_interpolate", &flow, &grid, &datetimes);
+        let normalization = Normalization::default();
+
+        let (u_vert, _, rho) = interpolate(&data, normalization, datetimes[0]).unwrap();
+        assert!((u_vert[[2, 0]] - flow.frame(6, 8, 0.0).u_vert[[2, 0]] / 100.0).abs() < 0.000000001);
+        let (_, u_hori, _) = interpolate(&data, normalization, datetimes[0] + Duration::minutes(15)).unwrap();
+        let expected = 0.75 * flow.frame(6, 8, 0.0).u_hori[[0, 2]] + 0.25 * flow.frame(6, 8, 1.0).u_hori[[0, 2]];
+        assert!((u_hori[[0, 2]] - expected / 100.0).abs() < 0.000000001);
+        assert!(rho.iter().all(|rho| (rho - 1.0).abs() < 0.01));
+        assert!(interpolate(&data, normalization, datetimes[1] + Duration::minutes(1)).is_none());
+    }
+
+    #[test]
+    fn test_inflow_boundaries_keep_margin() {
+        // 一様流を流入境界で与え続けると、marginは狭まらず、何層重ねても一様流のまま
+        let grid = GeoGrid::new(35.0, 135.0, -0.05, 0.0625, 7, 9);
+        let datetimes = vec![Utc.with_ymd_and_hms(2020, 3, 20, 0, 0, 0).unwrap(), Utc.with_ymd_and_hms(2020, 3, 20, 1, 0, 0).unwrap()];
+        let flow = SyntheticFlow::Uniform { u_vert: 0.0, u_hori: 5.0 };
+        let data = synthetic_data("lbm_rust_test_boundary_inflow", &flow, &grid, &datetimes);
+        let normalization = Normalization::default();
+
+        let model = Model::with_boundary(7, 9, 1, 6, Boundary::Inflow);
+        let inflows = inflow_boundaries(&data, normalization, datetimes[0], datetimes[1], 6).unwrap();
+        assert_eq!(6, inflows.len());
+        let (u_vert, u_hori, rho) = interpolate(&data, normalization, datetimes[0]).unwrap();
+        let forward = model.forward_with_inflow(u_vert, u_hori, rho, &inflows);
+        assert_eq!(1, model.output_margin());
+        assert!(forward.output().u_hori().iter().all(|u| (u - 0.05).abs() < 0.000000001));
+        assert!(forward.output().u_vert().iter().all(|u| u.abs() < 0.000000001));
+    }
+}
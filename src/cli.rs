@@ -0,0 +1,567 @@
+use std::{error::Error, fmt, fs::{self, File, OpenOptions}, io::Write, path::{Path, PathBuf}, process::{Command, ExitCode}};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use ndarray::{Array2, Zip};
+use ndarray_npy::{ReadNpyExt, WriteNpyExt};
+use lbm_rust::config::{Config, ConfigError, DataFormat};
+use lbm_rust::lbm::{interior, Boundary, Conservation};
+use lbm_rust::boundary;
+use lbm_rust::collision::CollisionOperator;
+use lbm_rust::model::{self, Forward, Model, Normalization};
+use lbm_rust::repo::{self, Coarsening, MeteorologicalData, MeteorologicalType, Region};
+use lbm_rust::synthetic::{SyntheticFlow, write_synthetic_data};
+
+// 終了コード 0:成功  1:実行時のエラー  2:引数や設定ファイルの誤り(clapの使い方の誤りも2)
+const EXIT_FAILURE: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+
+// 格子単位の(u_vert, u_hori, rho)
+type LatticeFields = (Array2<f64>, Array2<f64>, Array2<f64>);
+
+#[derive(Parser)]
+#[command(name = "lbm_rust", version, about = "Learnable lattice Boltzmann model for surface wind forecasting")]
+struct Cli {
+    /// Experiment configuration (TOML). Values can be overridden with LBM__<SECTION>__<KEY> environment variables
+    #[arg(short, long, global = true, default_value = "config.toml")]
+    config: PathBuf,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Convert GRIB or NetCDF files into the npy/ layout under data.data_dir
+    Convert {
+        #[arg(long, value_enum)]
+        from: ConvertFrom,
+        /// GRIB conversion script (run with DATA_DIR set to data.data_dir)
+        #[arg(long, default_value = "src/grib2npy.py")]
+        script: PathBuf,
+        #[arg(long, default_value = "python3")]
+        python: String,
+    },
+    /// Write synthetic u_vert/u_hori/pressure frames for the train and test periods into data.data_dir
+    Synthetic {
+        #[arg(long, value_enum, default_value = "taylor-green")]
+        flow: FlowKind,
+        /// Characteristic wind speed [m/s]
+        #[arg(long, default_value_t = 10.0)]
+        speed: f64,
+        /// Viscosity for the Taylor-Green vortex [cells^2/h]
+        #[arg(long, default_value_t = 1.0)]
+        nu: f64,
+    },
+    /// Train a model on the training period and save checkpoints
+    Train {
+        /// Continue from this checkpoint instead of the default weights
+        #[arg(long)]
+        resume: Option<PathBuf>,
+    },
+    /// Forecast the wind one step ahead of DATETIME and write it as npy
+    Predict {
+        #[arg(long)]
+        checkpoint: PathBuf,
+        /// RFC 3339, e.g. 2020-03-20T00:00:00Z
+        #[arg(long)]
+        datetime: DateTime<Utc>,
+        /// Output directory (default: <data_dir>pred/)
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Compare the forecast skill against persistence over the test period
+    Evaluate {
+        #[arg(long)]
+        checkpoint: PathBuf,
+    },
+    /// Print the shape and weight statistics of a checkpoint
+    Inspect {
+        #[arg(long)]
+        checkpoint: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ConvertFrom {
+    Grib,
+    Netcdf,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FlowKind {
+    Uniform,
+    TaylorGreen,
+    GaussianLow,
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Level {
+    Warn,
+    Info,
+    Debug,
+}
+
+struct Logger {
+    level: Level,
+    file: Option<File>,
+}
+
+#[derive(Debug)]
+struct CliError(String);
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for CliError {}
+
+pub fn run() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Commands::Inspect { ref checkpoint } => inspect(checkpoint),
+        ref command => match Config::load(&cli.config) {
+            Ok(config) => run_with_config(command, &config),
+            Err(e) => {
+                eprintln!("error: {}: {}", cli.config.display(), e);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        },
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            if e.downcast_ref::<ConfigError>().is_some() { ExitCode::from(EXIT_USAGE) } else { ExitCode::from(EXIT_FAILURE) }
+        }
+    }
+}
+
+fn run_with_config(command: &Commands, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut logger = Logger::new(config)?;
+    match command {
+        Commands::Convert { from, script, python } => convert(config, &mut logger, *from, script, python),
+        Commands::Synthetic { flow, speed, nu } => synthetic(config, &mut logger, *flow, *speed, *nu),
+        Commands::Train { resume } => train(config, &mut logger, resume.as_deref()),
+        Commands::Predict { checkpoint, datetime, output } => predict(config, &mut logger, checkpoint, *datetime, output.as_deref()),
+        Commands::Evaluate { checkpoint } => evaluate(config, &mut logger, checkpoint),
+        Commands::Inspect { checkpoint } => inspect(checkpoint),
+    }
+}
+
+fn convert(config: &Config, logger: &mut Logger, from: ConvertFrom, script: &Path, python: &str) -> Result<(), Box<dyn Error>> {
+    match from {
+        ConvertFrom::Grib => {
+            logger.log(Level::Info, &format!("running {} {}", python, script.display()));
+            let status = Command::new(python).arg(script).env("DATA_DIR", &config.data.data_dir).status()?;
+            if !status.success() {
+                return Err(Box::new(CliError(format!("{} exited with {}", script.display(), status))));
+            }
+        }
+        ConvertFrom::Netcdf => {
+            let path = config.data.netcdf_path.as_ref()
+                .ok_or_else(|| ConfigError::Invalid("data.netcdf_path is required to convert netcdf".to_string()))?;
+            let datetimes = all_datetimes(config)?;
+            let data = repo::get_meteorological_data_from_netcdf(path, datetimes.clone(), &config.netcdf_variables(), &Region::new(None, Coarsening::None))
+                .map_err(|e| CliError(format!("{}: {}", path, e)))?;
+            let npy_dir = Path::new(&config.data.data_dir).join("npy");
+            fs::create_dir_all(&npy_dir)?;
+            for datetime in datetimes {
+                let suffix = datetime.format("%Y%m%d%H").to_string() + ".npy";
+                for (prefix, meteorological_type) in [("u_vert_", MeteorologicalType::UVert), ("u_hori_", MeteorologicalType::UHori), ("pressure_", MeteorologicalType::Pressure)] {
+                    let arr = data.get(datetime, meteorological_type).unwrap();
+                    arr.write_npy(File::create(npy_dir.join(prefix.to_string() + &suffix))?)?;
+                }
+            }
+            let grid = data.grid();
+            logger.log(Level::Info, &format!(
+                "wrote {} frames; set [data.grid] lat_first = {}, lon_first = {}, d_lat = {}, d_lon = {}, row = {}, col = {}",
+                data.datetimes().len(), grid.lat()[0], grid.lon()[0], grid.d_lat(), grid.d_lon(), grid.row(), grid.col()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn synthetic(config: &Config, logger: &mut Logger, flow: FlowKind, speed: f64, nu: f64) -> Result<(), Box<dyn Error>> {
+    let grid = config.grid().ok_or_else(|| ConfigError::Invalid("[data.grid] is required to write synthetic data".to_string()))?;
+    let (row, col) = (grid.row() as f64, grid.col() as f64);
+    let flow = match flow {
+        FlowKind::Uniform => SyntheticFlow::Uniform { u_vert: 0.0, u_hori: speed },
+        FlowKind::TaylorGreen => SyntheticFlow::TaylorGreen { u0: speed, nu },
+        FlowKind::GaussianLow => SyntheticFlow::GaussianLow {
+            center: (row / 2.0, col / 4.0),
+            velocity: (0.0, col / 2.0 / 24.0), // 1日で領域の半分くらい東に進む
+            depth: 2000.0,
+            radius: row.min(col) / 8.0,
+            max_wind: speed,
+        },
+    };
+    let datetimes = all_datetimes(config)?;
+    write_synthetic_data(&config.data.data_dir, &flow, &grid, &datetimes)?;
+    logger.log(Level::Info, &format!("wrote {} synthetic frames to {}npy/", datetimes.len(), config.data.data_dir));
+    Ok(())
+}
+
+fn train(config: &Config, logger: &mut Logger, resume: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let range = config.period.train.as_ref().ok_or_else(|| ConfigError::Invalid("[period.train] is required to train".to_string()))?;
+    let datetimes = range.datetimes();
+    if datetimes.len() < 2 {
+        return Err(Box::new(ConfigError::Invalid("[period.train] must contain at least two datetimes".to_string())));
+    }
+    let data = load_data(config, datetimes.clone())?;
+    let mut model = match resume {
+        Some(dir) => load_model(dir)?,
+        None => new_model(config, &data)?,
+    };
+    check_shape(&model, &data)?;
+    model.set_conservation_penalty(config.training.conservation_penalty);
+    let checkpoint_dir = Path::new(&config.checkpoint.dir);
+
+    let mut mean_prev = f64::INFINITY;
+    for epoch in 1..=config.training.epochs {
+        let mut total = 0.0;
+        for pair in datetimes.windows(2) {
+            let (u_vert_ans, u_hori_ans, _) = lattice_input(&data, &model, pair[1])?;
+            let forward = forward(&data, &model, pair[0], pair[1])?;
+            let loss = model.train_step(config.training.eta, &forward, &u_vert_ans, &u_hori_ans);
+            let drift = max_drift(&forward);
+            logger.log(Level::Debug, &format!("epoch {} {}: loss {:.6e} max drift mass {:.6e} momentum_vert {:.6e} momentum_hori {:.6e}", epoch, pair[0], loss, drift.mass, drift.momentum_vert, drift.momentum_hori));
+            total += loss;
+        }
+        let mean = total / (datetimes.len() - 1) as f64;
+        if !mean.is_finite() {
+            return Err(Box::new(CliError(format!("loss diverged at epoch {}", epoch))));
+        }
+        logger.log(Level::Info, &format!("epoch {}/{}: mean loss {:.6e}", epoch, config.training.epochs, mean));
+        if mean > mean_prev {
+            logger.log(Level::Warn, &format!("mean loss increased from {:.6e}; training.eta may be too large", mean_prev));
+        }
+        mean_prev = mean;
+        if config.checkpoint.save_every > 0 && epoch % config.checkpoint.save_every == 0 {
+            model.save(checkpoint_dir.join(format!("epoch_{:04}", epoch)))?;
+        }
+    }
+    model.save(checkpoint_dir.join("latest"))?;
+    logger.log(Level::Info, &format!("saved {}", checkpoint_dir.join("latest").display()));
+    Ok(())
+}
+
+fn predict(config: &Config, logger: &mut Logger, checkpoint: &Path, datetime: DateTime<Utc>, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let model = load_model(checkpoint)?;
+    let data = load_data(config, vec![datetime])?;
+    check_shape(&model, &data)?;
+    // 先の時刻のデータはないので、流入境界のときもmarginは入力の値のままにする
+    let (u_vert, u_hori, rho) = lattice_input(&data, &model, datetime)?;
+    let forward = model.forward(u_vert, u_hori, rho);
+    check_finite(&forward, datetime)?;
+    let normalization = model.normalization();
+    let (u_vert, u_hori) = forward.output_velocity();
+    let u_vert = normalization.velocity_from_lattice(&u_vert.to_owned());
+    let u_hori = normalization.velocity_from_lattice(&u_hori.to_owned());
+    // 保存はnpyと同じく北向き正のv、東向き正のu 計算できないmarginはNaNのまま
+    let (u, v) = data.grid().to_meteorological_velocity(&u_vert, &u_hori);
+
+    let output = output.map(Path::to_path_buf).unwrap_or_else(|| Path::new(&config.data.data_dir).join("pred"));
+    fs::create_dir_all(&output)?;
+    let suffix = datetime.format("%Y%m%d%H").to_string() + ".npy";
+    v.write_npy(File::create(output.join("u_vert_".to_string() + &suffix))?)?;
+    u.write_npy(File::create(output.join("u_hori_".to_string() + &suffix))?)?;
+    logger.log(Level::Info, &format!("wrote forecast from {} to {}", datetime, output.display()));
+    Ok(())
+}
+
+fn evaluate(config: &Config, logger: &mut Logger, checkpoint: &Path) -> Result<(), Box<dyn Error>> {
+    let range = config.period.test.as_ref().ok_or_else(|| ConfigError::Invalid("[period.test] is required to evaluate".to_string()))?;
+    let datetimes = range.datetimes();
+    if datetimes.len() < 2 {
+        return Err(Box::new(ConfigError::Invalid("[period.test] must contain at least two datetimes".to_string())));
+    }
+    let model = load_model(checkpoint)?;
+    let data = load_data(config, datetimes.clone())?;
+    check_shape(&model, &data)?;
+    let scale = model.normalization().velocity_scale;
+    let margin = model.output_margin();
+
+    let (mut model_total, mut persistence_total) = (0.0, 0.0);
+    println!("{:<26} {:>14} {:>18}", "datetime", "rmse[m/s]", "persistence[m/s]");
+    for pair in datetimes.windows(2) {
+        let (u_vert, u_hori, _) = lattice_input(&data, &model, pair[0])?;
+        let (u_vert_ans, u_hori_ans, _) = lattice_input(&data, &model, pair[1])?;
+        let persistence = model::rmse(u_vert.view(), u_hori.view(), u_vert_ans.view(), u_hori_ans.view(), margin) * scale;
+        let forward = forward(&data, &model, pair[0], pair[1])?;
+        let (u_vert, u_hori) = forward.output_velocity();
+        let rmse = model::rmse(u_vert, u_hori, u_vert_ans.view(), u_hori_ans.view(), margin) * scale;
+        println!("{:<26} {:>14.4} {:>18.4}", pair[0].to_rfc3339(), rmse, persistence);
+        model_total += rmse;
+        persistence_total += persistence;
+    }
+    let n = (datetimes.len() - 1) as f64;
+    println!("{:<26} {:>14.4} {:>18.4}", "mean", model_total / n, persistence_total / n);
+    logger.log(Level::Info, &format!("evaluated {} over {} pairs", checkpoint.display(), datetimes.len() - 1));
+    Ok(())
+}
+
+fn inspect(checkpoint: &Path) -> Result<(), Box<dyn Error>> {
+    let model = load_model(checkpoint)?;
+    let normalization = model.normalization();
+    println!("checkpoint: {}", checkpoint.display());
+    println!("grid: {} x {}  layers: {}  margin: {} (output margin {})  boundary: {:?}", model.row(), model.col(), model.layers(), model.margin(), model.output_margin(), model.boundary());
+    println!("velocity_scale: {} m/s  pressure_ref: {} Pa", normalization.velocity_scale, normalization.pressure_ref);
+    println!("collision: {:?}", model.collision());
+    if model.conservation_penalty() > 0.0 {
+        println!("conservation_penalty: {}", model.conservation_penalty());
+    }
+    if let Some(smagorinsky) = model.smagorinsky() {
+        println!("smagorinsky: {:?}", smagorinsky);
+    }
+    println!("{:<16} {:>12} {:>12} {:>12}", "weight", "min", "mean", "max");
+    for (k, w) in model.streaming_weights().iter().enumerate() {
+        print_stats(&format!("streaming[{}].w0", k), w.w0().iter());
+        print_stats(&format!("streaming[{}].w1", k), w.w1().iter());
+    }
+    for (k, w) in model.colliding_weights().iter().enumerate() {
+        print_stats(&format!("colliding[{}].w1", k), w.w1().iter());
+        print_stats(&format!("colliding[{}].w2", k), w.w2().iter());
+        print_stats(&format!("colliding[{}].w3", k), w.w3().iter());
+        print_stats(&format!("colliding[{}].w4", k), w.w4().iter());
+        if let CollisionOperator::Mrt { .. } = w.operator() {
+            println!("colliding[{}].rates {:?}", k, w.rates());
+        }
+        if let Some(smagorinsky) = w.smagorinsky().filter(|smagorinsky| smagorinsky.trainable) {
+            println!("colliding[{}].smagorinsky {}", k, smagorinsky.constant);
+        }
+    }
+    if let Some(coriolis) = model.coriolis() {
+        print_stats("coriolis", coriolis.iter());
+    }
+    Ok(())
+}
+
+// marginのNaNは除いて集計する
+fn print_stats<'a, I: Iterator<Item = &'a f64>>(name: &str, values: I) {
+    let (mut min, mut max, mut sum, mut count) = (f64::INFINITY, f64::NEG_INFINITY, 0.0, 0);
+    for x in values.filter(|x| !x.is_nan()) {
+        min = min.min(*x);
+        max = max.max(*x);
+        sum += x;
+        count += 1;
+    }
+    println!("{:<16} {:>12.6} {:>12.6} {:>12.6}", name, min, sum / count as f64, max);
+}
+
+fn all_datetimes(config: &Config) -> Result<Vec<DateTime<Utc>>, ConfigError> {
+    let mut datetimes: Vec<DateTime<Utc>> = [&config.period.train, &config.period.test].into_iter().flatten()
+        .flat_map(|range| range.datetimes())
+        .collect();
+    datetimes.sort();
+    datetimes.dedup();
+    if datetimes.is_empty() {
+        return Err(ConfigError::Invalid("[period.train] or [period.test] is required".to_string()));
+    }
+    Ok(datetimes)
+}
+
+// 読めないファイルや形の合わない重みは終了コード1にする
+fn load_model(checkpoint: &Path) -> Result<Model, CliError> {
+    Model::load(checkpoint).map_err(|e| CliError(format!("{}: {}", checkpoint.display(), e)))
+}
+
+fn load_data(config: &Config, datetimes: Vec<DateTime<Utc>>) -> Result<MeteorologicalData, Box<dyn Error>> {
+    let region = config.region();
+    match config.data.format {
+        DataFormat::Npy => Ok(repo::get_meteorological_data_from_dir(&config.data.data_dir, datetimes, &config.grid().unwrap(), &region)
+            .map_err(|e| CliError(e.to_string()))?),
+        DataFormat::NetCdf => {
+            let path = config.data.netcdf_path.as_ref().unwrap();
+            Ok(repo::get_meteorological_data_from_netcdf(path, datetimes, &config.netcdf_variables(), &region)
+                .map_err(|e| CliError(format!("{}: {}", path, e)))?)
+        }
+    }
+}
+
+fn new_model(config: &Config, data: &MeteorologicalData) -> Result<Model, ConfigError> {
+    let (row, col) = data.grid().shape();
+    if config.model.row.unwrap_or(row) != row || config.model.col.unwrap_or(col) != col {
+        return Err(ConfigError::Invalid(format!("model size does not match the region ({} x {})", row, col)));
+    }
+    let fits = match config.model.boundary {
+        Boundary::Shrink => config.model.margin > 0 && 2 * (config.model.margin + config.model.layers - 1) < row.min(col),
+        Boundary::Periodic => true,
+        Boundary::Inflow => config.model.margin > 0 && 2 * config.model.margin < row.min(col),
+    };
+    if !fits {
+        return Err(ConfigError::Invalid(format!("margin {} and {} layers do not fit in {} x {}", config.model.margin, config.model.layers, row, col)));
+    }
+    let mut model = Model::with_boundary(row, col, config.model.margin, config.model.layers, config.model.boundary);
+    model.set_normalization(Normalization { velocity_scale: config.model.velocity_scale, pressure_ref: config.model.pressure_ref });
+    if let Some(path) = &config.model.obstacle {
+        let obstacle = File::open(path).ok().and_then(|file| Array2::<f64>::read_npy(file).ok())
+            .ok_or_else(|| ConfigError::Invalid(format!("cannot read model.obstacle {}", path)))?;
+        if obstacle.dim() != (row, col) {
+            return Err(ConfigError::Invalid(format!("model.obstacle must be {} x {}", row, col)));
+        }
+        model.set_obstacle(obstacle.mapv(|x| x != 0.0));
+    }
+    model.set_edges(config.model.edges);
+    model.set_collision(config.model.collision);
+    model.set_smagorinsky(config.model.smagorinsky);
+    if config.model.coriolis {
+        // 格子単位の速度1で1ステップに1マス(南北方向)進む
+        let time_step = data.grid().dy() / config.model.velocity_scale;
+        model.set_coriolis(data.grid().lattice_coriolis(time_step));
+    }
+    Ok(model)
+}
+
+// 浮力は行を高さとする鉛直断面用で、緯度経度の格子では北向きの力になってしまうので使えない
+fn check_shape(model: &Model, data: &MeteorologicalData) -> Result<(), CliError> {
+    if data.grid().shape() != (model.row(), model.col()) {
+        return Err(CliError(format!("checkpoint is {} x {} but the region is {} x {}", model.row(), model.col(), data.grid().row(), data.grid().col())));
+    }
+    if model.buoyancy().is_some() {
+        return Err(CliError("checkpoint has buoyancy, which is only for vertical sections (rows = height) and cannot be used on a lat/lon grid".to_string()));
+    }
+    Ok(())
+}
+
+fn lattice_input(data: &MeteorologicalData, model: &Model, datetime: DateTime<Utc>) -> Result<LatticeFields, CliError> {
+    let missing = || CliError(format!("no data for {}", datetime));
+    let (u_vert, u_hori) = data.lattice_velocity(datetime).ok_or_else(missing)?;
+    let pressure = data.get(datetime, MeteorologicalType::Pressure).ok_or_else(missing)?;
+    let (u_vert, u_hori, rho) = model.normalization().to_lattice(&u_vert, &u_hori, pressure);
+    let mut finite = true;
+    Zip::from(&u_vert).and(&u_hori).and(&rho).for_each(|u_vert, u_hori, rho| {
+        finite &= u_vert.is_finite() && u_hori.is_finite() && rho.is_finite();
+    });
+    if !finite {
+        return Err(CliError(format!("data for {} contains missing values", datetime)));
+    }
+    Ok((u_vert, u_hori, rho))
+}
+
+// startの場からendの場を予測する 流入境界のときはstartからendまでのデータを補間してmarginを埋める
+fn forward(data: &MeteorologicalData, model: &Model, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Forward, CliError> {
+    let (u_vert, u_hori, rho) = lattice_input(data, model, start)?;
+    let forward = if model.boundary() != Boundary::Inflow {
+        model.forward(u_vert, u_hori, rho)
+    } else {
+        let inflows = boundary::inflow_boundaries(data, model.normalization(), start, end, model.layers())
+            .ok_or_else(|| CliError(format!("no boundary data between {} and {}", start, end)))?;
+        model.forward_with_inflow(u_vert, u_hori, rho, &inflows)
+    };
+    check_finite(&forward, start)?;
+    Ok(forward)
+}
+
+// marginのNaNと区別するため、内側にNaN, infが出たら発散したとしてエラーにする
+fn check_finite(forward: &Forward, start: DateTime<Utc>) -> Result<(), CliError> {
+    let (output, (u_vert, u_hori)) = (forward.output(), forward.output_velocity());
+    let (u_vert, u_hori) = (interior(u_vert, output.margin()), interior(u_hori, output.margin()));
+    let finite = u_vert.iter().chain(u_hori.iter()).chain(output.rho_interior().iter()).all(|x| x.is_finite());
+    if !finite {
+        return Err(CliError(format!("forecast from {} diverged (non-finite wind or density inside the margin); try model.collision type = \"regularized\" or a larger velocity_scale", start)));
+    }
+    Ok(())
+}
+
+// 各streamの質量と運動量の変化の絶対値の最大
+fn max_drift(forward: &Forward) -> Conservation {
+    forward.conservation_drift().iter().fold(Conservation::default(), |max, drift| Conservation {
+        mass: max.mass.max(drift.mass.abs()),
+        momentum_vert: max.momentum_vert.max(drift.momentum_vert.abs()),
+        momentum_hori: max.momentum_hori.max(drift.momentum_hori.abs()),
+    })
+}
+
+impl Logger {
+    fn new(config: &Config) -> Result<Logger, Box<dyn Error>> {
+        let level = match config.logging.level.as_str() {
+            "error" | "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            other => return Err(Box::new(ConfigError::Invalid(format!("unknown logging.level {}", other)))),
+        };
+        let file = match &config.logging.file {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(Logger { level, file })
+    }
+
+    fn log(&mut self, level: Level, msg: &str) {
+        if level > self.level {
+            return;
+        }
+        let line = format!("{} {}", Utc::now().format("%Y-%m-%dT%H:%M:%S"), msg);
+        eprintln!("{}", line);
+        if let Some(file) = &mut self.file {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    #[test]
+    fn test_check_finite() {
+        let start = Utc.with_ymd_and_hms(2020, 3, 20, 0, 0, 0).unwrap();
+        let model = Model::new(7, 7, 1, 2);
+        let forward = model.forward(Array2::from_elem((7, 7), 0.01), Array2::from_elem((7, 7), 0.02), Array2::ones((7, 7)));
+        assert!(check_finite(&forward, start).is_ok());
+
+        // 速度がオーバーフローするほど大きいと発散する
+        let forward = model.forward(Array2::from_elem((7, 7), 1e200), Array2::from_elem((7, 7), 0.02), Array2::ones((7, 7)));
+        let message = check_finite(&forward, start).unwrap_err().to_string();
+        assert!(message.contains("diverged"), "{}", message);
+    }
+
+    // 質量だけでなく運動量の変化も集計する
+    #[test]
+    fn test_max_drift() {
+        use ndarray::{Array3, s};
+        let (row, col) = (6, 7);
+        let (u_vert, u_hori, rho) = (Array2::from_elem((row, col), 0.01), Array2::from_elem((row, col), 0.02), Array2::ones((row, col)));
+        let model = Model::with_boundary(row, col, 0, 2, Boundary::Periodic);
+        let drift = max_drift(&model.forward(u_vert.clone(), u_hori.clone(), rho.clone()));
+        assert!(drift.mass < 0.000000001 && drift.momentum_vert < 0.000000001 && drift.momentum_hori < 0.000000001, "{:?}", drift);
+
+        // 東向き(q = 5)の成分に足すと、質量と横の運動量が同じだけ増える
+        let dir = std::env::temp_dir().join("lbm_rust_test_max_drift");
+        model.save(&dir).unwrap();
+        let path = dir.join("streaming_1_w0.npy");
+        let mut w0 = Array3::<f64>::read_npy(File::open(&path).unwrap()).unwrap();
+        w0.slice_mut(s![.., .., 5]).fill(0.01);
+        w0.write_npy(File::create(&path).unwrap()).unwrap();
+        let model = load_model(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let drift = max_drift(&model.forward(u_vert, u_hori, rho));
+        assert!((drift.mass - 0.01 * (row * col) as f64).abs() < 0.000000001, "{:?}", drift);
+        assert!((drift.momentum_hori - 0.01 * (row * col) as f64).abs() < 0.000000001, "{:?}", drift);
+        assert!(drift.momentum_vert < 0.000000001, "{:?}", drift);
+    }
+
+    // 緯度経度の格子では浮力のあるチェックポイントを使わない
+    #[test]
+    fn test_check_shape_rejects_buoyancy() {
+        use lbm_rust::collision::Buoyancy;
+        use lbm_rust::geo::GeoGrid;
+        let dir = std::env::temp_dir().join("lbm_rust_test_check_shape_rejects_buoyancy");
+        let data_dir = dir.to_str().unwrap().to_string() + "/";
+        let grid = GeoGrid::new(35.0, 135.0, -0.05, 0.0625, 7, 7);
+        let datetimes = vec![Utc.with_ymd_and_hms(2020, 3, 20, 0, 0, 0).unwrap()];
+        write_synthetic_data(&data_dir, &SyntheticFlow::Uniform { u_vert: 0.0, u_hori: 5.0 }, &grid, &datetimes).unwrap();
+        let data = repo::get_meteorological_data_from_dir(&data_dir, datetimes, &grid, &Region::new(None, Coarsening::None)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut model = Model::new(7, 7, 1, 2);
+        assert!(check_shape(&model, &data).is_ok());
+        model.set_buoyancy(Some(Buoyancy { coefficient: 0.01, reference: 290.0 }));
+        let message = check_shape(&model, &data).unwrap_err().to_string();
+        assert!(message.contains("buoyancy"), "{}", message);
+    }
+}
@@ -0,0 +1,422 @@
+use serde::{Deserialize, Serialize};
+use crate::lattice::{Lattice, D2Q9};
+
+// collideの緩和のしかた
+// どれもf_next = f - A (f - feq) + (I - A/2) Sの形で、Aは9x9の行列(collision_matrix())、SはGuoの外力項
+// fの添字はi = (dr+1) * 3 + (dc+1)  BGK(τ = 2)はA = I/2で、f_next = (feq + f) / 2 + 3/4 S
+// TRTはfを逆向きの方向(添字8 - i)と組にして、対称部分と反対称部分を別の緩和率で緩和する
+// MRTはモーメント m = M fの空間で成分ごとに緩和する A = M^-1 diag(rates) M
+// 正則化(regularized)はf - feqを2次までのエルミート多項式に射影(P)してから緩和する A = I - (1 - rate) P
+// 3次以上(ε, q)の非平衡部分を捨てるので、格子単位の風速が大きいときにBGKより安定
+// Smagorinskyを使うときは、粘性を決める緩和率(viscous_rate())をセルごとの歪み速度から決めた値に置き換える
+
+// ratesはモーメント(rho, e, ε, j_hori, q_hori, j_vert, q_vert, p_xx, p_xy)ごとの緩和率 0 < rate < 2
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CollisionOperator {
+    // 1つの緩和率0.5(τ = 2)で、平衡分布の係数w1..w4を学習する
+    #[default]
+    Bgk,
+    // rateは対称部分の緩和率(粘性 既定はBGKと同じ0.5)、反対称部分の緩和率はmagic Λ = (1/rate - 1/2)(1/rate_anti - 1/2)から決める
+    // Λ = 1/4でbounce-backの壁の位置が格子の中間にちょうど来る
+    Trt {
+        #[serde(default = "default_rate")]
+        rate: f64,
+        #[serde(default = "default_trt_magic")]
+        magic: f64,
+    },
+    // rateは緩和率(既定はBGKと同じ0.5)
+    Regularized {
+        #[serde(default = "default_rate")]
+        rate: f64,
+    },
+    // trainableのときは層ごとにratesも学習する
+    Mrt {
+        #[serde(default = "default_mrt_rates")]
+        rates: [f64; 9],
+        #[serde(default)]
+        trainable: bool,
+    },
+}
+
+// Smagorinskyの渦粘性 constantはSmagorinsky定数C_s、trainableのときは層ごとに学習する
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Smagorinsky {
+    pub constant: f64,
+    #[serde(default)]
+    pub trainable: bool,
+}
+
+// Boussinesq近似の浮力 温度の偏差に比例する外力F_vert = -coefficient * (T - reference)を上向き(-vert)にかける
+// 行を高さとする鉛直断面(上の行ほど高い)で使う 緯度経度の格子では-vertが北向きになるので使えない(cliはエラーにする) coefficientは格子単位のβ g 密度は基準値1で近似するので、外力はfによらない
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Buoyancy {
+    pub coefficient: f64,
+    #[serde(default)]
+    pub reference: f64,
+}
+
+impl Buoyancy {
+    pub fn force_vert(self, temperature: f64) -> f64 {
+        -self.coefficient * (temperature - self.reference)
+    }
+}
+
+// smagorinsky_rate()の値と、その微分
+pub struct SmagorinskyRate {
+    pub rate: f64,
+    pub d_neq: [f64; 9],
+    pub d_rho: f64,
+    pub d_constant: f64,
+    pub d_rate0: f64,
+}
+
+// 保存量(rho, j)と応力(粘性 τ = 2)はBGKと同じ0.5、それ以外(エネルギーと熱流束)は少し速く緩和して安定にする
+pub const MRT_RATES: [f64; 9] = [0.5, 1.4, 1.4, 0.5, 1.2, 0.5, 1.2, 0.5, 0.5];
+
+fn default_mrt_rates() -> [f64; 9] { MRT_RATES }
+fn default_rate() -> f64 { 0.5 }
+fn default_trt_magic() -> f64 { 0.25 }
+
+impl CollisionOperator {
+    // 層ごとの緩和率の初期値 BGKのときはすべて0.5
+    pub fn initial_rates(self) -> [f64; 9] {
+        match self {
+            CollisionOperator::Bgk | CollisionOperator::Trt { .. } | CollisionOperator::Regularized { .. } => [0.5; 9],
+            CollisionOperator::Mrt { rates, .. } => rates,
+        }
+    }
+
+    pub fn rates_trainable(self) -> bool {
+        matches!(self, CollisionOperator::Mrt { trainable: true, .. })
+    }
+
+    // 粘性を決める緩和率 BGKは全体、TRTは対称部分、MRTは応力(p_xx, p_xy)の緩和率
+    pub fn viscous_rate(self, rates: &[f64; 9]) -> f64 {
+        match self {
+            CollisionOperator::Bgk => 0.5,
+            CollisionOperator::Trt { rate, .. } | CollisionOperator::Regularized { rate } => rate,
+            CollisionOperator::Mrt { .. } => rates[7],
+        }
+    }
+}
+
+// Lallemand & LuoのD2Q9のモーメント 行が直交しているので、M^-1 = M^T diag(1 / |行|^2)
+pub fn moment_matrix() -> [[f64; 9]; 9] {
+    let mut m = [[0.0; 9]; 9];
+    for i in 0..9 {
+        let (dr, dc) = D2Q9::velocity(i);
+        let e2 = dr * dr + dc * dc;
+        let column = [
+            1.0,
+            -4.0 + 3.0 * e2,
+            4.0 - 10.5 * e2 + 4.5 * e2 * e2,
+            dc,
+            (-5.0 + 3.0 * e2) * dc,
+            dr,
+            (-5.0 + 3.0 * e2) * dr,
+            dc * dc - dr * dr,
+            dc * dr,
+        ];
+        for (row, x) in m.iter_mut().zip(column) {
+            row[i] = x;
+        }
+    }
+    m
+}
+
+fn moment_norms(m: &[[f64; 9]; 9]) -> [f64; 9] {
+    let mut norms = [0.0; 9];
+    for (norm, row) in norms.iter_mut().zip(m) {
+        *norm = row.iter().map(|x| x * x).sum();
+    }
+    norms
+}
+
+// f_next = f - A (f - feq) + (I - A/2) SのA ratesはCollidingWeightが持っている層ごとの値
+pub fn collision_matrix(operator: CollisionOperator, rates: &[f64; 9]) -> [[f64; 9]; 9] {
+    let mut a = [[0.0; 9]; 9];
+    match operator {
+        CollisionOperator::Bgk => {
+            for (i, row) in a.iter_mut().enumerate() {
+                row[i] = 0.5;
+            }
+        }
+        CollisionOperator::Trt { rate, magic } => {
+            let rate_anti = 1.0 / (0.5 + magic / (1.0 / rate - 0.5));
+            for (i, row) in a.iter_mut().enumerate() {
+                row[i] += (rate + rate_anti) / 2.0;
+                row[D2Q9.opposite(i)] += (rate - rate_anti) / 2.0;
+            }
+        }
+        CollisionOperator::Regularized { rate } => {
+            let p = hermite_projection();
+            for (i, (row, p_row)) in a.iter_mut().zip(&p).enumerate() {
+                for (a_ij, p_ij) in row.iter_mut().zip(p_row) {
+                    *a_ij = -(1.0 - rate) * p_ij;
+                }
+                row[i] += 1.0;
+            }
+        }
+        CollisionOperator::Mrt { .. } => {
+            let m = moment_matrix();
+            let norms = moment_norms(&m);
+            for (i, row) in a.iter_mut().enumerate() {
+                for (j, a_ij) in row.iter_mut().enumerate() {
+                    *a_ij = (0..9).map(|k| m[k][i] * rates[k] * m[k][j] / norms[k]).sum();
+                }
+            }
+        }
+    }
+    a
+}
+
+// 2次までのエルミート多項式への射影 (P x)_i = C_i (a0 + e_i・a1 / cs^2 + Q_i : a2 / (2 cs^4))
+// a0 = Σx、a1 = Σe x、a2 = ΣQ x、Q = e e - cs^2 I  質量、運動量、応力は変えない
+pub fn hermite_projection() -> [[f64; 9]; 9] {
+    let cs2 = D2Q9.sound_speed2();
+    let mut p = [[0.0; 9]; 9];
+    for (i, row) in p.iter_mut().enumerate() {
+        let (dr_i, dc_i) = D2Q9::velocity(i);
+        let c_i = D2Q9::WEIGHTS[i];
+        for (j, p_ij) in row.iter_mut().enumerate() {
+            let (dr_j, dc_j) = D2Q9::velocity(j);
+            let e_prod = dr_i * dr_j + dc_i * dc_j;
+            let q_prod = e_prod * e_prod - cs2 * (dr_i * dr_i + dc_i * dc_i) - cs2 * (dr_j * dr_j + dc_j * dc_j) + 2.0 * cs2 * cs2;
+            *p_ij = c_i * (1.0 + e_prod / cs2 + q_prod / (2.0 * cs2 * cs2));
+        }
+    }
+    p
+}
+
+// viscous_rate()をrateに置き換えたときのA
+pub fn collision_matrix_with_viscous_rate(operator: CollisionOperator, rates: &[f64; 9], rate: f64) -> [[f64; 9]; 9] {
+    match operator {
+        CollisionOperator::Bgk => {
+            let mut a = [[0.0; 9]; 9];
+            for (i, row) in a.iter_mut().enumerate() {
+                row[i] = rate;
+            }
+            a
+        }
+        CollisionOperator::Trt { magic, .. } => collision_matrix(CollisionOperator::Trt { rate, magic }, rates),
+        CollisionOperator::Regularized { .. } => collision_matrix(CollisionOperator::Regularized { rate }, rates),
+        CollisionOperator::Mrt { .. } => {
+            let mut rates = *rates;
+            rates[7] = rate;
+            rates[8] = rate;
+            collision_matrix(operator, &rates)
+        }
+    }
+}
+
+// collision_matrix_with_viscous_rate()をrateで微分したもの
+pub fn viscous_rate_derivative(operator: CollisionOperator, rate: f64) -> [[f64; 9]; 9] {
+    let mut d = [[0.0; 9]; 9];
+    match operator {
+        CollisionOperator::Bgk => {
+            for (i, row) in d.iter_mut().enumerate() {
+                row[i] = 1.0;
+            }
+        }
+        CollisionOperator::Trt { magic, .. } => {
+            // rate_anti = 1 / (1/2 + Λ / (1/rate - 1/2))
+            let rate_anti = 1.0 / (0.5 + magic / (1.0 / rate - 0.5));
+            let d_rate_anti = -rate_anti * rate_anti * magic / ((1.0 / rate - 0.5).powi(2) * rate * rate);
+            for (i, row) in d.iter_mut().enumerate() {
+                row[i] += (1.0 + d_rate_anti) / 2.0;
+                row[D2Q9.opposite(i)] += (1.0 - d_rate_anti) / 2.0;
+            }
+        }
+        CollisionOperator::Regularized { .. } => d = hermite_projection(),
+        CollisionOperator::Mrt { .. } => {
+            let m = moment_matrix();
+            let norms = moment_norms(&m);
+            for (i, row) in d.iter_mut().enumerate() {
+                for (j, d_ij) in row.iter_mut().enumerate() {
+                    *d_ij = [7, 8].iter().map(|&k| m[k][i] * m[k][j] / norms[k]).sum();
+                }
+            }
+        }
+    }
+    d
+}
+
+// Smagorinskyの有効緩和率 τ = (τ0 + sqrt(τ0^2 + 18√2 C_s^2 |Π| / rho)) / 2、τ0 = 1 / rate0 (格子間隔1)
+// Π = Σe e (f - feq)は非平衡部分の運動量流束で、歪み速度に比例する
+pub fn smagorinsky_rate(rate0: f64, constant: f64, neq: &[f64; 9], rho: f64) -> SmagorinskyRate {
+    let (mut pi_vv, mut pi_vh, mut pi_hh) = (0.0, 0.0, 0.0);
+    for (i, n) in neq.iter().enumerate() {
+        let (dr, dc) = D2Q9::velocity(i);
+        pi_vv += dr * dr * n;
+        pi_vh += dr * dc * n;
+        pi_hh += dc * dc * n;
+    }
+    let pi_norm = (pi_vv * pi_vv + 2.0 * pi_vh * pi_vh + pi_hh * pi_hh).sqrt();
+    let coef = 18.0 * 2.0_f64.sqrt() * constant * constant / rho;
+    let tau0 = 1.0 / rate0;
+    let root = (tau0 * tau0 + coef * pi_norm).sqrt();
+    let rate = 2.0 / (tau0 + root);
+    let d_k = -rate * rate / (4.0 * root); // d(rate)/d(18√2 C_s^2 |Π| / rho)
+    let mut d_neq = [0.0; 9];
+    if pi_norm > 0.0 {
+        for (i, d) in d_neq.iter_mut().enumerate() {
+            let (dr, dc) = D2Q9::velocity(i);
+            *d = d_k * coef * (pi_vv * dr * dr + 2.0 * pi_vh * dr * dc + pi_hh * dc * dc) / pi_norm;
+        }
+    }
+    SmagorinskyRate {
+        rate,
+        d_neq,
+        d_rho: -d_k * coef * pi_norm / rho,
+        d_constant: d_k * 36.0 * 2.0_f64.sqrt() * constant * pi_norm / rho,
+        d_rate0: rate * rate * (1.0 + tau0 / root) / (2.0 * rate0 * rate0),
+    }
+}
+
+// a * v
+pub fn mul(a: &[[f64; 9]; 9], v: &[f64; 9]) -> [f64; 9] {
+    let mut out = [0.0; 9];
+    for (o, row) in out.iter_mut().zip(a) {
+        *o = row.iter().zip(v).map(|(a, v)| a * v).sum();
+    }
+    out
+}
+
+// a^T * v backprop用
+pub fn mul_transpose(a: &[[f64; 9]; 9], v: &[f64; 9]) -> [f64; 9] {
+    let mut out = [0.0; 9];
+    for (row, v) in a.iter().zip(v) {
+        for (o, a) in out.iter_mut().zip(row) {
+            *o += a * v;
+        }
+    }
+    out
+}
+
+// MRTの緩和率での微分 f_next = f - M^-1 diag(rates) M (f - feq) + M^-1 (I - diag(rates)/2) M S なので
+// dL/d(rate_k) = -(M^-T delta)_k * ((M (f - feq))_k + (M S)_k / 2)
+pub fn rate_gradient(delta: &[f64; 9], neq: &[f64; 9], source: &[f64; 9]) -> [f64; 9] {
+    let m = moment_matrix();
+    let norms = moment_norms(&m);
+    let (m_neq, m_source) = (mul(&m, neq), mul(&m, source));
+    let mut grad = [0.0; 9];
+    for k in 0..9 {
+        let m_delta: f64 = m[k].iter().zip(delta).map(|(m, d)| m * d).sum::<f64>() / norms[k];
+        grad[k] = -m_delta * (m_neq[k] + m_source[k] / 2.0);
+    }
+    grad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collision_matrix() {
+        // 行が直交していること、緩和率がすべて0.5のMRTはBGKと同じになること
+        let m = moment_matrix();
+        for k in 0..9 {
+            for l in 0..k {
+                assert!(m[k].iter().zip(&m[l]).map(|(a, b)| a * b).sum::<f64>().abs() < 0.000000001);
+            }
+        }
+        let mrt = collision_matrix(CollisionOperator::Mrt { rates: [0.5; 9], trainable: false }, &[0.5; 9]);
+        let bgk = collision_matrix(CollisionOperator::Bgk, &[0.5; 9]);
+        for i in 0..9 {
+            for j in 0..9 {
+                assert!((mrt[i][j] - bgk[i][j]).abs() < 0.000000001);
+            }
+        }
+        // TRTはrho, e, ε, p_xx, p_xyを対称部分の、j, qを反対称部分の緩和率にしたMRTと同じ Λ = 9/4ならBGK
+        let trt = collision_matrix(CollisionOperator::Trt { rate: 0.5, magic: 0.25 }, &[0.5; 9]);
+        let rates = [0.5, 0.5, 0.5, 1.5, 1.5, 1.5, 1.5, 0.5, 0.5];
+        let mrt = collision_matrix(CollisionOperator::Mrt { rates, trainable: false }, &rates);
+        let trt_bgk = collision_matrix(CollisionOperator::Trt { rate: 0.5, magic: 2.25 }, &[0.5; 9]);
+        for i in 0..9 {
+            for j in 0..9 {
+                assert!((trt[i][j] - mrt[i][j]).abs() < 0.000000001);
+                assert!((trt_bgk[i][j] - bgk[i][j]).abs() < 0.000000001);
+            }
+        }
+
+        // エルミート射影は射影で、応力は残し、ε, qは消す
+        let p = hermite_projection();
+        for i in 0..9 {
+            for j in 0..9 {
+                let p2: f64 = (0..9).map(|k| p[i][k] * p[k][j]).sum();
+                assert!((p2 - p[i][j]).abs() < 0.000000001);
+            }
+        }
+        let (u_vert, u_hori) = (0.1, -0.2);
+        let mut feq = [0.0; 9];
+        for (i, feq) in feq.iter_mut().enumerate() {
+            let (dr, dc) = D2Q9::velocity(i);
+            let u_prod = dr * u_vert + dc * u_hori;
+            *feq = D2Q9::WEIGHTS[i] * (1.0 + 3.0 * u_prod + 4.5 * u_prod * u_prod - 1.5 * (u_vert * u_vert + u_hori * u_hori));
+        }
+        for (x, y) in mul(&p, &feq).iter().zip(&feq) {
+            assert!((x - y).abs() < 0.000000001);
+        }
+        for k in [2, 4, 6] {
+            assert!(mul(&p, &m[k]).iter().all(|x| x.abs() < 0.000000001), "moment {}", k);
+        }
+
+        // 保存量の緩和率に関係なく、質量と運動量の差は(feqが保存するとき)残らない
+        let a = collision_matrix(CollisionOperator::Mrt { rates: MRT_RATES, trainable: false }, &MRT_RATES);
+        let mut neq = [0.0; 9];
+        for (i, n) in neq.iter_mut().enumerate() {
+            *n = 0.01 * m[1][i] - 0.02 * m[2][i] + 0.03 * m[4][i] + 0.02 * m[7][i]; // rho, jの成分がない
+        }
+        let relaxed = mul(&a, &neq);
+        for k in [0, 3, 5] {
+            assert!(m[k].iter().zip(&relaxed).map(|(m, x)| m * x).sum::<f64>().abs() < 0.000000001);
+        }
+    }
+
+    #[test]
+    fn test_smagorinsky_rate() {
+        // 基準の緩和率ではcollision_matrix()と同じ、dA/d(rate)は中心差分と同じになること
+        let operators = [
+            CollisionOperator::Bgk,
+            CollisionOperator::Trt { rate: 0.6, magic: 0.25 },
+            CollisionOperator::Regularized { rate: 0.6 },
+            CollisionOperator::Mrt { rates: MRT_RATES, trainable: false },
+        ];
+        let h = 0.000001;
+        for operator in operators {
+            let rate = operator.viscous_rate(&MRT_RATES);
+            let (a, a_les) = (collision_matrix(operator, &MRT_RATES), collision_matrix_with_viscous_rate(operator, &MRT_RATES, rate));
+            let (plus, minus) = (collision_matrix_with_viscous_rate(operator, &MRT_RATES, 0.7 + h), collision_matrix_with_viscous_rate(operator, &MRT_RATES, 0.7 - h));
+            let d = viscous_rate_derivative(operator, 0.7);
+            for i in 0..9 {
+                for j in 0..9 {
+                    assert!((a[i][j] - a_les[i][j]).abs() < 0.000000001, "{:?}", operator);
+                    assert!(((plus[i][j] - minus[i][j]) / (2.0 * h) - d[i][j]).abs() < 0.000001, "{:?}", operator);
+                }
+            }
+        }
+
+        // 非平衡部分がない、あるいはC_s = 0のときは基準の緩和率のまま 歪みが大きいほど緩和率が下がる(粘性が増える)
+        let neq = [0.001, -0.002, 0.001, 0.003, 0.0, -0.001, 0.0, 0.002, -0.004];
+        assert!((smagorinsky_rate(0.6, 0.2, &[0.0; 9], 1.0).rate - 0.6).abs() < 0.000000001);
+        assert!((smagorinsky_rate(0.6, 0.0, &neq, 1.0).rate - 0.6).abs() < 0.000000001);
+        let les = smagorinsky_rate(0.6, 0.2, &neq, 1.0);
+        assert!(les.rate < 0.6);
+        let scaled: Vec<f64> = neq.iter().map(|n| n * 10.0).collect();
+        assert!(smagorinsky_rate(0.6, 0.2, &scaled.try_into().unwrap(), 1.0).rate < les.rate);
+        // d(rate)/d(neq), d(rate)/d(rho), d(rate)/d(C_s), d(rate)/d(rate0)を中心差分と比べる
+        let check = |numerical: f64, analytical: f64| assert!((numerical - analytical).abs() < 0.000001 * analytical.abs().max(1.0), "{} {}", numerical, analytical);
+        for i in 0..9 {
+            let (mut plus, mut minus) = (neq, neq);
+            plus[i] += h;
+            minus[i] -= h;
+            check((smagorinsky_rate(0.6, 0.2, &plus, 1.0).rate - smagorinsky_rate(0.6, 0.2, &minus, 1.0).rate) / (2.0 * h), les.d_neq[i]);
+        }
+        check((smagorinsky_rate(0.6, 0.2, &neq, 1.0 + h).rate - smagorinsky_rate(0.6, 0.2, &neq, 1.0 - h).rate) / (2.0 * h), les.d_rho);
+        check((smagorinsky_rate(0.6, 0.2 + h, &neq, 1.0).rate - smagorinsky_rate(0.6, 0.2 - h, &neq, 1.0).rate) / (2.0 * h), les.d_constant);
+        check((smagorinsky_rate(0.6 + h, 0.2, &neq, 1.0).rate - smagorinsky_rate(0.6 - h, 0.2, &neq, 1.0).rate) / (2.0 * h), les.d_rate0);
+    }
+}
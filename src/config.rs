@@ -0,0 +1,502 @@
+use std::{env, fmt, fs, io, path::Path};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use dotenv::dotenv;
+use crate::geo::GeoGrid;
+use crate::boundary::Edges;
+use crate::collision::{CollisionOperator, Smagorinsky};
+use crate::lbm::Boundary;
+use crate::repo::{Coarsening, GridRange, NetCdfVariables, Region};
+
+// 実験の設定 TOMLファイルから読み、環境変数で上書きできる
+// 上書きはLBM__<セクション>__<キー>の形(例: LBM__TRAINING__ETA=0.05)で、値はTOMLの値として解釈する(だめなら文字列)
+// 文字列のキー(STRING_KEYSか、ファイルで文字列になっているキー)は、"2020"や"true"でも文字列のまま入れる
+// 互換のため、DATA_DIRはdata.data_dirを上書きする
+// 例はconfig.example.tomlを参照
+
+const ENV_PREFIX: &str = "LBM__";
+const STRING_KEYS: [&str; 9] = [
+    "data.data_dir", "data.netcdf_path", "data.variables.u", "data.variables.v", "data.variables.pressure",
+    "model.obstacle", "checkpoint.dir", "logging.level", "logging.file",
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "cannot read config: {}", e),
+            ConfigError::Parse(msg) => write!(f, "cannot parse config: {}", msg),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data: DataConfig,
+    pub period: PeriodConfig,
+    pub region: RegionConfig,
+    pub model: ModelConfig,
+    pub training: TrainingConfig,
+    pub checkpoint: CheckpointConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DataFormat {
+    Npy,
+    #[serde(rename = "netcdf")]
+    NetCdf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DataConfig {
+    pub data_dir: String,
+    pub format: DataFormat,
+    pub netcdf_path: Option<String>,
+    pub variables: VariablesConfig,
+    pub grid: Option<GridConfig>, // 省略したときはnpyならMSMの格子
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VariablesConfig {
+    pub u: String,
+    pub v: String,
+    pub pressure: String,
+    pub level: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GridConfig {
+    pub lat_first: f64,
+    pub lon_first: f64,
+    pub d_lat: f64,
+    pub d_lon: f64,
+    pub row: usize,
+    pub col: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeriodConfig {
+    pub train: Option<DateRange>,
+    pub test: Option<DateRange>,
+}
+
+// [start, end]をinterval_hoursごとに区切った時刻
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DateRange {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    #[serde(default = "default_interval_hours")]
+    pub interval_hours: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoarseningMethod {
+    None,
+    Decimation,
+    BlockAverage,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegionConfig {
+    pub index: Option<IndexRangeConfig>,
+    pub lat_lon: Option<LatLonRangeConfig>,
+    pub coarsening: CoarseningMethod,
+    pub stride: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IndexRangeConfig {
+    pub row_start: usize,
+    pub row_end: usize,
+    pub col_start: usize,
+    pub col_end: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LatLonRangeConfig {
+    pub lat_min: f64,
+    pub lat_max: f64,
+    pub lon_min: f64,
+    pub lon_max: f64,
+}
+
+// row, colを省略したときはregionで切り出した後の形になる
+// velocity_scale[m/s]が格子単位の速度1、pressure_ref[Pa]が密度1に対応する
+// boundary = "periodic"のときはmargin = 0に、"inflow"のときは1以上にする
+// obstacleはモデルの格子と同じ形のnpy(0以外のセルが障害物)、edgesは端の条件(格子単位)
+// collisionはcollideの緩和のしかた(BGK, TRT, 正則化, MRT)、smagorinskyを書くと渦粘性で粘性を決める緩和率をセルごとに変える
+// coriolis = trueのときはデータの格子の緯度からコリオリ力をかける 1ステップの時間はdy / velocity_scale
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    pub row: Option<usize>,
+    pub col: Option<usize>,
+    pub layers: usize,
+    pub margin: usize,
+    pub boundary: Boundary,
+    pub obstacle: Option<String>,
+    pub edges: Edges,
+    pub collision: CollisionOperator,
+    pub smagorinsky: Option<Smagorinsky>,
+    pub coriolis: bool,
+    pub velocity_scale: f64,
+    pub pressure_ref: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Optimizer {
+    Sgd,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Loss {
+    VelocityMse, // (u_vert - u_vert_ans)^2 + (u_hori - u_hori_ans)^2 の1/2
+}
+
+// conservation_penaltyを正にすると、streamの重みが質量と運動量を作らないようにするペナルティを損失に足す
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrainingConfig {
+    pub eta: f64,
+    pub epochs: usize,
+    pub optimizer: Optimizer,
+    pub loss: Loss,
+    pub conservation_penalty: f64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckpointConfig {
+    pub dir: String,
+    pub save_every: usize, // 何epochごとに保存するか 0なら最後だけ
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String,
+    pub file: Option<String>,
+}
+
+fn default_interval_hours() -> i64 { 1 }
+
+impl Default for DataConfig {
+    fn default() -> DataConfig {
+        DataConfig { data_dir: "./".to_string(), format: DataFormat::Npy, netcdf_path: None, variables: VariablesConfig::default(), grid: None }
+    }
+}
+
+impl Default for VariablesConfig {
+    fn default() -> VariablesConfig {
+        let era5 = NetCdfVariables::era5_surface();
+        VariablesConfig { u: era5.u, v: era5.v, pressure: era5.pressure, level: era5.level }
+    }
+}
+
+impl Default for RegionConfig {
+    fn default() -> RegionConfig {
+        RegionConfig { index: None, lat_lon: None, coarsening: CoarseningMethod::None, stride: 1 }
+    }
+}
+
+impl Default for ModelConfig {
+    fn default() -> ModelConfig {
+        ModelConfig { row: None, col: None, layers: 1, margin: 1, boundary: Boundary::Shrink, obstacle: None, edges: Edges::default(), collision: CollisionOperator::Bgk, smagorinsky: None, coriolis: false, velocity_scale: 100.0, pressure_ref: 101325.0 }
+    }
+}
+
+impl Default for TrainingConfig {
+    fn default() -> TrainingConfig {
+        TrainingConfig { eta: 0.1, epochs: 1, optimizer: Optimizer::Sgd, loss: Loss::VelocityMse, conservation_penalty: 0.0 }
+    }
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> CheckpointConfig {
+        CheckpointConfig { dir: "checkpoints/".to_string(), save_every: 0 }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig { level: "info".to_string(), file: None }
+    }
+}
+
+impl Config {
+    // .envとプロセスの環境変数で上書きして読む
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        dotenv().ok();
+        let text = fs::read_to_string(path)?;
+        Config::from_toml_with_env(&text, env::vars())
+    }
+
+    pub fn from_toml_with_env<I: IntoIterator<Item = (String, String)>>(text: &str, vars: I) -> Result<Config, ConfigError> {
+        let mut value: toml::Value = text.parse().map_err(|e: toml::de::Error| ConfigError::Parse(e.to_string()))?;
+        for (key, raw) in vars {
+            let path: Vec<String> = if key == "DATA_DIR" {
+                vec!["data".to_string(), "data_dir".to_string()]
+            } else if let Some(rest) = key.strip_prefix(ENV_PREFIX) {
+                rest.split("__").map(|k| k.to_lowercase()).collect()
+            } else {
+                continue;
+            };
+            override_value(&mut value, &path, &raw)?;
+        }
+        let config: Config = value.try_into().map_err(|e: toml::de::Error| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.data.format == DataFormat::NetCdf && self.data.netcdf_path.is_none() {
+            return Err(ConfigError::Invalid("data.netcdf_path is required for netcdf".to_string()));
+        }
+        if self.region.index.is_some() && self.region.lat_lon.is_some() {
+            return Err(ConfigError::Invalid("region.index and region.lat_lon are exclusive".to_string()));
+        }
+        if self.region.stride == 0 {
+            return Err(ConfigError::Invalid("region.stride must be positive".to_string()));
+        }
+        if self.model.layers == 0 {
+            return Err(ConfigError::Invalid("model.layers must be positive".to_string()));
+        }
+        if self.model.boundary == Boundary::Periodic && self.model.margin != 0 {
+            return Err(ConfigError::Invalid("model.margin must be 0 for the periodic boundary".to_string()));
+        }
+        if self.model.boundary == Boundary::Inflow && self.model.margin == 0 {
+            return Err(ConfigError::Invalid("model.margin must be positive for the inflow boundary".to_string()));
+        }
+        if self.model.velocity_scale <= 0.0 || self.model.pressure_ref <= 0.0 {
+            return Err(ConfigError::Invalid("model.velocity_scale and model.pressure_ref must be positive".to_string()));
+        }
+        let stable = |rate: f64| 0.0 < rate && rate < 2.0;
+        let valid_collision = match self.model.collision {
+            CollisionOperator::Bgk => true,
+            CollisionOperator::Trt { rate, magic } => stable(rate) && magic > 0.0,
+            CollisionOperator::Regularized { rate } => stable(rate),
+            CollisionOperator::Mrt { rates, .. } => rates.iter().all(|rate| stable(*rate)),
+        };
+        if !valid_collision {
+            return Err(ConfigError::Invalid("model.collision rates must be in (0, 2) and magic must be positive".to_string()));
+        }
+        if self.model.smagorinsky.is_some_and(|smagorinsky| smagorinsky.constant < 0.0) {
+            return Err(ConfigError::Invalid("model.smagorinsky.constant must be non-negative".to_string()));
+        }
+        if self.training.conservation_penalty < 0.0 {
+            return Err(ConfigError::Invalid("training.conservation_penalty must be non-negative".to_string()));
+        }
+        for range in [&self.period.train, &self.period.test].into_iter().flatten() {
+            if range.start > range.end || range.interval_hours <= 0 {
+                return Err(ConfigError::Invalid("period must satisfy start <= end and interval_hours > 0".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn region(&self) -> Region {
+        let range = match (&self.region.index, &self.region.lat_lon) {
+            (Some(i), _) => Some(GridRange::Index { row_start: i.row_start, row_end: i.row_end, col_start: i.col_start, col_end: i.col_end }),
+            (_, Some(l)) => Some(GridRange::LatLon { lat_min: l.lat_min, lat_max: l.lat_max, lon_min: l.lon_min, lon_max: l.lon_max }),
+            _ => None,
+        };
+        let coarsening = match self.region.coarsening {
+            CoarseningMethod::None => Coarsening::None,
+            CoarseningMethod::Decimation => Coarsening::Decimation(self.region.stride),
+            CoarseningMethod::BlockAverage => Coarsening::BlockAverage(self.region.stride),
+        };
+        Region::new(range, coarsening)
+    }
+
+    // npyの格子 netcdfではファイルの座標変数から作るのでNone
+    pub fn grid(&self) -> Option<GeoGrid> {
+        match (&self.data.grid, self.data.format) {
+            (Some(g), _) => Some(GeoGrid::new(g.lat_first, g.lon_first, g.d_lat, g.d_lon, g.row, g.col)),
+            (None, DataFormat::Npy) => Some(GeoGrid::msm()),
+            (None, DataFormat::NetCdf) => None,
+        }
+    }
+
+    pub fn netcdf_variables(&self) -> NetCdfVariables {
+        let v = &self.data.variables;
+        NetCdfVariables { u: v.u.clone(), v: v.v.clone(), pressure: v.pressure.clone(), level: v.level }
+    }
+}
+
+impl DateRange {
+    pub fn datetimes(&self) -> Vec<DateTime<Utc>> {
+        let mut datetimes = Vec::new();
+        let mut datetime = self.start;
+        while datetime <= self.end {
+            datetimes.push(datetime);
+            datetime += Duration::hours(self.interval_hours);
+        }
+        datetimes
+    }
+}
+
+fn override_value(value: &mut toml::Value, path: &[String], raw: &str) -> Result<(), ConfigError> {
+    let (last, parents) = match path.split_last() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    let mut table = value;
+    for key in parents {
+        let map = table.as_table_mut().ok_or_else(|| ConfigError::Invalid(format!("{} is not a table", key)))?;
+        table = map.entry(key.clone()).or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+    }
+    let table = table.as_table_mut().ok_or_else(|| ConfigError::Invalid(format!("{} is not a table", path.join("."))))?;
+    // 文字列のキーはそのまま、それ以外の"0.05"や"true"はTOMLの値、"foo"のようにTOMLとして読めないものは文字列にする
+    let is_string = STRING_KEYS.contains(&path.join(".").as_str()) || matches!(table.get(last), Some(toml::Value::String(_)));
+    let parsed = if is_string {
+        toml::Value::String(raw.to_string())
+    } else {
+        format!("v = {}", raw).parse::<toml::Value>().ok()
+            .and_then(|t| t.get("v").cloned())
+            .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+    };
+    table.insert(last.clone(), parsed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use crate::boundary::EdgeCondition;
+    use super::*;
+
+    const TEXT: &str = r#"
+        [data]
+        data_dir = "/data/msm/"
+
+        [period.train]
+        start = "2020-03-20T00:00:00Z"
+        end = "2020-03-20T06:00:00Z"
+        interval_hours = 3
+
+        [region]
+        lat_lon = { lat_min = 33.0, lat_max = 36.0, lon_min = 130.0, lon_max = 136.0 }
+        coarsening = "block_average"
+        stride = 4
+
+        [model]
+        layers = 3
+        margin = 2
+        coriolis = true
+
+        [model.collision]
+        type = "mrt"
+        trainable = true
+
+        [model.smagorinsky]
+        constant = 0.1
+
+        [model.edges.west]
+        type = "velocity"
+        u_vert = 0.0
+        u_hori = 0.05
+
+        [training]
+        eta = 0.1
+        conservation_penalty = 0.01
+    "#;
+
+    #[test]
+    fn test_config_from_toml() {
+        let config = Config::from_toml_with_env(TEXT, Vec::new()).unwrap();
+        assert_eq!("/data/msm/", config.data.data_dir);
+        assert_eq!(3, config.model.layers);
+        assert!(config.model.coriolis);
+        assert_eq!(CollisionOperator::Mrt { rates: crate::collision::MRT_RATES, trainable: true }, config.model.collision);
+        assert_eq!(Some(Smagorinsky { constant: 0.1, trainable: false }), config.model.smagorinsky);
+        assert_eq!(Some(EdgeCondition::Velocity { u_vert: 0.0, u_hori: 0.05 }), config.model.edges.west);
+        assert_eq!(None, config.model.edges.east);
+        assert_eq!(CoarseningMethod::BlockAverage, config.region.coarsening);
+        assert_eq!(Optimizer::Sgd, config.training.optimizer);
+        assert_eq!(0.01, config.training.conservation_penalty);
+        assert_eq!(
+            vec![
+                Utc.with_ymd_and_hms(2020, 3, 20, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2020, 3, 20, 3, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2020, 3, 20, 6, 0, 0).unwrap(),
+            ],
+            config.period.train.as_ref().unwrap().datetimes()
+        );
+        // 3度 / 0.05度 + 1 = 61行 -> 15行、6度 / 0.0625度 + 1 = 97列 -> 24列
+        assert_eq!((15, 24), config.region().shape(&config.grid().unwrap()));
+    }
+
+    #[test]
+    fn test_config_env_override() {
+        let vars = vec![
+            ("DATA_DIR".to_string(), "/tmp/data/".to_string()),
+            ("LBM__TRAINING__ETA".to_string(), "0.05".to_string()),
+            ("LBM__CHECKPOINT__DIR".to_string(), "/tmp/ckpt/".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let config = Config::from_toml_with_env(TEXT, vars).unwrap();
+        assert_eq!("/tmp/data/", config.data.data_dir);
+        assert_eq!(0.05, config.training.eta);
+        assert_eq!("/tmp/ckpt/", config.checkpoint.dir);
+
+        let vars = vec![("LBM__MODEL__LAYERS".to_string(), "0".to_string())];
+        assert!(matches!(Config::from_toml_with_env(TEXT, vars), Err(ConfigError::Invalid(_))));
+        let vars = vec![("LBM__MODEL__BOUNDARY".to_string(), "periodic".to_string())];
+        assert!(matches!(Config::from_toml_with_env(TEXT, vars), Err(ConfigError::Invalid(_))));
+        let vars = vec![("LBM__MODEL__BOUNDARY".to_string(), "periodic".to_string()), ("LBM__MODEL__MARGIN".to_string(), "0".to_string())];
+        assert_eq!(Boundary::Periodic, Config::from_toml_with_env(TEXT, vars).unwrap().model.boundary);
+        let vars = vec![("LBM__MODEL__DEPTH".to_string(), "2".to_string())];
+        assert!(matches!(Config::from_toml_with_env(TEXT, vars), Err(ConfigError::Parse(_))));
+    }
+
+    // 文字列のキーには、数値や真偽値に見える値も文字列として入る
+    #[test]
+    fn test_config_env_override_string_keys() {
+        let vars = vec![
+            ("LBM__DATA__DATA_DIR".to_string(), "2020".to_string()),
+            ("LBM__CHECKPOINT__DIR".to_string(), "true".to_string()),
+            ("LBM__DATA__VARIABLES__U".to_string(), "10".to_string()),
+            ("LBM__MODEL__OBSTACLE".to_string(), "1.5".to_string()),
+        ];
+        let config = Config::from_toml_with_env(TEXT, vars).unwrap();
+        assert_eq!("2020", config.data.data_dir);
+        assert_eq!("true", config.checkpoint.dir);
+        assert_eq!("10", config.data.variables.u);
+        assert_eq!(Some("1.5".to_string()), config.model.obstacle);
+
+        // STRING_KEYSにないキーでも、ファイルで文字列なら文字列のまま
+        let text = TEXT.replace("[model.smagorinsky]", "[model.smagorinsky]\n        note = \"x\"");
+        let mut value: toml::Value = text.parse().unwrap();
+        override_value(&mut value, &["model".to_string(), "smagorinsky".to_string(), "note".to_string()], "42").unwrap();
+        assert_eq!(Some(&toml::Value::String("42".to_string())), value["model"]["smagorinsky"].get("note"));
+    }
+}
@@ -0,0 +1,216 @@
+use ndarray::{Array1, Array2, Zip, s};
+
+// 行ごとの緯度lat、列ごとの経度lonを持つ格子
+// d_lat, d_lonは1行(1列)進んだときの変化量で、符号付き(MSMは北から南に並ぶのでd_latは負)
+// 気象学のu(東向き正), v(北向き正)とlbmのu_hori(右向き正), u_vert(下向き正！)の変換もここで行う
+
+const EARTH_RADIUS: f64 = 6371000.0;
+const EARTH_ANGULAR_VELOCITY: f64 = 7.2921e-5; // [rad/s]
+const MSM_ROW: usize = 505;
+const MSM_COL: usize = 481;
+const MSM_LAT_FIRST: f64 = 47.6;
+const MSM_LON_FIRST: f64 = 120.0;
+const MSM_D_LAT: f64 = -0.05;
+const MSM_D_LON: f64 = 0.0625;
+const GRID_EPS: f64 = 0.000001;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Projection {
+    LatLon, // 等緯度経度格子
+}
+
+#[derive(Clone, Debug)]
+pub struct GeoGrid {
+    row: usize,
+    col: usize,
+    lat: Array1<f64>,
+    lon: Array1<f64>,
+    d_lat: f64,
+    d_lon: f64,
+    projection: Projection,
+}
+
+impl GeoGrid {
+    pub fn new(lat_first: f64, lon_first: f64, d_lat: f64, d_lon: f64, row: usize, col: usize) -> GeoGrid {
+        let lat = Array1::from_shape_fn(row, |r| lat_first + r as f64 * d_lat);
+        let lon = Array1::from_shape_fn(col, |c| lon_first + c as f64 * d_lon);
+        GeoGrid { row, col, lat, lon, d_lat, d_lon, projection: Projection::LatLon }
+    }
+
+    // 座標の配列から作る 等間隔であることを仮定する
+    pub fn from_coordinates(lat: Array1<f64>, lon: Array1<f64>) -> GeoGrid {
+        if lat.is_empty() || lon.is_empty() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let d_lat = if lat.len() > 1 { lat[1] - lat[0] } else { 0.0 };
+        let d_lon = if lon.len() > 1 { lon[1] - lon[0] } else { 0.0 };
+        GeoGrid { row: lat.len(), col: lon.len(), lat, lon, d_lat, d_lon, projection: Projection::LatLon }
+    }
+
+    // MSMの地上データの格子
+    pub fn msm() -> GeoGrid {
+        GeoGrid::new(MSM_LAT_FIRST, MSM_LON_FIRST, MSM_D_LAT, MSM_D_LON, MSM_ROW, MSM_COL)
+    }
+
+    pub fn row(&self) -> usize { self.row }
+    pub fn col(&self) -> usize { self.col }
+    pub fn shape(&self) -> (usize, usize) { (self.row, self.col) }
+    pub fn lat(&self) -> &Array1<f64> { &self.lat }
+    pub fn lon(&self) -> &Array1<f64> { &self.lon }
+    pub fn d_lat(&self) -> f64 { self.d_lat }
+    pub fn d_lon(&self) -> f64 { self.d_lon }
+    pub fn projection(&self) -> Projection { self.projection }
+
+    // 各セルの緯度 (row, col)の形で返す
+    pub fn lat_field(&self) -> Array2<f64> {
+        Array2::from_shape_fn((self.row, self.col), |(r, _)| self.lat[r])
+    }
+
+    // 各セルのコリオリパラメータ f = 2Ω sinφ [1/s]
+    pub fn coriolis_parameter(&self) -> Array2<f64> {
+        self.lat_field().mapv(|lat| 2.0 * EARTH_ANGULAR_VELOCITY * lat.to_radians().sin())
+    }
+
+    // lbmのCollidingWeight::set_coriolis()に渡す格子単位のコリオリパラメータ time_stepは1ステップの時間[s]
+    // du/dt = f v、dv/dt = -f uを(u_vert, u_hori)に直すと du_hori/dt = s f u_vert、du_vert/dt = -s f u_hori (sは格子の向きの符号の積)
+    pub fn lattice_coriolis(&self, time_step: f64) -> Array2<f64> {
+        let (vert_sign, hori_sign) = self.lattice_sign();
+        self.coriolis_parameter() * (vert_sign * hori_sign * time_step)
+    }
+
+    // 格子間隔[m] dyは南北、dxは東西でr行目の緯度によって変わる
+    pub fn dy(&self) -> f64 {
+        EARTH_RADIUS * self.d_lat.abs().to_radians()
+    }
+
+    pub fn dx(&self, r: usize) -> f64 {
+        EARTH_RADIUS * self.lat[r].to_radians().cos() * self.d_lon.abs().to_radians()
+    }
+
+    // [lat_min, lat_max]に入る行の範囲[start, end)
+    pub fn row_range(&self, lat_min: f64, lat_max: f64) -> (usize, usize) {
+        index_range(&self.lat, lat_min, lat_max)
+    }
+
+    // [lon_min, lon_max]に入る列の範囲[start, end)
+    pub fn col_range(&self, lon_min: f64, lon_max: f64) -> (usize, usize) {
+        index_range(&self.lon, lon_min, lon_max)
+    }
+
+    pub fn crop(&self, row_start: usize, row_end: usize, col_start: usize, col_end: usize) -> GeoGrid {
+        if row_start >= row_end || col_start >= col_end || row_end > self.row || col_end > self.col {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let lat = self.lat.slice(s![row_start..row_end]).to_owned();
+        let lon = self.lon.slice(s![col_start..col_end]).to_owned();
+        GeoGrid { row: lat.len(), col: lon.len(), lat, lon, d_lat: self.d_lat, d_lon: self.d_lon, projection: self.projection }
+    }
+
+    pub fn decimate(&self, stride: usize) -> GeoGrid {
+        let lat = self.lat.slice(s![..;stride]).to_owned();
+        let lon = self.lon.slice(s![..;stride]).to_owned();
+        let d_lat = self.d_lat * stride as f64;
+        let d_lon = self.d_lon * stride as f64;
+        GeoGrid { row: lat.len(), col: lon.len(), lat, lon, d_lat, d_lon, projection: self.projection }
+    }
+
+    // ブロック平均したときの格子 座標はブロックの中心になる
+    pub fn block_average(&self, stride: usize) -> GeoGrid {
+        let (row, col) = (self.row / stride, self.col / stride);
+        let lat = Array1::from_shape_fn(row, |r| self.lat.slice(s![r*stride..(r+1)*stride]).mean().unwrap());
+        let lon = Array1::from_shape_fn(col, |c| self.lon.slice(s![c*stride..(c+1)*stride]).mean().unwrap());
+        let d_lat = self.d_lat * stride as f64;
+        let d_lon = self.d_lon * stride as f64;
+        GeoGrid { row, col, lat, lon, d_lat, d_lon, projection: self.projection }
+    }
+
+    // 気象学のu(東向き正), v(北向き正) -> (u_vert, u_hori)
+    pub fn to_lattice_velocity(&self, u: &Array2<f64>, v: &Array2<f64>) -> (Array2<f64>, Array2<f64>) {
+        if [self.row, self.col] != u.shape() || [self.row, self.col] != v.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let (vert_sign, hori_sign) = self.lattice_sign();
+        let mut u_vert = Array2::<f64>::zeros((self.row, self.col));
+        let mut u_hori = Array2::<f64>::zeros((self.row, self.col));
+        Zip::from(&mut u_vert).and(&mut u_hori).and(u).and(v).for_each(|u_vert, u_hori, u, v| {
+            *u_vert = vert_sign * v;
+            *u_hori = hori_sign * u;
+        });
+        (u_vert, u_hori)
+    }
+
+    // (u_vert, u_hori) -> 気象学のu(東向き正), v(北向き正)
+    pub fn to_meteorological_velocity(&self, u_vert: &Array2<f64>, u_hori: &Array2<f64>) -> (Array2<f64>, Array2<f64>) {
+        if [self.row, self.col] != u_vert.shape() || [self.row, self.col] != u_hori.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let (vert_sign, hori_sign) = self.lattice_sign();
+        let mut u = Array2::<f64>::zeros((self.row, self.col));
+        let mut v = Array2::<f64>::zeros((self.row, self.col));
+        Zip::from(&mut u).and(&mut v).and(u_vert).and(u_hori).for_each(|u, v, u_vert, u_hori| {
+            *u = hori_sign * u_hori;
+            *v = vert_sign * u_vert;
+        });
+        (u, v)
+    }
+
+    // 北向き(東向き)の速度が格子の下向き(右向き)で何倍になるか ±1
+    fn lattice_sign(&self) -> (f64, f64) {
+        let vert_sign = if self.d_lat < 0.0 { -1.0 } else { 1.0 };
+        let hori_sign = if self.d_lon < 0.0 { -1.0 } else { 1.0 };
+        (vert_sign, hori_sign)
+    }
+}
+
+fn index_range(coord: &Array1<f64>, min: f64, max: f64) -> (usize, usize) {
+    let inside: Vec<usize> = coord.iter().enumerate()
+        .filter(|(_, x)| min - GRID_EPS <= **x && **x <= max + GRID_EPS)
+        .map(|(i, _)| i)
+        .collect();
+    match (inside.first(), inside.last()) {
+        (Some(start), Some(end)) => (*start, end + 1),
+        _ => panic!("panicked at line {} in {}", line!(), file!()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::arr2;
+    use super::*;
+
+    #[test]
+    fn test_geo_grid_msm() {
+        let grid = GeoGrid::msm();
+        assert_eq!((505, 481), grid.shape());
+        assert!((grid.lat()[504] - 22.4).abs() < GRID_EPS);
+        assert!((grid.lon()[480] - 150.0).abs() < GRID_EPS);
+        assert_eq!((2, 4), grid.row_range(47.45, 47.5));
+        assert_eq!((1, 3), grid.col_range(120.0625, 120.125));
+        assert!((grid.dy() - 5559.75).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_geo_grid_velocity_conversion() {
+        let grid = GeoGrid::new(35.0, 135.0, -0.05, 0.0625, 1, 2);
+        let u = arr2(&[[1.0, -2.0]]);
+        let v = arr2(&[[3.0, 0.5]]);
+        let (u_vert, u_hori) = grid.to_lattice_velocity(&u, &v);
+        assert_eq!(arr2(&[[-3.0, -0.5]]), u_vert);
+        assert_eq!(arr2(&[[1.0, -2.0]]), u_hori);
+        let (u_back, v_back) = grid.to_meteorological_velocity(&u_vert, &u_hori);
+        assert_eq!(u, u_back);
+        assert_eq!(v, v_back);
+    }
+
+    #[test]
+    fn test_geo_grid_lattice_coriolis() {
+        let grid = GeoGrid::new(30.0, 135.0, -0.05, 0.0625, 2, 1);
+        assert!((grid.coriolis_parameter()[[0, 0]] - EARTH_ANGULAR_VELOCITY).abs() < 0.000000000001);
+        // 北半球で東向きの風は南(MSMでは下向き)に曲がる
+        let coriolis = grid.lattice_coriolis(60.0);
+        assert!(coriolis[[0, 0]] < 0.0);
+        assert!((coriolis[[0, 0]] + 60.0 * EARTH_ANGULAR_VELOCITY).abs() < 0.000000000001);
+        let flipped = GeoGrid::new(-30.0, 135.0, 0.05, 0.0625, 2, 1);
+        assert!(flipped.lattice_coriolis(60.0)[[0, 0]] < 0.0);
+    }
+}
@@ -0,0 +1,460 @@
+// 重みの勾配の確認 train_step()で求めた勾配(解析的)と、重みを1つだけ±hずらして損失を計算し直した中心差分(数値的)を比べる
+// 解析的な勾配は(更新前の重み - 更新後の重み) / eta 全層の変化分を計算してからまとめて更新するので、etaによらず正確に取り出せる
+// 損失にはModel::penalty()(保存則のペナルティ)も足す ペナルティもずらした重みで流し直した場で計算する
+// forwardは損失を計算し直すたびに呼ぶ Model::forward()、forward_with_inflow()、forward_with_scalar()のどれでもよい
+// forward_with_scalar()は浮力の温度も含めて勾配が合う(浮力があるときは2層までしか流せない)
+// ScalarModelはcheck_scalar_gradient()、Model3dはcheck_gradient_3d()で同じように確かめる
+
+use std::fmt;
+use ndarray::{Array2, Array3};
+use crate::collision::Smagorinsky;
+use crate::lattice::{Lattice, D2Q5, D2Q9};
+use crate::model::{loss, Forward, Model};
+use crate::model3d::{loss3d, Forward3d, Model3d};
+use crate::model_scalar::{scalar_loss, ScalarForward, ScalarModel};
+
+// 相対誤差の分母の下限 勾配がほとんど0の重みで、中心差分の丸め誤差だけで相対誤差が大きくならないようにする
+pub const RELATIVE_ERROR_FLOOR: f64 = 0.000001;
+
+// Modelの確かめる重みの1成分 indexは重みの配列の添字(r, c, q)、cellは(r, c)
+// ScalarModelはScalarParameter、Model3dはParameter3dで、モデルごとに型を分けて違うモデルの重みを渡せないようにする
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parameter {
+    StreamingW0 { layer: usize, index: [usize; 3] },
+    StreamingW1 { layer: usize, index: [usize; 3] },
+    CollidingW1 { layer: usize, index: [usize; 3] },
+    CollidingW2 { layer: usize, index: [usize; 3] },
+    CollidingW3 { layer: usize, index: [usize; 3] },
+    CollidingW4 { layer: usize, index: [usize; 3] },
+    // MRTの緩和率 学習するときだけ
+    Rate { layer: usize, moment: usize },
+    // Smagorinsky定数 学習するときだけ
+    SmagorinskyConstant { layer: usize },
+    // 外力 学習するときだけ
+    ForceVert { layer: usize, cell: [usize; 2] },
+    ForceHori { layer: usize, cell: [usize; 2] },
+}
+
+// ScalarModelの重み qはD2Q5の添字
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScalarParameter {
+    StreamingW0 { layer: usize, index: [usize; 3] },
+    StreamingW1 { layer: usize, index: [usize; 3] },
+    CollidingW1 { layer: usize, index: [usize; 3] },
+    Rate { layer: usize, cell: [usize; 2] },
+}
+
+// Model3dの重み indexは(l, r, c, q) CollidingWeight3dにはw2がない
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parameter3d {
+    StreamingW0 { layer: usize, index: [usize; 4] },
+    StreamingW1 { layer: usize, index: [usize; 4] },
+    CollidingW1 { layer: usize, index: [usize; 4] },
+    CollidingW3 { layer: usize, index: [usize; 4] },
+    CollidingW4 { layer: usize, index: [usize; 4] },
+}
+
+// Pは確かめた重みの型(Parameter, ScalarParameter, Parameter3d)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GradientCheck<P = Parameter> {
+    pub parameter: P,
+    pub numerical: f64,
+    pub analytical: f64,
+    pub relative_error: f64,
+}
+
+impl<P: fmt::Debug> fmt::Display for GradientCheck<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: numerical {:.6e} analytical {:.6e} relative error {:.3e}", self.parameter, self.numerical, self.analytical, self.relative_error)
+    }
+}
+
+impl Parameter {
+    pub fn value(self, model: &Model) -> f64 {
+        match self {
+            Parameter::StreamingW0 { layer, index } => model.streaming_weights()[layer].w0()[index],
+            Parameter::StreamingW1 { layer, index } => model.streaming_weights()[layer].w1()[index],
+            Parameter::CollidingW1 { layer, index } => model.colliding_weights()[layer].w1()[index],
+            Parameter::CollidingW2 { layer, index } => model.colliding_weights()[layer].w2()[index],
+            Parameter::CollidingW3 { layer, index } => model.colliding_weights()[layer].w3()[index],
+            Parameter::CollidingW4 { layer, index } => model.colliding_weights()[layer].w4()[index],
+            Parameter::Rate { layer, moment } => model.colliding_weights()[layer].rates()[moment],
+            Parameter::SmagorinskyConstant { layer } => model.colliding_weights()[layer].smagorinsky().unwrap().constant,
+            Parameter::ForceVert { layer, cell } => model.colliding_weights()[layer].force_vert()[cell],
+            Parameter::ForceHori { layer, cell } => model.colliding_weights()[layer].force_hori()[cell],
+        }
+    }
+
+    pub fn set_value(self, model: &mut Model, value: f64) {
+        match self {
+            Parameter::StreamingW0 { layer, index } | Parameter::StreamingW1 { layer, index } => {
+                let weight = &mut model.streaming_weights_mut()[layer];
+                let (mut w0, mut w1) = (weight.w0().to_owned(), weight.w1().to_owned());
+                if let Parameter::StreamingW0 { .. } = self { w0[index] = value; } else { w1[index] = value; }
+                weight.set(w0, w1);
+            },
+            Parameter::CollidingW1 { layer, index } | Parameter::CollidingW2 { layer, index } | Parameter::CollidingW3 { layer, index } | Parameter::CollidingW4 { layer, index } => {
+                let weight = &mut model.colliding_weights_mut()[layer];
+                let (mut w1, mut w2, mut w3, mut w4) = (weight.w1().to_owned(), weight.w2().to_owned(), weight.w3().to_owned(), weight.w4().to_owned());
+                match self {
+                    Parameter::CollidingW1 { .. } => w1[index] = value,
+                    Parameter::CollidingW2 { .. } => w2[index] = value,
+                    Parameter::CollidingW3 { .. } => w3[index] = value,
+                    _ => w4[index] = value,
+                }
+                weight.set(w1, w2, w3, w4);
+            },
+            Parameter::Rate { layer, moment } => {
+                let weight = &mut model.colliding_weights_mut()[layer];
+                let mut rates = weight.rates();
+                rates[moment] = value;
+                weight.set_rates(rates);
+            },
+            Parameter::SmagorinskyConstant { layer } => {
+                let weight = &mut model.colliding_weights_mut()[layer];
+                let smagorinsky = weight.smagorinsky().map(|smagorinsky| Smagorinsky { constant: value, ..smagorinsky });
+                weight.set_smagorinsky(smagorinsky);
+            },
+            Parameter::ForceVert { layer, cell } | Parameter::ForceHori { layer, cell } => {
+                let weight = &mut model.colliding_weights_mut()[layer];
+                let (mut force_vert, mut force_hori) = (weight.force_vert().to_owned(), weight.force_hori().to_owned());
+                if let Parameter::ForceVert { .. } = self { force_vert[cell] = value; } else { force_hori[cell] = value; }
+                weight.set_force(force_vert, force_hori);
+            },
+        }
+    }
+}
+
+impl ScalarParameter {
+    pub fn value(self, model: &ScalarModel) -> f64 {
+        match self {
+            ScalarParameter::StreamingW0 { layer, index } => model.streaming_weights()[layer].w0()[index],
+            ScalarParameter::StreamingW1 { layer, index } => model.streaming_weights()[layer].w1()[index],
+            ScalarParameter::CollidingW1 { layer, index } => model.colliding_weights()[layer].w1()[index],
+            ScalarParameter::Rate { layer, cell } => model.colliding_weights()[layer].rate()[cell],
+        }
+    }
+
+    pub fn set_value(self, model: &mut ScalarModel, value: f64) {
+        match self {
+            ScalarParameter::StreamingW0 { layer, index } | ScalarParameter::StreamingW1 { layer, index } => {
+                let weight = &mut model.streaming_weights_mut()[layer];
+                let (mut w0, mut w1) = (weight.w0().to_owned(), weight.w1().to_owned());
+                if let ScalarParameter::StreamingW0 { .. } = self { w0[index] = value; } else { w1[index] = value; }
+                weight.set(w0, w1);
+            },
+            ScalarParameter::CollidingW1 { layer, index } => {
+                let weight = &mut model.colliding_weights_mut()[layer];
+                let (mut w1, rate) = (weight.w1().to_owned(), weight.rate().to_owned());
+                w1[index] = value;
+                weight.set(w1, rate);
+            },
+            ScalarParameter::Rate { layer, cell } => {
+                let weight = &mut model.colliding_weights_mut()[layer];
+                let (w1, mut rate) = (weight.w1().to_owned(), weight.rate().to_owned());
+                rate[cell] = value;
+                weight.set(w1, rate);
+            },
+        }
+    }
+}
+
+impl Parameter3d {
+    pub fn value(self, model: &Model3d) -> f64 {
+        match self {
+            Parameter3d::StreamingW0 { layer, index } => model.streaming_weights()[layer].w0()[index],
+            Parameter3d::StreamingW1 { layer, index } => model.streaming_weights()[layer].w1()[index],
+            Parameter3d::CollidingW1 { layer, index } => model.colliding_weights()[layer].w1()[index],
+            Parameter3d::CollidingW3 { layer, index } => model.colliding_weights()[layer].w3()[index],
+            Parameter3d::CollidingW4 { layer, index } => model.colliding_weights()[layer].w4()[index],
+        }
+    }
+
+    pub fn set_value(self, model: &mut Model3d, value: f64) {
+        match self {
+            Parameter3d::StreamingW0 { layer, index } | Parameter3d::StreamingW1 { layer, index } => {
+                let weight = &mut model.streaming_weights_mut()[layer];
+                let (mut w0, mut w1) = (weight.w0().to_owned(), weight.w1().to_owned());
+                if let Parameter3d::StreamingW0 { .. } = self { w0[index] = value; } else { w1[index] = value; }
+                weight.set(w0, w1);
+            },
+            Parameter3d::CollidingW1 { layer, index } | Parameter3d::CollidingW3 { layer, index } | Parameter3d::CollidingW4 { layer, index } => {
+                let weight = &mut model.colliding_weights_mut()[layer];
+                let (mut w1, mut w3, mut w4) = (weight.w1().to_owned(), weight.w3().to_owned(), weight.w4().to_owned());
+                match self {
+                    Parameter3d::CollidingW1 { .. } => w1[index] = value,
+                    Parameter3d::CollidingW3 { .. } => w3[index] = value,
+                    _ => w4[index] = value,
+                }
+                weight.set(w1, w3, w4);
+            },
+        }
+    }
+}
+
+// 乱数を使わずに選ぶ内側(marginより内)の成分の添字(r, c, q)
+fn sample_index(row: usize, col: usize, q: usize, margin: usize, layer: usize, n: usize) -> [usize; 3] {
+    let (rows, cols) = (row - 2 * margin, col - 2 * margin);
+    [margin + (n * 5 + layer) % rows, margin + (n * 3 + 2 * layer + 1) % cols, (n * 4 + layer) % q]
+}
+
+// 学習する重みのすべての種類から、層ごとにper_weight個ずつ内側(marginより内)の成分を選ぶ 乱数は使わずに毎回同じ成分を選ぶ
+pub fn sample_parameters(model: &Model, per_weight: usize) -> Vec<Parameter> {
+    let mut parameters = Vec::new();
+    let index = |margin: usize, layer: usize, n: usize| sample_index(model.row(), model.col(), D2Q9.q(), margin, layer, n);
+    for (layer, weight) in model.streaming_weights().iter().enumerate() {
+        for n in 0..per_weight {
+            let i = index(weight.margin(), layer, n);
+            parameters.push(Parameter::StreamingW0 { layer, index: i });
+            parameters.push(Parameter::StreamingW1 { layer, index: index(weight.margin(), layer, n + per_weight) });
+        }
+    }
+    for (layer, weight) in model.colliding_weights().iter().enumerate() {
+        for n in 0..per_weight {
+            let colliding: [fn(usize, [usize; 3]) -> Parameter; 4] = [
+                |layer, index| Parameter::CollidingW1 { layer, index },
+                |layer, index| Parameter::CollidingW2 { layer, index },
+                |layer, index| Parameter::CollidingW3 { layer, index },
+                |layer, index| Parameter::CollidingW4 { layer, index },
+            ];
+            for (m, parameter) in colliding.into_iter().enumerate() {
+                parameters.push(parameter(layer, index(weight.margin(), layer, n + (m + 1) * per_weight)));
+            }
+            if weight.force_trainable() {
+                let [r, c, _] = index(weight.margin(), layer, n);
+                parameters.push(Parameter::ForceVert { layer, cell: [r, c] });
+                let [r, c, _] = index(weight.margin(), layer, n + per_weight);
+                parameters.push(Parameter::ForceHori { layer, cell: [r, c] });
+            }
+        }
+        if weight.operator().rates_trainable() {
+            parameters.extend((0..9).map(|moment| Parameter::Rate { layer, moment }));
+        }
+        if weight.smagorinsky().is_some_and(|smagorinsky| smagorinsky.trainable) {
+            parameters.push(Parameter::SmagorinskyConstant { layer });
+        }
+    }
+    parameters
+}
+
+// ScalarModelの重み(w0, w1、collideのw1とrate)から、層ごとにper_weight個ずつ選ぶ
+pub fn sample_scalar_parameters(model: &ScalarModel, per_weight: usize) -> Vec<ScalarParameter> {
+    let mut parameters = Vec::new();
+    let index = |margin: usize, layer: usize, n: usize| sample_index(model.row(), model.col(), D2Q5.q(), margin, layer, n);
+    for (layer, weight) in model.streaming_weights().iter().enumerate() {
+        for n in 0..per_weight {
+            parameters.push(ScalarParameter::StreamingW0 { layer, index: index(weight.margin(), layer, n) });
+            parameters.push(ScalarParameter::StreamingW1 { layer, index: index(weight.margin(), layer, n + per_weight) });
+        }
+    }
+    for (layer, weight) in model.colliding_weights().iter().enumerate() {
+        for n in 0..per_weight {
+            parameters.push(ScalarParameter::CollidingW1 { layer, index: index(weight.margin(), layer, n) });
+            let [r, c, _] = index(weight.margin(), layer, n + per_weight);
+            parameters.push(ScalarParameter::Rate { layer, cell: [r, c] });
+        }
+    }
+    parameters
+}
+
+// Model3dの重み(w0, w1、collideのw1, w3, w4)から、層ごとにper_weight個ずつ選ぶ 気圧面も順に変える
+pub fn sample_parameters_3d(model: &Model3d, per_weight: usize) -> Vec<Parameter3d> {
+    let mut parameters = Vec::new();
+    let index = |margin: usize, layer: usize, n: usize| {
+        let [r, c, q] = sample_index(model.row(), model.col(), model.lattice().q(), margin, layer, n);
+        [(n + layer) % model.level(), r, c, q]
+    };
+    for (layer, weight) in model.streaming_weights().iter().enumerate() {
+        for n in 0..per_weight {
+            parameters.push(Parameter3d::StreamingW0 { layer, index: index(weight.margin(), layer, n) });
+            parameters.push(Parameter3d::StreamingW1 { layer, index: index(weight.margin(), layer, n + per_weight) });
+        }
+    }
+    for (layer, weight) in model.colliding_weights().iter().enumerate() {
+        for n in 0..per_weight {
+            let colliding: [fn(usize, [usize; 4]) -> Parameter3d; 3] = [
+                |layer, index| Parameter3d::CollidingW1 { layer, index },
+                |layer, index| Parameter3d::CollidingW3 { layer, index },
+                |layer, index| Parameter3d::CollidingW4 { layer, index },
+            ];
+            for (m, parameter) in colliding.into_iter().enumerate() {
+                parameters.push(parameter(layer, index(weight.margin(), layer, n + (m + 1) * per_weight)));
+            }
+        }
+    }
+    parameters
+}
+
+// 中心差分(刻みh)とtrain_step()の勾配を比べる共通部分 trainはeta = 1でtrain_step()を1回呼ぶ、loss_ofはずらしたモデルの損失
+fn compare<M: Clone, P: Copy>(model: &M, parameters: &[P], h: f64, value: impl Fn(P, &M) -> f64, set_value: impl Fn(P, &mut M, f64), train: impl FnOnce(&mut M), loss_of: impl Fn(&M) -> f64) -> Vec<GradientCheck<P>> {
+    let mut trained = model.clone();
+    train(&mut trained);
+
+    parameters.iter().map(|&parameter| {
+        let value_now = value(parameter, model);
+        let loss_at = |value: f64| {
+            let mut perturbed = model.clone();
+            set_value(parameter, &mut perturbed, value);
+            loss_of(&perturbed)
+        };
+        let numerical = (loss_at(value_now + h) - loss_at(value_now - h)) / (2.0 * h);
+        let analytical = value_now - value(parameter, &trained);
+        let relative_error = (numerical - analytical).abs() / numerical.abs().max(analytical.abs()).max(RELATIVE_ERROR_FLOOR);
+        GradientCheck { parameter, numerical, analytical, relative_error }
+    }).collect()
+}
+
+// parametersのそれぞれについて中心差分(刻みh)とtrain_step()の勾配を比べる modelは変えない
+pub fn check_gradient<F: Fn(&Model) -> Forward>(model: &Model, forward: F, u_vert_ans: &Array2<f64>, u_hori_ans: &Array2<f64>, parameters: &[Parameter], h: f64) -> Vec<GradientCheck> {
+    compare(model, parameters, h, Parameter::value, Parameter::set_value,
+        |trained| { let forward_now = forward(trained); trained.train_step(1.0, &forward_now, u_vert_ans, u_hori_ans); },
+        |perturbed| {
+            let forward_perturbed = forward(perturbed);
+            loss(&forward_perturbed, u_vert_ans, u_hori_ans) + perturbed.penalty(&forward_perturbed)
+        })
+}
+
+// ScalarModel用 windはforwardで使う風(ScalarModel::forward()に渡したもの)
+pub fn check_scalar_gradient<F: Fn(&ScalarModel) -> ScalarForward>(model: &ScalarModel, forward: F, wind: &Forward, phi_ans: &Array2<f64>, parameters: &[ScalarParameter], h: f64) -> Vec<GradientCheck<ScalarParameter>> {
+    compare(model, parameters, h, ScalarParameter::value, ScalarParameter::set_value,
+        |trained| { let forward_now = forward(trained); trained.train_step(1.0, &forward_now, wind, phi_ans); },
+        |perturbed| scalar_loss(forward(perturbed).output(), phi_ans))
+}
+
+// Model3d用
+pub fn check_gradient_3d<F: Fn(&Model3d) -> Forward3d>(model: &Model3d, forward: F, u_level_ans: &Array3<f64>, u_vert_ans: &Array3<f64>, u_hori_ans: &Array3<f64>, parameters: &[Parameter3d], h: f64) -> Vec<GradientCheck<Parameter3d>> {
+    compare(model, parameters, h, Parameter3d::value, Parameter3d::set_value,
+        |trained| { let forward_now = forward(trained); trained.train_step(1.0, &forward_now, u_level_ans, u_vert_ans, u_hori_ans); },
+        |perturbed| loss3d(forward(perturbed).output(), u_level_ans, u_vert_ans, u_hori_ans))
+}
+
+// 一番ずれている結果 checksが空のときはNone
+pub fn worst<P>(checks: &[GradientCheck<P>]) -> Option<&GradientCheck<P>> {
+    checks.iter().max_by(|a, b| a.relative_error.total_cmp(&b.relative_error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::{Buoyancy, CollisionOperator, MRT_RATES};
+    use crate::lbm3d::Lattice3d;
+
+    // MRTの緩和率、Smagorinsky定数、外力も学習するモデルで、選んだ全成分の勾配が中心差分と合うこと
+    // TRT(BGKにならないmagic)と正則化でも同じように確かめる
+    #[test]
+    fn test_check_gradient() {
+        let (row, col) = (9, 10);
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.02 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((row, col), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let rho = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.01 * ((r + c) as f64).cos());
+        let (u_vert_ans, u_hori_ans) = (Array2::from_elem((row, col), 0.01), Array2::from_elem((row, col), 0.02));
+        let operators = [
+            CollisionOperator::Mrt { rates: MRT_RATES, trainable: true },
+            CollisionOperator::Trt { rate: 0.6, magic: 0.1875 },
+            CollisionOperator::Regularized { rate: 0.6 },
+        ];
+        for operator in operators {
+            let mut model = Model::new(row, col, 1, 3);
+            model.set_collision(operator);
+            model.set_smagorinsky(Some(Smagorinsky { constant: 0.15, trainable: true }));
+            model.set_force(Array2::from_shape_fn((row, col), |(r, c)| 0.002 * ((r + c) as f64).cos()), Array2::from_elem((row, col), -0.001));
+            model.set_force_trainable(true);
+
+            let parameters = sample_parameters(&model, 2);
+            let rates = if operator.rates_trainable() { 9 } else { 0 };
+            assert_eq!(2 * 3 * 2 + 2 * (2 * 6 + rates + 1), parameters.len());
+            let checks = check_gradient(&model, |model| model.forward(u_vert.clone(), u_hori.clone(), rho.clone()), &u_vert_ans, &u_hori_ans, &parameters, 0.000001);
+            for check in &checks {
+                assert!(check.relative_error < 0.0001, "{:?}: {}", operator, check);
+            }
+            assert!(checks.iter().filter(|check| check.numerical.abs() > RELATIVE_ERROR_FLOOR).count() > parameters.len() / 2, "{:?}", operator);
+            assert!(worst(&checks).is_some());
+            // 元のモデルは変わらない
+            assert_eq!(operator.initial_rates(), model.colliding_weights()[0].rates());
+        }
+    }
+
+    // forward_with_scalar()で流した温度の浮力があっても、選んだ全成分の勾配が中心差分と合うこと
+    #[test]
+    fn test_check_gradient_with_scalar() {
+        let (row, col) = (9, 10);
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.02 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((row, col), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let rho = Array2::from_elem((row, col), 1.0);
+        let phi = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.5 * ((r + c) as f64 * 0.8).sin());
+        let (u_vert_ans, u_hori_ans) = (Array2::from_elem((row, col), 0.01), Array2::from_elem((row, col), 0.02));
+        let scalar_model = ScalarModel::new(row, col, 1, 2);
+        let mut model = Model::new(row, col, 1, 2);
+        model.set_buoyancy(Some(Buoyancy { coefficient: 0.02, reference: 1.0 }));
+
+        let parameters = sample_parameters(&model, 2);
+        let forward = |model: &Model| model.forward_with_scalar(&scalar_model, u_vert.clone(), u_hori.clone(), rho.clone(), phi.clone()).0;
+        let checks = check_gradient(&model, forward, &u_vert_ans, &u_hori_ans, &parameters, 0.000001);
+        for check in &checks {
+            assert!(check.relative_error < 0.0001, "{}", check);
+        }
+        assert!(checks.iter().filter(|check| check.numerical.abs() > RELATIVE_ERROR_FLOOR).count() > parameters.len() / 2);
+    }
+
+    // ScalarModelの重み(rateも)の勾配が中心差分と合うこと
+    #[test]
+    fn test_check_scalar_gradient() {
+        let (row, col) = (8, 9);
+        let wind_model = Model::new(row, col, 1, 3);
+        let wind = wind_model.forward(Array2::from_shape_fn((row, col), |(r, c)| 0.03 * ((r + 2 * c) as f64).cos()), Array2::from_shape_fn((row, col), |(r, c)| 0.05 * ((r * c) as f64).sin()), Array2::from_elem((row, col), 1.0));
+        let phi = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.3 * ((r * c) as f64).cos());
+        let phi_ans = Array2::from_shape_fn((row, col), |(r, _)| 0.1 * r as f64);
+        let mut model = ScalarModel::new(row, col, 1, 3);
+        model.set_diffusivity(0.1);
+
+        let parameters = sample_scalar_parameters(&model, 2);
+        assert_eq!(3 * 2 * 2 + 2 * 2 * 2, parameters.len());
+        let checks = check_scalar_gradient(&model, |model| model.forward(phi.clone(), &wind), &wind, &phi_ans, &parameters, 0.000001);
+        for check in &checks {
+            assert!(check.relative_error < 0.0001, "{}", check);
+        }
+        assert!(checks.iter().filter(|check| check.numerical.abs() > RELATIVE_ERROR_FLOOR).count() > parameters.len() / 2);
+    }
+
+    // D3Q19とD3Q27で、Model3dの重みの勾配が中心差分と合うこと
+    #[test]
+    fn test_check_gradient_3d() {
+        let (level, row, col) = (2, 8, 9);
+        let field = |f: &dyn Fn(usize, usize, usize) -> f64| Array3::from_shape_fn((level, row, col), |(l, r, c)| f(l, r, c));
+        let u_level = field(&|l, r, c| 0.01 * ((l + r + c) as f64).sin());
+        let u_vert = field(&|l, r, c| 0.02 * ((l + r + 2 * c) as f64).cos());
+        let u_hori = field(&|l, r, c| 0.03 * ((l * r + c) as f64).sin());
+        let rho = field(&|l, r, c| 1.0 + 0.01 * ((l + r * c) as f64).cos());
+        let ans = (field(&|_, _, _| 0.001), field(&|_, _, _| 0.01), field(&|_, _, _| -0.02));
+        for lattice in [Lattice3d::D3Q19, Lattice3d::D3Q27] {
+            let model = Model3d::new(lattice, level, row, col, 1, 3);
+            let parameters = sample_parameters_3d(&model, 2);
+            assert_eq!(3 * 2 * 2 + 2 * 2 * 3, parameters.len());
+            let forward = |model: &Model3d| model.forward(u_level.clone(), u_vert.clone(), u_hori.clone(), rho.clone());
+            let checks = check_gradient_3d(&model, forward, &ans.0, &ans.1, &ans.2, &parameters, 0.000001);
+            for check in &checks {
+                assert!(check.relative_error < 0.0001, "{:?}: {}", lattice, check);
+            }
+            assert!(checks.iter().filter(|check| check.numerical.abs() > RELATIVE_ERROR_FLOOR).count() > parameters.len() / 3, "{:?}", lattice);
+        }
+    }
+
+    // 勾配と合わない値を渡したときは相対誤差が大きくなること
+    #[test]
+    fn test_check_gradient_detects_mismatch() {
+        let (row, col) = (7, 7);
+        let model = Model::new(row, col, 1, 2);
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.02 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_elem((row, col), 0.01);
+        let rho = Array2::from_elem((row, col), 1.0);
+        let (u_vert_ans, u_hori_ans) = (Array2::from_elem((row, col), 0.01), Array2::from_elem((row, col), 0.02));
+        let parameters = [Parameter::StreamingW1 { layer: 0, index: [3, 3, 5] }];
+        let original = parameters[0].value(&model);
+        // +hずらしたときだけ入力もずらすと、train_step()の勾配と合わなくなる
+        let checks = check_gradient(&model, |perturbed| {
+            let shift = if parameters[0].value(perturbed) > original { 0.001 } else { 0.0 };
+            perturbed.forward(&u_vert + shift, u_hori.clone(), rho.clone())
+        }, &u_vert_ans, &u_hori_ans, &parameters, 0.000001);
+        assert!(checks[0].relative_error > 0.01, "{}", checks[0]);
+    }
+}
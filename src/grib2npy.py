@@ -7,6 +7,15 @@ import glob
 load_dotenv(override=True)
 DATA_DIR = os.getenv("DATA_DIR")
 
+# 温度と湿度は入っていないGRIBもあるので、名前で探して見つかったときだけ書き出す
+def select_values(grbs, short_names):
+    for short_name in short_names:
+        try:
+            return grbs.select(shortName=short_name)[0].values
+        except ValueError:
+            pass
+    return None
+
 def main():
     files = glob.glob(DATA_DIR + 'data/**/*')
     for file in files:
@@ -19,6 +28,12 @@ def main():
         np.save(DATA_DIR + 'npy/u_vert_' + basename_without_ext, u_vert)
         np.save(DATA_DIR + 'npy/u_hori_' + basename_without_ext, u_hori)
         np.save(DATA_DIR + 'npy/pressure_' + basename_without_ext, pressure)
+        temperature = select_values(grbs, ['t', '2t'])
+        if temperature is not None:
+            np.save(DATA_DIR + 'npy/temperature_' + basename_without_ext, temperature)
+        humidity = select_values(grbs, ['r', '2r'])
+        if humidity is not None:
+            np.save(DATA_DIR + 'npy/humidity_' + basename_without_ext, humidity)
 
 if __name__ == '__main__':
     main()
\ No newline at end of file
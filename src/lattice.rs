@@ -0,0 +1,141 @@
+// 格子(速度の組)の抽象 D2Q9(lbm.rs)、D2Q5(lbm_scalar.rs)、D3Q19/D3Q27(lbm3d.rs)が実装する Dは空間の次元
+// 新しい速度の組(D2Q5, D2Q17など)はLatticeを実装して足す 平衡分布や境界条件はvelocities(), weights(), opposite()だけを使って書く
+// 場と重みの型をLについて共通にしているのは2次元(Lattice<2>、lbm::LatticeInputField<L>など)だけ
+// Lattice<3>(lbm3d::Lattice3d)は速度と重みの表に使うだけで、lbm3d.rsの場と重みは気圧面の軸と鏡面反射があるので別に書いてある
+
+pub trait Lattice<const D: usize>: Copy {
+    // 速度 成分は格子単位の整数
+    fn velocities(self) -> Vec<[i32; D]>;
+
+    // velocities()と同じ順の重み Σw = 1、Σw e = 0、Σw e e = cs^2 I
+    fn weights(self) -> Vec<f64>;
+
+    // 音速の2乗 平衡分布はfeq = w * rho * (1 + e・u / cs^2 + (e・u)^2 / (2 cs^4) - u^2 / (2 cs^2))
+    fn sound_speed2(self) -> f64 {
+        1.0 / 3.0
+    }
+
+    // 平衡分布の係数(w1, w3, w4) = (1 / cs^2, 1 / (2 cs^4), -1 / (2 cs^2)) 入力の平衡分布と重みの初期値に使う
+    fn equilibrium_coefficients(self) -> (f64, f64, f64) {
+        let cs2 = self.sound_speed2();
+        (1.0 / cs2, 0.5 / (cs2 * cs2), -0.5 / cs2)
+    }
+
+    fn q(self) -> usize {
+        self.velocities().len()
+    }
+
+    // 逆向きの速度の添字 bounce-backで使う
+    fn opposite(self, q: usize) -> usize {
+        let velocities = self.velocities();
+        let e = velocities[q];
+        velocities.iter().position(|e_opp| (0..D).all(|a| e_opp[a] == -e[a])).unwrap()
+    }
+}
+
+// D2Q9 速度(dr, dc)は辞書順で、添字はq = (dr + 1) * 3 + (dc + 1)
+// lbm.rsの場と重みは(row, col, q)の配列で、q成分は[r, c, q]に入る(D2Q5も同じ)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct D2Q9;
+
+impl D2Q9 {
+    pub const VELOCITIES: [[i32; 2]; 9] = [[-1, -1], [-1, 0], [-1, 1], [0, -1], [0, 0], [0, 1], [1, -1], [1, 0], [1, 1]];
+    pub const WEIGHTS: [f64; 9] = [1.0/36.0, 1.0/9.0, 1.0/36.0, 1.0/9.0, 4.0/9.0, 1.0/9.0, 1.0/36.0, 1.0/9.0, 1.0/36.0];
+
+    // q番目の速度(dr, dc)をf64で
+    pub fn velocity(q: usize) -> (f64, f64) {
+        let [dr, dc] = D2Q9::VELOCITIES[q];
+        (dr as f64, dc as f64)
+    }
+}
+
+impl Lattice<2> for D2Q9 {
+    fn velocities(self) -> Vec<[i32; 2]> {
+        D2Q9::VELOCITIES.to_vec()
+    }
+
+    fn weights(self) -> Vec<f64> {
+        D2Q9::WEIGHTS.to_vec()
+    }
+
+    fn q(self) -> usize {
+        9
+    }
+
+    fn opposite(self, q: usize) -> usize {
+        8 - q
+    }
+}
+
+// D2Q5 温度や湿度のようなスカラーの移流拡散用 平衡分布が速度の1次までなので、5方向で足りる
+// 速度は(dr, dc)の辞書順で、逆向きの添字は4 - q
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct D2Q5;
+
+impl D2Q5 {
+    pub const VELOCITIES: [[i32; 2]; 5] = [[-1, 0], [0, -1], [0, 0], [0, 1], [1, 0]];
+    pub const WEIGHTS: [f64; 5] = [1.0/6.0, 1.0/6.0, 1.0/3.0, 1.0/6.0, 1.0/6.0];
+}
+
+impl Lattice<2> for D2Q5 {
+    fn velocities(self) -> Vec<[i32; 2]> {
+        D2Q5::VELOCITIES.to_vec()
+    }
+
+    fn weights(self) -> Vec<f64> {
+        D2Q5::WEIGHTS.to_vec()
+    }
+
+    fn q(self) -> usize {
+        5
+    }
+
+    // 速度の1次までなので、2次の係数は0
+    fn equilibrium_coefficients(self) -> (f64, f64, f64) {
+        (1.0 / self.sound_speed2(), 0.0, 0.0)
+    }
+
+    fn opposite(self, q: usize) -> usize {
+        4 - q
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lbm3d::Lattice3d;
+
+    // 重みの和が1、1次のモーメントが0、2次のモーメントがcs^2 I、逆向きの添字が合っていること
+    fn check_lattice<const D: usize, L: Lattice<D> + std::fmt::Debug>(lattice: L) {
+        let (velocities, weights) = (lattice.velocities(), lattice.weights());
+        assert_eq!(lattice.q(), velocities.len());
+        assert_eq!(lattice.q(), weights.len());
+        assert!((weights.iter().sum::<f64>() - 1.0).abs() < 0.000000001, "{:?}", lattice);
+        for a in 0..D {
+            let first: f64 = velocities.iter().zip(&weights).map(|(e, w)| w * e[a] as f64).sum();
+            assert!(first.abs() < 0.000000001, "{:?}", lattice);
+            for b in 0..D {
+                let second: f64 = velocities.iter().zip(&weights).map(|(e, w)| w * (e[a] * e[b]) as f64).sum();
+                let expected = if a == b { lattice.sound_speed2() } else { 0.0 };
+                assert!((second - expected).abs() < 0.000000001, "{:?}", lattice);
+            }
+        }
+        for (q, e) in velocities.iter().enumerate() {
+            let e_opp = velocities[lattice.opposite(q)];
+            assert!((0..D).all(|a| e_opp[a] == -e[a]), "{:?}", lattice);
+        }
+    }
+
+    #[test]
+    fn test_lattice() {
+        check_lattice(D2Q9);
+        check_lattice(D2Q5);
+        check_lattice(Lattice3d::D3Q19);
+        check_lattice(Lattice3d::D3Q27);
+        for (q, [dr, dc]) in D2Q9::VELOCITIES.into_iter().enumerate() {
+            assert_eq!(q as i32, (dr + 1) * 3 + (dc + 1));
+        }
+        let (w1, w3, w4) = D2Q9.equilibrium_coefficients();
+        assert!((w1 - 3.0).abs() < 0.000000001 && (w3 - 4.5).abs() < 0.000000001 && (w4 + 1.5).abs() < 0.000000001);
+    }
+}
@@ -1,93 +1,197 @@
-use ndarray::{Array, Array2, Array4, arr0, arr2, stack, Axis, Zip, s, azip};
+use ndarray::{Array2, Array3, ArrayView, ArrayView2, ArrayView3, CowArray, Dimension, Ix2, Slice, stack, Axis, Zip, s, azip};
+use serde::{Deserialize, Serialize};
+use crate::boundary::{EdgeCondition, Edges, apply_edge, edge_jacobian};
+use crate::collision::{self, Buoyancy, CollisionOperator, Smagorinsky};
+use crate::lattice::{Lattice, D2Q9};
 use ndarray_parallel::prelude::*;
 use std::f64::NAN;
+use std::ops::Sub;
 
 // 計算できない値についてはNaNを入れる
 // 外積(v x u)はdr * u_hori - dc * u_vert
 
 // 命名規則(必ず新しい規則はここに書く)
 // 1->2->3の順に書いていく eg. u_hori_nxnx
-// 1. f:粒子密度  feq:eq場の粒子密度  u:風速  rho:密度
+// 1. f:粒子密度  feq:eq場の粒子密度  u:風速  rho:密度  g, geq:スカラーの粒子密度(lbm_scalar)  phi:スカラー(温度、湿度)  rate:緩和率  temperature:浮力に使う温度(StreamedFieldに載せる)
 // 1. w0,w1,w2,w3,w4:そのレイヤーの重み  dw0,dw1,dw2,dw3,dw4:重みの変化分
-// 2. _vert:縦,緯線方向(下向き正！)  _hori:横,経線方向(右向き正)
+// 2. _vert:縦,緯線方向(下向き正！)  _hori:横,経線方向(右向き正)  _level:気圧面の方向(lbm3d 上向き正)
 // 3. _next:次の  _now:今の(例えばCollidingWeight->CollidedFieldという流れならWeightに対するfield)  _prev:前の
 // Weight(prev) -> Field(prev) -> Weight(now, あるいは添字なし) -> Field(now あるいは添字なし) -> Weight(next) -> Field(next)
-// row:行数  col:列数  r:r行(添字)  c:c列(添字) dr, dc
-// C:係数
+// row:行数  col:列数  r:r行(添字)  c:c列(添字) dr, dc  level:気圧面の数  l:l番目の気圧面(添字) dl  q:速度の添字(lattice::Lattice::velocities()の順 D2Q9ではq = (dr + 1) * 3 + (dc + 1))
+// D2Q9::WEIGHTS:係数  Conservation:質量と運動量の合計(mass, momentum_vert, momentum_hori)  速度と係数はlattice.rsにまとめ、ここでは(dr, dc)の2重ループを書かずにD2Q9::VELOCITIESを回す
+// Lattice*:格子Lについて共通の場と重み(LatticeInputField<L>など) fと重みは(row, col, q)の配列  InputFieldなどはD2Q9の、ScalarInputFieldなど(lbm_scalar)はD2Q5の別名
+// lat:緯度  lon:経度  u, v:気象学の風速(東向き正, 北向き正！) u_vert, u_horiとの変換はgeo::GeoGridで行う
 
-// TODO: 速度改善のために、[dr, dc, r, c]の順にするべきかも 遅かったら後で試してみる
+// TODO: 速度改善のために、[q, r, c]の順にするべきかも 遅かったら後で試してみる
 // TODO: fからu_vert, u_hori, rhoを計算するところは共通化できそう
 // TODO: それぞれの構造体がいまどういう状態か(stream()したか、collide()したか、重みの更新を行ったか)を記録して、不正な状態遷移を防ぐ
 
-const C: [[f64; 3]; 3] = [[1.0/36.0, 1.0/9.0, 1.0/36.0], [1.0/9.0, 4.0/9.0, 1.0/9.0], [1.0/36.0, 1.0/9.0, 1.0/36.0]];
 const ERROR_DELTA: f64 = 0.00000000001;
 
-pub struct InputField {
-    row: usize,
-    col: usize,
-    f: Array4<f64>,
-    u_vert: Array2<f64>,
-    u_hori: Array2<f64>,
+// 先頭2軸(r, c)からmarginぶんを取り除いたビュー NaNの入っていない内側だけを見たいときに使う
+// 重みの内側はinterior(weight.w1(), weight.margin())のように取る
+pub fn interior<D: Dimension>(mut view: ArrayView<'_, f64, D>, margin: usize) -> ArrayView<'_, f64, D> {
+    let row = view.len_of(Axis(0));
+    let col = view.len_of(Axis(1));
+    view.slice_axis_inplace(Axis(0), Slice::from(margin..row-margin));
+    view.slice_axis_inplace(Axis(1), Slice::from(margin..col-margin));
+    view
+}
+
+// streamのときに領域の外側のf_prevをどう扱うか 重みがstreamとbackpropの両方で使う
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Boundary {
+    // 外側は計算せずNaNのままにする streamするたびにmarginが1周ずつ増える
+    #[default]
+    Shrink,
+    // 反対側の端から回り込む(周期境界) marginは0のままで、何回streamしても領域が狭まらない
+    Periodic,
+    // 流入境界 streamの後にmarginのfを外から与えた平衡分布で埋める(fill_margin()) marginは増えない
+    Inflow,
+}
+
+// 格子Lの場と重みのフィールドは、lbm_scalarのD2Q5用の計算からも使うのでpub(crate)
+pub struct LatticeInputField<L> {
+    pub(crate) lattice: L,
+    pub(crate) row: usize,
+    pub(crate) col: usize,
+    pub(crate) f: Array3<f64>,
+    pub(crate) u_vert: Array2<f64>,
+    pub(crate) u_hori: Array2<f64>,
     // u2: Array2<f64>,
-    rho: Array2<f64>,
+    pub(crate) rho: Array2<f64>,
 }
 
-pub struct StreamingWeight {
-    row: usize,
-    col: usize,
-    margin: usize,
-    w0: Array4<f64>,
-    w1: Array4<f64>,
-    dw0: Array4<f64>,
-    dw1: Array4<f64>,
-    delta: Array4<f64>,
+pub type InputField = LatticeInputField<D2Q9>;
+
+#[derive(Clone)]
+pub struct LatticeStreamingWeight<L> {
+    pub(crate) lattice: L,
+    pub(crate) row: usize,
+    pub(crate) col: usize,
+    pub(crate) margin: usize,
+    pub(crate) boundary: Boundary,
+    pub(crate) obstacle: Option<Array2<bool>>, // trueのセルは障害物(地形など)
+    pub(crate) edges: Edges,
+    pub(crate) w0: Array3<f64>,
+    pub(crate) w1: Array3<f64>,
+    pub(crate) dw0: Array3<f64>,
+    pub(crate) dw1: Array3<f64>,
+    pub(crate) delta: Array3<f64>,
+    pub(crate) penalty_delta: Array3<f64>, // 保存則のペナルティの微分 penalty * (mass + e・momentum) delta_to_prev()でf_prevにも流す
 }
 
-pub struct StreamedField {
-    row: usize,
-    col: usize,
-    margin: usize,
-    f: Array4<f64>,
-    u_vert: Array2<f64>,
-    u_hori: Array2<f64>,
-    rho: Array2<f64>,
+pub type StreamingWeight = LatticeStreamingWeight<D2Q9>;
+
+pub struct LatticeStreamedField<L> {
+    pub(crate) lattice: L,
+    pub(crate) row: usize,
+    pub(crate) col: usize,
+    pub(crate) margin: usize,
+    pub(crate) f: Array3<f64>,
+    pub(crate) u_vert: Array2<f64>,
+    pub(crate) u_hori: Array2<f64>,
+    pub(crate) rho: Array2<f64>,
+    pub(crate) temperature: Option<Array2<f64>>, // 浮力を入れるときの温度 lbm_scalarで流したものを置く
 }
 
+pub type StreamedField = LatticeStreamedField<D2Q9>;
+
+// 衝突の重みはD2Q9だけ(MRTの行列が9x9) D2Q5はlbm_scalar::ScalarCollidingWeight
+#[derive(Clone)]
 pub struct CollidingWeight {
     row: usize,
     col: usize,
     margin: usize,
-    w1: Array4<f64>,
-    w2: Array4<f64>,
-    w3: Array4<f64>,
-    w4: Array4<f64>,
-    dw1: Array4<f64>,
-    dw2: Array4<f64>,
-    dw3: Array4<f64>,
-    dw4: Array4<f64>,
-    delta: Array4<f64>,
+    w1: Array3<f64>,
+    w2: Array3<f64>,
+    w3: Array3<f64>,
+    w4: Array3<f64>,
+    dw1: Array3<f64>,
+    dw2: Array3<f64>,
+    dw3: Array3<f64>,
+    dw4: Array3<f64>,
+    force_vert: Array2<f64>, // 外力(格子単位の力の密度) 初期値は0
+    force_hori: Array2<f64>,
+    dforce_vert: Array2<f64>,
+    dforce_hori: Array2<f64>,
+    force_trainable: bool,
+    coriolis: Option<Array2<f64>>, // 格子単位のコリオリパラメータ f * dt (格子の向きの符号込み)
+    operator: CollisionOperator,
+    rates: [f64; 9], // MRTのモーメントごとの緩和率 学習するときは層ごとに変わる
+    drates: [f64; 9],
+    smagorinsky: Option<Smagorinsky>, // Noneのときは緩和率が全セル共通
+    dsmagorinsky: f64,
+    buoyancy: Option<Buoyancy>, // StreamedFieldに温度があるときだけ効く
+    delta: Array3<f64>,
 }
 
-pub struct CollidedField {
-    row: usize,
-    col: usize,
-    margin: usize,
-    f: Array4<f64>,
+// split_delta()の結果 rho, constant, rate0はSmagorinskyの緩和率を通した分だけ(使わないときは0)
+struct SplitDelta {
+    f: [f64; 9],
+    feq: [f64; 9],
+    source: [f64; 9],
+    rho: f64,
+    constant: f64,
+    rate0: f64,
+}
+
+// marginより内側の質量と運動量の合計 StreamedField::conservation()、CollidedField::conservation()で計算する
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Conservation {
+    pub mass: f64,
+    pub momentum_vert: f64,
+    pub momentum_hori: f64,
+}
+
+pub struct LatticeCollidedField<L> {
+    pub(crate) lattice: L,
+    pub(crate) row: usize,
+    pub(crate) col: usize,
+    pub(crate) margin: usize,
+    pub(crate) f: Array3<f64>,
     // u_vert: Array2<f64>, // 多分必要ないので今のところコメントアウトしておく
     // u_hori: Array2<f64>,
     // rho: Array2<f64>,
-    feq: Array4<f64>,
+    pub(crate) feq: Array3<f64>,
 }
 
-impl InputField {
-    pub fn new(row: usize, col: usize) -> InputField {
-        let f = Array4::<f64>::zeros((row, col, 3, 3));
+pub type CollidedField = LatticeCollidedField<D2Q9>;
+
+// 平衡分布 feq = C * rho * (1 + w1 * u_prod + w3 * u_prod^2 + w2 * (e x u) + w4 * u^2)  wは[w1, w2, w3, w4]
+// 学習しないときの係数はLattice::equilibrium_coefficients() (w2 = 0)
+pub(crate) fn equilibrium(weight: f64, e: [i32; 2], u_vert: f64, u_hori: f64, rho: f64, w: [f64; 4]) -> f64 {
+    let (dr, dc) = (e[0] as f64, e[1] as f64);
+    let u_prod = u_vert * dr + u_hori * dc;
+    let u2 = u_vert * u_vert + u_hori * u_hori;
+    weight * rho * (1.0 + (w[2] * u_prod + w[0]) * u_prod + w[1] * (dr * u_hori - dc * u_vert) + w[3] * u2)
+}
+
+// marginの内側だけを値で埋めた(row, col, q)の配列
+fn interior_filled(row: usize, col: usize, q: usize, margin: usize, value: f64) -> Array3<f64> {
+    let mut w = Array3::<f64>::from_elem((row, col, q), NAN);
+    w.slice_mut(s![margin..row-margin, margin..col-margin, ..]).fill(value);
+    w
+}
+
+impl<L: Lattice<2> + Default> LatticeInputField<L> {
+    pub fn new(row: usize, col: usize) -> LatticeInputField<L> {
+        let lattice = L::default();
+        let f = Array3::<f64>::zeros((row, col, lattice.q()));
         let u_vert = Array2::<f64>::zeros((row, col));
         let u_hori = Array2::<f64>::zeros((row, col));
         let rho = Array2::<f64>::zeros((row, col));
-        InputField { row, col, f, u_vert, u_hori, rho }
+        LatticeInputField { lattice, row, col, f, u_vert, u_hori, rho }
     }
 
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn f(self: &Self) -> ArrayView3<'_, f64> { self.f.view() }
+    pub fn u_vert(self: &Self) -> ArrayView2<'_, f64> { self.u_vert.view() }
+    pub fn u_hori(self: &Self) -> ArrayView2<'_, f64> { self.u_hori.view() }
+    pub fn rho(self: &Self) -> ArrayView2<'_, f64> { self.rho.view() }
+
     pub fn set(self: &mut Self, u_vert: Array2<f64>, u_hori: Array2<f64>, rho: Array2<f64>) {
         if [self.row, self.col] != u_vert.shape() || [self.row, self.col] != u_hori.shape() || [self.row, self.col] != rho.shape() {
             panic!("panicked at line {} in {}", line!(), file!());
@@ -96,162 +200,494 @@ impl InputField {
         self.u_hori = u_hori;
         self.rho = rho;
 
-        for dr in -1..=1_i32 { 
-            for dc in -1..=1_i32 {
-                let mut f_slice = self.f.slice_mut(s![.., .., dr+1, dc+1]);
-                Zip::from(&mut f_slice).and(&self.u_vert).and(&self.u_hori).and(&self.rho)
-                    .for_each(|f, u_vert, u_hori, rho| {
-                        let dr_f = dr as f64; // -1., 0., 1.のいずれかに変換
-                        let dc_f = dc as f64;
-                        let u2 = u_vert * u_vert + u_hori * u_hori; // ここちょっと無駄な気もする
-                        let u_prod = u_vert * dr_f + u_hori * dc_f;
-                        *f = C[(dr+1) as usize][(dc+1) as usize] * rho * (1.0 + (3.0 + 4.5 * u_prod) * u_prod - 1.5 * u2);
-                    });
-            }
+        let (w1, w3, w4) = self.lattice.equilibrium_coefficients();
+        for (q, (e, weight)) in self.lattice.velocities().into_iter().zip(self.lattice.weights()).enumerate() {
+            let mut f_slice = self.f.slice_mut(s![.., .., q]);
+            Zip::from(&mut f_slice).and(&self.u_vert).and(&self.u_hori).and(&self.rho)
+                .for_each(|f, u_vert, u_hori, rho| {
+                    *f = equilibrium(weight, e, *u_vert, *u_hori, *rho, [w1, 0.0, w3, w4]);
+                });
         }
     }
 }
 
-impl StreamingWeight {
-    pub fn new(row: usize, col: usize, margin: usize) -> StreamingWeight {
-        let mut w0 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut w1 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut dw0 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut dw1 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut delta = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        w0.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        w1.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(1.0);
-        dw0.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        dw1.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        delta.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        StreamingWeight { row, col, margin, w0, w1, dw0, dw1, delta }
-    }
-
-    pub fn propagate_from_output(self: &mut Self, eta: f64, field_now: &StreamedField, field_prev: &CollidedField, u_vert_ans: &Array2<f64>, u_hori_ans: &Array2<f64>) {
-        let shape = [self.col, self.row];
-        if shape != [field_now.col, field_now.row] || shape != [field_prev.col, field_prev.col] || shape != u_vert_ans.shape() || shape != u_hori_ans.shape() {
+impl Boundary {
+    // 1回streamしたときにmarginがいくつ増えるか
+    pub fn margin_growth(self: Self) -> usize {
+        match self {
+            Boundary::Shrink => 1,
+            Boundary::Periodic | Boundary::Inflow => 0,
+        }
+    }
+}
+
+impl<L: Lattice<2> + Default> LatticeStreamingWeight<L> {
+    pub fn new(row: usize, col: usize, margin: usize) -> LatticeStreamingWeight<L> {
+        LatticeStreamingWeight::with_boundary(row, col, margin, Boundary::Shrink)
+    }
+
+    // 周期境界のときはmarginを0に、流入境界のときは1以上にすること
+    pub fn with_boundary(row: usize, col: usize, margin: usize, boundary: Boundary) -> LatticeStreamingWeight<L> {
+        if (boundary == Boundary::Periodic && margin != 0) || (boundary == Boundary::Inflow && margin == 0) {
             panic!("panicked at line {} in {}", line!(), file!());
         }
-        if self.margin != field_now.margin || self.margin != field_prev.margin + 1 {
+        let lattice = L::default();
+        let interior = |value: f64| interior_filled(row, col, lattice.q(), margin, value);
+        let (w0, w1, dw0, dw1, delta, penalty_delta) = (interior(0.0), interior(1.0), interior(0.0), interior(0.0), interior(0.0), interior(0.0));
+        LatticeStreamingWeight { lattice, row, col, margin, boundary, obstacle: None, edges: Edges::default(), w0, w1, dw0, dw1, delta, penalty_delta }
+    }
+
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn margin(self: &Self) -> usize { self.margin }
+    pub fn boundary(self: &Self) -> Boundary { self.boundary }
+    pub fn obstacle(self: &Self) -> Option<ArrayView2<'_, bool>> { self.obstacle.as_ref().map(|obstacle| obstacle.view()) }
+    pub fn edges(self: &Self) -> Edges { self.edges }
+    pub fn w0(self: &Self) -> ArrayView3<'_, f64> { self.w0.view() }
+    pub fn w1(self: &Self) -> ArrayView3<'_, f64> { self.w1.view() }
+    pub fn dw0(self: &Self) -> ArrayView3<'_, f64> { self.dw0.view() }
+    pub fn dw1(self: &Self) -> ArrayView3<'_, f64> { self.dw1.view() }
+    pub fn delta(self: &Self) -> ArrayView3<'_, f64> { self.delta.view() }
+
+    // 障害物のセルに入ろうとする粒子は、half-way bounce-backで逆向きになって元のセルに戻る
+    pub fn set_obstacle(self: &mut Self, obstacle: Array2<bool>) {
+        if [self.row, self.col] != obstacle.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.obstacle = Some(obstacle);
+    }
+
+    // 保存しておいた重みを戻す用
+    pub fn set(self: &mut Self, w0: Array3<f64>, w1: Array3<f64>) {
+        if [self.row, self.col, self.lattice.q()] != w0.shape() || [self.row, self.col, self.lattice.q()] != w1.shape() {
             panic!("panicked at line {} in {}", line!(), file!());
         }
+        self.w0 = w0;
+        self.w1 = w1;
+    }
+
+    // StreamedField::apply_obstacle_and_edges()を逆にたどる
+    // 障害物のセルのfは定数なのでdelta = 0、端の条件はf, f_innerについて1次式なので、ヤコビアンの転置をかける
+    // 流出の端は内側のセルの(端の条件を適用する前の)値を読むので、適用したのと逆の順番でたどる
+    pub(crate) fn apply_obstacle_and_edges_to_delta(self: &mut Self) {
+        for (r, c, normal, condition) in self.edge_nodes().into_iter().rev() {
+            let (jacobian, jacobian_inner) = edge_jacobian(normal, condition);
+            let (r_inner, c_inner) = ((r as i32 + normal.0) as usize, (c as i32 + normal.1) as usize);
+            let delta = self.delta.slice(s![r, c, ..]).to_vec();
+            let (mut delta_prev, mut delta_inner) = ([0.0; 9], [0.0; 9]);
+            for ((jacobian_row, jacobian_inner_row), d) in jacobian.iter().zip(&jacobian_inner).zip(delta) {
+                for (d_prev, a) in delta_prev.iter_mut().zip(jacobian_row) {
+                    *d_prev += a * d;
+                }
+                for (d_inner, b) in delta_inner.iter_mut().zip(jacobian_inner_row) {
+                    *d_inner += b * d;
+                }
+            }
+            for q in 0..9 {
+                self.delta[[r, c, q]] = delta_prev[q];
+                self.delta[[r_inner, c_inner, q]] += delta_inner[q];
+            }
+        }
+        if let Some(obstacle) = &self.obstacle {
+            for r in self.margin..self.row-self.margin {
+                for c in self.margin..self.col-self.margin {
+                    if obstacle[[r, c]] {
+                        self.delta.slice_mut(s![r, c, ..]).fill(0.0);
+                    }
+                }
+            }
+        }
+    }
 
-        // rho_now_inv
+    // 端の条件を与えるセル(障害物のセルは除く)と、そこでの内向きの法線(dr, dc) この順番で適用する
+    // 法線方向に1つ内側のセルも領域内にあること Outflowは内側のセルが障害物のときも除く(streamの結果のままにする)
+    fn edge_nodes(self: &Self) -> Vec<(usize, usize, (i32, i32), EdgeCondition)> {
+        let (margin, row, col) = (self.margin, self.row, self.col);
+        let mut nodes = Vec::new();
+        let edges = [
+            (self.edges.north, (1, 0)),
+            (self.edges.south, (-1, 0)),
+            (self.edges.west, (0, 1)),
+            (self.edges.east, (0, -1)),
+        ];
+        for (condition, normal) in edges {
+            let condition = match condition {
+                Some(condition) => condition,
+                None => continue,
+            };
+            let cells: Vec<(usize, usize)> = match normal {
+                (1, 0) => (margin..col-margin).map(|c| (margin, c)).collect(),
+                (-1, 0) => (margin..col-margin).map(|c| (row - margin - 1, c)).collect(),
+                (0, 1) => (margin..row-margin).map(|r| (r, margin)).collect(),
+                _ => (margin..row-margin).map(|r| (r, col - margin - 1)).collect(),
+            };
+            if (normal.0 != 0 && row - 2 * margin < 2) || (normal.1 != 0 && col - 2 * margin < 2) {
+                panic!("panicked at line {} in {}", line!(), file!());
+            }
+            for (r, c) in cells {
+                let inner = ((r as i32 + normal.0) as usize, (c as i32 + normal.1) as usize);
+                if self.obstacle.as_ref().is_some_and(|obstacle| obstacle[[r, c]] || (condition == EdgeCondition::Outflow && obstacle[inner])) {
+                    continue;
+                }
+                nodes.push((r, c, normal, condition));
+            }
+        }
+        nodes
+    }
+
+    // f = w0 + w1 * f_prevなので dw0 = -eta * delta、dw1 = -eta * delta * f_prev
+    pub(crate) fn set_dw(self: &mut Self, eta: f64, f_prev: &Array3<f64>) {
         let row = self.row as i32;
         let col = self.col as i32;
         let margin = self.margin as i32;
-        let mut inv_rho_now = Array2::<f64>::from_elem((self.row, self.col), NAN);
-        Zip::from(&mut inv_rho_now.slice_mut(s![margin..row-margin, margin..col-margin]))
-            .and(&field_now.rho.slice(s![margin..row-margin, margin..col-margin]))
-            .for_each(|inv_rho_now, rho_now| {
-                *inv_rho_now = 1.0 / rho_now;
-            });
-        for dr in -1..=1_i32 {
-            for dc in -1..=1_i32 {
-                Zip::from(&mut self.delta.slice_mut(s![margin..row-margin, margin..col-margin, dr+1, dc+1]))
-                    .and(&inv_rho_now.slice(s![margin..row-margin, margin..col-margin]))
-                    .and(&field_now.u_vert.slice(s![margin..row-margin, margin..col-margin]))
-                    .and(&field_now.u_hori.slice(s![margin..row-margin, margin..col-margin]))
-                    .and(&u_vert_ans.slice(s![margin..row-margin, margin..col-margin]))
-                    .and(&u_hori_ans.slice(s![margin..row-margin, margin..col-margin]))
-                    .for_each(|delta, inv_rho_now, u_vert_now, u_hori_now, u_vert_ans, u_hori_ans|{
-                        *delta = inv_rho_now * ((u_vert_now - u_vert_ans) * (dr as f64 - u_vert_now) + (u_hori_now - u_hori_ans) * (dc as f64 - u_hori_now));
-                    });
-
-                Zip::from(&mut self.dw0.slice_mut(s![margin..row-margin, margin..col-margin, dr+1, dc+1]))
-                    .and(&mut self.dw1.slice_mut(s![margin..row-margin, margin..col-margin, dr+1, dc+1]))
-                    .and(&self.delta.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]))
-                    .and(&field_prev.f.slice(s![margin-dr..row-dr-margin, margin-dc..col-dc-margin, dr+1, dc+1]))
-                    .for_each(|dw0, dw1, delta, f_prev|{
-                        *dw0 = -eta * delta;
-                        *dw1 = *dw0 * f_prev;
-                    });
+        for (q, e) in self.lattice.velocities().into_iter().enumerate() {
+            let f_prev_slice = shifted_f_prev(f_prev, q, e, margin, self);
+            Zip::from(&mut self.dw0.slice_mut(s![margin..row-margin, margin..col-margin, q]))
+                .and(&mut self.dw1.slice_mut(s![margin..row-margin, margin..col-margin, q]))
+                .and(&self.delta.slice(s![margin..row-margin, margin..col-margin, q]))
+                .and(&f_prev_slice)
+                .for_each(|dw0, dw1, delta, f_prev|{
+                    *dw0 = -eta * delta;
+                    *dw1 = *dw0 * f_prev;
+                });
+        }
+    }
+
+    // streamを逆にたどる f_next(r+dr, c+dc) = w0 + w1 * f(r, c)なので、delta(r, c) = delta_next(r+dr, c+dc) * w1_next(r+dr, c+dc)
+    // (r, c, q)は前の層の成分で、eはq番目の速度 この重みの計算範囲から外れる方向は0(周期境界のときは反対側から回り込む)
+    pub(crate) fn delta_to_prev(self: &Self, r: usize, c: usize, q: usize, e: [i32; 2]) -> f64 {
+        let (margin, row, col) = (self.margin as i32, self.row as i32, self.col as i32);
+        let [dr, dc] = e;
+        let (r_next, c_next) = match self.boundary {
+            Boundary::Shrink | Boundary::Inflow => (r as i32 + dr, c as i32 + dc),
+            Boundary::Periodic => ((r as i32 + dr).rem_euclid(row), (c as i32 + dc).rem_euclid(col)),
+        };
+        let in_range = |r: i32, c: i32| margin <= r && r < row - margin && margin <= c && c < col - margin;
+        let is_obstacle = |r: i32, c: i32| {
+            0 <= r && r < row && 0 <= c && c < col && self.obstacle.as_ref().is_some_and(|obstacle| obstacle[[r as usize, c as usize]])
+        };
+        let i_next = if is_obstacle(r as i32, c as i32) {
+            None // 障害物のセルのfはどこにも流れない
+        } else if is_obstacle(r_next, c_next) {
+            // 障害物で跳ね返って、同じセルの逆向きの成分になる
+            in_range(r as i32, c as i32).then(|| [r, c, self.lattice.opposite(q)])
+        } else {
+            in_range(r_next, c_next).then_some([r_next as usize, c_next as usize, q])
+        };
+        // 保存則のペナルティは作られた量w0 + (w1 - 1) f_prevを通してf_prevにもよる
+        i_next.map_or(0.0, |i| self.delta[i] * self.w1[i] + self.penalty_delta[i] * (self.w1[i] - 1.0))
+    }
+
+    // streamで作られる(消える)質量と運動量 恒等な重み(w0 = 0, w1 = 1)からのずれで、セルごとにΣ(w0 + (w1 - 1) f_prev)とΣe(w0 + (w1 - 1) f_prev)
+    // 障害物のセルは0 形は(row - 2 margin, col - 2 margin)
+    fn created(self: &Self, f_prev: &Array3<f64>) -> [Array2<f64>; 3] {
+        let (row, col, margin) = (self.row as i32, self.col as i32, self.margin as i32);
+        let shape = (self.row - 2 * self.margin, self.col - 2 * self.margin);
+        let mut created = [Array2::zeros(shape), Array2::zeros(shape), Array2::zeros(shape)];
+        for (q, e) in self.lattice.velocities().into_iter().enumerate() {
+            let [dr, dc] = e;
+            let f_prev_slice = shifted_f_prev(f_prev, q, e, margin, self);
+            let [mass, momentum_vert, momentum_hori] = &mut created;
+            Zip::from(mass).and(momentum_vert).and(momentum_hori)
+                .and(&self.w0.slice(s![margin..row-margin, margin..col-margin, q]))
+                .and(&self.w1.slice(s![margin..row-margin, margin..col-margin, q]))
+                .and(&f_prev_slice)
+                .for_each(|mass, momentum_vert, momentum_hori, w0, w1, f_prev| {
+                    let created = w0 + (w1 - 1.0) * f_prev;
+                    *mass += created;
+                    *momentum_vert += dr as f64 * created;
+                    *momentum_hori += dc as f64 * created;
+                });
+        }
+        if let Some(obstacle) = &self.obstacle {
+            let obstacle = obstacle.slice(s![margin..row-margin, margin..col-margin]);
+            for created in created.iter_mut() {
+                Zip::from(created).and(&obstacle).for_each(|created, obstacle| if *obstacle { *created = 0.0; });
             }
         }
+        created
+    }
+
+    // 保存則のペナルティ penalty / 2 * Σ(作られた質量^2 + 作られた運動量^2)
+    pub fn conservation_penalty(self: &Self, penalty: f64, field_prev: &LatticeCollidedField<L>) -> f64 {
+        self.conservation_penalty_from_f_prev(penalty, &field_prev.f)
+    }
+
+    pub fn conservation_penalty_with_input_field(self: &Self, penalty: f64, field_prev: &LatticeInputField<L>) -> f64 {
+        self.conservation_penalty_from_f_prev(penalty, &field_prev.f)
+    }
+
+    fn conservation_penalty_from_f_prev(self: &Self, penalty: f64, f_prev: &Array3<f64>) -> f64 {
+        self.created(f_prev).iter().map(|created| created.iter().map(|x| x * x).sum::<f64>()).sum::<f64>() * penalty / 2.0
+    }
+
+    // ペナルティの勾配をdw0, dw1に足す propagate_*()の後、前の層のpropagate_from_streaming_weight()とupdate()の前に呼ぶ
+    // d/dw0 = penalty * (mass + e・momentum)、d/dw1 = d/dw0 * f_prev f_prevへの勾配d/dw0 * (w1 - 1)はdelta_to_prev()で前の層に流す
+    pub fn add_conservation_penalty(self: &mut Self, eta: f64, penalty: f64, field_prev: &LatticeCollidedField<L>) {
+        self.add_conservation_penalty_from_f_prev(eta, penalty, &field_prev.f);
+    }
+
+    pub fn add_conservation_penalty_with_input_field(self: &mut Self, eta: f64, penalty: f64, field_prev: &LatticeInputField<L>) {
+        self.add_conservation_penalty_from_f_prev(eta, penalty, &field_prev.f);
+    }
+
+    fn add_conservation_penalty_from_f_prev(self: &mut Self, eta: f64, penalty: f64, f_prev: &Array3<f64>) {
+        let (row, col, margin) = (self.row as i32, self.col as i32, self.margin as i32);
+        let [mass, momentum_vert, momentum_hori] = self.created(f_prev);
+        for (q, e) in self.lattice.velocities().into_iter().enumerate() {
+            let [dr, dc] = e;
+            let f_prev_slice = shifted_f_prev(f_prev, q, e, margin, self);
+            let grad = (&mass + &(&momentum_vert * dr as f64) + &(&momentum_hori * dc as f64)) * penalty;
+            Zip::from(&mut self.dw0.slice_mut(s![margin..row-margin, margin..col-margin, q]))
+                .and(&mut self.dw1.slice_mut(s![margin..row-margin, margin..col-margin, q]))
+                .and(&grad).and(&f_prev_slice)
+                .for_each(|dw0, dw1, grad, f_prev| {
+                    *dw0 -= eta * grad;
+                    *dw1 -= eta * grad * f_prev;
+                });
+            self.penalty_delta.slice_mut(s![margin..row-margin, margin..col-margin, q]).assign(&grad);
+        }
     }
 
     pub fn update(self: &mut Self) {
         let margin = self.margin;
         let row = self.row;
         let col = self.col;
-        let mut w0_slice = self.w0.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]);
-        let mut w1_slice = self.w1.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]);
-        let dw0_slice = self.dw0.slice(s![margin..row-margin, margin..col-margin, .., ..]);
-        let dw1_slice = self.dw1.slice(s![margin..row-margin, margin..col-margin, .., ..]);
+        let mut w0_slice = self.w0.slice_mut(s![margin..row-margin, margin..col-margin, ..]);
+        let mut w1_slice = self.w1.slice_mut(s![margin..row-margin, margin..col-margin, ..]);
+        let dw0_slice = self.dw0.slice(s![margin..row-margin, margin..col-margin, ..]);
+        let dw1_slice = self.dw1.slice(s![margin..row-margin, margin..col-margin, ..]);
         Zip::from(&mut w0_slice).and(&dw0_slice).for_each(|w0, dw0|{ *w0 = *w0 + dw0; });
         Zip::from(&mut w1_slice).and(&dw1_slice).for_each(|w1, dw1|{ *w1 = *w1 + dw1; });
-        self.dw0.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        self.dw1.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
+        self.dw0.slice_mut(s![margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        self.dw1.slice_mut(s![margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        self.penalty_delta.slice_mut(s![margin..row-margin, margin..col-margin, ..]).fill(0.0);
+    }
+}
+
+impl StreamingWeight {
+    // 領域の端(marginの内側の最初/最後の行と列)の条件 streamの後に外から入ってくる成分を決める(Zou–He、流出)
+    // 端の条件はD2Q9の成分で書いてあるので、D2Q9の重みだけが持てる
+    pub fn set_edges(self: &mut Self, edges: Edges) {
+        self.edges = edges;
+    }
+
+    // 出力の風速はcolliding_weight_prev(field_prevを作ったcollide)の外力の半分を足したもの(CollidingWeight::physical_velocity())
+    // 外力を学習するときは、colliding_weight_prev.propagate_force_from_output()も呼ぶ
+    pub fn propagate_from_output(self: &mut Self, eta: f64, field_now: &StreamedField, field_prev: &CollidedField, colliding_weight_prev: &CollidingWeight, u_vert_ans: &Array2<f64>, u_hori_ans: &Array2<f64>) {
+        if [self.row, self.col] != [field_prev.row, field_prev.col] || self.margin != field_prev.margin + self.boundary.margin_growth() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.set_delta_from_output(field_now, Some(colliding_weight_prev), u_vert_ans, u_hori_ans);
+        self.apply_obstacle_and_edges_to_delta();
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    // 1層目(InputFieldから流す層)が出力層のとき用 collideがないので、出力の風速はΣe f / rhoのまま
+    pub fn propagate_from_output_with_input_field(self: &mut Self, eta: f64, field_now: &StreamedField, field_prev: &InputField, u_vert_ans: &Array2<f64>, u_hori_ans: &Array2<f64>) {
+        if [self.row, self.col] != [field_prev.row, field_prev.col] {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.set_delta_from_output(field_now, None, u_vert_ans, u_hori_ans);
+        self.apply_obstacle_and_edges_to_delta();
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    // colliding_weight_nextのdeltaが計算済みであること
+    pub fn propagate_from_colliding_weight(self: &mut Self, eta: f64, field_now: &StreamedField, field_prev: &CollidedField, colliding_weight_next: &CollidingWeight) {
+        if [self.row, self.col] != [field_prev.row, field_prev.col] || self.margin != field_prev.margin + self.boundary.margin_growth() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.set_delta_from_colliding_weight(field_now, colliding_weight_next);
+        self.apply_obstacle_and_edges_to_delta();
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    pub fn propagate_from_colliding_weight_with_input_field(self: &mut Self, eta: f64, field_now: &StreamedField, field_prev: &InputField, colliding_weight_next: &CollidingWeight) {
+        if [self.row, self.col] != [field_prev.row, field_prev.col] {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.set_delta_from_colliding_weight(field_now, colliding_weight_next);
+        self.apply_obstacle_and_edges_to_delta();
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    // 損失 1/2 * ((u_vert - u_vert_ans)^2 + (u_hori - u_hori_ans)^2) をfield_nowのfで微分したもの
+    // uは外力の半分を足した風速 u = (Σe f + F / 2) / rho、F = F0 + coriolis * rho * (-u_hori, u_vert)(Σe f / rhoの風速)
+    // du/df = (e - u) / rho - F0 / (2 rho^2)とコリオリ力の分 colliding_weight_prevがNoneのときは外力なし
+    fn set_delta_from_output(self: &mut Self, field_now: &StreamedField, colliding_weight_prev: Option<&CollidingWeight>, u_vert_ans: &Array2<f64>, u_hori_ans: &Array2<f64>) {
+        let shape = [self.row, self.col];
+        if shape != [field_now.row, field_now.col] || shape != u_vert_ans.shape() || shape != u_hori_ans.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        if self.margin != field_now.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+
+        for r in self.margin..self.row-self.margin {
+            for c in self.margin..self.col-self.margin {
+                let inv_rho_now = 1.0 / field_now.rho[[r, c]];
+                let (u_vert_now, u_hori_now) = (field_now.u_vert[[r, c]], field_now.u_hori[[r, c]]);
+                let (u_vert, u_hori, force_vert, force_hori, coriolis) = match colliding_weight_prev {
+                    Some(cw) => {
+                        let (u_vert, u_hori, _, _) = cw.forced_velocity(field_now, r, c);
+                        let (force_vert, force_hori, coriolis) = cw.force_and_coriolis(field_now, r, c);
+                        (u_vert, u_hori, force_vert, force_hori, coriolis)
+                    },
+                    None => (u_vert_now, u_hori_now, 0.0, 0.0, 0.0),
+                };
+                let (d_u_vert, d_u_hori) = (u_vert - u_vert_ans[[r, c]], u_hori - u_hori_ans[[r, c]]);
+                let d_rho = (d_u_vert * force_vert + d_u_hori * force_hori) * inv_rho_now / 2.0;
+                for (q, [dr, dc]) in D2Q9::VELOCITIES.into_iter().enumerate() {
+                    let (du_vert, du_hori) = (dr as f64 - u_vert_now, dc as f64 - u_hori_now);
+                    self.delta[[r, c, q]] = inv_rho_now * (d_u_vert * (du_vert - coriolis / 2.0 * du_hori) + d_u_hori * (du_hori + coriolis / 2.0 * du_vert) - d_rho);
+                }
+            }
+        }
+    }
+
+    // collide()を逆にたどる feq = C * rho * (1 + w1 * u_prod + w3 * u_prod^2 + w2 * (v x u) + w4 * u^2)、f_next = f - A (f - feq) + (I - A/2) S(u, F)
+    // rho = Σf、u = (Σe f + F / 2) / rhoなので、d(rho)/df = 1、du/df = (e - u) / rho
+    fn set_delta_from_colliding_weight(self: &mut Self, field_now: &StreamedField, colliding_weight_next: &CollidingWeight) {
+        if [self.row, self.col] != [field_now.row, field_now.col] || [self.row, self.col] != [colliding_weight_next.row, colliding_weight_next.col] {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        if self.margin != field_now.margin || self.margin != colliding_weight_next.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let cw = colliding_weight_next;
+        let a = cw.collision_matrix();
+        for r in self.margin..self.row-self.margin {
+            for c in self.margin..self.col-self.margin {
+                let rho = field_now.rho[[r, c]];
+                let (u_vert, u_hori, force_vert, force_hori) = cw.forced_velocity(field_now, r, c);
+                let u2 = u_vert * u_vert + u_hori * u_hori;
+                let SplitDelta { f: delta_f, feq: delta_feq, source: delta_source, rho: mut d_rho, .. } = cw.split_delta(&a, field_now, r, c);
+                let mut d_u_vert = 0.0;
+                let mut d_u_hori = 0.0;
+                let (mut d_force_vert, mut d_force_hori) = (0.0, 0.0);
+                for (q, [dr, dc]) in D2Q9::VELOCITIES.into_iter().enumerate() {
+                    let i = [r, c, q];
+                    let (dr_f, dc_f) = (dr as f64, dc as f64);
+                    let u_prod = u_vert * dr_f + u_hori * dc_f;
+                    let u_cross = dr_f * u_hori - dc_f * u_vert;
+                    let delta_eq = delta_feq[q] * D2Q9::WEIGHTS[q];
+                    d_rho += delta_eq * (1.0 + (cw.w3[i] * u_prod + cw.w1[i]) * u_prod + cw.w2[i] * u_cross + cw.w4[i] * u2);
+                    d_u_vert += delta_eq * rho * ((2.0 * cw.w3[i] * u_prod + cw.w1[i]) * dr_f - cw.w2[i] * dc_f + 2.0 * cw.w4[i] * u_vert);
+                    d_u_hori += delta_eq * rho * ((2.0 * cw.w3[i] * u_prod + cw.w1[i]) * dc_f + cw.w2[i] * dr_f + 2.0 * cw.w4[i] * u_hori);
+                    if force_vert != 0.0 || force_hori != 0.0 {
+                        let (d_source_u, d_source_force) = guo_source_grad(q, u_vert, u_hori, force_vert, force_hori);
+                        d_u_vert += delta_source[q] * d_source_u.0;
+                        d_u_hori += delta_source[q] * d_source_u.1;
+                        d_force_vert += delta_source[q] * d_source_force.0;
+                        d_force_hori += delta_source[q] * d_source_force.1;
+                    }
+                }
+                // コリオリ力はfによるので、dL/dF * dF/dfも足す
+                let coriolis = match &cw.coriolis {
+                    Some(coriolis) if rho > 0.0 => coriolis[[r, c]],
+                    _ => 0.0,
+                };
+                let d_force_vert = d_u_vert / (2.0 * rho) + d_force_vert;
+                let d_force_hori = d_u_hori / (2.0 * rho) + d_force_hori;
+                for (q, [dr, dc]) in D2Q9::VELOCITIES.into_iter().enumerate() {
+                    let i = [r, c, q];
+                    self.delta[i] = delta_f[q] + d_rho + (d_u_vert * (dr as f64 - u_vert) + d_u_hori * (dc as f64 - u_hori)) / rho;
+                    if coriolis != 0.0 {
+                        self.delta[i] += coriolis * (d_force_hori * dr as f64 - d_force_vert * dc as f64);
+                    }
+                }
+            }
+        }
     }
 }
 
-impl StreamedField {
-    pub fn new(row: usize, col: usize, margin: usize) -> StreamedField {
-        let mut f = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
+impl<L: Lattice<2> + Default> LatticeStreamedField<L> {
+    pub fn new(row: usize, col: usize, margin: usize) -> LatticeStreamedField<L> {
+        let lattice = L::default();
+        let f = interior_filled(row, col, lattice.q(), margin, 0.0);
         let mut u_vert = Array2::<f64>::from_elem((row, col), NAN);
         let mut u_hori = Array2::<f64>::from_elem((row, col), NAN);
         let mut rho = Array2::<f64>::from_elem((row, col), NAN);
-        f.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
         u_vert.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
         u_hori.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
         rho.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
-        StreamedField { row, col, margin, f, u_vert, u_hori, rho }
+        LatticeStreamedField { lattice, row, col, margin, f, u_vert, u_hori, rho, temperature: None }
     }
 
-    pub fn stream_from_input_field(self: &mut Self, input_field: &InputField, streaming_weight: &StreamingWeight) {
-        if [self.row, self.col] != [input_field.row, input_field.col] || [self.row, self.col] != [streaming_weight.row, streaming_weight.col] {
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn margin(self: &Self) -> usize { self.margin }
+    pub fn f(self: &Self) -> ArrayView3<'_, f64> { self.f.view() }
+    pub fn u_vert(self: &Self) -> ArrayView2<'_, f64> { self.u_vert.view() }
+    pub fn u_hori(self: &Self) -> ArrayView2<'_, f64> { self.u_hori.view() }
+    pub fn rho(self: &Self) -> ArrayView2<'_, f64> { self.rho.view() }
+    pub fn f_interior(self: &Self) -> ArrayView3<'_, f64> { interior(self.f.view(), self.margin) }
+    pub fn u_vert_interior(self: &Self) -> ArrayView2<'_, f64> { interior(self.u_vert.view(), self.margin) }
+    pub fn u_hori_interior(self: &Self) -> ArrayView2<'_, f64> { interior(self.u_hori.view(), self.margin) }
+    pub fn rho_interior(self: &Self) -> ArrayView2<'_, f64> { interior(self.rho.view(), self.margin) }
+    pub fn temperature(self: &Self) -> Option<ArrayView2<'_, f64>> { self.temperature.as_ref().map(|temperature| temperature.view()) }
+
+    // 層ごとにmarginが違うので、比べるときは同じmargin(一番内側の層のもの)で足す
+    pub fn conservation(self: &Self, margin: usize) -> Conservation {
+        if margin < self.margin {
             panic!("panicked at line {} in {}", line!(), file!());
         }
-        if self.margin != streaming_weight.margin {
+        Conservation::total(&self.f, &self.lattice.velocities(), margin)
+    }
+
+    // 浮力(CollidingWeight::set_buoyancy())の温度 marginの内側で値が入っていること 学習では定数として扱う
+    pub fn set_temperature(self: &mut Self, temperature: Array2<f64>) {
+        if [self.row, self.col] != temperature.shape() {
             panic!("panicked at line {} in {}", line!(), file!());
         }
-        let margin = self.margin as i32;
-        let row = self.row as i32;
-        let col = self.col as i32;
-        self.u_vert.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
-        self.u_hori.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
-        self.rho.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
+        self.temperature = Some(temperature);
+    }
 
-        for dr in -1..=1_i32 {
-            for dc in -1..=1_i32 {
-                let mut f_slice = self.f.slice_mut(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let w0_slice = streaming_weight.w0.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let w1_slice = streaming_weight.w1.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let f_prev_slice = input_field.f.slice(s![margin-dr..row-dr-margin, margin-dc..col-dc-margin, dr+1, dc+1]);
-                Zip::from(&mut f_slice).and(&w0_slice).and(&w1_slice).and(&f_prev_slice)
-                    .for_each(|f, w0, w1, f_prev| {
-                        *f = w0 + w1 * f_prev;
-                    });
-                
-                let f_slice = self.f.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let mut u_vert_slice = self.u_vert.slice_mut(s![margin..row-margin, margin..col-margin]);
-                let mut u_hori_slice = self.u_hori.slice_mut(s![margin..row-margin, margin..col-margin]);
-                let mut rho_slice = self.rho.slice_mut(s![margin..row-margin, margin..col-margin]);
-                Zip::from(&f_slice).and(&mut u_vert_slice).and(&mut u_hori_slice).and(&mut rho_slice)
-                    .for_each(|f, u_vert, u_hori, rho|{
-                        *u_vert += f * dr as f64;
-                        *u_hori += f * dc as f64;
-                        *rho += f;
-                    });
-            }
+    // 流入境界用 marginのf, u_vert, u_hori, rhoをboundary(平衡分布)の値で埋める 重みには依存しないのでbackpropでは0として扱われる
+    pub fn fill_margin(self: &mut Self, boundary: &LatticeInputField<L>) {
+        if [self.row, self.col] != [boundary.row, boundary.col] {
+            panic!("panicked at line {} in {}", line!(), file!());
         }
-
-        let mut u_vert_slice = self.u_vert.slice_mut(s![margin..row-margin, margin..col-margin]);
-        let mut u_hori_slice = self.u_hori.slice_mut(s![margin..row-margin, margin..col-margin]);
-        let rho_slice = self.rho.slice(s![margin..row-margin, margin..col-margin]);
-        Zip::from(&mut u_vert_slice).and(&mut u_hori_slice).and(&rho_slice).for_each(|u_vert, u_hori, rho| {
-            *u_vert /= rho;
-            *u_hori /= rho;
+        let (margin, row, col) = (self.margin, self.row, self.col);
+        let in_margin = |r: usize, c: usize| r < margin || r >= row - margin || c < margin || c >= col - margin;
+        Zip::indexed(&mut self.u_vert).and(&mut self.u_hori).and(&boundary.u_vert).and(&boundary.u_hori)
+            .for_each(|(r, c), u_vert, u_hori, u_vert_b, u_hori_b| {
+                if in_margin(r, c) {
+                    *u_vert = *u_vert_b;
+                    *u_hori = *u_hori_b;
+                }
+            });
+        Zip::indexed(&mut self.rho).and(&boundary.rho).for_each(|(r, c), rho, rho_b| {
+            if in_margin(r, c) { *rho = *rho_b; }
+        });
+        Zip::indexed(&mut self.f).and(&boundary.f).for_each(|(r, c, _), f, f_b| {
+            if in_margin(r, c) { *f = *f_b; }
         });
     }
 
-    pub fn stream_from_collided_field(self: &mut Self, collided_field: &CollidedField, streaming_weight: &StreamingWeight) {
+    pub fn stream_from_input_field(self: &mut Self, input_field: &LatticeInputField<L>, streaming_weight: &LatticeStreamingWeight<L>) {
+        if [self.row, self.col] != [input_field.row, input_field.col] || [self.row, self.col] != [streaming_weight.row, streaming_weight.col] {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        if self.margin != streaming_weight.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.stream(&input_field.f, streaming_weight);
+    }
+
+    pub fn stream_from_collided_field(self: &mut Self, collided_field: &LatticeCollidedField<L>, streaming_weight: &LatticeStreamingWeight<L>) {
         if [self.row, self.col] != [collided_field.row, collided_field.col] || [self.row, self.col] != [streaming_weight.row, streaming_weight.col] {
             panic!("panicked at line {} in {}", line!(), file!());
         }
-        if self.margin != streaming_weight.margin || self.margin != collided_field.margin + 1 {
+        if self.margin != streaming_weight.margin || self.margin != collided_field.margin + streaming_weight.boundary.margin_growth() {
             panic!("panicked at line {} in {}", line!(), file!());
         }
+        self.stream(&collided_field.f, streaming_weight);
+    }
+
+    // rho = 0のセル(障害物、スカラーが0のところ)はu = 0にする
+    fn stream(self: &mut Self, f_prev: &Array3<f64>, streaming_weight: &LatticeStreamingWeight<L>) {
         let margin = self.margin as i32;
         let row = self.row as i32;
         let col = self.col as i32;
@@ -259,95 +695,470 @@ impl StreamedField {
         self.u_hori.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
         self.rho.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
 
-        for dr in -1..=1_i32 {
-            for dc in -1..=1_i32 {
-                let mut f_slice = self.f.slice_mut(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let w0_slice = streaming_weight.w0.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let w1_slice = streaming_weight.w1.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let f_prev_slice = collided_field.f.slice(s![margin-dr..row-dr-margin, margin-dc..col-dc-margin, dr+1, dc+1]);
-                Zip::from(&mut f_slice).and(&w0_slice).and(&w1_slice).and(&f_prev_slice)
-                    .for_each(|f, w0, w1, f_prev| {
-                        *f = w0 + w1 * f_prev;
-                    });
-                
-                let f_slice = self.f.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let mut u_vert_slice = self.u_vert.slice_mut(s![margin..row-margin, margin..col-margin]);
-                let mut u_hori_slice = self.u_hori.slice_mut(s![margin..row-margin, margin..col-margin]);
-                let mut rho_slice = self.rho.slice_mut(s![margin..row-margin, margin..col-margin]);
-                Zip::from(&f_slice).and(&mut u_vert_slice).and(&mut u_hori_slice).and(&mut rho_slice)
-                    .for_each(|f, u_vert, u_hori, rho|{
-                        *u_vert += f * dr as f64;
-                        *u_hori += f * dc as f64;
-                        *rho += f;
-                    });
-            }
+        for (q, e) in self.lattice.velocities().into_iter().enumerate() {
+            let [dr, dc] = e;
+            let mut f_slice = self.f.slice_mut(s![margin..row-margin, margin..col-margin, q]);
+            let w0_slice = streaming_weight.w0.slice(s![margin..row-margin, margin..col-margin, q]);
+            let w1_slice = streaming_weight.w1.slice(s![margin..row-margin, margin..col-margin, q]);
+            let f_prev_slice = shifted_f_prev(f_prev, q, e, margin, streaming_weight);
+            Zip::from(&mut f_slice).and(&w0_slice).and(&w1_slice).and(&f_prev_slice)
+                .for_each(|f, w0, w1, f_prev| {
+                    *f = w0 + w1 * f_prev;
+                });
+
+            let f_slice = self.f.slice(s![margin..row-margin, margin..col-margin, q]);
+            let mut u_vert_slice = self.u_vert.slice_mut(s![margin..row-margin, margin..col-margin]);
+            let mut u_hori_slice = self.u_hori.slice_mut(s![margin..row-margin, margin..col-margin]);
+            let mut rho_slice = self.rho.slice_mut(s![margin..row-margin, margin..col-margin]);
+            Zip::from(&f_slice).and(&mut u_vert_slice).and(&mut u_hori_slice).and(&mut rho_slice)
+                .for_each(|f, u_vert, u_hori, rho|{
+                    *u_vert += f * dr as f64;
+                    *u_hori += f * dc as f64;
+                    *rho += f;
+                });
         }
 
         let mut u_vert_slice = self.u_vert.slice_mut(s![margin..row-margin, margin..col-margin]);
         let mut u_hori_slice = self.u_hori.slice_mut(s![margin..row-margin, margin..col-margin]);
         let rho_slice = self.rho.slice(s![margin..row-margin, margin..col-margin]);
         Zip::from(&mut u_vert_slice).and(&mut u_hori_slice).and(&rho_slice).for_each(|u_vert, u_hori, rho| {
-            *u_vert /= rho;
-            *u_hori /= rho;
+            if *rho != 0.0 {
+                *u_vert /= rho;
+                *u_hori /= rho;
+            }
         });
+
+        self.apply_obstacle_and_edges(streaming_weight);
+    }
+
+    // 障害物のセルは粒子がいないものとしてf = 0, u = 0, rho = 0にする
+    // 端の条件(Zou–He、流出)は、外から入ってくる成分を決めてからu, rhoを計算し直す
+    fn apply_obstacle_and_edges(self: &mut Self, streaming_weight: &LatticeStreamingWeight<L>) {
+        let (margin, row, col) = (self.margin, self.row, self.col);
+        if let Some(obstacle) = &streaming_weight.obstacle {
+            for r in margin..row-margin {
+                for c in margin..col-margin {
+                    if obstacle[[r, c]] {
+                        self.f.slice_mut(s![r, c, ..]).fill(0.0);
+                        self.u_vert[[r, c]] = 0.0;
+                        self.u_hori[[r, c]] = 0.0;
+                        self.rho[[r, c]] = 0.0;
+                    }
+                }
+            }
+        }
+        let velocities = self.lattice.velocities();
+        for (r, c, normal, condition) in streaming_weight.edge_nodes() {
+            let (r_inner, c_inner) = ((r as i32 + normal.0) as usize, (c as i32 + normal.1) as usize);
+            let f_inner = self.f.slice(s![r_inner, c_inner, ..]).to_vec();
+            let mut f = self.f.slice_mut(s![r, c, ..]);
+            apply_edge(f.as_slice_mut().unwrap(), &f_inner, normal, condition);
+            let (mut rho, mut u_vert, mut u_hori) = (0.0, 0.0, 0.0);
+            for (f_e, [dr, dc]) in f.iter().zip(&velocities) {
+                rho += f_e;
+                u_vert += f_e * *dr as f64;
+                u_hori += f_e * *dc as f64;
+            }
+            self.rho[[r, c]] = rho;
+            self.u_vert[[r, c]] = u_vert / rho;
+            self.u_hori[[r, c]] = u_hori / rho;
+        }
     }
 }
 
 impl CollidingWeight {
     pub fn new(row: usize, col: usize, margin: usize) -> CollidingWeight {
-        let mut w1 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut w2 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut w3 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut w4 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut dw1 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut dw2 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut dw3 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut dw4 = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut delta = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        w1.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(3.0);
-        w2.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        w3.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(4.5);
-        w4.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(-1.5);
-        dw1.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        dw2.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        dw3.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        dw4.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        delta.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        CollidingWeight { row, col, margin, w1, w2, w3, w4, dw1, dw2, dw3, dw4, delta }
+        let interior = |value: f64| interior_filled(row, col, D2Q9.q(), margin, value);
+        let (w1_eq, w3_eq, w4_eq) = D2Q9.equilibrium_coefficients();
+        let (w1, w2, w3, w4) = (interior(w1_eq), interior(0.0), interior(w3_eq), interior(w4_eq));
+        let (dw1, dw2, dw3, dw4) = (interior(0.0), interior(0.0), interior(0.0), interior(0.0));
+        let delta = interior(0.0);
+        let mut force_vert = Array2::<f64>::from_elem((row, col), NAN);
+        force_vert.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
+        let force_hori = force_vert.clone();
+        let dforce_vert = force_vert.clone();
+        let dforce_hori = force_vert.clone();
+        CollidingWeight { row, col, margin, w1, w2, w3, w4, dw1, dw2, dw3, dw4, force_vert, force_hori, dforce_vert, dforce_hori, force_trainable: false, coriolis: None, operator: CollisionOperator::Bgk, rates: [0.5; 9], drates: [0.0; 9], smagorinsky: None, dsmagorinsky: 0.0, buoyancy: None, delta }
+    }
+
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn margin(self: &Self) -> usize { self.margin }
+    pub fn w1(self: &Self) -> ArrayView3<'_, f64> { self.w1.view() }
+    pub fn w2(self: &Self) -> ArrayView3<'_, f64> { self.w2.view() }
+    pub fn w3(self: &Self) -> ArrayView3<'_, f64> { self.w3.view() }
+    pub fn w4(self: &Self) -> ArrayView3<'_, f64> { self.w4.view() }
+    pub fn dw1(self: &Self) -> ArrayView3<'_, f64> { self.dw1.view() }
+    pub fn dw2(self: &Self) -> ArrayView3<'_, f64> { self.dw2.view() }
+    pub fn dw3(self: &Self) -> ArrayView3<'_, f64> { self.dw3.view() }
+    pub fn dw4(self: &Self) -> ArrayView3<'_, f64> { self.dw4.view() }
+    pub fn force_vert(self: &Self) -> ArrayView2<'_, f64> { self.force_vert.view() }
+    pub fn force_hori(self: &Self) -> ArrayView2<'_, f64> { self.force_hori.view() }
+    pub fn dforce_vert(self: &Self) -> ArrayView2<'_, f64> { self.dforce_vert.view() }
+    pub fn dforce_hori(self: &Self) -> ArrayView2<'_, f64> { self.dforce_hori.view() }
+    pub fn force_trainable(self: &Self) -> bool { self.force_trainable }
+    pub fn coriolis(self: &Self) -> Option<ArrayView2<'_, f64>> { self.coriolis.as_ref().map(|coriolis| coriolis.view()) }
+    pub fn operator(self: &Self) -> CollisionOperator { self.operator }
+    pub fn rates(self: &Self) -> [f64; 9] { self.rates }
+    pub fn drates(self: &Self) -> [f64; 9] { self.drates }
+    pub fn smagorinsky(self: &Self) -> Option<Smagorinsky> { self.smagorinsky }
+    pub fn dsmagorinsky(self: &Self) -> f64 { self.dsmagorinsky }
+    pub fn buoyancy(self: &Self) -> Option<Buoyancy> { self.buoyancy }
+    pub fn delta(self: &Self) -> ArrayView3<'_, f64> { self.delta.view() }
+
+    // 保存しておいた重みを戻す用
+    pub fn set(self: &mut Self, w1: Array3<f64>, w2: Array3<f64>, w3: Array3<f64>, w4: Array3<f64>) {
+        let shape = [self.row, self.col, D2Q9.q()];
+        if shape != w1.shape() || shape != w2.shape() || shape != w3.shape() || shape != w4.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.w1 = w1;
+        self.w2 = w2;
+        self.w3 = w3;
+        self.w4 = w4;
+    }
+    // セルごとの外力(F_vert, F_hori) collide()でGuoの外力項として入る
+    pub fn set_force(self: &mut Self, force_vert: Array2<f64>, force_hori: Array2<f64>) {
+        if [self.row, self.col] != force_vert.shape() || [self.row, self.col] != force_hori.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.force_vert = force_vert;
+        self.force_hori = force_hori;
+    }
+
+    // trueのときは外力も重みと一緒に学習する
+    pub fn set_force_trainable(self: &mut Self, force_trainable: bool) {
+        self.force_trainable = force_trainable;
+    }
+
+    // コリオリ力 F = rho * f * (-u_hori, u_vert)を外力に足す fは格子単位(geo::GeoGrid::lattice_coriolis()で作る)
+    // rho * u = Σe fについて線形なので、deltaの計算ではdF/df = f * (-dc, dr)
+    pub fn set_coriolis(self: &mut Self, coriolis: Array2<f64>) {
+        if [self.row, self.col] != coriolis.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.coriolis = Some(coriolis);
+    }
+
+    // 緩和率はoperatorの初期値に戻る
+    pub fn set_operator(self: &mut Self, operator: CollisionOperator) {
+        self.operator = operator;
+        self.rates = operator.initial_rates();
+        self.drates = [0.0; 9];
+    }
+
+    // 保存しておいた緩和率を戻す用
+    pub fn set_rates(self: &mut Self, rates: [f64; 9]) {
+        self.rates = rates;
+    }
+
+    // Smagorinskyの渦粘性を入れる Noneで外す 学習した定数は層ごとに変わる
+    pub fn set_smagorinsky(self: &mut Self, smagorinsky: Option<Smagorinsky>) {
+        self.smagorinsky = smagorinsky;
+        self.dsmagorinsky = 0.0;
+    }
+
+    // 温度の偏差に比例する浮力を外力に足す Noneで外す 温度はcollide()に渡すStreamedFieldに置く
+    pub fn set_buoyancy(self: &mut Self, buoyancy: Option<Buoyancy>) {
+        self.buoyancy = buoyancy;
+    }
+
+    fn collision_matrix(self: &Self) -> [[f64; 9]; 9] {
+        collision::collision_matrix(self.operator, &self.rates)
+    }
+
+    // (r, c)でのA Smagorinskyのときは非平衡部分neqから緩和率を決め直す 障害物のセル(rho = 0)ではaのまま
+    fn cell_collision_matrix(self: &Self, a: &[[f64; 9]; 9], neq: &[f64; 9], rho: f64) -> ([[f64; 9]; 9], Option<collision::SmagorinskyRate>) {
+        match self.smagorinsky {
+            Some(smagorinsky) if rho > 0.0 => {
+                let rate0 = self.operator.viscous_rate(&self.rates);
+                let les = collision::smagorinsky_rate(rate0, smagorinsky.constant, neq, rho);
+                (collision::collision_matrix_with_viscous_rate(self.operator, &self.rates, les.rate), Some(les))
+            }
+            _ => (*a, None),
+        }
+    }
+
+    // (r, c)のdelta(f_nextでの微分)を、f_next = f - A (f - feq) + (I - A/2) Sのf, feq, Sでの微分に分ける
+    // Smagorinskyのときは、Aの緩和率がneq = f - feqとrhoによる分も足す
+    fn split_delta(self: &Self, a: &[[f64; 9]; 9], field: &StreamedField, r: usize, c: usize) -> SplitDelta {
+        let mut delta = [0.0; 9];
+        for (q, delta) in delta.iter_mut().enumerate() {
+            *delta = self.delta[[r, c, q]];
+        }
+        let (mut a, mut les, mut neq, mut source) = (*a, None, [0.0; 9], [0.0; 9]);
+        if self.smagorinsky.is_some() {
+            (neq, source) = self.neq_and_source(field, &self.equilibrium(field, r, c), r, c);
+            (a, les) = self.cell_collision_matrix(&a, &neq, field.rho[[r, c]]);
+        }
+        let delta_feq = collision::mul_transpose(&a, &delta);
+        let mut split = SplitDelta { f: [0.0; 9], feq: delta_feq, source: [0.0; 9], rho: 0.0, constant: 0.0, rate0: 0.0 };
+        for i in 0..9 {
+            split.f[i] = delta[i] - delta_feq[i];
+            split.source[i] = delta[i] - delta_feq[i] / 2.0;
+        }
+        if let Some(les) = les {
+            // dL/d(rate) = -delta・(dA/d(rate)) (neq + S / 2)
+            let d_a = collision::viscous_rate_derivative(self.operator, les.rate);
+            let d_a_neq = collision::mul(&d_a, &neq);
+            let d_a_source = collision::mul(&d_a, &source);
+            let d_rate: f64 = (0..9).map(|i| -delta[i] * (d_a_neq[i] + d_a_source[i] / 2.0)).sum();
+            for i in 0..9 {
+                split.f[i] += d_rate * les.d_neq[i];
+                split.feq[i] -= d_rate * les.d_neq[i];
+            }
+            split.rho = d_rate * les.d_rho;
+            split.constant = d_rate * les.d_constant;
+            split.rate0 = d_rate * les.d_rate0;
+        }
+        split
+    }
+
+    // (r, c)での平衡分布 CollidedField::collide()のfeqと同じ式
+    fn equilibrium(self: &Self, field: &StreamedField, r: usize, c: usize) -> [f64; 9] {
+        let rho = field.rho[[r, c]];
+        let (u_vert, u_hori, _, _) = self.forced_velocity(field, r, c);
+        let mut feq = [0.0; 9];
+        for (q, feq) in feq.iter_mut().enumerate() {
+            let i = [r, c, q];
+            *feq = equilibrium(D2Q9::WEIGHTS[q], D2Q9::VELOCITIES[q], u_vert, u_hori, rho, [self.w1[i], self.w2[i], self.w3[i], self.w4[i]]);
+        }
+        feq
+    }
+
+    // (r, c)での非平衡部分f - feqと外力項S collideとbackpropで使う
+    fn neq_and_source(self: &Self, field: &StreamedField, feq: &[f64; 9], r: usize, c: usize) -> ([f64; 9], [f64; 9]) {
+        let (u_vert, u_hori, force_vert, force_hori) = self.forced_velocity(field, r, c);
+        let (mut neq, mut source) = ([0.0; 9], [0.0; 9]);
+        for i in 0..9 {
+            neq[i] = field.f[[r, c, i]] - feq[i];
+            if force_vert != 0.0 || force_hori != 0.0 {
+                source[i] = guo_source(i, u_vert, u_hori, force_vert, force_hori);
+            }
+        }
+        (neq, source)
+    }
+
+    // (r, c)での外力の半分を足した風速 u = (Σe f + F / 2) / rhoと外力 障害物のセル(rho = 0)では外力を0とする
+    fn forced_velocity(self: &Self, field: &StreamedField, r: usize, c: usize) -> (f64, f64, f64, f64) {
+        let rho = field.rho[[r, c]];
+        let (u_vert, u_hori) = (field.u_vert[[r, c]], field.u_hori[[r, c]]);
+        if rho <= 0.0 {
+            return (u_vert, u_hori, 0.0, 0.0);
+        }
+        let (mut force_vert, mut force_hori, coriolis) = self.force_and_coriolis(field, r, c);
+        force_vert -= coriolis * rho * u_hori;
+        force_hori += coriolis * rho * u_vert;
+        (u_vert + force_vert / (2.0 * rho), u_hori + force_hori / (2.0 * rho), force_vert, force_hori)
+    }
+
+    // (r, c)でのfによらない外力(set_force()の外力と浮力)とコリオリパラメータ 障害物のセル(rho = 0)ではどれも0
+    fn force_and_coriolis(self: &Self, field: &StreamedField, r: usize, c: usize) -> (f64, f64, f64) {
+        if field.rho[[r, c]] <= 0.0 {
+            return (0.0, 0.0, 0.0);
+        }
+        let (mut force_vert, force_hori) = (self.force_vert[[r, c]], self.force_hori[[r, c]]);
+        if let (Some(buoyancy), Some(temperature)) = (self.buoyancy, &field.temperature) {
+            force_vert += buoyancy.force_vert(temperature[[r, c]]);
+        }
+        let coriolis = self.coriolis.as_ref().map_or(0.0, |coriolis| coriolis[[r, c]]);
+        (force_vert, force_hori, coriolis)
+    }
+
+    // このcollideの外力の半分を足したfieldの風速(Guo) streamの後のΣe f / rhoではなく、これが流体の速度になる
+    // fieldはこのcollideの後にstreamした場でもよい(marginがこの重み以上であること) marginはNaN
+    pub fn physical_velocity(self: &Self, field: &StreamedField) -> (Array2<f64>, Array2<f64>) {
+        if [self.row, self.col] != [field.row, field.col] || field.margin < self.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let mut u_vert = Array2::<f64>::from_elem((self.row, self.col), NAN);
+        let mut u_hori = Array2::<f64>::from_elem((self.row, self.col), NAN);
+        for r in field.margin..field.row-field.margin {
+            for c in field.margin..field.col-field.margin {
+                (u_vert[[r, c]], u_hori[[r, c]], _, _) = self.forced_velocity(field, r, c);
+            }
+        }
+        (u_vert, u_hori)
+    }
+
+    // 出力の風速をphysical_velocity()で取るときの、損失の外力での微分 du/dF = 1 / (2 rho) 外力を学習するときだけ
+    // propagate_from_streaming_weight()と足し合わせる
+    pub fn propagate_force_from_output(self: &mut Self, eta: f64, output: &StreamedField, u_vert_ans: &Array2<f64>, u_hori_ans: &Array2<f64>) {
+        if [self.row, self.col] != u_vert_ans.shape() || [self.row, self.col] != u_hori_ans.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        if !self.force_trainable {
+            return;
+        }
+        let (u_vert, u_hori) = self.physical_velocity(output);
+        for r in output.margin..output.row-output.margin {
+            for c in output.margin..output.col-output.margin {
+                let rho = output.rho[[r, c]];
+                if rho > 0.0 {
+                    self.dforce_vert[[r, c]] -= eta * (u_vert[[r, c]] - u_vert_ans[[r, c]]) / (2.0 * rho);
+                    self.dforce_hori[[r, c]] -= eta * (u_hori[[r, c]] - u_hori_ans[[r, c]]) / (2.0 * rho);
+                }
+            }
+        }
+    }
+
+    // streaming_weight_nextのdeltaが計算済みであること deltaはLatticeStreamingWeight::delta_to_prev()でstreamを逆にたどる
+    // field_prevの外側1周はstreaming_weight_nextの計算範囲から外れる方向があるので、その分は0になる(周期境界のときは反対側から回り込む)
+    pub fn propagate_from_streaming_weight(self: &mut Self, eta: f64, field_prev: &StreamedField, streaming_weight_next: &StreamingWeight) {
+        if [self.row, self.col] != [field_prev.row, field_prev.col] || [self.row, self.col] != [streaming_weight_next.row, streaming_weight_next.col] {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        if self.margin != field_prev.margin || self.margin + streaming_weight_next.boundary.margin_growth() != streaming_weight_next.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let sw = streaming_weight_next;
+        let a = self.collision_matrix();
+        self.drates = [0.0; 9];
+        self.dsmagorinsky = 0.0;
+        for r in self.margin..self.row-self.margin {
+            for c in self.margin..self.col-self.margin {
+                let rho = field_prev.rho[[r, c]];
+                let (u_vert, u_hori, force_vert, force_hori) = self.forced_velocity(field_prev, r, c);
+                let u2 = u_vert * u_vert + u_hori * u_hori;
+                let (mut d_u_vert, mut d_u_hori, mut d_force_vert, mut d_force_hori) = (0.0, 0.0, 0.0, 0.0);
+                for (q, e) in D2Q9::VELOCITIES.into_iter().enumerate() {
+                    self.delta[[r, c, q]] = sw.delta_to_prev(r, c, q, e);
+                }
+
+                let split = self.split_delta(&a, field_prev, r, c);
+                let (delta_feq, delta_source) = (split.feq, split.source);
+                for (q, [dr, dc]) in D2Q9::VELOCITIES.into_iter().enumerate() {
+                    let i = [r, c, q];
+                    let u_prod = u_vert * dr as f64 + u_hori * dc as f64;
+                    let u_cross = dr as f64 * u_hori - dc as f64 * u_vert;
+                    let delta_eq = delta_feq[q] * D2Q9::WEIGHTS[q] * rho;
+                    self.dw1[i] = -eta * delta_eq * u_prod;
+                    self.dw2[i] = -eta * delta_eq * u_cross;
+                    self.dw3[i] = -eta * delta_eq * u_prod * u_prod;
+                    self.dw4[i] = -eta * delta_eq * u2;
+
+                    if self.force_trainable {
+                        let (dr_f, dc_f) = (dr as f64, dc as f64);
+                        let (d_source_u, d_source_force) = guo_source_grad(q, u_vert, u_hori, force_vert, force_hori);
+                        d_u_vert += delta_eq * ((2.0 * self.w3[i] * u_prod + self.w1[i]) * dr_f - self.w2[i] * dc_f + 2.0 * self.w4[i] * u_vert) + delta_source[q] * d_source_u.0;
+                        d_u_hori += delta_eq * ((2.0 * self.w3[i] * u_prod + self.w1[i]) * dc_f + self.w2[i] * dr_f + 2.0 * self.w4[i] * u_hori) + delta_source[q] * d_source_u.1;
+                        d_force_vert += delta_source[q] * d_source_force.0;
+                        d_force_hori += delta_source[q] * d_source_force.1;
+                    }
+                }
+                // u = (Σe f + F / 2) / rhoなので du/dF = 1 / (2 rho) 出力の分(propagate_force_from_output())に足す
+                if self.force_trainable && rho > 0.0 {
+                    self.dforce_vert[[r, c]] -= eta * (d_u_vert / (2.0 * rho) + d_force_vert);
+                    self.dforce_hori[[r, c]] -= eta * (d_u_hori / (2.0 * rho) + d_force_hori);
+                }
+                // 緩和率とSmagorinsky定数は層で共通なので、全セルの分を足す
+                if self.operator.rates_trainable() {
+                    let mut delta = [0.0; 9];
+                    for (q, delta) in delta.iter_mut().enumerate() {
+                        *delta = self.delta[[r, c, q]];
+                    }
+                    let (neq, source) = self.neq_and_source(field_prev, &self.equilibrium(field_prev, r, c), r, c);
+                    let les = self.smagorinsky.is_some() && rho > 0.0;
+                    for (k, (drate, grad)) in self.drates.iter_mut().zip(collision::rate_gradient(&delta, &neq, &source)).enumerate() {
+                        // Smagorinskyのときは応力の緩和率がrates[7]を基準にセルごとに決まる
+                        if les && k >= 7 {
+                            continue;
+                        }
+                        *drate -= eta * grad;
+                    }
+                    if les {
+                        self.drates[7] -= eta * split.rate0;
+                    }
+                }
+                if self.smagorinsky.is_some_and(|smagorinsky| smagorinsky.trainable) {
+                    self.dsmagorinsky -= eta * split.constant;
+                }
+            }
+        }
     }
 
     pub fn update(self: &mut Self) {
         let margin = self.margin;
         let row = self.row;
         let col = self.col;
-        let mut w1_slice = self.w1.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]);
-        let mut w2_slice = self.w2.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]);
-        let mut w3_slice = self.w3.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]);
-        let mut w4_slice = self.w4.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]);
-        let dw1_slice = self.dw1.slice(s![margin..row-margin, margin..col-margin, .., ..]);
-        let dw2_slice = self.dw2.slice(s![margin..row-margin, margin..col-margin, .., ..]);
-        let dw3_slice = self.dw3.slice(s![margin..row-margin, margin..col-margin, .., ..]);
-        let dw4_slice = self.dw4.slice(s![margin..row-margin, margin..col-margin, .., ..]);
+        let mut w1_slice = self.w1.slice_mut(s![margin..row-margin, margin..col-margin, ..]);
+        let mut w2_slice = self.w2.slice_mut(s![margin..row-margin, margin..col-margin, ..]);
+        let mut w3_slice = self.w3.slice_mut(s![margin..row-margin, margin..col-margin, ..]);
+        let mut w4_slice = self.w4.slice_mut(s![margin..row-margin, margin..col-margin, ..]);
+        let dw1_slice = self.dw1.slice(s![margin..row-margin, margin..col-margin, ..]);
+        let dw2_slice = self.dw2.slice(s![margin..row-margin, margin..col-margin, ..]);
+        let dw3_slice = self.dw3.slice(s![margin..row-margin, margin..col-margin, ..]);
+        let dw4_slice = self.dw4.slice(s![margin..row-margin, margin..col-margin, ..]);
         Zip::from(&mut w1_slice).and(&dw1_slice).for_each(|w1, dw1|{ *w1 = *w1 + dw1; });
         Zip::from(&mut w2_slice).and(&dw2_slice).for_each(|w2, dw2|{ *w2 = *w2 + dw2; });
         Zip::from(&mut w3_slice).and(&dw3_slice).for_each(|w3, dw3|{ *w3 = *w3 + dw3; });
         Zip::from(&mut w4_slice).and(&dw4_slice).for_each(|w4, dw4|{ *w4 = *w4 + dw4; });
-        self.dw1.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        self.dw2.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        self.dw3.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        self.dw4.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
+        self.dw1.slice_mut(s![margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        self.dw2.slice_mut(s![margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        self.dw3.slice_mut(s![margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        self.dw4.slice_mut(s![margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        if self.operator.rates_trainable() {
+            for (rate, drate) in self.rates.iter_mut().zip(&mut self.drates) {
+                *rate += *drate;
+                *drate = 0.0;
+            }
+        }
+        if let Some(smagorinsky) = self.smagorinsky.as_mut().filter(|smagorinsky| smagorinsky.trainable) {
+            smagorinsky.constant += self.dsmagorinsky;
+            self.dsmagorinsky = 0.0;
+        }
+        if self.force_trainable {
+            Zip::from(&mut self.force_vert.slice_mut(s![margin..row-margin, margin..col-margin]))
+                .and(&self.dforce_vert.slice(s![margin..row-margin, margin..col-margin]))
+                .for_each(|force, dforce| { *force += dforce; });
+            Zip::from(&mut self.force_hori.slice_mut(s![margin..row-margin, margin..col-margin]))
+                .and(&self.dforce_hori.slice(s![margin..row-margin, margin..col-margin]))
+                .for_each(|force, dforce| { *force += dforce; });
+            self.dforce_vert.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
+            self.dforce_hori.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
+        }
     }
 }
 
-impl CollidedField {
-    pub fn new(row: usize, col: usize, margin: usize) -> CollidedField {
-        let mut f = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        let mut feq = Array4::<f64>::from_elem((row, col, 3, 3), NAN);
-        f.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        feq.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(0.0);
-        CollidedField { row, col, margin, f, feq }
+impl<L: Lattice<2> + Default> LatticeCollidedField<L> {
+    pub fn new(row: usize, col: usize, margin: usize) -> LatticeCollidedField<L> {
+        let lattice = L::default();
+        let f = interior_filled(row, col, lattice.q(), margin, 0.0);
+        let feq = interior_filled(row, col, lattice.q(), margin, 0.0);
+        LatticeCollidedField { lattice, row, col, margin, f, feq }
     }
 
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn margin(self: &Self) -> usize { self.margin }
+    pub fn f(self: &Self) -> ArrayView3<'_, f64> { self.f.view() }
+    pub fn feq(self: &Self) -> ArrayView3<'_, f64> { self.feq.view() }
+    pub fn f_interior(self: &Self) -> ArrayView3<'_, f64> { interior(self.f.view(), self.margin) }
+    pub fn feq_interior(self: &Self) -> ArrayView3<'_, f64> { interior(self.feq.view(), self.margin) }
+
+    pub fn conservation(self: &Self, margin: usize) -> Conservation {
+        if margin < self.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        Conservation::total(&self.f, &self.lattice.velocities(), margin)
+    }
+
+    // 流入境界用 marginのf, feqをboundary(平衡分布)の値で埋める
+    pub fn fill_margin(self: &mut Self, boundary: &LatticeInputField<L>) {
+        if [self.row, self.col] != [boundary.row, boundary.col] {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let (margin, row, col) = (self.margin, self.row, self.col);
+        Zip::indexed(&mut self.f).and(&mut self.feq).and(&boundary.f).for_each(|(r, c, _), f, feq, f_b| {
+            if r < margin || r >= row - margin || c < margin || c >= col - margin {
+                *f = *f_b;
+                *feq = *f_b;
+            }
+        });
+    }
+}
+
+impl CollidedField {
     pub fn collide(self: &mut Self, streamed_field: &StreamedField, colliding_weight: &CollidingWeight) {
         if [self.row, self.col] != [streamed_field.row, streamed_field.col] || [self.row, self.col] != [colliding_weight.row, colliding_weight.col] {
             panic!("panicked at line {} in {}", line!(), file!());
@@ -355,44 +1166,22 @@ impl CollidedField {
         if self.margin != streamed_field.margin || self.margin != colliding_weight.margin {
             panic!("panicked at line {} in {}", line!(), file!());
         }
-        let margin = self.margin as i32;
-        let row = self.row as i32;
-        let col = self.col as i32;
-        self.feq.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]).fill(1.0); // zip from and and ...が6つまでしか繋いでくれないのでちょっと工夫
-
-        for dr in -1..=1_i32 { 
-            for dc in -1..=1_i32 {
-                let mut feq_slice = self.feq.slice_mut(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let u_vert_prev_slice = streamed_field.u_vert.slice(s![margin..row-margin, margin..col-margin]);
-                let u_hori_prev_slice = streamed_field.u_hori.slice(s![margin..row-margin, margin..col-margin]);
-                let rho_prev_slice = streamed_field.rho.slice(s![margin..row-margin, margin..col-margin]);
-                let w1_slice = colliding_weight.w1.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let w2_slice = colliding_weight.w2.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let w3_slice = colliding_weight.w3.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                let w4_slice = colliding_weight.w4.slice(s![margin..row-margin, margin..col-margin, dr+1, dc+1]);
-                Zip::from(&mut feq_slice).and(&u_vert_prev_slice).and(&u_hori_prev_slice).and(&w1_slice).and(&w3_slice).for_each(|feq, u_vert_prev, u_hori_prev, w1, w3|{
-                    let u_prod = u_vert_prev * dr as f64 + u_hori_prev * dc as f64;
-                    *feq += (w3 * u_prod + w1) * u_prod;
-                });
-                Zip::from(&mut feq_slice).and(&u_vert_prev_slice).and(&u_hori_prev_slice).and(&w2_slice).for_each(|feq, u_vert_prev, u_hori_prev, w2|{
-                    *feq += w2 * (dr as f64 * u_hori_prev - dc as f64 * u_vert_prev);
-                });
-                Zip::from(&mut feq_slice).and(&u_vert_prev_slice).and(&u_hori_prev_slice).and(&w4_slice).for_each(|feq, u_vert_prev, u_hori_prev, w4|{
-                    let u2 = u_vert_prev * u_vert_prev + u_hori_prev * u_hori_prev;
-                    *feq += w4 * u2;
-                });
-                Zip::from(&mut feq_slice).and(&rho_prev_slice).for_each(|feq, rho_prev|{
-                    *feq *= C[(dr+1) as usize][(dc+1) as usize] * rho_prev;
-                });
+        // 平衡分布の風速には外力の半分を足す(Guo)
+        // f_next = f - A (f - feq) + (I - A/2) S  BGKならA = I/2 Smagorinskyのときはセルごとに緩和率が変わる
+        let a = colliding_weight.collision_matrix();
+        for r in self.margin..self.row-self.margin {
+            for c in self.margin..self.col-self.margin {
+                let feq = colliding_weight.equilibrium(streamed_field, r, c);
+                let (neq, source) = colliding_weight.neq_and_source(streamed_field, &feq, r, c);
+                let (a, _) = colliding_weight.cell_collision_matrix(&a, &neq, streamed_field.rho[[r, c]]);
+                let (relaxed, relaxed_source) = (collision::mul(&a, &neq), collision::mul(&a, &source));
+                for q in 0..9 {
+                    let i = [r, c, q];
+                    self.feq[i] = feq[q];
+                    self.f[i] = streamed_field.f[i] - relaxed[q] + source[q] - relaxed_source[q] / 2.0;
+                }
             }
         }
-        
-        let mut f_slice = self.f.slice_mut(s![margin..row-margin, margin..col-margin, .., ..]);
-        let feq_slice = self.feq.slice(s![margin..row-margin, margin..col-margin, .., ..]);
-        let f_prev_slice = streamed_field.f.slice(s![margin..row-margin, margin..col-margin, .., ..]);
-        Zip::from(&mut f_slice).and(&feq_slice).and(&f_prev_slice).for_each(|f, feq, f_prev| {
-            *f = (feq + f_prev) / 2.0;
-        });
     }
 }
 
@@ -402,8 +1191,76 @@ macro_rules! assert_delta {
     }
 }
 
+impl Conservation {
+    fn total(f: &Array3<f64>, velocities: &[[i32; 2]], margin: usize) -> Conservation {
+        let mut total = Conservation::default();
+        for ((_, _, q), f) in interior(f.view(), margin).indexed_iter() {
+            let [dr, dc] = velocities[q];
+            total.mass += f;
+            total.momentum_vert += dr as f64 * f;
+            total.momentum_hori += dc as f64 * f;
+        }
+        total
+    }
+}
+
+impl Sub for Conservation {
+    type Output = Conservation;
+
+    fn sub(self: Self, other: Conservation) -> Conservation {
+        Conservation { mass: self.mass - other.mass, momentum_vert: self.momentum_vert - other.momentum_vert, momentum_hori: self.momentum_hori - other.momentum_hori }
+    }
+}
+
+// Guoの外力項 S = C * ((e - u) / cs^2 + (e・u) e / cs^4)・F cs^2はD2Q9.sound_speed2()
+// Σ S = 0、Σe S = Fで、collideでは(I - A/2) Sを足すので(BGKなら3/4 S)、運動量は1ステップでFだけ増える
+fn guo_source(q: usize, u_vert: f64, u_hori: f64, force_vert: f64, force_hori: f64) -> f64 {
+    let (dr_f, dc_f) = D2Q9::velocity(q);
+    let u_prod = dr_f * u_vert + dc_f * u_hori;
+    let force_prod = dr_f * force_vert + dc_f * force_hori;
+    let cs2 = D2Q9.sound_speed2();
+    D2Q9::WEIGHTS[q] * (((dr_f - u_vert) * force_vert + (dc_f - u_hori) * force_hori) / cs2 + u_prod * force_prod / (cs2 * cs2))
+}
+
+// guo_source()をuとFで微分したもの ((dS/du_vert, dS/du_hori), (dS/dF_vert, dS/dF_hori))
+fn guo_source_grad(q: usize, u_vert: f64, u_hori: f64, force_vert: f64, force_hori: f64) -> ((f64, f64), (f64, f64)) {
+    let (dr_f, dc_f) = D2Q9::velocity(q);
+    let u_prod = dr_f * u_vert + dc_f * u_hori;
+    let force_prod = dr_f * force_vert + dc_f * force_hori;
+    let s = D2Q9::WEIGHTS[q];
+    let cs2 = D2Q9.sound_speed2();
+    let (a1, a2) = (1.0 / cs2, 1.0 / (cs2 * cs2));
+    (
+        (s * (a2 * dr_f * force_prod - a1 * force_vert), s * (a2 * dc_f * force_prod - a1 * force_hori)),
+        (s * (a1 * (dr_f - u_vert) + a2 * u_prod * dr_f), s * (a1 * (dc_f - u_hori) + a2 * u_prod * dc_f)),
+    )
+}
+
+// 方向q(速度e = (dr, dc))について、marginの内側の各セル(r, c)にf_prev[r-dr, c-dc]を並べたもの
+// 周期境界のときは添字が反対側に回り込み、f_prev[r-dr, c-dc]が障害物のときは跳ね返ったf_prev[r, c, -e]になるので、コピーして作る
+fn shifted_f_prev<'a, L: Lattice<2>>(f_prev: &'a Array3<f64>, q: usize, e: [i32; 2], margin: i32, streaming_weight: &LatticeStreamingWeight<L>) -> CowArray<'a, f64, Ix2> {
+    let [dr, dc] = e;
+    let row = f_prev.shape()[0] as i32;
+    let col = f_prev.shape()[1] as i32;
+    if streaming_weight.boundary != Boundary::Periodic && streaming_weight.obstacle.is_none() {
+        return f_prev.slice(s![margin-dr..row-dr-margin, margin-dc..col-dc-margin, q]).into();
+    }
+    Array2::from_shape_fn(((row - 2 * margin) as usize, (col - 2 * margin) as usize), |(r, c)| {
+        let (r, c) = (r as i32 + margin, c as i32 + margin);
+        let (r_prev, c_prev) = match streaming_weight.boundary {
+            Boundary::Periodic => ((r - dr).rem_euclid(row), (c - dc).rem_euclid(col)),
+            _ => (r - dr, c - dc),
+        };
+        match &streaming_weight.obstacle {
+            Some(obstacle) if obstacle[[r_prev as usize, c_prev as usize]] => f_prev[[r as usize, c as usize, streaming_weight.lattice.opposite(q)]],
+            _ => f_prev[[r_prev as usize, c_prev as usize, q]],
+        }
+    }).into()
+}
+
 #[cfg(test)]
 mod tests {
+    use ndarray::{Array, arr0, arr1, arr2};
     use super::*;
 
     #[test]
@@ -424,9 +1281,9 @@ mod tests {
             let u_hori = arr2(&[[-0.2, -0.1], [0.2, 0.2]]);
             let rho = arr2(&[[1.0, 0.8], [0.9, 1.1]]);
             input_field.set(u_vert, u_hori, rho);
-            assert_delta!( 0.39111111111111111111111, *input_field.f.get((0, 0, 1, 1)).unwrap(), ERROR_DELTA ); // 1
-            assert_delta!( 0.00822222222222222222222, *input_field.f.get((0, 1, 0, 2)).unwrap(), ERROR_DELTA ); // 2
-            assert_delta!( 0.05622222222222222222222, *input_field.f.get((1, 1, 1, 0)).unwrap(), ERROR_DELTA ); // 3
+            assert_delta!( 0.39111111111111111111111, *input_field.f.get((0, 0, 4)).unwrap(), ERROR_DELTA ); // 1
+            assert_delta!( 0.00822222222222222222222, *input_field.f.get((0, 1, 2)).unwrap(), ERROR_DELTA ); // 2
+            assert_delta!( 0.05622222222222222222222, *input_field.f.get((1, 1, 3)).unwrap(), ERROR_DELTA ); // 3
         }
     }
 
@@ -434,11 +1291,11 @@ mod tests {
     #[test]
     fn test_streamed_field_stream_from_input_field(){
         let mut input_field = InputField::new(3, 3);
-        input_field.f = Array::range(1., 81.5, 1.).into_shape((3, 3, 3, 3)).unwrap();
+        input_field.f = Array::range(1., 81.5, 1.).into_shape((3, 3, 9)).unwrap();
         let mut streaming_weight = StreamingWeight::new(3, 3, 1);
         let mut streamed_field = StreamedField::new(3, 3, 1);
-        streaming_weight.w0 = streaming_weight.w0 + Array::range(0., 40.2, 0.5).into_shape((3, 3, 3, 3)).unwrap();
-        streaming_weight.w1 = streaming_weight.w1 + Array::range(81., 0.5, -1.).into_shape((3, 3, 3, 3)).unwrap(); // あえて足していることに注意
+        streaming_weight.w0 = streaming_weight.w0 + Array::range(0., 40.2, 0.5).into_shape((3, 3, 9)).unwrap();
+        streaming_weight.w1 = streaming_weight.w1 + Array::range(81., 0.5, -1.).into_shape((3, 3, 9)).unwrap(); // あえて足していることに注意
         for _ in 0..5 {
             streamed_field.stream_from_input_field(&input_field, &streaming_weight);
             // println!("{}", streamed_field.f);
@@ -452,16 +1309,14 @@ mod tests {
                     assert!( streamed_field.u_hori.get((r, c)).unwrap().is_nan() );
                     assert!( streamed_field.rho.get((r, c)).unwrap().is_nan() );
     
-                    for dr in 0..=2 {
-                        for dc in 0..=2 {
-                            assert!( streamed_field.f.get((r, c, dr, dc)).unwrap().is_nan() );
-                        }
+                    for q in 0..9 {
+                        assert!( streamed_field.f.get((r, c, q)).unwrap().is_nan() );
                     }
                 }
             }
-            assert_delta!( *streamed_field.f.get((1, 1, 1, 1)).unwrap(), 1742.0, ERROR_DELTA );
-            assert_delta!( *streamed_field.f.get((1, 1, 0, 2)).unwrap(), 2527.0, ERROR_DELTA );
-            assert_delta!( *streamed_field.f.get((1, 1, 1, 0)).unwrap(), 2126.5, ERROR_DELTA );
+            assert_delta!( *streamed_field.f.get((1, 1, 4)).unwrap(), 1742.0, ERROR_DELTA );
+            assert_delta!( *streamed_field.f.get((1, 1, 2)).unwrap(), 2527.0, ERROR_DELTA );
+            assert_delta!( *streamed_field.f.get((1, 1, 3)).unwrap(), 2126.5, ERROR_DELTA );
             assert_delta!( *streamed_field.rho.get((1, 1)).unwrap(), 16158.0, ERROR_DELTA );
             assert_delta!( *streamed_field.u_vert.get((1, 1)).unwrap(), -0.41942072038, ERROR_DELTA );
             assert_delta!( *streamed_field.u_hori.get((1, 1)).unwrap(), -0.13980690679, ERROR_DELTA );
@@ -471,11 +1326,11 @@ mod tests {
     #[test]
     fn test_stream_field_stream_from_collided_field() {
         let mut collided_field = CollidedField::new(3, 3, 0);
-        collided_field.f = Array::range(1., 81.5, 1.).into_shape((3, 3, 3, 3)).unwrap();
+        collided_field.f = Array::range(1., 81.5, 1.).into_shape((3, 3, 9)).unwrap();
         let mut streaming_weight = StreamingWeight::new(3, 3, 1);
         let mut streamed_field = StreamedField::new(3, 3, 1);
-        streaming_weight.w0 = streaming_weight.w0 + Array::range(0., 40.2, 0.5).into_shape((3, 3, 3, 3)).unwrap();
-        streaming_weight.w1 = streaming_weight.w1 + Array::range(81., 0.5, -1.).into_shape((3, 3, 3, 3)).unwrap(); // あえて足していることに注意
+        streaming_weight.w0 = streaming_weight.w0 + Array::range(0., 40.2, 0.5).into_shape((3, 3, 9)).unwrap();
+        streaming_weight.w1 = streaming_weight.w1 + Array::range(81., 0.5, -1.).into_shape((3, 3, 9)).unwrap(); // あえて足していることに注意
         for _ in 0..5 {
             streamed_field.stream_from_collided_field(&collided_field, &streaming_weight);
             // println!("{}", streamed_field.f);
@@ -489,16 +1344,14 @@ mod tests {
                     assert!( streamed_field.u_hori.get((r, c)).unwrap().is_nan() );
                     assert!( streamed_field.rho.get((r, c)).unwrap().is_nan() );
     
-                    for dr in 0..=2 {
-                        for dc in 0..=2 {
-                            assert!( streamed_field.f.get((r, c, dr, dc)).unwrap().is_nan() );
-                        }
+                    for q in 0..9 {
+                        assert!( streamed_field.f.get((r, c, q)).unwrap().is_nan() );
                     }
                 }
             }
-            assert_delta!( *streamed_field.f.get((1, 1, 1, 1)).unwrap(), 1742.0, ERROR_DELTA );
-            assert_delta!( *streamed_field.f.get((1, 1, 0, 2)).unwrap(), 2527.0, ERROR_DELTA );
-            assert_delta!( *streamed_field.f.get((1, 1, 1, 0)).unwrap(), 2126.5, ERROR_DELTA );
+            assert_delta!( *streamed_field.f.get((1, 1, 4)).unwrap(), 1742.0, ERROR_DELTA );
+            assert_delta!( *streamed_field.f.get((1, 1, 2)).unwrap(), 2527.0, ERROR_DELTA );
+            assert_delta!( *streamed_field.f.get((1, 1, 3)).unwrap(), 2126.5, ERROR_DELTA );
             assert_delta!( *streamed_field.rho.get((1, 1)).unwrap(), 16158.0, ERROR_DELTA );
             assert_delta!( *streamed_field.u_vert.get((1, 1)).unwrap(), -0.41942072038, ERROR_DELTA );
             assert_delta!( *streamed_field.u_hori.get((1, 1)).unwrap(), -0.13980690679, ERROR_DELTA );
@@ -510,14 +1363,14 @@ mod tests {
         let mut collided_field = CollidedField::new(3, 3, 1);
         let mut colliding_weight = CollidingWeight::new(3, 3, 1);
         let mut streamed_field = StreamedField::new(3, 3, 1);
-        streamed_field.f.slice_mut(s![1, 1, .., ..]).assign(&arr2(&[[1., 2., 3.], [6., 5., 4.], [7., 8., 9.]]));
+        streamed_field.f.slice_mut(s![1, 1, ..]).assign(&arr1(&[1., 2., 3., 6., 5., 4., 7., 8., 9.]));
         streamed_field.u_vert.slice_mut(s![1, 1]).assign(&arr0(0.4));
         streamed_field.u_hori.slice_mut(s![1, 1]).assign(&arr0(0.0444444444444444444));
         streamed_field.rho.slice_mut(s![1, 1]).assign(&arr0(45.));
-        colliding_weight.w1 = colliding_weight.w1 + arr2(&[[0., 0.1, 0.2], [0.3, 0.4, 0.5], [0.6, 0.7, 0.8]]); // Nan + ... = Nan を利用
-        colliding_weight.w2 = colliding_weight.w2 + arr2(&[[0.8, 0.7, 0.6], [0.5, 0.4, 0.3], [0.2, 0.1, 0.]]);
-        colliding_weight.w3 = colliding_weight.w3 + arr2(&[[0.1, 0.4, 0.7], [0.2, 0.5, 0.8], [0.3, 0.6, 0.9]]);
-        colliding_weight.w4 = colliding_weight.w4 + arr2(&[[0.9, 0.6, 0.3], [0.8, 0.5, 0.2], [0.7, 0.4, 0.1]]);
+        colliding_weight.w1 = colliding_weight.w1 + arr1(&[0., 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8]); // Nan + ... = Nan を利用
+        colliding_weight.w2 = colliding_weight.w2 + arr1(&[0.8, 0.7, 0.6, 0.5, 0.4, 0.3, 0.2, 0.1, 0.]);
+        colliding_weight.w3 = colliding_weight.w3 + arr1(&[0.1, 0.4, 0.7, 0.2, 0.5, 0.8, 0.3, 0.6, 0.9]);
+        colliding_weight.w4 = colliding_weight.w4 + arr1(&[0.9, 0.6, 0.3, 0.8, 0.5, 0.2, 0.7, 0.4, 0.1]);
 
         for _ in 0..5 {
             collided_field.collide(&streamed_field, &colliding_weight);
@@ -529,24 +1382,174 @@ mod tests {
                     // assert!( collided_field.u_hori.get((r, c)).unwrap().is_nan() );
                     // assert!( collided_field.rho.get((r, c)).unwrap().is_nan() );
     
-                    for dr in 0..=2 {
-                        for dc in 0..=2 {
-                            assert!( collided_field.feq.get((r, c, dr, dc)).unwrap().is_nan() );
-                        }
+                    for q in 0..9 {
+                        assert!( collided_field.feq.get((r, c, q)).unwrap().is_nan() );
                     }
                 }
             }
-            assert_delta!( *collided_field.feq.get((1, 1, 0, 1)).unwrap(), 1.835555555555555, ERROR_DELTA );
-            assert_delta!( *collided_field.feq.get((1, 1, 1, 1)).unwrap(), 16.760493827160495, ERROR_DELTA );
-            assert_delta!( *collided_field.feq.get((1, 1, 1, 2)).unwrap(), 4.177283950617283, ERROR_DELTA );
-            assert_delta!( *collided_field.feq.get((1, 1, 2, 0)).unwrap(), 3.5576543209876546, ERROR_DELTA );
-            assert_delta!( *collided_field.f.get((1, 1, 0, 1)).unwrap(), 3.835555555555555 / 2.0, ERROR_DELTA );
-            assert_delta!( *collided_field.f.get((1, 1, 1, 1)).unwrap(), 21.760493827160495 / 2.0, ERROR_DELTA );
-            assert_delta!( *collided_field.f.get((1, 1, 1, 2)).unwrap(), 8.177283950617283 / 2.0, ERROR_DELTA );
-            assert_delta!( *collided_field.f.get((1, 1, 2, 0)).unwrap(), 10.5576543209876546 / 2.0, ERROR_DELTA );
+            assert_delta!( *collided_field.feq.get((1, 1, 1)).unwrap(), 1.835555555555555, ERROR_DELTA );
+            assert_delta!( *collided_field.feq.get((1, 1, 4)).unwrap(), 16.760493827160495, ERROR_DELTA );
+            assert_delta!( *collided_field.feq.get((1, 1, 5)).unwrap(), 4.177283950617283, ERROR_DELTA );
+            assert_delta!( *collided_field.feq.get((1, 1, 6)).unwrap(), 3.5576543209876546, ERROR_DELTA );
+            assert_delta!( *collided_field.f.get((1, 1, 1)).unwrap(), 3.835555555555555 / 2.0, ERROR_DELTA );
+            assert_delta!( *collided_field.f.get((1, 1, 4)).unwrap(), 21.760493827160495 / 2.0, ERROR_DELTA );
+            assert_delta!( *collided_field.f.get((1, 1, 5)).unwrap(), 8.177283950617283 / 2.0, ERROR_DELTA );
+            assert_delta!( *collided_field.f.get((1, 1, 6)).unwrap(), 10.5576543209876546 / 2.0, ERROR_DELTA );
         }
     }
 
+    #[test]
+    fn test_collided_field_collide_with_force() {
+        // 静止した一様な場に外力をかけると、質量はそのままで運動量がFだけ増える
+        let mut input_field = InputField::new(3, 3);
+        input_field.set(Array2::zeros((3, 3)), Array2::zeros((3, 3)), Array2::from_elem((3, 3), 1.2));
+        let mut streamed_field = StreamedField::new(3, 3, 1);
+        streamed_field.stream_from_input_field(&input_field, &StreamingWeight::new(3, 3, 1));
+        let mut colliding_weight = CollidingWeight::new(3, 3, 1);
+        colliding_weight.set_force(Array2::from_elem((3, 3), 0.003), Array2::from_elem((3, 3), -0.002));
+        let mut collided_field = CollidedField::new(3, 3, 1);
+        collided_field.collide(&streamed_field, &colliding_weight);
+
+        let f = collided_field.f.slice(s![1, 1, ..]);
+        let momentum = |e: &dyn Fn([i32; 2]) -> f64| f.indexed_iter().map(|(q, f)| e(D2Q9::VELOCITIES[q]) * f).sum::<f64>();
+        assert_delta!( f.sum(), 1.2, ERROR_DELTA );
+        assert_delta!( momentum(&|[dr, _]| dr as f64), 0.003, ERROR_DELTA );
+        assert_delta!( momentum(&|[_, dc]| dc as f64), -0.002, ERROR_DELTA );
+    }
+
+    #[test]
+    fn test_collided_field_collide_with_buoyancy() {
+        // 温度が基準より高いセルは上向き(-vert)に、低いセルは下向きに運動量が増える 温度がなければ効かない
+        let mut input_field = InputField::new(3, 4);
+        input_field.set(Array2::zeros((3, 4)), Array2::zeros((3, 4)), Array2::from_elem((3, 4), 1.0));
+        let mut streamed_field = StreamedField::new(3, 4, 1);
+        streamed_field.stream_from_input_field(&input_field, &StreamingWeight::new(3, 4, 1));
+        let mut colliding_weight = CollidingWeight::new(3, 4, 1);
+        colliding_weight.set_buoyancy(Some(Buoyancy { coefficient: 0.01, reference: 290.0 }));
+        let momentum_vert = |collided_field: &CollidedField, c: usize| {
+            collided_field.f.slice(s![1, c, ..]).indexed_iter().map(|(q, f): (usize, &f64)| D2Q9::VELOCITIES[q][0] as f64 * f).sum::<f64>()
+        };
+        let mut collided_field = CollidedField::new(3, 4, 1);
+        collided_field.collide(&streamed_field, &colliding_weight);
+        assert_delta!( momentum_vert(&collided_field, 1), 0.0, ERROR_DELTA );
+
+        streamed_field.set_temperature(Array2::from_shape_fn((3, 4), |(_, c)| if c == 1 { 292.0 } else { 289.5 }));
+        collided_field.collide(&streamed_field, &colliding_weight);
+        assert_delta!( momentum_vert(&collided_field, 1), -0.02, ERROR_DELTA );
+        assert_delta!( momentum_vert(&collided_field, 2), 0.005, ERROR_DELTA );
+        assert_delta!( collided_field.f.slice(s![1, 1, ..]).sum(), 1.0, ERROR_DELTA );
+    }
+
+    #[test]
+    fn test_collided_field_collide_with_coriolis() {
+        // 一様流にコリオリ力をかけると、運動量がrho * f * (-u_hori, u_vert)だけ変わる
+        let mut input_field = InputField::new(3, 3);
+        input_field.set(Array2::from_elem((3, 3), 0.02), Array2::from_elem((3, 3), 0.1), Array2::from_elem((3, 3), 1.2));
+        let mut streamed_field = StreamedField::new(3, 3, 1);
+        streamed_field.stream_from_input_field(&input_field, &StreamingWeight::new(3, 3, 1));
+        let mut colliding_weight = CollidingWeight::new(3, 3, 1);
+        colliding_weight.set_coriolis(Array2::from_elem((3, 3), 0.01));
+        let mut collided_field = CollidedField::new(3, 3, 1);
+        collided_field.collide(&streamed_field, &colliding_weight);
+
+        let f = collided_field.f.slice(s![1, 1, ..]);
+        let momentum = |e: &dyn Fn([i32; 2]) -> f64| f.indexed_iter().map(|(q, f)| e(D2Q9::VELOCITIES[q]) * f).sum::<f64>();
+        assert_delta!( f.sum(), 1.2, ERROR_DELTA );
+        assert_delta!( momentum(&|[dr, _]| dr as f64), 1.2 * (0.02 - 0.01 * 0.1), ERROR_DELTA );
+        assert_delta!( momentum(&|[_, dc]| dc as f64), 1.2 * (0.1 + 0.01 * 0.02), ERROR_DELTA );
+    }
+
+    #[test]
+    fn test_streamed_field_stream_periodic() {
+        // 右下向き(dr = 1, dc = 1)の粒子は右下の角から左上の角へ回り込む
+        let mut collided_field = CollidedField::new(3, 4, 0);
+        collided_field.f.fill(1.0);
+        collided_field.f[[2, 3, 8]] = 5.0;
+        collided_field.f[[0, 1, 1]] = 3.0;
+        let streaming_weight = StreamingWeight::with_boundary(3, 4, 0, Boundary::Periodic);
+        let mut streamed_field = StreamedField::new(3, 4, 0);
+        for _ in 0..5 {
+            streamed_field.stream_from_collided_field(&collided_field, &streaming_weight);
+            assert!( streamed_field.f.iter().all(|f| !f.is_nan()) );
+            assert_delta!( streamed_field.f[[0, 0, 8]], 5.0, ERROR_DELTA );
+            assert_delta!( streamed_field.f[[2, 1, 1]], 3.0, ERROR_DELTA );
+            assert_delta!( streamed_field.f[[1, 1, 8]], 1.0, ERROR_DELTA );
+            assert_delta!( streamed_field.rho.sum(), 12.0 * 9.0 + 4.0 + 2.0, ERROR_DELTA );
+        }
+    }
+
+    #[test]
+    fn test_streamed_field_stream_bounce_back() {
+        // (1, 2)が障害物 (1, 1)から右に向かう粒子は跳ね返って(1, 1)の左向きになる
+        let mut obstacle = Array2::<bool>::from_elem((3, 4), false);
+        obstacle[[1, 2]] = true;
+        let mut collided_field = CollidedField::new(3, 4, 0);
+        collided_field.f.fill(1.0);
+        collided_field.f[[1, 1, 5]] = 7.0;
+        let mut streaming_weight = StreamingWeight::with_boundary(3, 4, 0, Boundary::Periodic);
+        streaming_weight.set_obstacle(obstacle);
+        let mut streamed_field = StreamedField::new(3, 4, 0);
+        for _ in 0..5 {
+            streamed_field.stream_from_collided_field(&collided_field, &streaming_weight);
+            assert_delta!( streamed_field.f[[1, 1, 3]], 7.0, ERROR_DELTA );
+            assert_delta!( streamed_field.f[[1, 3, 5]], 1.0, ERROR_DELTA ); // (1, 2)からは何も来ないが、周期境界で(1, 3)の右向きは(1, 2)から来るので跳ね返りの1.0
+            assert!( streamed_field.f.slice(s![1, 2, ..]).iter().all(|f| *f == 0.0) );
+            assert_eq!( streamed_field.u_hori[[1, 2]], 0.0 );
+            // 障害物以外のセルの粒子の数は保存される
+            assert_delta!( streamed_field.rho.sum(), 11.0 * 9.0 + 6.0, ERROR_DELTA );
+        }
+    }
+
+    #[test]
+    fn test_streamed_field_stream_outflow() {
+        // 東端を流出にすると、東端の左向きの成分は1つ内側のセルと同じになる
+        let mut collided_field = CollidedField::new(3, 4, 0);
+        collided_field.f = Array::range(1., 108.5, 1.).into_shape((3, 4, 9)).unwrap();
+        let mut streaming_weight = StreamingWeight::with_boundary(3, 4, 0, Boundary::Periodic);
+        streaming_weight.set_edges(Edges { east: Some(EdgeCondition::Outflow), ..Edges::default() });
+        let mut streamed_field = StreamedField::new(3, 4, 0);
+        let mut periodic_field = StreamedField::new(3, 4, 0);
+        periodic_field.stream_from_collided_field(&collided_field, &StreamingWeight::with_boundary(3, 4, 0, Boundary::Periodic));
+        for _ in 0..5 {
+            streamed_field.stream_from_collided_field(&collided_field, &streaming_weight);
+            for r in 0..3 {
+                for dr in 0..3 {
+                    assert_delta!( streamed_field.f[[r, 3, 3 * dr]], streamed_field.f[[r, 2, 3 * dr]], ERROR_DELTA );
+                    assert_delta!( streamed_field.f[[r, 3, 3 * dr + 2]], periodic_field.f[[r, 3, 3 * dr + 2]], ERROR_DELTA );
+                }
+                let rho: f64 = streamed_field.f.slice(s![r, 3, ..]).sum();
+                assert_delta!( streamed_field.rho[[r, 3]], rho, ERROR_DELTA );
+            }
+        }
+
+        // 内側のセルが障害物のときは、障害物の0を写さずにstreamの結果のままにする
+        let mut obstacle = Array2::from_elem((3, 4), false);
+        obstacle[[1, 2]] = true;
+        streaming_weight.set_obstacle(obstacle);
+        streamed_field.stream_from_collided_field(&collided_field, &streaming_weight);
+        for dr in 0..3 {
+            assert_delta!( streamed_field.f[[1, 3, 3 * dr]], periodic_field.f[[1, 3, 3 * dr]], ERROR_DELTA );
+            assert_delta!( streamed_field.f[[0, 3, 3 * dr]], streamed_field.f[[0, 2, 3 * dr]], ERROR_DELTA );
+        }
+    }
+
+    #[test]
+    fn test_interior_views() {
+        let mut streamed_field = StreamedField::new(5, 4, 1);
+        streamed_field.u_vert.slice_mut(s![1..4, 1..3]).fill(0.1);
+        assert_eq!( streamed_field.u_vert().shape(), &[5, 4] );
+        assert_eq!( streamed_field.u_vert_interior().shape(), &[3, 2] );
+        assert!( streamed_field.u_vert_interior().iter().all(|u| *u == 0.1) );
+        assert_eq!( streamed_field.f_interior().shape(), &[3, 2, 9] );
+
+        let collided_field = CollidedField::new(5, 4, 2);
+        assert_eq!( collided_field.feq_interior().shape(), &[1, 0, 9] );
+        assert!( collided_field.feq().iter().any(|f| f.is_nan()) );
+
+        let colliding_weight = CollidingWeight::new(5, 4, 1);
+        assert!( interior(colliding_weight.w3(), colliding_weight.margin()).iter().all(|w| !w.is_nan()) );
+    }
+
     #[test]
     fn test_streaming_weight_propagate_from_output() {
         let mut field_prev = CollidedField::new(3, 3, 0);
@@ -558,39 +1561,39 @@ mod tests {
         field_now.u_vert.slice_mut(s![1, 1]).assign(&arr0( -2.0 / 45.0));
         field_now.u_hori.slice_mut(s![1, 1]).assign(&arr0( 6.0 / 45.0));
         field_now.rho.slice_mut(s![1, 1]).assign(&arr0(45.0));
-        field_prev.f = Array::range(1., 81.5, 1.).into_shape((3, 3, 3, 3)).unwrap();
+        field_prev.f = Array::range(1., 81.5, 1.).into_shape((3, 3, 9)).unwrap();
+        // 外力がなければ出力の風速はΣe f / rhoのまま
+        let colliding_weight_prev = CollidingWeight::new(3, 3, 0);
 
         for _ in 0..5 {
-            streaming_weight.propagate_from_output(eta, &field_now, &field_prev, &u_vert_ans, &u_hori_ans);
+            streaming_weight.propagate_from_output(eta, &field_now, &field_prev, &colliding_weight_prev, &u_vert_ans, &u_hori_ans);
             
             for r in 0..=2 {
                 for c in 0..=2 {
                     if r == 1 && c == 1 { continue; }
     
-                    for dr in 0..=2 {
-                        for dc in 0..=2 {
-                            assert!( streaming_weight.delta.get((r, c, dr, dc)).unwrap().is_nan() );
-                            assert!( streaming_weight.w0.get((r, c, dr, dc)).unwrap().is_nan() );
-                            assert!( streaming_weight.w1.get((r, c, dr, dc)).unwrap().is_nan() );
-                        }
+                    for q in 0..9 {
+                        assert!( streaming_weight.delta.get((r, c, q)).unwrap().is_nan() );
+                        assert!( streaming_weight.w0.get((r, c, q)).unwrap().is_nan() );
+                        assert!( streaming_weight.w1.get((r, c, q)).unwrap().is_nan() );
                     }
                 }
             }
 
-            assert_delta!( streaming_weight.delta.get((1, 1, 0, 1)).unwrap(), 0.00627709190672153635116, ERROR_DELTA );
-            assert_delta!( streaming_weight.delta.get((1, 1, 1, 2)).unwrap(), -0.0073031550068587105624, ERROR_DELTA );
-            assert_delta!( streaming_weight.delta.get((1, 1, 1, 1)).unwrap(), 0.00084499314128943758573, ERROR_DELTA );
-            assert_delta!( streaming_weight.delta.get((1, 1, 2, 0)).unwrap(), 0.00356104252400548696844, ERROR_DELTA );
+            assert_delta!( streaming_weight.delta.get((1, 1, 1)).unwrap(), 0.00627709190672153635116, ERROR_DELTA );
+            assert_delta!( streaming_weight.delta.get((1, 1, 5)).unwrap(), -0.0073031550068587105624, ERROR_DELTA );
+            assert_delta!( streaming_weight.delta.get((1, 1, 4)).unwrap(), 0.00084499314128943758573, ERROR_DELTA );
+            assert_delta!( streaming_weight.delta.get((1, 1, 6)).unwrap(), 0.00356104252400548696844, ERROR_DELTA );
 
-            assert_delta!( streaming_weight.dw0.get((1, 1, 0, 1)).unwrap(), -0.000627709190672153635116, ERROR_DELTA );
-            assert_delta!( streaming_weight.dw0.get((1, 1, 1, 2)).unwrap(), 0.0007303155006858710562414, ERROR_DELTA );
-            assert_delta!( streaming_weight.dw0.get((1, 1, 1, 1)).unwrap(), -0.000084499314128943758573, ERROR_DELTA );
-            assert_delta!( streaming_weight.dw0.get((1, 1, 2, 0)).unwrap(), -0.000356104252400548696844, ERROR_DELTA );
+            assert_delta!( streaming_weight.dw0.get((1, 1, 1)).unwrap(), -0.000627709190672153635116, ERROR_DELTA );
+            assert_delta!( streaming_weight.dw0.get((1, 1, 5)).unwrap(), 0.0007303155006858710562414, ERROR_DELTA );
+            assert_delta!( streaming_weight.dw0.get((1, 1, 4)).unwrap(), -0.000084499314128943758573, ERROR_DELTA );
+            assert_delta!( streaming_weight.dw0.get((1, 1, 6)).unwrap(), -0.000356104252400548696844, ERROR_DELTA );
 
-            assert_delta!( streaming_weight.dw1.get((1, 1, 0, 1)).unwrap(), -0.0408010973936899862825788, ERROR_DELTA );
-            assert_delta!( streaming_weight.dw1.get((1, 1, 1, 2)).unwrap(), 0.0241004115226337448559670, ERROR_DELTA );
-            assert_delta!( streaming_weight.dw1.get((1, 1, 1, 1)).unwrap(), -0.0034644718792866941015089, ERROR_DELTA );
-            assert_delta!( streaming_weight.dw1.get((1, 1, 2, 0)).unwrap(), -0.0089026063100137174211248, ERROR_DELTA );
+            assert_delta!( streaming_weight.dw1.get((1, 1, 1)).unwrap(), -0.0408010973936899862825788, ERROR_DELTA );
+            assert_delta!( streaming_weight.dw1.get((1, 1, 5)).unwrap(), 0.0241004115226337448559670, ERROR_DELTA );
+            assert_delta!( streaming_weight.dw1.get((1, 1, 4)).unwrap(), -0.0034644718792866941015089, ERROR_DELTA );
+            assert_delta!( streaming_weight.dw1.get((1, 1, 6)).unwrap(), -0.0089026063100137174211248, ERROR_DELTA );
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,587 @@
+use ndarray::{Array3, Array4, ArrayView3, ArrayView4, s};
+use serde::{Deserialize, Serialize};
+use crate::lattice::Lattice;
+
+// 3次元(気圧面 x 行 x 列)の格子ボルツマン法 複数の気圧面(例えば1000/925/850/700hPa)をまとめて流す
+// lbm.rsのD2Q9と同じ順に InputField3d -> StreamingWeight3d -> StreamedField3d -> CollidingWeight3d -> CollidedField3d -> ...
+// fは(level, row, col, q)の4次元で、qはLattice3d::velocities()の添字 速度は(dl, dr, dc)
+// 気圧面の方向にはmarginを取らず、最下層と最上層では鏡面反射する(気圧面方向の成分だけ向きを変える free-slip)
+// 行と列の方向はBoundary::Shrinkと同じで、streamするたびにmarginが1周ずつ増える
+// collideはBGK(A = I/2)で、feq = C * rho * (1 + w1 * u_prod + w3 * u_prod^2 + w4 * u^2) 2次元のw2(v x u)に当たる項はない
+// 入力はrepo::get_multilevel_data_from_netcdf()で読んだNetCDF(ERA5の気圧面など)だけで、GRIBの気圧面データは扱わない(grib2npy.pyは地上のデータだけを書き出す)
+
+// 3次元の格子 D3Q19は立方体の頂点方向(3成分とも0でない8方向)を除いたもの
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lattice3d {
+    #[default]
+    D3Q19,
+    D3Q27,
+}
+
+pub struct InputField3d {
+    lattice: Lattice3d,
+    level: usize,
+    row: usize,
+    col: usize,
+    f: Array4<f64>,
+    u_level: Array3<f64>,
+    u_vert: Array3<f64>,
+    u_hori: Array3<f64>,
+    rho: Array3<f64>,
+}
+
+#[derive(Clone)]
+pub struct StreamingWeight3d {
+    lattice: Lattice3d,
+    level: usize,
+    row: usize,
+    col: usize,
+    margin: usize,
+    w0: Array4<f64>,
+    w1: Array4<f64>,
+    dw0: Array4<f64>,
+    dw1: Array4<f64>,
+    delta: Array4<f64>,
+}
+
+pub struct StreamedField3d {
+    lattice: Lattice3d,
+    level: usize,
+    row: usize,
+    col: usize,
+    margin: usize,
+    f: Array4<f64>,
+    u_level: Array3<f64>,
+    u_vert: Array3<f64>,
+    u_hori: Array3<f64>,
+    rho: Array3<f64>,
+}
+
+#[derive(Clone)]
+pub struct CollidingWeight3d {
+    lattice: Lattice3d,
+    level: usize,
+    row: usize,
+    col: usize,
+    margin: usize,
+    w1: Array4<f64>,
+    w3: Array4<f64>,
+    w4: Array4<f64>,
+    dw1: Array4<f64>,
+    dw3: Array4<f64>,
+    dw4: Array4<f64>,
+    delta: Array4<f64>,
+}
+
+pub struct CollidedField3d {
+    lattice: Lattice3d,
+    level: usize,
+    row: usize,
+    col: usize,
+    margin: usize,
+    f: Array4<f64>,
+    feq: Array4<f64>,
+}
+
+impl Lattice<3> for Lattice3d {
+    // (dl, dr, dc) 辞書順に並べるので、逆向きの添字はq() - 1 - q
+    fn velocities(self: Self) -> Vec<[i32; 3]> {
+        let mut velocities = Vec::new();
+        for dl in -1..=1_i32 {
+            for dr in -1..=1_i32 {
+                for dc in -1..=1_i32 {
+                    if self == Lattice3d::D3Q19 && dl != 0 && dr != 0 && dc != 0 {
+                        continue;
+                    }
+                    velocities.push([dl, dr, dc]);
+                }
+            }
+        }
+        velocities
+    }
+
+    // velocities()と同じ順の重み 0でない成分の数で決まる どちらもcs^2 = 1/3
+    fn weights(self: Self) -> Vec<f64> {
+        let table = match self {
+            Lattice3d::D3Q19 => [1.0/3.0, 1.0/18.0, 1.0/36.0, 0.0],
+            Lattice3d::D3Q27 => [8.0/27.0, 2.0/27.0, 1.0/54.0, 1.0/216.0],
+        };
+        self.velocities().iter().map(|e| table[e.iter().filter(|x| **x != 0).count()]).collect()
+    }
+
+    fn q(self: Self) -> usize {
+        match self {
+            Lattice3d::D3Q19 => 19,
+            Lattice3d::D3Q27 => 27,
+        }
+    }
+
+    fn opposite(self: Self, q: usize) -> usize {
+        self.q() - 1 - q
+    }
+}
+
+impl Lattice3d {
+    // 気圧面方向の成分dlだけ向きを変えた速度の添字
+    pub fn reflected(self: Self, q: usize) -> usize {
+        self.reflections()[q]
+    }
+
+    // reflected()の表 qごとに呼ぶとvelocities()を作り直すので、ループの前にvelocities()と一緒に作っておく
+    pub fn reflections(self: Self) -> Vec<usize> {
+        let velocities = self.velocities();
+        velocities.iter().map(|[dl, dr, dc]| velocities.iter().position(|e| *e == [-dl, *dr, *dc]).unwrap()).collect()
+    }
+}
+
+// (l, r, c)に流れ込むq成分の元 気圧面の外から来る成分は、同じ気圧面で鏡面反射したもの reflectionsはLattice3d::reflections()
+fn source(reflections: &[usize], e: [i32; 3], level: usize, l: usize, r: usize, c: usize, q: usize) -> [usize; 4] {
+    let l_prev = l as i32 - e[0];
+    let (r_prev, c_prev) = ((r as i32 - e[1]) as usize, (c as i32 - e[2]) as usize);
+    if l_prev < 0 || l_prev >= level as i32 {
+        [l, r_prev, c_prev, reflections[q]]
+    } else {
+        [l_prev as usize, r_prev, c_prev, q]
+    }
+}
+
+// 平衡分布 InputField3dは学習しない重み(Lattice::equilibrium_coefficients())で作る
+fn equilibrium(weight: f64, e: [i32; 3], u: [f64; 3], rho: f64, w1: f64, w3: f64, w4: f64) -> f64 {
+    let u_prod = e[0] as f64 * u[0] + e[1] as f64 * u[1] + e[2] as f64 * u[2];
+    let u2 = u[0] * u[0] + u[1] * u[1] + u[2] * u[2];
+    weight * rho * (1.0 + (w3 * u_prod + w1) * u_prod + w4 * u2)
+}
+
+// f(Array4)の(l, r, c)から(rho, [u_level, u_vert, u_hori])を計算する
+fn moments(f: &Array4<f64>, velocities: &[[i32; 3]], l: usize, r: usize, c: usize) -> (f64, [f64; 3]) {
+    let (mut rho, mut u) = (0.0, [0.0; 3]);
+    for (q, e) in velocities.iter().enumerate() {
+        let f_q = f[[l, r, c, q]];
+        rho += f_q;
+        for a in 0..3 {
+            u[a] += f_q * e[a] as f64;
+        }
+    }
+    (rho, [u[0] / rho, u[1] / rho, u[2] / rho])
+}
+
+impl InputField3d {
+    pub fn new(lattice: Lattice3d, level: usize, row: usize, col: usize) -> InputField3d {
+        let f = Array4::<f64>::zeros((level, row, col, lattice.q()));
+        let zeros = Array3::<f64>::zeros((level, row, col));
+        InputField3d { lattice, level, row, col, f, u_level: zeros.clone(), u_vert: zeros.clone(), u_hori: zeros.clone(), rho: zeros }
+    }
+
+    pub fn lattice(self: &Self) -> Lattice3d { self.lattice }
+    pub fn level(self: &Self) -> usize { self.level }
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn f(self: &Self) -> ArrayView4<'_, f64> { self.f.view() }
+    pub fn u_level(self: &Self) -> ArrayView3<'_, f64> { self.u_level.view() }
+    pub fn u_vert(self: &Self) -> ArrayView3<'_, f64> { self.u_vert.view() }
+    pub fn u_hori(self: &Self) -> ArrayView3<'_, f64> { self.u_hori.view() }
+    pub fn rho(self: &Self) -> ArrayView3<'_, f64> { self.rho.view() }
+
+    pub fn set(self: &mut Self, u_level: Array3<f64>, u_vert: Array3<f64>, u_hori: Array3<f64>, rho: Array3<f64>) {
+        let shape = [self.level, self.row, self.col];
+        if shape != u_level.shape() || shape != u_vert.shape() || shape != u_hori.shape() || shape != rho.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let (velocities, weights) = (self.lattice.velocities(), self.lattice.weights());
+        let (w1, w3, w4) = self.lattice.equilibrium_coefficients();
+        for ((l, r, c), rho) in rho.indexed_iter() {
+            let u = [u_level[[l, r, c]], u_vert[[l, r, c]], u_hori[[l, r, c]]];
+            for (q, e) in velocities.iter().enumerate() {
+                self.f[[l, r, c, q]] = equilibrium(weights[q], *e, u, *rho, w1, w3, w4);
+            }
+        }
+        self.u_level = u_level;
+        self.u_vert = u_vert;
+        self.u_hori = u_hori;
+        self.rho = rho;
+    }
+}
+
+impl StreamingWeight3d {
+    pub fn new(lattice: Lattice3d, level: usize, row: usize, col: usize, margin: usize) -> StreamingWeight3d {
+        let interior = |value: f64| {
+            let mut w = Array4::<f64>::from_elem((level, row, col, lattice.q()), f64::NAN);
+            w.slice_mut(s![.., margin..row-margin, margin..col-margin, ..]).fill(value);
+            w
+        };
+        StreamingWeight3d { lattice, level, row, col, margin, w0: interior(0.0), w1: interior(1.0), dw0: interior(0.0), dw1: interior(0.0), delta: interior(0.0) }
+    }
+
+    pub fn lattice(self: &Self) -> Lattice3d { self.lattice }
+    pub fn level(self: &Self) -> usize { self.level }
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn margin(self: &Self) -> usize { self.margin }
+    pub fn w0(self: &Self) -> ArrayView4<'_, f64> { self.w0.view() }
+    pub fn w1(self: &Self) -> ArrayView4<'_, f64> { self.w1.view() }
+    pub fn dw0(self: &Self) -> ArrayView4<'_, f64> { self.dw0.view() }
+    pub fn dw1(self: &Self) -> ArrayView4<'_, f64> { self.dw1.view() }
+    pub fn delta(self: &Self) -> ArrayView4<'_, f64> { self.delta.view() }
+
+    // 保存しておいた重みを戻す用
+    pub fn set(self: &mut Self, w0: Array4<f64>, w1: Array4<f64>) {
+        if self.w0.shape() != w0.shape() || self.w1.shape() != w1.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.w0 = w0;
+        self.w1 = w1;
+    }
+
+    pub fn propagate_from_output(self: &mut Self, eta: f64, field_now: &StreamedField3d, field_prev: &CollidedField3d, ans: [&Array3<f64>; 3]) {
+        if self.margin != field_prev.margin + 1 {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.set_delta_from_output(field_now, ans);
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    // 1層目(InputField3dから流す層)が出力層のとき用
+    pub fn propagate_from_output_with_input_field(self: &mut Self, eta: f64, field_now: &StreamedField3d, field_prev: &InputField3d, ans: [&Array3<f64>; 3]) {
+        self.set_delta_from_output(field_now, ans);
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    // colliding_weight_nextのdeltaが計算済みであること
+    pub fn propagate_from_colliding_weight(self: &mut Self, eta: f64, field_now: &StreamedField3d, field_prev: &CollidedField3d, colliding_weight_next: &CollidingWeight3d) {
+        if self.margin != field_prev.margin + 1 {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.set_delta_from_colliding_weight(field_now, colliding_weight_next);
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    pub fn propagate_from_colliding_weight_with_input_field(self: &mut Self, eta: f64, field_now: &StreamedField3d, field_prev: &InputField3d, colliding_weight_next: &CollidingWeight3d) {
+        self.set_delta_from_colliding_weight(field_now, colliding_weight_next);
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    // 損失 1/2 * Σ(u - u_ans)^2 (u_level, u_vert, u_horiの3成分)をfield_nowのfで微分したもの
+    // ansは[u_level_ans, u_vert_ans, u_hori_ans]
+    fn set_delta_from_output(self: &mut Self, field_now: &StreamedField3d, ans: [&Array3<f64>; 3]) {
+        let shape = [self.level, self.row, self.col];
+        if shape != [field_now.level, field_now.row, field_now.col] || ans.iter().any(|ans| shape != ans.shape()) || self.margin != field_now.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let velocities = self.lattice.velocities();
+        for l in 0..self.level {
+            for r in self.margin..self.row-self.margin {
+                for c in self.margin..self.col-self.margin {
+                    let rho = field_now.rho[[l, r, c]];
+                    let u = [field_now.u_level[[l, r, c]], field_now.u_vert[[l, r, c]], field_now.u_hori[[l, r, c]]];
+                    let d_u: Vec<f64> = (0..3).map(|a| u[a] - ans[a][[l, r, c]]).collect();
+                    for (q, e) in velocities.iter().enumerate() {
+                        self.delta[[l, r, c, q]] = (0..3).map(|a| d_u[a] * (e[a] as f64 - u[a])).sum::<f64>() / rho;
+                    }
+                }
+            }
+        }
+    }
+
+    // collideを逆にたどる f_next = f - (f - feq) / 2、rho = Σf、u = Σe f / rhoなので du/df = (e - u) / rho
+    fn set_delta_from_colliding_weight(self: &mut Self, field_now: &StreamedField3d, colliding_weight_next: &CollidingWeight3d) {
+        let cw = colliding_weight_next;
+        if [self.level, self.row, self.col] != [cw.level, cw.row, cw.col] || self.margin != field_now.margin || self.margin != cw.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let (velocities, weights) = (self.lattice.velocities(), self.lattice.weights());
+        for l in 0..self.level {
+            for r in self.margin..self.row-self.margin {
+                for c in self.margin..self.col-self.margin {
+                    let rho = field_now.rho[[l, r, c]];
+                    let u = [field_now.u_level[[l, r, c]], field_now.u_vert[[l, r, c]], field_now.u_hori[[l, r, c]]];
+                    let u2 = u[0] * u[0] + u[1] * u[1] + u[2] * u[2];
+                    let (mut d_rho, mut d_u) = (0.0, [0.0; 3]);
+                    for (q, e) in velocities.iter().enumerate() {
+                        let i = [l, r, c, q];
+                        let u_prod = e[0] as f64 * u[0] + e[1] as f64 * u[1] + e[2] as f64 * u[2];
+                        let delta_eq = cw.delta[i] / 2.0 * weights[q];
+                        d_rho += delta_eq * (1.0 + (cw.w3[i] * u_prod + cw.w1[i]) * u_prod + cw.w4[i] * u2);
+                        for a in 0..3 {
+                            d_u[a] += delta_eq * rho * ((2.0 * cw.w3[i] * u_prod + cw.w1[i]) * e[a] as f64 + 2.0 * cw.w4[i] * u[a]);
+                        }
+                    }
+                    for (q, e) in velocities.iter().enumerate() {
+                        let i = [l, r, c, q];
+                        self.delta[i] = cw.delta[i] / 2.0 + d_rho + (0..3).map(|a| d_u[a] * (e[a] as f64 - u[a])).sum::<f64>() / rho;
+                    }
+                }
+            }
+        }
+    }
+
+    // f = w0 + w1 * f_prevなので dw0 = -eta * delta、dw1 = -eta * delta * f_prev
+    fn set_dw(self: &mut Self, eta: f64, f_prev: &Array4<f64>) {
+        let (velocities, reflections) = (self.lattice.velocities(), self.lattice.reflections());
+        for l in 0..self.level {
+            for r in self.margin..self.row-self.margin {
+                for c in self.margin..self.col-self.margin {
+                    for (q, e) in velocities.iter().enumerate() {
+                        let i = [l, r, c, q];
+                        self.dw0[i] = -eta * self.delta[i];
+                        self.dw1[i] = self.dw0[i] * f_prev[source(&reflections, *e, self.level, l, r, c, q)];
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn update(self: &mut Self) {
+        let margin = self.margin;
+        let (row, col) = (self.row, self.col);
+        let mut w0 = self.w0.slice_mut(s![.., margin..row-margin, margin..col-margin, ..]);
+        w0 += &self.dw0.slice(s![.., margin..row-margin, margin..col-margin, ..]);
+        let mut w1 = self.w1.slice_mut(s![.., margin..row-margin, margin..col-margin, ..]);
+        w1 += &self.dw1.slice(s![.., margin..row-margin, margin..col-margin, ..]);
+        self.dw0.slice_mut(s![.., margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        self.dw1.slice_mut(s![.., margin..row-margin, margin..col-margin, ..]).fill(0.0);
+    }
+}
+
+impl StreamedField3d {
+    pub fn new(lattice: Lattice3d, level: usize, row: usize, col: usize, margin: usize) -> StreamedField3d {
+        let mut f = Array4::<f64>::from_elem((level, row, col, lattice.q()), f64::NAN);
+        f.slice_mut(s![.., margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        let mut zeros = Array3::<f64>::from_elem((level, row, col), f64::NAN);
+        zeros.slice_mut(s![.., margin..row-margin, margin..col-margin]).fill(0.0);
+        StreamedField3d { lattice, level, row, col, margin, f, u_level: zeros.clone(), u_vert: zeros.clone(), u_hori: zeros.clone(), rho: zeros }
+    }
+
+    pub fn lattice(self: &Self) -> Lattice3d { self.lattice }
+    pub fn level(self: &Self) -> usize { self.level }
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn margin(self: &Self) -> usize { self.margin }
+    pub fn f(self: &Self) -> ArrayView4<'_, f64> { self.f.view() }
+    pub fn u_level(self: &Self) -> ArrayView3<'_, f64> { self.u_level.view() }
+    pub fn u_vert(self: &Self) -> ArrayView3<'_, f64> { self.u_vert.view() }
+    pub fn u_hori(self: &Self) -> ArrayView3<'_, f64> { self.u_hori.view() }
+    pub fn rho(self: &Self) -> ArrayView3<'_, f64> { self.rho.view() }
+
+    pub fn stream_from_input_field(self: &mut Self, input_field: &InputField3d, streaming_weight: &StreamingWeight3d) {
+        if [self.level, self.row, self.col] != [input_field.level, input_field.row, input_field.col] || self.lattice != input_field.lattice {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.stream(&input_field.f, streaming_weight);
+    }
+
+    pub fn stream_from_collided_field(self: &mut Self, collided_field: &CollidedField3d, streaming_weight: &StreamingWeight3d) {
+        if [self.level, self.row, self.col] != [collided_field.level, collided_field.row, collided_field.col] || self.lattice != collided_field.lattice {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        if self.margin != collided_field.margin + 1 {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.stream(&collided_field.f, streaming_weight);
+    }
+
+    fn stream(self: &mut Self, f_prev: &Array4<f64>, streaming_weight: &StreamingWeight3d) {
+        let sw = streaming_weight;
+        if [self.level, self.row, self.col] != [sw.level, sw.row, sw.col] || self.margin != sw.margin || self.lattice != sw.lattice {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let (velocities, reflections) = (self.lattice.velocities(), self.lattice.reflections());
+        for l in 0..self.level {
+            for r in self.margin..self.row-self.margin {
+                for c in self.margin..self.col-self.margin {
+                    for (q, e) in velocities.iter().enumerate() {
+                        let i = [l, r, c, q];
+                        self.f[i] = sw.w0[i] + sw.w1[i] * f_prev[source(&reflections, *e, self.level, l, r, c, q)];
+                    }
+                    let (rho, u) = moments(&self.f, &velocities, l, r, c);
+                    self.rho[[l, r, c]] = rho;
+                    self.u_level[[l, r, c]] = u[0];
+                    self.u_vert[[l, r, c]] = u[1];
+                    self.u_hori[[l, r, c]] = u[2];
+                }
+            }
+        }
+    }
+}
+
+impl CollidingWeight3d {
+    pub fn new(lattice: Lattice3d, level: usize, row: usize, col: usize, margin: usize) -> CollidingWeight3d {
+        let interior = |value: f64| {
+            let mut w = Array4::<f64>::from_elem((level, row, col, lattice.q()), f64::NAN);
+            w.slice_mut(s![.., margin..row-margin, margin..col-margin, ..]).fill(value);
+            w
+        };
+        let (w1, w3, w4) = lattice.equilibrium_coefficients();
+        CollidingWeight3d {
+            lattice, level, row, col, margin,
+            w1: interior(w1), w3: interior(w3), w4: interior(w4),
+            dw1: interior(0.0), dw3: interior(0.0), dw4: interior(0.0), delta: interior(0.0),
+        }
+    }
+
+    pub fn lattice(self: &Self) -> Lattice3d { self.lattice }
+    pub fn level(self: &Self) -> usize { self.level }
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn margin(self: &Self) -> usize { self.margin }
+    pub fn w1(self: &Self) -> ArrayView4<'_, f64> { self.w1.view() }
+    pub fn w3(self: &Self) -> ArrayView4<'_, f64> { self.w3.view() }
+    pub fn w4(self: &Self) -> ArrayView4<'_, f64> { self.w4.view() }
+    pub fn dw1(self: &Self) -> ArrayView4<'_, f64> { self.dw1.view() }
+    pub fn dw3(self: &Self) -> ArrayView4<'_, f64> { self.dw3.view() }
+    pub fn dw4(self: &Self) -> ArrayView4<'_, f64> { self.dw4.view() }
+    pub fn delta(self: &Self) -> ArrayView4<'_, f64> { self.delta.view() }
+
+    // 保存しておいた重みを戻す用
+    pub fn set(self: &mut Self, w1: Array4<f64>, w3: Array4<f64>, w4: Array4<f64>) {
+        if self.w1.shape() != w1.shape() || self.w3.shape() != w3.shape() || self.w4.shape() != w4.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.w1 = w1;
+        self.w3 = w3;
+        self.w4 = w4;
+    }
+
+    // streaming_weight_nextのdeltaが計算済みであること
+    // f_next(x + e) = w0 + w1 * f(x)なので、delta(x) = delta_next(x + e) * w1_next(x + e) 気圧面の外に出る成分は鏡面反射した向きになる
+    pub fn propagate_from_streaming_weight(self: &mut Self, eta: f64, field_prev: &StreamedField3d, streaming_weight_next: &StreamingWeight3d) {
+        let sw = streaming_weight_next;
+        if [self.level, self.row, self.col] != [sw.level, sw.row, sw.col] || self.margin != field_prev.margin || self.margin + 1 != sw.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let (velocities, weights, reflections) = (self.lattice.velocities(), self.lattice.weights(), self.lattice.reflections());
+        let in_range = |r: i32, c: i32| sw.margin as i32 <= r && r < (sw.row - sw.margin) as i32 && sw.margin as i32 <= c && c < (sw.col - sw.margin) as i32;
+        for l in 0..self.level {
+            for r in self.margin..self.row-self.margin {
+                for c in self.margin..self.col-self.margin {
+                    let rho = field_prev.rho[[l, r, c]];
+                    let u = [field_prev.u_level[[l, r, c]], field_prev.u_vert[[l, r, c]], field_prev.u_hori[[l, r, c]]];
+                    let u2 = u[0] * u[0] + u[1] * u[1] + u[2] * u[2];
+                    for (q, e) in velocities.iter().enumerate() {
+                        let i = [l, r, c, q];
+                        let l_next = l as i32 + e[0];
+                        let (r_next, c_next) = (r as i32 + e[1], c as i32 + e[2]);
+                        self.delta[i] = if in_range(r_next, c_next) {
+                            let i_next = if l_next < 0 || l_next >= self.level as i32 {
+                                [l, r_next as usize, c_next as usize, reflections[q]]
+                            } else {
+                                [l_next as usize, r_next as usize, c_next as usize, q]
+                            };
+                            sw.delta[i_next] * sw.w1[i_next]
+                        } else {
+                            0.0
+                        };
+                        let u_prod = e[0] as f64 * u[0] + e[1] as f64 * u[1] + e[2] as f64 * u[2];
+                        let delta_eq = self.delta[i] / 2.0 * weights[q] * rho;
+                        self.dw1[i] = -eta * delta_eq * u_prod;
+                        self.dw3[i] = -eta * delta_eq * u_prod * u_prod;
+                        self.dw4[i] = -eta * delta_eq * u2;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn update(self: &mut Self) {
+        let margin = self.margin;
+        let (row, col) = (self.row, self.col);
+        for (w, dw) in [(&mut self.w1, &mut self.dw1), (&mut self.w3, &mut self.dw3), (&mut self.w4, &mut self.dw4)] {
+            let mut w = w.slice_mut(s![.., margin..row-margin, margin..col-margin, ..]);
+            w += &dw.slice(s![.., margin..row-margin, margin..col-margin, ..]);
+            dw.slice_mut(s![.., margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        }
+    }
+}
+
+impl CollidedField3d {
+    pub fn new(lattice: Lattice3d, level: usize, row: usize, col: usize, margin: usize) -> CollidedField3d {
+        let mut f = Array4::<f64>::from_elem((level, row, col, lattice.q()), f64::NAN);
+        f.slice_mut(s![.., margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        let feq = f.clone();
+        CollidedField3d { lattice, level, row, col, margin, f, feq }
+    }
+
+    pub fn lattice(self: &Self) -> Lattice3d { self.lattice }
+    pub fn level(self: &Self) -> usize { self.level }
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn margin(self: &Self) -> usize { self.margin }
+    pub fn f(self: &Self) -> ArrayView4<'_, f64> { self.f.view() }
+    pub fn feq(self: &Self) -> ArrayView4<'_, f64> { self.feq.view() }
+
+    pub fn collide(self: &mut Self, streamed_field: &StreamedField3d, colliding_weight: &CollidingWeight3d) {
+        let (sf, cw) = (streamed_field, colliding_weight);
+        if [self.level, self.row, self.col] != [sf.level, sf.row, sf.col] || [self.level, self.row, self.col] != [cw.level, cw.row, cw.col] {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        if self.margin != sf.margin || self.margin != cw.margin || self.lattice != sf.lattice || self.lattice != cw.lattice {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let (velocities, weights) = (self.lattice.velocities(), self.lattice.weights());
+        for l in 0..self.level {
+            for r in self.margin..self.row-self.margin {
+                for c in self.margin..self.col-self.margin {
+                    let u = [sf.u_level[[l, r, c]], sf.u_vert[[l, r, c]], sf.u_hori[[l, r, c]]];
+                    for (q, e) in velocities.iter().enumerate() {
+                        let i = [l, r, c, q];
+                        self.feq[i] = equilibrium(weights[q], *e, u, sf.rho[[l, r, c]], cw.w1[i], cw.w3[i], cw.w4[i]);
+                        self.f[i] = sf.f[i] - (sf.f[i] - self.feq[i]) / 2.0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lattice3d() {
+        // 鏡面反射の添字が合っていること 重みと逆向きの添字はlattice.rsで確かめる
+        for lattice in [Lattice3d::D3Q19, Lattice3d::D3Q27] {
+            let velocities = lattice.velocities();
+            for (q, e) in velocities.iter().enumerate() {
+                assert_eq!([-e[0], e[1], e[2]], velocities[lattice.reflected(q)]);
+                assert_eq!(q, lattice.reflected(lattice.reflected(q)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_streamed_field3d_stream_and_collide() {
+        // 一様な水平の流れはstreamしてもcollideしても変わらない(上下の端は鏡面反射なので、水平の運動量は変わらない)
+        for lattice in [Lattice3d::D3Q19, Lattice3d::D3Q27] {
+            let (level, row, col) = (3, 6, 7);
+            let mut input_field = InputField3d::new(lattice, level, row, col);
+            input_field.set(Array3::zeros((level, row, col)), Array3::from_elem((level, row, col), 0.02), Array3::from_elem((level, row, col), -0.01), Array3::from_elem((level, row, col), 1.0));
+            let mut streamed_field = StreamedField3d::new(lattice, level, row, col, 1);
+            streamed_field.stream_from_input_field(&input_field, &StreamingWeight3d::new(lattice, level, row, col, 1));
+            for l in 0..level {
+                assert!((streamed_field.rho()[[l, 3, 3]] - 1.0).abs() < 0.000000001);
+                assert!((streamed_field.u_vert()[[l, 3, 3]] - 0.02).abs() < 0.000000001);
+                assert!((streamed_field.u_hori()[[l, 2, 4]] + 0.01).abs() < 0.000000001);
+                assert!(streamed_field.u_level()[[l, 2, 4]].abs() < 0.000000001);
+            }
+            assert!(streamed_field.rho()[[0, 0, 3]].is_nan());
+
+            let mut collided_field = CollidedField3d::new(lattice, level, row, col, 1);
+            collided_field.collide(&streamed_field, &CollidingWeight3d::new(lattice, level, row, col, 1));
+            for q in 0..lattice.q() {
+                assert!((collided_field.f()[[1, 3, 3, q]] - input_field.f()[[1, 3, 3, q]]).abs() < 0.000000001);
+            }
+
+            // 上向きの風は最上層で反射するので、質量は上に溜まり、気圧面方向の運動量はなくなる
+            let mut input_field = InputField3d::new(lattice, 2, row, col);
+            input_field.set(Array3::from_elem((2, row, col), 0.05), Array3::zeros((2, row, col)), Array3::zeros((2, row, col)), Array3::from_elem((2, row, col), 1.0));
+            let mut streamed_field = StreamedField3d::new(lattice, 2, row, col, 1);
+            streamed_field.stream_from_input_field(&input_field, &StreamingWeight3d::new(lattice, 2, row, col, 1));
+            let rho: f64 = (0..2).map(|l| streamed_field.rho()[[l, 3, 3]]).sum();
+            assert!((rho - 2.0).abs() < 0.000000001);
+            assert!(streamed_field.rho()[[1, 3, 3]] > streamed_field.rho()[[0, 3, 3]]);
+            assert!(streamed_field.u_level()[[0, 3, 3]].abs() < 0.000000001);
+            assert!(streamed_field.u_level()[[1, 3, 3]].abs() < 0.000000001);
+        }
+    }
+}
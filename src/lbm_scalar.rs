@@ -0,0 +1,292 @@
+use ndarray::{Array2, Array3, ArrayView2, ArrayView3, s};
+use crate::lattice::{Lattice, D2Q5};
+use crate::lbm::{self, LatticeCollidedField, LatticeInputField, LatticeStreamedField, LatticeStreamingWeight, StreamedField};
+
+// 温度や湿度のようなスカラーphiの移流拡散をD2Q5の格子ボルツマン法で解く 風はlbm.rsのStreamedFieldから受け取る
+// lbm.rsと同じ順に ScalarInputField -> ScalarStreamingWeight -> ScalarStreamedField -> ScalarCollidingWeight -> ScalarCollidedField -> ...
+// 場とstreamの重みはlbm.rsのLattice*をD2Q5にしたもので、stream、重みの更新、streamの逆伝播はD2Q9と共通 ここにはD2Q5だけの部分を書く
+// gは(row, col, q)のf、phi = Σgはrhoに入る(g(), phi()はf(), rho()と同じもの) qはD2Q5::VELOCITIESの添字
+// 行と列の方向はBoundary::Shrinkと同じで、streamするたびにmarginが1周ずつ増える
+// collideはBGKで、geq = C * phi * (1 + w1 * u_prod)、g_next = g - rate * (g - geq) 拡散係数はcs^2 * (1 / rate - 1/2)
+// 風は定数として扱い、風の重みには勾配を流さない
+
+pub type ScalarInputField = LatticeInputField<D2Q5>;
+pub type ScalarStreamingWeight = LatticeStreamingWeight<D2Q5>;
+pub type ScalarStreamedField = LatticeStreamedField<D2Q5>;
+pub type ScalarCollidedField = LatticeCollidedField<D2Q5>;
+
+// rateはセルごとの緩和率
+#[derive(Clone)]
+pub struct ScalarCollidingWeight {
+    row: usize,
+    col: usize,
+    margin: usize,
+    w1: Array3<f64>,
+    rate: Array2<f64>,
+    dw1: Array3<f64>,
+    drate: Array2<f64>,
+    delta: Array3<f64>,
+}
+
+// 平衡分布 lbm::equilibrium()の2次の項を0にしたもの w1の初期値はD2Q5.equilibrium_coefficients()
+fn equilibrium(q: usize, u_vert: f64, u_hori: f64, phi: f64, w1: f64) -> f64 {
+    lbm::equilibrium(D2Q5::WEIGHTS[q], D2Q5::VELOCITIES[q], u_vert, u_hori, phi, [w1, 0.0, 0.0, 0.0])
+}
+
+// marginの内側だけを値で埋めた重み
+fn interior3(row: usize, col: usize, margin: usize, value: f64) -> Array3<f64> {
+    let mut w = Array3::<f64>::from_elem((row, col, D2Q5.q()), f64::NAN);
+    w.slice_mut(s![margin..row-margin, margin..col-margin, ..]).fill(value);
+    w
+}
+
+fn interior2(row: usize, col: usize, margin: usize, value: f64) -> Array2<f64> {
+    let mut w = Array2::<f64>::from_elem((row, col), f64::NAN);
+    w.slice_mut(s![margin..row-margin, margin..col-margin]).fill(value);
+    w
+}
+
+// 風の場が(r, c)で計算済みであること
+fn check_velocity(velocity: &StreamedField, row: usize, col: usize, margin: usize) {
+    if [velocity.row(), velocity.col()] != [row, col] || velocity.margin() > margin {
+        panic!("panicked at line {} in {}", line!(), file!());
+    }
+}
+
+impl ScalarInputField {
+    pub fn g(self: &Self) -> ArrayView3<'_, f64> { self.f() }
+    pub fn phi(self: &Self) -> ArrayView2<'_, f64> { self.rho() }
+
+    // u_vert, u_horiは風の入力(格子単位)
+    pub fn set_phi(self: &mut Self, phi: Array2<f64>, u_vert: ArrayView2<f64>, u_hori: ArrayView2<f64>) {
+        self.set(u_vert.to_owned(), u_hori.to_owned(), phi);
+    }
+}
+
+impl ScalarStreamingWeight {
+    pub fn propagate_from_output(self: &mut Self, eta: f64, field_now: &ScalarStreamedField, field_prev: &ScalarCollidedField, phi_ans: &Array2<f64>) {
+        if self.margin != field_prev.margin + self.boundary.margin_growth() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.set_delta_from_output(field_now, phi_ans);
+        self.apply_obstacle_and_edges_to_delta();
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    // 1層目(ScalarInputFieldから流す層)が出力層のとき用
+    pub fn propagate_from_output_with_input_field(self: &mut Self, eta: f64, field_now: &ScalarStreamedField, field_prev: &ScalarInputField, phi_ans: &Array2<f64>) {
+        self.set_delta_from_output(field_now, phi_ans);
+        self.apply_obstacle_and_edges_to_delta();
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    // colliding_weight_nextのdeltaが計算済みであること velocityはcolliding_weight_nextでcollideしたときの風
+    pub fn propagate_from_colliding_weight(self: &mut Self, eta: f64, field_now: &ScalarStreamedField, field_prev: &ScalarCollidedField, colliding_weight_next: &ScalarCollidingWeight, velocity: &StreamedField) {
+        if self.margin != field_prev.margin + self.boundary.margin_growth() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.set_delta_from_colliding_weight(field_now, colliding_weight_next, velocity);
+        self.apply_obstacle_and_edges_to_delta();
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    pub fn propagate_from_colliding_weight_with_input_field(self: &mut Self, eta: f64, field_now: &ScalarStreamedField, field_prev: &ScalarInputField, colliding_weight_next: &ScalarCollidingWeight, velocity: &StreamedField) {
+        self.set_delta_from_colliding_weight(field_now, colliding_weight_next, velocity);
+        self.apply_obstacle_and_edges_to_delta();
+        self.set_dw(eta, &field_prev.f);
+    }
+
+    // 損失 1/2 * Σ(phi - phi_ans)^2をfield_nowのgで微分したもの phi = Σgなので全成分で同じ
+    fn set_delta_from_output(self: &mut Self, field_now: &ScalarStreamedField, phi_ans: &Array2<f64>) {
+        if [self.row, self.col] != [field_now.row, field_now.col] || [self.row, self.col] != phi_ans.shape() || self.margin != field_now.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let phi = field_now.phi();
+        for r in self.margin..self.row-self.margin {
+            for c in self.margin..self.col-self.margin {
+                let d_phi = phi[[r, c]] - phi_ans[[r, c]];
+                self.delta.slice_mut(s![r, c, ..]).fill(d_phi);
+            }
+        }
+    }
+
+    // collideを逆にたどる g_next = g - rate * (g - geq)、geq = C * phi * (1 + w1 * u_prod)、phi = Σg
+    // dL/dg = (1 - rate) * delta_next + rate * Σ delta_next * C * (1 + w1 * u_prod)
+    fn set_delta_from_colliding_weight(self: &mut Self, field_now: &ScalarStreamedField, colliding_weight_next: &ScalarCollidingWeight, velocity: &StreamedField) {
+        let cw = colliding_weight_next;
+        if [self.row, self.col] != [cw.row, cw.col] || self.margin != field_now.margin || self.margin != cw.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        check_velocity(velocity, self.row, self.col, self.margin);
+        let (u_vert, u_hori) = (velocity.u_vert(), velocity.u_hori());
+        for r in self.margin..self.row-self.margin {
+            for c in self.margin..self.col-self.margin {
+                let rate = cw.rate[[r, c]];
+                let d_phi: f64 = (0..D2Q5.q()).map(|q| cw.delta[[r, c, q]] * equilibrium(q, u_vert[[r, c]], u_hori[[r, c]], 1.0, cw.w1[[r, c, q]])).sum();
+                for q in 0..D2Q5.q() {
+                    self.delta[[r, c, q]] = (1.0 - rate) * cw.delta[[r, c, q]] + rate * d_phi;
+                }
+            }
+        }
+    }
+}
+
+impl ScalarStreamedField {
+    pub fn g(self: &Self) -> ArrayView3<'_, f64> { self.f() }
+    pub fn phi(self: &Self) -> ArrayView2<'_, f64> { self.rho() }
+}
+
+impl ScalarCollidingWeight {
+    pub fn new(row: usize, col: usize, margin: usize) -> ScalarCollidingWeight {
+        let (w1, _, _) = D2Q5.equilibrium_coefficients();
+        ScalarCollidingWeight {
+            row, col, margin,
+            w1: interior3(row, col, margin, w1), rate: interior2(row, col, margin, 1.0),
+            dw1: interior3(row, col, margin, 0.0), drate: interior2(row, col, margin, 0.0), delta: interior3(row, col, margin, 0.0),
+        }
+    }
+
+    pub fn row(self: &Self) -> usize { self.row }
+    pub fn col(self: &Self) -> usize { self.col }
+    pub fn margin(self: &Self) -> usize { self.margin }
+    pub fn w1(self: &Self) -> ArrayView3<'_, f64> { self.w1.view() }
+    pub fn rate(self: &Self) -> ArrayView2<'_, f64> { self.rate.view() }
+    pub fn dw1(self: &Self) -> ArrayView3<'_, f64> { self.dw1.view() }
+    pub fn drate(self: &Self) -> ArrayView2<'_, f64> { self.drate.view() }
+    pub fn delta(self: &Self) -> ArrayView3<'_, f64> { self.delta.view() }
+
+    // 保存しておいた重みを戻す用
+    pub fn set(self: &mut Self, w1: Array3<f64>, rate: Array2<f64>) {
+        if self.w1.shape() != w1.shape() || self.rate.shape() != rate.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.w1 = w1;
+        self.rate = rate;
+    }
+
+    // 拡散係数(格子単位)からrate = 1 / (diffusivity / cs^2 + 1/2)にする
+    pub fn set_diffusivity(self: &mut Self, diffusivity: f64) {
+        if diffusivity < 0.0 {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let (margin, row, col) = (self.margin, self.row, self.col);
+        self.rate.slice_mut(s![margin..row-margin, margin..col-margin]).fill(1.0 / (diffusivity / D2Q5.sound_speed2() + 0.5));
+    }
+
+    // streaming_weight_nextのdeltaが計算済みであること velocityはfield_prevをcollideしたときの風
+    // deltaはLatticeStreamingWeight::delta_to_prev()でstreamを逆にたどる
+    // dw1 = -eta * delta * rate * C * phi * u_prod、drate = -eta * Σ delta * (geq - g)
+    pub fn propagate_from_streaming_weight(self: &mut Self, eta: f64, field_prev: &ScalarStreamedField, streaming_weight_next: &ScalarStreamingWeight, velocity: &StreamedField) {
+        let sw = streaming_weight_next;
+        if [self.row, self.col] != [sw.row(), sw.col()] || self.margin != field_prev.margin() || self.margin + sw.boundary().margin_growth() != sw.margin() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        check_velocity(velocity, self.row, self.col, self.margin);
+        let (u_vert, u_hori) = (velocity.u_vert(), velocity.u_hori());
+        let (phi_prev, g_prev) = (field_prev.phi(), field_prev.g());
+        for r in self.margin..self.row-self.margin {
+            for c in self.margin..self.col-self.margin {
+                let (phi, rate) = (phi_prev[[r, c]], self.rate[[r, c]]);
+                let mut drate = 0.0;
+                for (q, [dr, dc]) in D2Q5::VELOCITIES.into_iter().enumerate() {
+                    let i = [r, c, q];
+                    self.delta[i] = sw.delta_to_prev(r, c, q, [dr, dc]);
+                    let u_prod = dr as f64 * u_vert[[r, c]] + dc as f64 * u_hori[[r, c]];
+                    let geq = equilibrium(q, u_vert[[r, c]], u_hori[[r, c]], phi, self.w1[i]);
+                    self.dw1[i] = -eta * self.delta[i] * rate * D2Q5::WEIGHTS[q] * phi * u_prod;
+                    drate += self.delta[i] * (geq - g_prev[i]);
+                }
+                self.drate[[r, c]] = -eta * drate;
+            }
+        }
+    }
+
+    pub fn update(self: &mut Self) {
+        let margin = self.margin;
+        let (row, col) = (self.row, self.col);
+        let mut w1 = self.w1.slice_mut(s![margin..row-margin, margin..col-margin, ..]);
+        w1 += &self.dw1.slice(s![margin..row-margin, margin..col-margin, ..]);
+        let mut rate = self.rate.slice_mut(s![margin..row-margin, margin..col-margin]);
+        rate += &self.drate.slice(s![margin..row-margin, margin..col-margin]);
+        self.dw1.slice_mut(s![margin..row-margin, margin..col-margin, ..]).fill(0.0);
+        self.drate.slice_mut(s![margin..row-margin, margin..col-margin]).fill(0.0);
+    }
+}
+
+impl ScalarCollidedField {
+    pub fn g(self: &Self) -> ArrayView3<'_, f64> { self.f() }
+    pub fn geq(self: &Self) -> ArrayView3<'_, f64> { self.feq() }
+
+    // velocityは同じ層の風 marginがstreamed_fieldと同じであること
+    pub fn collide(self: &mut Self, streamed_field: &ScalarStreamedField, colliding_weight: &ScalarCollidingWeight, velocity: &StreamedField) {
+        let (sf, cw) = (streamed_field, colliding_weight);
+        if [self.row, self.col] != [sf.row, sf.col] || [self.row, self.col] != [cw.row, cw.col] || self.margin != sf.margin || self.margin != cw.margin {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        check_velocity(velocity, self.row, self.col, self.margin);
+        let (u_vert, u_hori) = (velocity.u_vert(), velocity.u_hori());
+        let (phi, g) = (sf.phi(), sf.g());
+        for r in self.margin..self.row-self.margin {
+            for c in self.margin..self.col-self.margin {
+                let rate = cw.rate[[r, c]];
+                for q in 0..D2Q5.q() {
+                    let i = [r, c, q];
+                    self.feq[i] = equilibrium(q, u_vert[[r, c]], u_hori[[r, c]], phi[[r, c]], cw.w1[i]);
+                    self.f[i] = g[i] - rate * (g[i] - self.feq[i]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lbm::{InputField, StreamingWeight};
+
+    // 一様な風の入力と、それを流した場(marginの内側だけ計算済み)
+    fn uniform_velocity(row: usize, col: usize, margin: usize, u_vert: f64, u_hori: f64) -> (InputField, StreamedField) {
+        let mut input_field = InputField::new(row, col);
+        input_field.set(Array2::from_elem((row, col), u_vert), Array2::from_elem((row, col), u_hori), Array2::from_elem((row, col), 1.0));
+        let mut velocity = StreamedField::new(row, col, margin);
+        velocity.stream_from_input_field(&input_field, &StreamingWeight::new(row, col, margin));
+        (input_field, velocity)
+    }
+
+    #[test]
+    fn test_scalar_stream_and_collide() {
+        // 一様なphiは風があってもstreamしてもcollideしても変わらない
+        let (row, col) = (7, 8);
+        let (wind_input, velocity) = uniform_velocity(row, col, 1, 0.05, -0.03);
+        let mut input_field = ScalarInputField::new(row, col);
+        input_field.set_phi(Array2::from_elem((row, col), 2.0), wind_input.u_vert(), wind_input.u_hori());
+        let mut streamed_field = ScalarStreamedField::new(row, col, 1);
+        streamed_field.stream_from_input_field(&input_field, &ScalarStreamingWeight::new(row, col, 1));
+        let mut collided_field = ScalarCollidedField::new(row, col, 1);
+        collided_field.collide(&streamed_field, &ScalarCollidingWeight::new(row, col, 1), &velocity);
+        for r in 1..row-1 {
+            for c in 1..col-1 {
+                assert!((streamed_field.phi()[[r, c]] - 2.0).abs() < 0.000000001);
+                assert!((collided_field.g().slice(s![r, c, ..]).sum() - 2.0).abs() < 0.000000001);
+            }
+        }
+
+        // 1点に置いたphiは風下に流れて、collideでは保存される
+        let (row, col) = (9, 9);
+        let (wind_input, velocity) = uniform_velocity(row, col, 1, 0.0, 0.1);
+        let mut phi = Array2::zeros((row, col));
+        phi[[4, 4]] = 1.0;
+        let mut input_field = ScalarInputField::new(row, col);
+        input_field.set_phi(phi, wind_input.u_vert(), wind_input.u_hori());
+        let mut streamed_field = ScalarStreamedField::new(row, col, 1);
+        streamed_field.stream_from_input_field(&input_field, &ScalarStreamingWeight::new(row, col, 1));
+        assert!(streamed_field.phi()[[4, 5]] > streamed_field.phi()[[4, 3]]);
+        assert!((streamed_field.phi().slice(s![1..row-1, 1..col-1]).sum() - 1.0).abs() < 0.000000001);
+        let mut colliding_weight = ScalarCollidingWeight::new(row, col, 1);
+        colliding_weight.set_diffusivity(0.1);
+        assert!((colliding_weight.rate()[[4, 4]] - 1.0 / 0.8).abs() < 0.000000001);
+        let mut collided_field = ScalarCollidedField::new(row, col, 1);
+        collided_field.collide(&streamed_field, &colliding_weight, &velocity);
+        assert!((collided_field.g().slice(s![1..row-1, 1..col-1, ..]).sum() - 1.0).abs() < 0.000000001);
+    }
+}
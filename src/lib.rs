@@ -0,0 +1,30 @@
+// 学習可能な格子ボルツマン法(LBM)による地上風の予測
+// lattice:速度の組の抽象(Lattice)とD2Q9  lbm:場と重み  boundary:境界条件  collision:collideの緩和(BGK, TRT, 正則化, MRT, Smagorinsky)  model:レイヤーを重ねたモデルと学習  lbm3d, model3d:複数の気圧面をまとめて流すD3Q19/D3Q27版  lbm_scalar, model_scalar:風に乗せて温度や湿度を流すD2Q5の移流拡散(ライブラリのみ CLIからは使わない)  gradient_check:重みの勾配を中心差分で確かめる  repo, netcdf, geo:気象データの読み込みと格子  synthetic:人工データ  config:実験設定
+// バイナリ(main.rs, cli.rs)もこのクレートの公開APIだけを使って書く
+
+pub mod repo;
+pub mod lattice;
+pub mod lbm;
+pub mod geo;
+pub mod netcdf;
+pub mod synthetic;
+pub mod config;
+pub mod model;
+pub mod boundary;
+pub mod collision;
+pub mod lbm3d;
+pub mod model3d;
+pub mod lbm_scalar;
+pub mod model_scalar;
+pub mod gradient_check;
+
+pub use lattice::{Lattice, D2Q5, D2Q9};
+pub use lbm::{Boundary, InputField, StreamingWeight, StreamedField, CollidingWeight, CollidedField, Conservation};
+pub use model::{Model, Forward, Normalization};
+pub use geo::GeoGrid;
+pub use repo::{MeteorologicalData, MeteorologicalType, MultiLevelData, Region, GridRange, Coarsening};
+pub use lbm3d::Lattice3d;
+pub use model3d::{Model3d, Forward3d};
+pub use model_scalar::{ScalarModel, ScalarForward};
+pub use config::Config;
+pub use collision::{CollisionOperator, Smagorinsky};
@@ -1,5 +1,7 @@
-mod repo;
-mod lbm;
+mod cli;
 
-fn main() {
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    cli::run()
 }
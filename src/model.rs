@@ -0,0 +1,973 @@
+use std::{fs::{self, File}, io, path::Path};
+use ndarray::{Array, Array1, Array2, Array3, ArrayView2, Dimension, Ix4, Zip};
+use ndarray_npy::{ReadNpyExt, ReadableElement, WriteNpyExt};
+use serde::{Deserialize, Serialize};
+use crate::boundary::Edges;
+use crate::collision::{Buoyancy, CollisionOperator, Smagorinsky};
+use crate::lbm::{interior, Boundary, CollidedField, CollidingWeight, Conservation, InputField, StreamedField, StreamingWeight};
+use crate::lattice::{Lattice, D2Q9};
+use crate::lbm_scalar::{ScalarCollidedField, ScalarInputField, ScalarStreamedField};
+use crate::model_scalar::{ScalarForward, ScalarModel};
+
+// InputField -> StreamingWeight -> StreamedField -> CollidingWeight -> CollidedField -> StreamingWeight -> ... -> StreamedField
+// layers層のとき、streamはlayers回、collideはlayers - 1回で、最後のStreamedFieldの風速を出力とする
+// k層目のmarginはmargin + k(streamするたびに1周ずつ計算できる範囲が狭まる) 周期境界のときはどの層もmargin = 0
+// 流入境界のときはどの層もmarginのままで、k回目のstreamの後にinflows[k]でmarginを埋める(boundary::inflow_boundaries()で作る)
+
+const META_FILENAME: &str = "model.toml";
+const OBSTACLE_FILENAME: &str = "obstacle.npy";
+const CORIOLIS_FILENAME: &str = "coriolis.npy";
+
+// 物理量と格子単位の変換 u = u[m/s] / velocity_scale、rho = pressure[Pa] / pressure_ref
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Normalization {
+    pub velocity_scale: f64,
+    pub pressure_ref: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    row: usize,
+    col: usize,
+    margin: usize,
+    layers: usize,
+    #[serde(default)]
+    boundary: Boundary, // テーブルより前に書く必要がある
+    #[serde(default)]
+    force_trainable: bool,
+    #[serde(default)]
+    conservation_penalty: f64,
+    normalization: Normalization,
+    #[serde(default)]
+    edges: Edges,
+    #[serde(default)]
+    collision: CollisionOperator,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    smagorinsky: Vec<Smagorinsky>, // 層ごと 使わないときは空(空の配列はテーブルの後に書けないので書かない)
+    #[serde(default)]
+    buoyancy: Option<Buoyancy>,
+}
+
+#[derive(Clone)]
+pub struct Model {
+    row: usize,
+    col: usize,
+    margin: usize,
+    layers: usize,
+    normalization: Normalization,
+    boundary: Boundary,
+    conservation_penalty: f64, // 0のときは使わない
+    streaming_weights: Vec<StreamingWeight>,
+    colliding_weights: Vec<CollidingWeight>,
+}
+
+// forward()の途中の場 backpropで使う
+// u_vert, u_horiは出力の風速 最後のcollideの外力の半分を足したもの(CollidingWeight::physical_velocity()) 1層のときはΣe f / rhoのまま
+pub struct Forward {
+    input_field: InputField,
+    streamed_fields: Vec<StreamedField>,
+    collided_fields: Vec<CollidedField>,
+    u_vert: Array2<f64>,
+    u_hori: Array2<f64>,
+}
+
+impl Default for Normalization {
+    fn default() -> Normalization {
+        Normalization { velocity_scale: 100.0, pressure_ref: 101325.0 }
+    }
+}
+
+impl Normalization {
+    pub fn to_lattice(self, u_vert: &Array2<f64>, u_hori: &Array2<f64>, pressure: &Array2<f64>) -> (Array2<f64>, Array2<f64>, Array2<f64>) {
+        (u_vert / self.velocity_scale, u_hori / self.velocity_scale, pressure / self.pressure_ref)
+    }
+
+    pub fn velocity_from_lattice(self, u: &Array2<f64>) -> Array2<f64> {
+        u * self.velocity_scale
+    }
+}
+
+impl Model {
+    pub fn new(row: usize, col: usize, margin: usize, layers: usize) -> Model {
+        Model::with_boundary(row, col, margin, layers, Boundary::Shrink)
+    }
+
+    // 周期境界のときはmargin = 0
+    pub fn with_boundary(row: usize, col: usize, margin: usize, layers: usize, boundary: Boundary) -> Model {
+        let valid = match boundary {
+            Boundary::Shrink => margin > 0 && layers > 0 && 2 * (margin + layers - 1) < row.min(col),
+            Boundary::Periodic => margin == 0 && layers > 0 && row > 0 && col > 0,
+            Boundary::Inflow => margin > 0 && layers > 0 && 2 * margin < row.min(col),
+        };
+        if !valid {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let layer_margin = |k: usize| margin + k * boundary.margin_growth();
+        let streaming_weights = (0..layers).map(|k| StreamingWeight::with_boundary(row, col, layer_margin(k), boundary)).collect();
+        let colliding_weights = (0..layers-1).map(|k| CollidingWeight::new(row, col, layer_margin(k))).collect();
+        Model { row, col, margin, layers, normalization: Normalization::default(), boundary, conservation_penalty: 0.0, streaming_weights, colliding_weights }
+    }
+
+    pub fn row(&self) -> usize { self.row }
+    pub fn col(&self) -> usize { self.col }
+    pub fn margin(&self) -> usize { self.margin }
+    pub fn layers(&self) -> usize { self.layers }
+    pub fn normalization(&self) -> Normalization { self.normalization }
+    pub fn boundary(&self) -> Boundary { self.boundary }
+    pub fn streaming_weights(&self) -> &[StreamingWeight] { &self.streaming_weights }
+    pub fn colliding_weights(&self) -> &[CollidingWeight] { &self.colliding_weights }
+    pub(crate) fn streaming_weights_mut(&mut self) -> &mut [StreamingWeight] { &mut self.streaming_weights }
+    pub(crate) fn colliding_weights_mut(&mut self) -> &mut [CollidingWeight] { &mut self.colliding_weights }
+
+    pub fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+    }
+
+    // 障害物と端の条件はすべての層で同じにする
+    pub fn obstacle(&self) -> Option<ArrayView2<'_, bool>> { self.streaming_weights[0].obstacle() }
+    pub fn edges(&self) -> Edges { self.streaming_weights[0].edges() }
+
+    pub fn set_obstacle(&mut self, obstacle: Array2<bool>) {
+        self.streaming_weights.iter_mut().for_each(|w| w.set_obstacle(obstacle.clone()));
+    }
+
+    pub fn set_edges(&mut self, edges: Edges) {
+        self.streaming_weights.iter_mut().for_each(|w| w.set_edges(edges));
+    }
+
+    // collideの緩和のしかたはすべての層で同じ(MRTの緩和率を学習するときは層ごとに変わる)
+    pub fn collision(&self) -> CollisionOperator {
+        self.colliding_weights.first().map_or(CollisionOperator::Bgk, |w| w.operator())
+    }
+
+    pub fn set_collision(&mut self, operator: CollisionOperator) {
+        self.colliding_weights.iter_mut().for_each(|w| w.set_operator(operator));
+    }
+
+    // Smagorinsky定数はすべてのcollideで同じ値から始める 学習するときは層ごとに変わる
+    pub fn smagorinsky(&self) -> Option<Smagorinsky> {
+        self.colliding_weights.first().and_then(|w| w.smagorinsky())
+    }
+
+    pub fn set_smagorinsky(&mut self, smagorinsky: Option<Smagorinsky>) {
+        self.colliding_weights.iter_mut().for_each(|w| w.set_smagorinsky(smagorinsky));
+    }
+
+    // 浮力は学習しないので、すべての層で同じ forward_with_scalar()で流した温度で効く
+    // 行を高さとする鉛直断面用 緯度経度の格子(cli)では使えない
+    pub fn buoyancy(&self) -> Option<Buoyancy> {
+        self.colliding_weights.first().and_then(|w| w.buoyancy())
+    }
+
+    pub fn set_buoyancy(&mut self, buoyancy: Option<Buoyancy>) {
+        self.colliding_weights.iter_mut().for_each(|w| w.set_buoyancy(buoyancy));
+    }
+
+    // 外力(格子単位)はすべてのcollideで同じ値から始める 学習するときは層ごとに変わる
+    pub fn set_force(&mut self, force_vert: Array2<f64>, force_hori: Array2<f64>) {
+        self.colliding_weights.iter_mut().for_each(|w| w.set_force(force_vert.clone(), force_hori.clone()));
+    }
+
+    // コリオリパラメータ(格子単位)はすべてのcollideで同じ layersが1のときはcollideがないので使われない
+    pub fn coriolis(&self) -> Option<ArrayView2<'_, f64>> {
+        self.colliding_weights.first().and_then(|w| w.coriolis())
+    }
+
+    pub fn set_coriolis(&mut self, coriolis: Array2<f64>) {
+        self.colliding_weights.iter_mut().for_each(|w| w.set_coriolis(coriolis.clone()));
+    }
+
+    pub fn conservation_penalty(&self) -> f64 {
+        self.conservation_penalty
+    }
+
+    // streamの重みが質量と運動量を作らない(消さない)ように、penalty / 2 * Σ(作られた量^2)を損失に足して学習する 0で外す
+    pub fn set_conservation_penalty(&mut self, penalty: f64) {
+        if penalty < 0.0 {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.conservation_penalty = penalty;
+    }
+
+    // forwardの各streamの保存則のペナルティの合計 train_step()は前の層の場を通した分も含めてこれも小さくする
+    pub fn penalty(&self, forward: &Forward) -> f64 {
+        if self.conservation_penalty == 0.0 {
+            return 0.0;
+        }
+        self.streaming_weights.iter().enumerate().map(|(k, w)| match k {
+            0 => w.conservation_penalty_with_input_field(self.conservation_penalty, &forward.input_field),
+            _ => w.conservation_penalty(self.conservation_penalty, &forward.collided_fields[k-1]),
+        }).sum()
+    }
+
+    pub fn force_trainable(&self) -> bool {
+        self.colliding_weights.first().is_some_and(|w| w.force_trainable())
+    }
+
+    pub fn set_force_trainable(&mut self, force_trainable: bool) {
+        self.colliding_weights.iter_mut().for_each(|w| w.set_force_trainable(force_trainable));
+    }
+
+    // 出力のStreamedFieldのmargin
+    pub fn output_margin(&self) -> usize {
+        self.layer_margin(self.layers - 1)
+    }
+
+    // k層目のStreamedFieldのmargin
+    fn layer_margin(&self, k: usize) -> usize {
+        self.margin + k * self.boundary.margin_growth()
+    }
+
+    // u_vert, u_hori, rhoは格子単位
+    // 流入境界のときは、marginを入力の値のまま固定する
+    pub fn forward(&self, u_vert: Array2<f64>, u_hori: Array2<f64>, rho: Array2<f64>) -> Forward {
+        self.forward_inner(u_vert, u_hori, rho, None)
+    }
+
+    // 流入境界用 inflowsはstreamごと(layers個)の境界
+    pub fn forward_with_inflow(&self, u_vert: Array2<f64>, u_hori: Array2<f64>, rho: Array2<f64>, inflows: &[InputField]) -> Forward {
+        if self.boundary != Boundary::Inflow || inflows.len() != self.layers {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        self.forward_inner(u_vert, u_hori, rho, Some(inflows))
+    }
+
+    fn forward_inner(&self, u_vert: Array2<f64>, u_hori: Array2<f64>, rho: Array2<f64>, inflows: Option<&[InputField]>) -> Forward {
+        let mut input_field = InputField::new(self.row, self.col);
+        input_field.set(u_vert, u_hori, rho);
+        let inflow = |k: usize| inflows.map_or(&input_field, |inflows| &inflows[k]);
+        let mut streamed_fields = Vec::with_capacity(self.layers);
+        let mut collided_fields = Vec::with_capacity(self.layers - 1);
+
+        let mut streamed_field = StreamedField::new(self.row, self.col, self.margin);
+        streamed_field.stream_from_input_field(&input_field, &self.streaming_weights[0]);
+        if self.boundary == Boundary::Inflow {
+            streamed_field.fill_margin(inflow(0));
+        }
+        streamed_fields.push(streamed_field);
+        for k in 1..self.layers {
+            let mut collided_field = CollidedField::new(self.row, self.col, self.layer_margin(k - 1));
+            collided_field.collide(&streamed_fields[k-1], &self.colliding_weights[k-1]);
+            if self.boundary == Boundary::Inflow {
+                collided_field.fill_margin(inflow(k - 1));
+            }
+            let mut streamed_field = StreamedField::new(self.row, self.col, self.layer_margin(k));
+            streamed_field.stream_from_collided_field(&collided_field, &self.streaming_weights[k]);
+            if self.boundary == Boundary::Inflow {
+                streamed_field.fill_margin(inflow(k));
+            }
+            collided_fields.push(collided_field);
+            streamed_fields.push(streamed_field);
+        }
+
+        Forward::new(input_field, streamed_fields, collided_fields, self.colliding_weights.last())
+    }
+
+    // 温度phiをscalar_modelで風と一緒に流し、各層のStreamedFieldに温度として置く 浮力(set_buoyancy())はこの温度で効く
+    // 風と温度の間には勾配を流さないので、train_step()はそれぞれのForwardで別に呼ぶ Boundary::Shrinkで、大きさとmarginと層の数がscalar_modelと同じこと
+    // 3層以上だと2層目からの温度が1層目の風で流されて風の重みによるので、浮力があるときは2層までにする(その勾配は流さないため)
+    pub fn forward_with_scalar(&self, scalar_model: &ScalarModel, u_vert: Array2<f64>, u_hori: Array2<f64>, rho: Array2<f64>, phi: Array2<f64>) -> (Forward, ScalarForward) {
+        if self.boundary != Boundary::Shrink || [scalar_model.row(), scalar_model.col(), scalar_model.margin(), scalar_model.layers()] != [self.row, self.col, self.margin, self.layers] {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        if self.buoyancy().is_some() && self.layers > 2 {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let mut input_field = InputField::new(self.row, self.col);
+        input_field.set(u_vert, u_hori, rho);
+        let mut scalar_input_field = ScalarInputField::new(self.row, self.col);
+        scalar_input_field.set_phi(phi, input_field.u_vert(), input_field.u_hori());
+        let (mut streamed_fields, mut collided_fields): (Vec<StreamedField>, _) = (Vec::with_capacity(self.layers), Vec::with_capacity(self.layers - 1));
+        let (mut scalar_streamed_fields, mut scalar_collided_fields) = (Vec::with_capacity(self.layers), Vec::with_capacity(self.layers - 1));
+
+        for k in 0..self.layers {
+            let (margin, streaming_weight, scalar_streaming_weight) = (self.layer_margin(k), &self.streaming_weights[k], &scalar_model.streaming_weights()[k]);
+            let mut streamed_field = StreamedField::new(self.row, self.col, margin);
+            let mut scalar_streamed_field = ScalarStreamedField::new(self.row, self.col, margin);
+            if k == 0 {
+                streamed_field.stream_from_input_field(&input_field, streaming_weight);
+                scalar_streamed_field.stream_from_input_field(&scalar_input_field, scalar_streaming_weight);
+            } else {
+                streamed_field.stream_from_collided_field(&collided_fields[k-1], streaming_weight);
+                scalar_streamed_field.stream_from_collided_field(&scalar_collided_fields[k-1], scalar_streaming_weight);
+            }
+            // 出力の温度は1層目の風で流されて風の重みによるので、出力の風速の浮力は最後のcollideの温度で計算する(温度はScalarForwardの出力を見る)
+            let temperature = if k > 0 && k + 1 == self.layers { streamed_fields[k-1].temperature().unwrap().to_owned() } else { scalar_streamed_field.phi().to_owned() };
+            streamed_field.set_temperature(temperature);
+            if k + 1 < self.layers {
+                let mut collided_field = CollidedField::new(self.row, self.col, margin);
+                collided_field.collide(&streamed_field, &self.colliding_weights[k]);
+                let mut scalar_collided_field = ScalarCollidedField::new(self.row, self.col, margin);
+                scalar_collided_field.collide(&scalar_streamed_field, &scalar_model.colliding_weights()[k], &streamed_field);
+                collided_fields.push(collided_field);
+                scalar_collided_fields.push(scalar_collided_field);
+            }
+            streamed_fields.push(streamed_field);
+            scalar_streamed_fields.push(scalar_streamed_field);
+        }
+
+        (Forward::new(input_field, streamed_fields, collided_fields, self.colliding_weights.last()), ScalarForward::new(scalar_input_field, scalar_streamed_fields, scalar_collided_fields))
+    }
+
+    // 全層の重みの変化分を計算してから、まとめて更新する 戻り値は更新前の損失(保存則のペナルティ込み)
+    pub fn train_step(&mut self, eta: f64, forward: &Forward, u_vert_ans: &Array2<f64>, u_hori_ans: &Array2<f64>) -> f64 {
+        let last = self.layers - 1;
+        if last == 0 {
+            self.streaming_weights[0].propagate_from_output_with_input_field(eta, &forward.streamed_fields[0], &forward.input_field, u_vert_ans, u_hori_ans);
+        } else {
+            self.streaming_weights[last].propagate_from_output(eta, &forward.streamed_fields[last], &forward.collided_fields[last-1], &self.colliding_weights[last-1], u_vert_ans, u_hori_ans);
+            self.colliding_weights[last-1].propagate_force_from_output(eta, forward.output(), u_vert_ans, u_hori_ans);
+        }
+        self.add_conservation_penalty(eta, forward, last);
+        for k in (0..last).rev() {
+            let (streaming_weights_now, streaming_weights_next) = self.streaming_weights.split_at_mut(k + 1);
+            self.colliding_weights[k].propagate_from_streaming_weight(eta, &forward.streamed_fields[k], &streaming_weights_next[0]);
+            if k == 0 {
+                streaming_weights_now[k].propagate_from_colliding_weight_with_input_field(eta, &forward.streamed_fields[k], &forward.input_field, &self.colliding_weights[k]);
+            } else {
+                streaming_weights_now[k].propagate_from_colliding_weight(eta, &forward.streamed_fields[k], &forward.collided_fields[k-1], &self.colliding_weights[k]);
+            }
+            self.add_conservation_penalty(eta, forward, k);
+        }
+
+        let penalty = self.penalty(forward);
+        self.streaming_weights.iter_mut().for_each(|w| w.update());
+        self.colliding_weights.iter_mut().for_each(|w| w.update());
+        loss(forward, u_vert_ans, u_hori_ans) + penalty
+    }
+
+    // k層目のstreamのペナルティの勾配を足す 前の層のcollideにも流すので、その層のpropagate_from_streaming_weight()より前に呼ぶ
+    fn add_conservation_penalty(&mut self, eta: f64, forward: &Forward, k: usize) {
+        if self.conservation_penalty == 0.0 {
+            return;
+        }
+        let (penalty, w) = (self.conservation_penalty, &mut self.streaming_weights[k]);
+        match k {
+            0 => w.add_conservation_penalty_with_input_field(eta, penalty, &forward.input_field),
+            _ => w.add_conservation_penalty(eta, penalty, &forward.collided_fields[k-1]),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let meta = Meta { row: self.row, col: self.col, margin: self.margin, layers: self.layers, boundary: self.boundary, force_trainable: self.force_trainable(), conservation_penalty: self.conservation_penalty, normalization: self.normalization, edges: self.edges(), collision: self.collision(),
+            smagorinsky: self.colliding_weights.iter().filter_map(|w| w.smagorinsky()).collect(), buoyancy: self.buoyancy() };
+        fs::write(dir.join(META_FILENAME), toml::to_string(&meta).map_err(io::Error::other)?)?;
+        if let Some(obstacle) = self.obstacle() {
+            obstacle.write_npy(File::create(dir.join(OBSTACLE_FILENAME))?).map_err(io::Error::other)?;
+        }
+        if let Some(coriolis) = self.coriolis() {
+            coriolis.write_npy(File::create(dir.join(CORIOLIS_FILENAME))?).map_err(io::Error::other)?;
+        }
+        for (k, w) in self.streaming_weights.iter().enumerate() {
+            write_npy(dir, &format!("streaming_{}_w0.npy", k), &w.w0().to_owned())?;
+            write_npy(dir, &format!("streaming_{}_w1.npy", k), &w.w1().to_owned())?;
+        }
+        for (k, w) in self.colliding_weights.iter().enumerate() {
+            write_npy(dir, &format!("colliding_{}_w1.npy", k), &w.w1().to_owned())?;
+            write_npy(dir, &format!("colliding_{}_w2.npy", k), &w.w2().to_owned())?;
+            write_npy(dir, &format!("colliding_{}_w3.npy", k), &w.w3().to_owned())?;
+            write_npy(dir, &format!("colliding_{}_w4.npy", k), &w.w4().to_owned())?;
+            w.force_vert().write_npy(File::create(dir.join(format!("colliding_{}_force_vert.npy", k)))?).map_err(io::Error::other)?;
+            w.force_hori().write_npy(File::create(dir.join(format!("colliding_{}_force_hori.npy", k)))?).map_err(io::Error::other)?;
+            if let CollisionOperator::Mrt { .. } = w.operator() {
+                Array1::from(w.rates().to_vec()).write_npy(File::create(dir.join(format!("colliding_{}_rates.npy", k)))?).map_err(io::Error::other)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Model> {
+        let dir = dir.as_ref();
+        let meta: Meta = toml::from_str(&fs::read_to_string(dir.join(META_FILENAME))?).map_err(io::Error::other)?;
+        let mut model = Model::with_boundary(meta.row, meta.col, meta.margin, meta.layers, meta.boundary);
+        model.normalization = meta.normalization;
+        model.set_edges(meta.edges);
+        model.set_collision(meta.collision);
+        let (row, col) = (meta.row, meta.col);
+        if dir.join(OBSTACLE_FILENAME).exists() {
+            model.set_obstacle(read_npy(dir, OBSTACLE_FILENAME, &[row, col])?);
+        }
+        if dir.join(CORIOLIS_FILENAME).exists() {
+            model.set_coriolis(read_npy(dir, CORIOLIS_FILENAME, &[row, col])?);
+        }
+        for (k, w) in model.streaming_weights.iter_mut().enumerate() {
+            w.set(read_weight_npy(dir, &format!("streaming_{}_w0.npy", k), row, col)?, read_weight_npy(dir, &format!("streaming_{}_w1.npy", k), row, col)?);
+        }
+        for (k, w) in model.colliding_weights.iter_mut().enumerate() {
+            w.set(
+                read_weight_npy(dir, &format!("colliding_{}_w1.npy", k), row, col)?,
+                read_weight_npy(dir, &format!("colliding_{}_w2.npy", k), row, col)?,
+                read_weight_npy(dir, &format!("colliding_{}_w3.npy", k), row, col)?,
+                read_weight_npy(dir, &format!("colliding_{}_w4.npy", k), row, col)?,
+            );
+            // 外力を入れる前に保存したモデルにはファイルがない
+            let (force_vert, force_hori) = (format!("colliding_{}_force_vert.npy", k), format!("colliding_{}_force_hori.npy", k));
+            if dir.join(&force_vert).exists() && dir.join(&force_hori).exists() {
+                w.set_force(read_npy(dir, &force_vert, &[row, col])?, read_npy(dir, &force_hori, &[row, col])?);
+            }
+        }
+        model.set_force_trainable(meta.force_trainable);
+        model.set_conservation_penalty(meta.conservation_penalty);
+        for (w, smagorinsky) in model.colliding_weights.iter_mut().zip(meta.smagorinsky) {
+            w.set_smagorinsky(Some(smagorinsky));
+        }
+        model.set_buoyancy(meta.buoyancy);
+        for (k, w) in model.colliding_weights.iter_mut().enumerate() {
+            let name = format!("colliding_{}_rates.npy", k);
+            if dir.join(&name).exists() {
+                let rates: Array1<f64> = read_npy(dir, &name, &[9])?;
+                w.set_rates(rates.to_vec().try_into().unwrap());
+            }
+        }
+        Ok(model)
+    }
+}
+
+impl Forward {
+    fn new(input_field: InputField, streamed_fields: Vec<StreamedField>, collided_fields: Vec<CollidedField>, colliding_weight_last: Option<&CollidingWeight>) -> Forward {
+        let output = streamed_fields.last().unwrap();
+        let (u_vert, u_hori) = match colliding_weight_last {
+            Some(colliding_weight) => colliding_weight.physical_velocity(output),
+            None => (output.u_vert().to_owned(), output.u_hori().to_owned()),
+        };
+        Forward { input_field, streamed_fields, collided_fields, u_vert, u_hori }
+    }
+
+    pub fn input_field(&self) -> &InputField {
+        &self.input_field
+    }
+
+    pub fn output(&self) -> &StreamedField {
+        self.streamed_fields.last().unwrap()
+    }
+
+    pub fn streamed_fields(&self) -> &[StreamedField] {
+        &self.streamed_fields
+    }
+
+    // 出力の風速(外力の半分を足したもの) 損失や予測にはoutput().u_vert()ではなくこれを使う
+    pub fn output_velocity(&self) -> (ArrayView2<'_, f64>, ArrayView2<'_, f64>) {
+        (self.u_vert.view(), self.u_hori.view())
+    }
+
+    // stream、collideのたびの質量と運動量の合計(streamed_fields[0], collided_fields[0], streamed_fields[1], ...の順)
+    // どれも出力と同じmarginの内側で足す 周期境界でなければ、この範囲の外との出入りの分も変わる
+    pub fn conservation(&self) -> Vec<Conservation> {
+        let margin = self.output().margin();
+        let mut totals = vec![self.streamed_fields[0].conservation(margin)];
+        for (collided_field, streamed_field) in self.collided_fields.iter().zip(&self.streamed_fields[1..]) {
+            totals.push(collided_field.conservation(margin));
+            totals.push(streamed_field.conservation(margin));
+        }
+        totals
+    }
+
+    // conservation()の隣どうしの差 1回のstreamかcollideで変わった量
+    pub fn conservation_drift(&self) -> Vec<Conservation> {
+        self.conservation().windows(2).map(|pair| pair[1] - pair[0]).collect()
+    }
+
+    pub fn collided_fields(&self) -> &[CollidedField] {
+        &self.collided_fields
+    }
+}
+
+// 1/2 * Σ((u_vert - u_vert_ans)^2 + (u_hori - u_hori_ans)^2) uは出力の風速(Forward::output_velocity()) marginの内側だけ足す
+pub fn loss(forward: &Forward, u_vert_ans: &Array2<f64>, u_hori_ans: &Array2<f64>) -> f64 {
+    let (u_vert, u_hori) = forward.output_velocity();
+    squared_error(u_vert, u_hori, u_vert_ans.view(), u_hori_ans.view(), forward.output().margin()).0 / 2.0
+}
+
+// 風速のベクトル誤差の二乗平均平方根 marginの内側だけで計算する
+pub fn rmse(u_vert: ArrayView2<f64>, u_hori: ArrayView2<f64>, u_vert_ans: ArrayView2<f64>, u_hori_ans: ArrayView2<f64>, margin: usize) -> f64 {
+    let (sum, count) = squared_error(u_vert, u_hori, u_vert_ans, u_hori_ans, margin);
+    (sum / count as f64).sqrt()
+}
+
+fn squared_error(u_vert: ArrayView2<f64>, u_hori: ArrayView2<f64>, u_vert_ans: ArrayView2<f64>, u_hori_ans: ArrayView2<f64>, margin: usize) -> (f64, usize) {
+    let (row, col) = u_vert_ans.dim();
+    let mut sum = 0.0;
+    Zip::from(interior(u_vert, margin))
+        .and(interior(u_hori, margin))
+        .and(interior(u_vert_ans, margin))
+        .and(interior(u_hori_ans, margin))
+        .for_each(|u_vert, u_hori, u_vert_ans, u_hori_ans| {
+            sum += (u_vert - u_vert_ans).powi(2) + (u_hori - u_hori_ans).powi(2);
+        });
+    (sum, (row - 2 * margin) * (col - 2 * margin))
+}
+
+fn write_npy(dir: &Path, name: &str, arr: &Array3<f64>) -> io::Result<()> {
+    arr.write_npy(File::create(dir.join(name))?).map_err(io::Error::other)
+}
+
+// 形がshapeと違うときはset()などでpanicさせずにErrを返す
+pub(crate) fn read_npy<A: ReadableElement, D: Dimension>(dir: &Path, name: &str, shape: &[usize]) -> io::Result<Array<A, D>> {
+    let path = dir.join(name);
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e));
+    let arr = Array::<A, D>::read_npy(File::open(&path).map_err(|e| invalid(e.to_string()))?).map_err(|e| invalid(e.to_string()))?;
+    if arr.shape() != shape {
+        return Err(invalid(format!("shape {:?} does not match {:?}", arr.shape(), shape)));
+    }
+    Ok(arr)
+}
+
+// 重みは(row, col, q)で保存する 以前の(row, col, 3, 3)のファイルも成分が同じ順に並んでいるので、形を直して読む
+fn read_weight_npy(dir: &Path, name: &str, row: usize, col: usize) -> io::Result<Array3<f64>> {
+    read_npy(dir, name, &[row, col, D2Q9.q()]).or_else(|e| match read_npy::<f64, Ix4>(dir, name, &[row, col, 3, 3]) {
+        Ok(arr) => Ok(arr.into_shape((row, col, D2Q9.q())).map_err(io::Error::other)?),
+        Err(_) => Err(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use crate::gradient_check::{check_gradient, sample_parameters, Parameter};
+    use super::*;
+
+    fn uniform(row: usize, col: usize, x: f64) -> Array2<f64> {
+        Array2::from_elem((row, col), x)
+    }
+
+    #[test]
+    fn test_model_forward_default_weights() {
+        // 初期の重みはw0 = 0, w1 = 1の単なるstreamとτ = 2のBGKなので、一様流はそのまま流れる
+        let model = Model::new(8, 9, 1, 3);
+        let forward = model.forward(uniform(8, 9, 0.05), uniform(8, 9, -0.1), uniform(8, 9, 1.0));
+        let output = forward.output();
+        assert_eq!(3, output.margin());
+        assert!(output.u_vert()[[2, 2]].is_nan());
+        assert!((output.u_vert()[[3, 3]] - 0.05).abs() < 0.000000001);
+        assert!((output.u_hori()[[4, 5]] + 0.1).abs() < 0.000000001);
+        assert!((output.rho()[[4, 5]] - 1.0).abs() < 0.000000001);
+    }
+
+    #[test]
+    fn test_model_train_step_decreases_loss() {
+        for layers in 1..=3 {
+            let mut model = Model::new(9, 10, 1, layers);
+            let u_vert = Array2::from_shape_fn((9, 10), |(r, c)| 0.02 * ((r + c) as f64).sin());
+            let u_hori = Array2::from_shape_fn((9, 10), |(r, c)| 0.03 * ((r * c) as f64).cos());
+            let rho = uniform(9, 10, 1.0);
+            let u_vert_ans = uniform(9, 10, 0.01);
+            let u_hori_ans = uniform(9, 10, -0.02);
+            let mut losses = Vec::new();
+            for _ in 0..5 {
+                let forward = model.forward(u_vert.clone(), u_hori.clone(), rho.clone());
+                losses.push(model.train_step(0.05, &forward, &u_vert_ans, &u_hori_ans));
+            }
+            for k in 1..losses.len() {
+                assert!(losses[k] < losses[k-1], "layers: {}, losses: {:?}", layers, losses);
+            }
+        }
+    }
+
+    #[test]
+    fn test_model_periodic_boundary() {
+        // 周期境界ではmarginが0のまま、一様流が領域全体でそのまま流れる
+        let model = Model::with_boundary(6, 7, 0, 4, Boundary::Periodic);
+        assert_eq!(0, model.output_margin());
+        let forward = model.forward(uniform(6, 7, 0.05), uniform(6, 7, -0.1), uniform(6, 7, 1.0));
+        assert!(forward.output().u_vert().iter().all(|u| (u - 0.05).abs() < 0.000000001));
+        assert!(forward.output().u_hori().iter().all(|u| (u + 0.1).abs() < 0.000000001));
+
+        let mut model = model;
+        let u_vert = Array2::from_shape_fn((6, 7), |(r, c)| 0.02 * ((r + c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((6, 7), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let mut losses = Vec::new();
+        for _ in 0..5 {
+            let forward = model.forward(u_vert.clone(), u_hori.clone(), uniform(6, 7, 1.0));
+            losses.push(model.train_step(0.05, &forward, &uniform(6, 7, 0.01), &uniform(6, 7, -0.02)));
+        }
+        for k in 1..losses.len() {
+            assert!(losses[k] < losses[k-1], "losses: {:?}", losses);
+        }
+    }
+
+    #[test]
+    fn test_model_train_step_with_inflow_boundary() {
+        let mut model = Model::with_boundary(6, 7, 1, 4, Boundary::Inflow);
+        let u_vert = Array2::from_shape_fn((6, 7), |(r, c)| 0.02 * ((r + c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((6, 7), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let mut losses = Vec::new();
+        for _ in 0..5 {
+            let forward = model.forward(u_vert.clone(), u_hori.clone(), uniform(6, 7, 1.0));
+            losses.push(model.train_step(0.05, &forward, &uniform(6, 7, 0.01), &uniform(6, 7, -0.02)));
+        }
+        for k in 1..losses.len() {
+            assert!(losses[k] < losses[k-1], "losses: {:?}", losses);
+        }
+    }
+
+    #[test]
+    fn test_model_train_step_with_collision() {
+        let operators = [
+            CollisionOperator::Trt { rate: 0.5, magic: 0.25 },
+            CollisionOperator::Regularized { rate: 0.5 },
+            CollisionOperator::Mrt { rates: crate::collision::MRT_RATES, trainable: true },
+        ];
+        for operator in operators {
+            let mut model = Model::new(9, 10, 1, 3);
+            model.set_collision(operator);
+            let u_vert = Array2::from_shape_fn((9, 10), |(r, c)| 0.02 * ((r + c) as f64).sin());
+            let u_hori = Array2::from_shape_fn((9, 10), |(r, c)| 0.03 * ((r * c) as f64).cos());
+            let mut losses = Vec::new();
+            for _ in 0..5 {
+                let forward = model.forward(u_vert.clone(), u_hori.clone(), uniform(9, 10, 1.0));
+                losses.push(model.train_step(0.05, &forward, &uniform(9, 10, 0.01), &uniform(9, 10, -0.02)));
+            }
+            for k in 1..losses.len() {
+                assert!(losses[k] < losses[k-1], "collision: {:?}, losses: {:?}", operator, losses);
+            }
+        }
+    }
+
+    // 格子単位で0.4の速いせん断流を粘性の小さい緩和率で流すと、BGK(TRTでmagicを(1/rate - 1/2)^2にしたもの)は発散するが、正則化は有界のまま
+    #[test]
+    fn test_model_regularized_high_velocity() {
+        let (n, rate, layers) = (16, 1.95, 100);
+        let bgk = CollisionOperator::Trt { rate, magic: (1.0 / rate - 0.5).powi(2) };
+        let max_speed = |operator: CollisionOperator| {
+            let mut model = Model::with_boundary(n, n, 0, layers, Boundary::Periodic);
+            model.set_collision(operator);
+            let u_vert = Array2::from_shape_fn((n, n), |(_, c)| 0.05 * (2.0 * std::f64::consts::PI * c as f64 / n as f64).sin());
+            let u_hori = Array2::from_shape_fn((n, n), |(r, _)| if r < n / 2 { 0.4 } else { -0.4 });
+            let forward = model.forward(u_vert, u_hori, uniform(n, n, 1.0));
+            forward.output().u_hori().iter().chain(forward.output().u_vert().iter()).fold(0.0_f64, |max, u| max.max(u.abs()))
+        };
+        let (bgk_speed, regularized_speed) = (max_speed(bgk), max_speed(CollisionOperator::Regularized { rate }));
+        assert!(bgk_speed > 1.0, "{}", bgk_speed);
+        assert!(regularized_speed < 1.0, "{}", regularized_speed);
+    }
+
+    // train_step()の重みの変化分 -dL/dw を中心差分と比べる ansは(0.01, 0.02)の一様な風
+    fn assert_gradient<F: Fn(&Model) -> Forward>(model: &Model, forward: F, parameters: &[Parameter], name: &str) {
+        let (row, col) = (model.row, model.col);
+        let checks = check_gradient(model, forward, &uniform(row, col, 0.01), &uniform(row, col, 0.02), parameters, 0.000001);
+        for check in &checks {
+            assert!(check.relative_error < 0.0001, "{} {}", name, check);
+        }
+    }
+
+    fn streaming_w1(parameters: &[(usize, [usize; 3])]) -> Vec<Parameter> {
+        parameters.iter().map(|&(layer, index)| Parameter::StreamingW1 { layer, index }).collect()
+    }
+
+    #[test]
+    fn test_model_gradient_with_obstacle_and_edges() {
+        use crate::boundary::EdgeCondition;
+        let (row, col) = (7, 8);
+        let mut obstacle = Array2::<bool>::from_elem((row, col), false);
+        obstacle[[3, 4]] = true;
+        obstacle[[4, 4]] = true;
+        let mut model = Model::with_boundary(row, col, 0, 3, Boundary::Periodic);
+        model.set_obstacle(obstacle);
+        model.set_edges(Edges {
+            west: Some(EdgeCondition::Velocity { u_vert: 0.0, u_hori: 0.03 }),
+            east: Some(EdgeCondition::Pressure { rho: 1.0 }),
+            ..Edges::default()
+        });
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.02 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((row, col), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let rho = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.01 * ((r + c) as f64).cos());
+
+        // (層, 添字) 障害物の隣、西端、東端、障害物から離れたセル
+        let parameters = streaming_w1(&[(0, [3, 3, 5]), (1, [2, 0, 7]), (1, [5, 7, 5]), (1, [1, 7, 2]), (2, [2, 4, 7]), (0, [0, 2, 4])]);
+        assert_gradient(&model, |model| model.forward(u_vert.clone(), u_hori.clone(), rho.clone()), &parameters, "edges");
+    }
+
+    // 流出の端の勾配 南端の1つ内側に障害物を置いて、そこの端のセルが除かれることも確かめる
+    #[test]
+    fn test_model_gradient_with_outflow_edges() {
+        use crate::boundary::EdgeCondition;
+        let (row, col) = (7, 8);
+        let mut obstacle = Array2::<bool>::from_elem((row, col), false);
+        obstacle[[3, 4]] = true;
+        obstacle[[5, 2]] = true;
+        let mut model = Model::with_boundary(row, col, 0, 3, Boundary::Periodic);
+        model.set_obstacle(obstacle);
+        model.set_edges(Edges {
+            west: Some(EdgeCondition::Velocity { u_vert: 0.0, u_hori: 0.03 }),
+            north: Some(EdgeCondition::Outflow),
+            south: Some(EdgeCondition::Outflow),
+            ..Edges::default()
+        });
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.02 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((row, col), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let rho = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.01 * ((r + c) as f64).cos());
+        let forward = |model: &Model| model.forward(u_vert.clone(), u_hori.clone(), rho.clone());
+        // 障害物の隣の南端のセルは0を写さない
+        assert!((forward(&model).output().rho()[[6, 2]] - 1.0).abs() < 0.1);
+
+        // (層, 添字) 北端とその内側、南端の内側、南端の障害物の隣、西端と北端の角
+        let parameters = streaming_w1(&[(1, [0, 3, 1]), (2, [1, 3, 7]), (1, [5, 5, 0]), (1, [6, 2, 1]), (2, [6, 3, 6]), (1, [0, 0, 5])]);
+        assert_gradient(&model, forward, &parameters, "outflow");
+    }
+
+    // 外力を学習するときの外力と重みの変化分を中心差分と比べる コリオリ力もかける
+    #[test]
+    fn test_model_gradient_with_force() {
+        let (row, col) = (9, 10);
+        let mut model = Model::new(row, col, 1, 3);
+        model.set_force(Array2::from_shape_fn((row, col), |(r, c)| 0.002 * ((r + c) as f64).cos()), Array2::from_shape_fn((row, col), |(r, c)| -0.001 * ((r * c) as f64).sin()));
+        model.set_force_trainable(true);
+        model.set_coriolis(Array2::from_shape_fn((row, col), |(r, _)| -0.05 - 0.01 * r as f64));
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.02 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((row, col), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let rho = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.01 * ((r + c) as f64).cos());
+
+        // 外力と、外力があるときのcollideの重みとstreamの重み
+        let mut parameters = vec![
+            Parameter::ForceVert { layer: 0, cell: [3, 4] },
+            Parameter::ForceHori { layer: 0, cell: [5, 6] },
+            Parameter::ForceVert { layer: 1, cell: [4, 4] },
+            Parameter::ForceHori { layer: 1, cell: [3, 5] },
+            Parameter::CollidingW2 { layer: 0, index: [3, 4, 1] },
+            Parameter::CollidingW2 { layer: 1, index: [4, 5, 8] },
+        ];
+        parameters.extend(streaming_w1(&[(0, [3, 3, 5]), (1, [4, 6, 0])]));
+        assert_gradient(&model, |model| model.forward(u_vert.clone(), u_hori.clone(), rho.clone()), &parameters, "force");
+    }
+
+    // 出力の風速は最後のcollideの外力の半分を足したもの 1層のときはcollideがないのでそのまま
+    #[test]
+    fn test_forward_output_velocity_with_force() {
+        let (row, col) = (6, 7);
+        let mut model = Model::new(row, col, 1, 2);
+        model.set_force(uniform(row, col, 0.002), uniform(row, col, -0.004));
+        let forward = model.forward(uniform(row, col, 0.01), uniform(row, col, 0.02), uniform(row, col, 1.1));
+        let (output, (u_vert, u_hori)) = (forward.output(), forward.output_velocity());
+        let rho = output.rho()[[3, 3]];
+        assert!((u_vert[[3, 3]] - output.u_vert()[[3, 3]] - 0.002 / (2.0 * rho)).abs() < 0.000000001);
+        assert!((u_hori[[3, 3]] - output.u_hori()[[3, 3]] + 0.004 / (2.0 * rho)).abs() < 0.000000001);
+        assert!(u_vert[[1, 1]].is_nan());
+
+        let forward = Model::new(row, col, 1, 1).forward(uniform(row, col, 0.01), uniform(row, col, 0.02), uniform(row, col, 1.1));
+        assert_eq!(forward.output().u_hori_interior(), interior(forward.output_velocity().1, 1));
+    }
+
+    // 浮力があると3層以上では温度から風への勾配が足りないので流さない
+    #[test]
+    #[should_panic]
+    fn test_model_forward_with_scalar_rejects_buoyancy_with_3_layers() {
+        let (row, col) = (9, 10);
+        let mut model = Model::new(row, col, 1, 3);
+        model.set_buoyancy(Some(Buoyancy { coefficient: 0.02, reference: 1.0 }));
+        model.forward_with_scalar(&ScalarModel::new(row, col, 1, 3), uniform(row, col, 0.01), uniform(row, col, 0.02), uniform(row, col, 1.0), uniform(row, col, 1.0));
+    }
+
+    // 浮力があるときの重みの変化分を中心差分と比べる 温度から風への勾配は流さないので、温度が風によらないように2層(衝突は入力の温度だけ使う)にする
+    #[test]
+    fn test_model_gradient_with_buoyancy() {
+        let (row, col) = (9, 10);
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.02 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((row, col), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let rho = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.01 * ((r + c) as f64).cos());
+        let phi = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.5 * ((r + c) as f64 * 0.8).sin());
+        let scalar_model = ScalarModel::new(row, col, 1, 2);
+        let mut model = Model::new(row, col, 1, 2);
+        model.set_buoyancy(Some(Buoyancy { coefficient: 0.02, reference: 1.0 }));
+        let forward = |model: &Model| model.forward_with_scalar(&scalar_model, u_vert.clone(), u_hori.clone(), rho.clone(), phi.clone()).0;
+        // 浮力が効いていること
+        let mut without_buoyancy = model.clone();
+        without_buoyancy.set_buoyancy(None);
+        let (u_vert_ans, u_hori_ans) = (uniform(row, col, 0.01), uniform(row, col, 0.02));
+        assert!((loss(&forward(&model), &u_vert_ans, &u_hori_ans) - loss(&forward(&without_buoyancy), &u_vert_ans, &u_hori_ans)).abs() > 0.000001);
+
+        let mut parameters = vec![
+            Parameter::CollidingW1 { layer: 0, index: [3, 4, 1] },
+            Parameter::CollidingW1 { layer: 0, index: [4, 5, 8] },
+        ];
+        parameters.extend(streaming_w1(&[(0, [3, 3, 5]), (1, [4, 6, 0])]));
+        assert_gradient(&model, forward, &parameters, "buoyancy");
+    }
+
+    // 周期境界で既定の重みならstreamもcollideも質量と運動量を変えない streamの重みをずらすとそのstreamで変わる
+    #[test]
+    fn test_forward_conservation() {
+        let (row, col) = (6, 7);
+        let mut model = Model::with_boundary(row, col, 0, 3, Boundary::Periodic);
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.02 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((row, col), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let rho = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.01 * ((r + c) as f64).cos());
+        let forward = model.forward(u_vert.clone(), u_hori.clone(), rho.clone());
+        assert_eq!(5, forward.conservation().len());
+        assert!((forward.conservation()[0].mass - rho.sum()).abs() < 0.000000001);
+        for drift in forward.conservation_drift() {
+            assert!(drift.mass.abs() < 0.000000001 && drift.momentum_vert.abs() < 0.000000001 && drift.momentum_hori.abs() < 0.000000001, "{:?}", drift);
+        }
+        assert_eq!(0.0, model.penalty(&forward));
+
+        let w1 = model.streaming_weights[2].w1().to_owned();
+        let w0 = Array3::from_shape_fn(w1.dim(), |(r, _, q)| if r == 2 && q == 5 { 0.01 } else { 0.0 });
+        model.streaming_weights[2].set(w0, w1);
+        model.set_conservation_penalty(2.0);
+        let forward = model.forward(u_vert, u_hori, rho);
+        let drift = forward.conservation_drift();
+        assert!((drift[3].mass - 0.01 * col as f64).abs() < 0.000000001);
+        assert!((drift[3].momentum_hori - 0.01 * col as f64).abs() < 0.000000001);
+        assert!(drift[3].momentum_vert.abs() < 0.000000001);
+        // セルごとに質量と横の運動量が0.01ずつ 2 / 2 * (0.01^2 + 0.01^2) * col
+        assert!((model.penalty(&forward) - 0.0002 * col as f64).abs() < 0.000000001);
+    }
+
+    // 保存則のペナルティの勾配も中心差分と合うこと 前の層の場を通した分も流す 障害物で跳ね返る成分も確かめる
+    // streamの重みが恒等だと勾配が0なので、ずらしてから確かめる
+    #[test]
+    fn test_model_gradient_with_conservation_penalty() {
+        let (row, col) = (9, 10);
+        let mut model = Model::new(row, col, 1, 3);
+        for w in model.streaming_weights.iter_mut() {
+            let w0 = Array3::from_shape_fn((row, col, 9), |(r, c, q)| 0.001 * ((r + 2 * c + q / 3 + 3 * (q % 3)) as f64).sin());
+            let w1 = Array3::from_shape_fn((row, col, 9), |(r, c, q)| 1.0 + 0.02 * ((r * c + (q / 3) * (q % 3)) as f64).cos());
+            w.set(w0, w1);
+        }
+        model.set_conservation_penalty(0.5);
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.02 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((row, col), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let rho = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.01 * ((r + c) as f64).cos());
+        let forward = |model: &Model| model.forward(u_vert.clone(), u_hori.clone(), rho.clone());
+        assert!(model.penalty(&forward(&model)) > 0.000001);
+
+        assert_gradient(&model, forward, &sample_parameters(&model, 3), "penalty");
+        let mut with_obstacle = model.clone();
+        let mut obstacle = Array2::from_elem((row, col), false);
+        obstacle[[4, 5]] = true;
+        with_obstacle.set_obstacle(obstacle);
+        assert_gradient(&with_obstacle, forward, &sample_parameters(&with_obstacle, 3), "obstacle");
+
+        // ペナルティだけで学習すると、作られる質量と運動量が減る
+        let mut trained = model.clone();
+        let penalty = model.penalty(&forward(&model));
+        for _ in 0..5 {
+            let forward_now = forward(&trained);
+            trained.train_step(0.1, &forward_now, &forward_now.output().u_vert().to_owned(), &forward_now.output().u_hori().to_owned());
+        }
+        assert!(trained.penalty(&forward(&trained)) < penalty);
+    }
+
+    // MRTの緩和率と重みの変化分を中心差分と比べる 外力もかけて(I - A/2) Sの部分も確かめる
+    #[test]
+    fn test_model_gradient_with_mrt() {
+        use crate::collision::MRT_RATES;
+        let (row, col) = (9, 10);
+        let mut model = Model::new(row, col, 1, 3);
+        model.set_collision(CollisionOperator::Mrt { rates: MRT_RATES, trainable: true });
+        model.set_force(uniform(row, col, 0.001), uniform(row, col, -0.002));
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.02 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((row, col), |(r, c)| 0.03 * ((r * c) as f64).cos());
+        let rho = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.01 * ((r + c) as f64).cos());
+
+        let mut parameters: Vec<Parameter> = [(0, 1), (0, 4), (0, 7), (1, 2), (1, 6), (1, 8)].into_iter().map(|(layer, moment)| Parameter::Rate { layer, moment }).collect();
+        parameters.extend(streaming_w1(&[(0, [3, 3, 5]), (1, [4, 6, 0]), (2, [5, 5, 7])]));
+        assert_gradient(&model, |model| model.forward(u_vert.clone(), u_hori.clone(), rho.clone()), &parameters, "mrt");
+    }
+
+    // Smagorinskyの定数と重みの変化分を中心差分と比べる 緩和率がf, feq, rhoによる分も確かめる
+    #[test]
+    fn test_model_gradient_with_smagorinsky() {
+        use crate::collision::MRT_RATES;
+        let (row, col) = (9, 10);
+        let operators = [
+            CollisionOperator::Bgk,
+            CollisionOperator::Trt { rate: 0.6, magic: 0.25 },
+            CollisionOperator::Regularized { rate: 0.6 },
+            CollisionOperator::Mrt { rates: MRT_RATES, trainable: true },
+        ];
+        let u_vert = Array2::from_shape_fn((row, col), |(r, c)| 0.05 * ((r + 2 * c) as f64).sin());
+        let u_hori = Array2::from_shape_fn((row, col), |(r, c)| 0.06 * ((r * c) as f64).cos());
+        let rho = Array2::from_shape_fn((row, col), |(r, c)| 1.0 + 0.01 * ((r + c) as f64).cos());
+        for operator in operators {
+            let mut model = Model::new(row, col, 1, 3);
+            model.set_collision(operator);
+            model.set_smagorinsky(Some(Smagorinsky { constant: 0.3, trainable: true }));
+            model.set_force(uniform(row, col, 0.001), uniform(row, col, -0.002));
+
+            let mut parameters = vec![
+                Parameter::SmagorinskyConstant { layer: 0 },
+                Parameter::SmagorinskyConstant { layer: 1 },
+                Parameter::CollidingW1 { layer: 0, index: [3, 3, 5] },
+                Parameter::CollidingW1 { layer: 1, index: [4, 6, 0] },
+            ];
+            if operator.rates_trainable() {
+                parameters.extend([(0, 7), (1, 8), (1, 4)].into_iter().map(|(layer, moment)| Parameter::Rate { layer, moment }));
+            }
+            parameters.extend(streaming_w1(&[(0, [3, 3, 5]), (1, [4, 6, 0]), (2, [5, 5, 7])]));
+            assert_gradient(&model, |model| model.forward(u_vert.clone(), u_hori.clone(), rho.clone()), &parameters, &format!("{:?}", operator));
+        }
+    }
+
+    #[test]
+    fn test_model_save_and_load() {
+        let dir = env::temp_dir().join("lbm_rust_test_model_save_and_load");
+        let mut model = Model::new(7, 7, 1, 2);
+        model.set_normalization(Normalization { velocity_scale: 50.0, pressure_ref: 100000.0 });
+        model.set_force(uniform(7, 7, 0.001), uniform(7, 7, 0.0));
+        model.set_force_trainable(true);
+        model.set_coriolis(uniform(7, 7, -0.004));
+        model.set_collision(CollisionOperator::Mrt { rates: crate::collision::MRT_RATES, trainable: true });
+        model.colliding_weights[0].set_rates([0.5, 1.3, 1.1, 0.5, 1.2, 0.5, 1.2, 0.6, 0.6]);
+        model.set_smagorinsky(Some(Smagorinsky { constant: 0.2, trainable: true }));
+        model.set_buoyancy(Some(Buoyancy { coefficient: 0.003, reference: 280.0 }));
+        model.set_conservation_penalty(0.2);
+        let forward = model.forward(uniform(7, 7, 0.01), uniform(7, 7, 0.02), uniform(7, 7, 1.0));
+        model.train_step(0.1, &forward, &uniform(7, 7, 0.0), &uniform(7, 7, 0.0));
+        model.save(&dir).unwrap();
+        let loaded = Model::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(2, loaded.layers());
+        assert_eq!(50.0, loaded.normalization().velocity_scale);
+        assert_eq!(model.streaming_weights()[1].w1()[[3, 3, 1]], loaded.streaming_weights()[1].w1()[[3, 3, 1]]);
+        assert_eq!(model.colliding_weights()[0].w3()[[2, 4, 8]], loaded.colliding_weights()[0].w3()[[2, 4, 8]]);
+        assert!(loaded.force_trainable());
+        assert_eq!(Some(-0.004), loaded.coriolis().map(|coriolis| coriolis[[1, 1]]));
+        assert_eq!(model.collision(), loaded.collision());
+        assert_eq!(model.colliding_weights()[0].rates(), loaded.colliding_weights()[0].rates());
+        assert_eq!(model.colliding_weights()[0].smagorinsky(), loaded.colliding_weights()[0].smagorinsky());
+        assert_eq!(model.colliding_weights()[0].force_vert()[[3, 2]], loaded.colliding_weights()[0].force_vert()[[3, 2]]);
+        assert_eq!(model.buoyancy(), loaded.buoyancy());
+        assert_eq!(0.2, loaded.conservation_penalty());
+    }
+
+    // Smagorinskyを使わないモデルも保存できること
+    #[test]
+    fn test_model_save_and_load_without_smagorinsky() {
+        let dir = env::temp_dir().join("lbm_rust_test_model_save_and_load_without_smagorinsky");
+        Model::new(7, 7, 1, 2).save(&dir).unwrap();
+        let loaded = Model::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(None, loaded.colliding_weights()[0].smagorinsky());
+    }
+
+    // 以前の(row, col, 3, 3)で保存した重みも同じ値で読める
+    #[test]
+    fn test_model_load_old_weight_shape() {
+        let dir = env::temp_dir().join("lbm_rust_test_model_load_old_weight_shape");
+        let mut model = Model::new(7, 7, 1, 2);
+        let w1 = Array3::from_shape_fn((7, 7, 9), |(r, c, q)| 1.0 + 0.01 * (r + 2 * c + 3 * q) as f64);
+        let w0 = model.streaming_weights[1].w0().to_owned();
+        model.streaming_weights[1].set(w0, w1.clone());
+        model.save(&dir).unwrap();
+        w1.into_shape((7, 7, 3, 3)).unwrap().write_npy(File::create(dir.join("streaming_1_w1.npy")).unwrap()).unwrap();
+        let loaded = Model::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(model.streaming_weights()[1].w1(), loaded.streaming_weights()[1].w1());
+    }
+
+    // 重みの形がmetaと合わないチェックポイントはpanicせずにErr
+    #[test]
+    fn test_model_load_shape_mismatch() {
+        let dir = env::temp_dir().join("lbm_rust_test_model_load_shape_mismatch");
+        let model = Model::new(7, 7, 1, 2);
+        model.save(&dir).unwrap();
+        Array3::<f64>::zeros((7, 6, 9)).write_npy(File::create(dir.join("colliding_0_w2.npy")).unwrap()).unwrap();
+        let result = Model::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let message = result.err().unwrap().to_string();
+        assert!(message.contains("colliding_0_w2.npy"), "{}", message);
+    }
+}
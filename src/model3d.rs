@@ -0,0 +1,302 @@
+use std::{fs, io, path::Path};
+use ndarray::{Array3, Array4, Axis, s};
+use ndarray_npy::WriteNpyExt;
+use serde::{Deserialize, Serialize};
+use crate::lattice::Lattice;
+use crate::lbm3d::{CollidedField3d, CollidingWeight3d, InputField3d, Lattice3d, StreamedField3d, StreamingWeight3d};
+use crate::model::{read_npy, Normalization};
+
+// 複数の気圧面をまとめて流すモデル 層の重ね方はModelと同じで、marginはstreamするたびに1周ずつ増える(Boundary::Shrinkだけ)
+// 入出力は(level, row, col)の3次元 levelの添字が増える向き(上向き)がu_levelの正
+
+const META_FILENAME: &str = "model3d.toml";
+
+#[derive(Serialize, Deserialize)]
+struct Meta3d {
+    lattice: Lattice3d,
+    level: usize,
+    row: usize,
+    col: usize,
+    margin: usize,
+    layers: usize,
+    normalization: Normalization,
+}
+
+#[derive(Clone)]
+pub struct Model3d {
+    lattice: Lattice3d,
+    level: usize,
+    row: usize,
+    col: usize,
+    margin: usize,
+    layers: usize,
+    normalization: Normalization,
+    streaming_weights: Vec<StreamingWeight3d>,
+    colliding_weights: Vec<CollidingWeight3d>,
+}
+
+// forward()の途中の場 backpropで使う
+pub struct Forward3d {
+    input_field: InputField3d,
+    streamed_fields: Vec<StreamedField3d>,
+    collided_fields: Vec<CollidedField3d>,
+}
+
+impl Model3d {
+    pub fn new(lattice: Lattice3d, level: usize, row: usize, col: usize, margin: usize, layers: usize) -> Model3d {
+        if level == 0 || margin == 0 || layers == 0 || 2 * (margin + layers - 1) >= row.min(col) {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let streaming_weights = (0..layers).map(|k| StreamingWeight3d::new(lattice, level, row, col, margin + k)).collect();
+        let colliding_weights = (0..layers-1).map(|k| CollidingWeight3d::new(lattice, level, row, col, margin + k)).collect();
+        Model3d { lattice, level, row, col, margin, layers, normalization: Normalization::default(), streaming_weights, colliding_weights }
+    }
+
+    pub fn lattice(&self) -> Lattice3d { self.lattice }
+    pub fn level(&self) -> usize { self.level }
+    pub fn row(&self) -> usize { self.row }
+    pub fn col(&self) -> usize { self.col }
+    pub fn margin(&self) -> usize { self.margin }
+    pub fn layers(&self) -> usize { self.layers }
+    pub fn normalization(&self) -> Normalization { self.normalization }
+    pub fn streaming_weights(&self) -> &[StreamingWeight3d] { &self.streaming_weights }
+    pub fn colliding_weights(&self) -> &[CollidingWeight3d] { &self.colliding_weights }
+    pub(crate) fn streaming_weights_mut(&mut self) -> &mut [StreamingWeight3d] { &mut self.streaming_weights }
+    pub(crate) fn colliding_weights_mut(&mut self) -> &mut [CollidingWeight3d] { &mut self.colliding_weights }
+
+    pub fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+    }
+
+    // 出力のStreamedField3dのmargin
+    pub fn output_margin(&self) -> usize {
+        self.margin + self.layers - 1
+    }
+
+    // u_level, u_vert, u_hori, rhoは格子単位
+    pub fn forward(&self, u_level: Array3<f64>, u_vert: Array3<f64>, u_hori: Array3<f64>, rho: Array3<f64>) -> Forward3d {
+        let (lattice, level, row, col) = (self.lattice, self.level, self.row, self.col);
+        let mut input_field = InputField3d::new(lattice, level, row, col);
+        input_field.set(u_level, u_vert, u_hori, rho);
+        let mut streamed_fields = Vec::with_capacity(self.layers);
+        let mut collided_fields = Vec::with_capacity(self.layers - 1);
+
+        let mut streamed_field = StreamedField3d::new(lattice, level, row, col, self.margin);
+        streamed_field.stream_from_input_field(&input_field, &self.streaming_weights[0]);
+        streamed_fields.push(streamed_field);
+        for k in 1..self.layers {
+            let mut collided_field = CollidedField3d::new(lattice, level, row, col, self.margin + k - 1);
+            collided_field.collide(&streamed_fields[k-1], &self.colliding_weights[k-1]);
+            let mut streamed_field = StreamedField3d::new(lattice, level, row, col, self.margin + k);
+            streamed_field.stream_from_collided_field(&collided_field, &self.streaming_weights[k]);
+            collided_fields.push(collided_field);
+            streamed_fields.push(streamed_field);
+        }
+
+        Forward3d { input_field, streamed_fields, collided_fields }
+    }
+
+    // 全層の重みの変化分を計算してから、まとめて更新する 戻り値は更新前の損失
+    pub fn train_step(&mut self, eta: f64, forward: &Forward3d, u_level_ans: &Array3<f64>, u_vert_ans: &Array3<f64>, u_hori_ans: &Array3<f64>) -> f64 {
+        let ans = [u_level_ans, u_vert_ans, u_hori_ans];
+        let last = self.layers - 1;
+        if last == 0 {
+            self.streaming_weights[0].propagate_from_output_with_input_field(eta, &forward.streamed_fields[0], &forward.input_field, ans);
+        } else {
+            self.streaming_weights[last].propagate_from_output(eta, &forward.streamed_fields[last], &forward.collided_fields[last-1], ans);
+        }
+        for k in (0..last).rev() {
+            let (streaming_weights_now, streaming_weights_next) = self.streaming_weights.split_at_mut(k + 1);
+            self.colliding_weights[k].propagate_from_streaming_weight(eta, &forward.streamed_fields[k], &streaming_weights_next[0]);
+            if k == 0 {
+                streaming_weights_now[k].propagate_from_colliding_weight_with_input_field(eta, &forward.streamed_fields[k], &forward.input_field, &self.colliding_weights[k]);
+            } else {
+                streaming_weights_now[k].propagate_from_colliding_weight(eta, &forward.streamed_fields[k], &forward.collided_fields[k-1], &self.colliding_weights[k]);
+            }
+        }
+
+        self.streaming_weights.iter_mut().for_each(|w| w.update());
+        self.colliding_weights.iter_mut().for_each(|w| w.update());
+        loss3d(forward.output(), u_level_ans, u_vert_ans, u_hori_ans)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let meta = Meta3d { lattice: self.lattice, level: self.level, row: self.row, col: self.col, margin: self.margin, layers: self.layers, normalization: self.normalization };
+        fs::write(dir.join(META_FILENAME), toml::to_string(&meta).map_err(io::Error::other)?)?;
+        for (k, w) in self.streaming_weights.iter().enumerate() {
+            write_npy(dir, &format!("streaming_{}_w0.npy", k), &w.w0().to_owned())?;
+            write_npy(dir, &format!("streaming_{}_w1.npy", k), &w.w1().to_owned())?;
+        }
+        for (k, w) in self.colliding_weights.iter().enumerate() {
+            write_npy(dir, &format!("colliding_{}_w1.npy", k), &w.w1().to_owned())?;
+            write_npy(dir, &format!("colliding_{}_w3.npy", k), &w.w3().to_owned())?;
+            write_npy(dir, &format!("colliding_{}_w4.npy", k), &w.w4().to_owned())?;
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Model3d> {
+        let dir = dir.as_ref();
+        let meta: Meta3d = toml::from_str(&fs::read_to_string(dir.join(META_FILENAME))?).map_err(io::Error::other)?;
+        let mut model = Model3d::new(meta.lattice, meta.level, meta.row, meta.col, meta.margin, meta.layers);
+        model.normalization = meta.normalization;
+        let shape = [meta.level, meta.row, meta.col, meta.lattice.q()];
+        for (k, w) in model.streaming_weights.iter_mut().enumerate() {
+            w.set(read_npy(dir, &format!("streaming_{}_w0.npy", k), &shape)?, read_npy(dir, &format!("streaming_{}_w1.npy", k), &shape)?);
+        }
+        for (k, w) in model.colliding_weights.iter_mut().enumerate() {
+            w.set(
+                read_npy(dir, &format!("colliding_{}_w1.npy", k), &shape)?,
+                read_npy(dir, &format!("colliding_{}_w3.npy", k), &shape)?,
+                read_npy(dir, &format!("colliding_{}_w4.npy", k), &shape)?,
+            );
+        }
+        Ok(model)
+    }
+}
+
+impl Forward3d {
+    pub fn output(&self) -> &StreamedField3d {
+        self.streamed_fields.last().unwrap()
+    }
+
+    pub fn streamed_fields(&self) -> &[StreamedField3d] {
+        &self.streamed_fields
+    }
+
+    pub fn collided_fields(&self) -> &[CollidedField3d] {
+        &self.collided_fields
+    }
+}
+
+// 損失 1/2 * Σ(u - u_ans)^2 すべての気圧面の、marginの内側の3成分で計算する
+pub fn loss3d(output: &StreamedField3d, u_level_ans: &Array3<f64>, u_vert_ans: &Array3<f64>, u_hori_ans: &Array3<f64>) -> f64 {
+    let (margin, row, col) = (output.margin(), output.row(), output.col());
+    let interior = s![.., margin..row-margin, margin..col-margin];
+    let mut sum = 0.0;
+    for (u, ans) in [(output.u_level(), u_level_ans), (output.u_vert(), u_vert_ans), (output.u_hori(), u_hori_ans)] {
+        sum += (&u.slice(interior) - &ans.slice(interior)).mapv(|d| d * d).sum();
+    }
+    sum / 2.0
+}
+
+// 気象データ(MultiLevelData::lattice_velocity()の向き)を格子単位にする
+// u_levelは1秒あたりに進む気圧面の数なので1ステップの時間time_step[s]をかける
+// 気圧面ではrhoの代わりにジオポテンシャルphi[m^2/s^2]を使う 圧力の力cs^2 ∇rhoが-∇phiと同じになるように、rho = 1 + (phi - 面の平均) / (cs^2 * velocity_scale^2)
+pub fn to_lattice_3d(normalization: Normalization, time_step: f64, u_level: &Array3<f64>, u_vert: &Array3<f64>, u_hori: &Array3<f64>, geopotential: &Array3<f64>) -> (Array3<f64>, Array3<f64>, Array3<f64>, Array3<f64>) {
+    let velocity_scale = normalization.velocity_scale;
+    // D3Q19もD3Q27もcs^2は同じ
+    let cs2 = Lattice3d::D3Q19.sound_speed2();
+    let mut rho = geopotential.clone();
+    for mut phi in rho.axis_iter_mut(Axis(0)) {
+        let mean = phi.mean().unwrap();
+        phi.mapv_inplace(|phi| 1.0 + (phi - mean) / (cs2 * velocity_scale * velocity_scale));
+    }
+    (u_level * time_step, u_vert / velocity_scale, u_hori / velocity_scale, rho)
+}
+
+fn write_npy(dir: &Path, name: &str, arr: &Array4<f64>) -> io::Result<()> {
+    arr.write_npy(fs::File::create(dir.join(name))?).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use super::*;
+    use crate::lattice::Lattice;
+
+    fn field(level: usize, row: usize, col: usize, f: impl Fn(usize, usize, usize) -> f64) -> Array3<f64> {
+        Array3::from_shape_fn((level, row, col), |(l, r, c)| f(l, r, c))
+    }
+
+    #[test]
+    fn test_model3d_train_step() {
+        for lattice in [Lattice3d::D3Q19, Lattice3d::D3Q27] {
+            let (level, row, col) = (3, 8, 9);
+            let mut model = Model3d::new(lattice, level, row, col, 1, 3);
+            let u_level = field(level, row, col, |l, r, c| 0.005 * ((l + r + c) as f64).sin());
+            let u_vert = field(level, row, col, |l, r, c| 0.02 * ((l + r + 2 * c) as f64).cos());
+            let u_hori = field(level, row, col, |l, r, c| 0.03 * ((l * r + c) as f64).sin());
+            let rho = field(level, row, col, |_, _, _| 1.0);
+            let ans = (field(level, row, col, |_, _, _| 0.0), field(level, row, col, |_, _, _| 0.01), field(level, row, col, |_, _, _| -0.02));
+            let mut losses = Vec::new();
+            for _ in 0..5 {
+                let forward = model.forward(u_level.clone(), u_vert.clone(), u_hori.clone(), rho.clone());
+                losses.push(model.train_step(0.05, &forward, &ans.0, &ans.1, &ans.2));
+            }
+            for k in 1..losses.len() {
+                assert!(losses[k] < losses[k-1], "{:?} {:?}", lattice, losses);
+            }
+        }
+    }
+
+    // train_step()の重みの変化分 -dL/dw を中心差分と比べる 上下の端での反射も通るようにする
+    #[test]
+    fn test_model3d_gradient() {
+        use crate::gradient_check::{check_gradient_3d, Parameter3d};
+        let (level, row, col) = (2, 8, 9);
+        let u_level = field(level, row, col, |l, r, c| 0.01 * ((l + r + c) as f64).sin());
+        let u_vert = field(level, row, col, |l, r, c| 0.02 * ((l + r + 2 * c) as f64).cos());
+        let u_hori = field(level, row, col, |l, r, c| 0.03 * ((l * r + c) as f64).sin());
+        let rho = field(level, row, col, |l, r, c| 1.0 + 0.01 * ((l + r * c) as f64).cos());
+        let ans = (field(level, row, col, |_, _, _| 0.001), field(level, row, col, |_, _, _| 0.01), field(level, row, col, |_, _, _| -0.02));
+        for lattice in [Lattice3d::D3Q19, Lattice3d::D3Q27] {
+            let model = Model3d::new(lattice, level, row, col, 1, 3);
+            let q = lattice.velocities().iter().position(|e| *e == [1, 0, 1]).unwrap();
+            let mut parameters: Vec<Parameter3d> = [(0, [0, 3, 3, q]), (1, [1, 4, 5, q]), (2, [0, 4, 4, 0]), (0, [1, 2, 6, lattice.q() - 1])].into_iter()
+                .map(|(layer, index)| Parameter3d::StreamingW1 { layer, index }).collect();
+            parameters.push(Parameter3d::CollidingW3 { layer: 0, index: [0, 3, 3, q] });
+            parameters.push(Parameter3d::CollidingW3 { layer: 1, index: [1, 4, 5, 2] });
+            let forward = |model: &Model3d| model.forward(u_level.clone(), u_vert.clone(), u_hori.clone(), rho.clone());
+            for check in check_gradient_3d(&model, forward, &ans.0, &ans.1, &ans.2, &parameters, 0.000001) {
+                assert!(check.relative_error < 0.0001, "{:?} {}", lattice, check);
+            }
+        }
+    }
+
+    #[test]
+    fn test_model3d_save_and_load() {
+        let dir = env::temp_dir().join("lbm_rust_test_model3d_save_and_load");
+        let (level, row, col) = (2, 7, 7);
+        let mut model = Model3d::new(Lattice3d::D3Q27, level, row, col, 1, 2);
+        let zeros = field(level, row, col, |_, _, _| 0.0);
+        let forward = model.forward(zeros.clone(), field(level, row, col, |_, _, _| 0.01), zeros.clone(), field(level, row, col, |_, _, _| 1.0));
+        model.train_step(0.1, &forward, &zeros, &zeros, &zeros);
+        model.save(&dir).unwrap();
+        let loaded = Model3d::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(Lattice3d::D3Q27, loaded.lattice());
+        assert_eq!(2, loaded.level());
+        assert_eq!(model.streaming_weights()[1].w1()[[1, 3, 3, 4]], loaded.streaming_weights()[1].w1()[[1, 3, 3, 4]]);
+        assert_eq!(model.colliding_weights()[0].w1()[[0, 2, 4, 10]], loaded.colliding_weights()[0].w1()[[0, 2, 4, 10]]);
+    }
+
+    // 格子がmodel3d.tomlと違う(D3Q19のファイルをD3Q27として読む)ときはpanicせずにErr
+    #[test]
+    fn test_model3d_load_shape_mismatch() {
+        let dir = env::temp_dir().join("lbm_rust_test_model3d_load_shape_mismatch");
+        let model = Model3d::new(Lattice3d::D3Q27, 2, 7, 7, 1, 2);
+        model.save(&dir).unwrap();
+        Array4::<f64>::zeros((2, 7, 7, Lattice3d::D3Q19.q())).write_npy(fs::File::create(dir.join("colliding_0_w3.npy")).unwrap()).unwrap();
+        let result = Model3d::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let message = result.err().unwrap().to_string();
+        assert!(message.contains("colliding_0_w3.npy"), "{}", message);
+    }
+
+    #[test]
+    fn test_to_lattice_3d() {
+        let normalization = Normalization { velocity_scale: 100.0, pressure_ref: 101325.0 };
+        let u_level = field(2, 2, 2, |_, _, _| 0.001);
+        let geopotential = field(2, 2, 2, |l, r, _| 1000.0 * l as f64 + 10000.0 * r as f64);
+        let (u_level, u_vert, _, rho) = to_lattice_3d(normalization, 60.0, &u_level, &field(2, 2, 2, |_, _, _| 5.0), &u_level, &geopotential);
+        assert!((u_level[[0, 0, 0]] - 0.06).abs() < 0.000000001);
+        assert!((u_vert[[1, 1, 1]] - 0.05).abs() < 0.000000001);
+        // 面ごとの平均からのずれ 10000 m^2/s^2は3 * 10000 / 100^2 = 3
+        assert!((rho[[0, 0, 0]] - (1.0 - 1.5)).abs() < 0.000000001);
+        assert!((rho[[1, 1, 0]] - (1.0 + 1.5)).abs() < 0.000000001);
+    }
+}
@@ -0,0 +1,263 @@
+use std::{fs, io, path::Path};
+use ndarray::{Array2, s};
+use ndarray_npy::WriteNpyExt;
+use serde::{Deserialize, Serialize};
+use crate::lbm_scalar::{ScalarCollidedField, ScalarCollidingWeight, ScalarInputField, ScalarStreamedField, ScalarStreamingWeight};
+use crate::lattice::{Lattice, D2Q5};
+use crate::model::{read_npy, Forward};
+
+// 温度や湿度を風(Model::forward()の結果)に乗せて流すモデル 層の重ね方はModelと同じで、marginはstreamするたびに1周ずつ増える
+// k層目のcollideではModelのk層目のStreamedFieldの風を使う 風のmarginはこのモデルのmargin以下であること
+// 浮力で温度を風に返すときはModel::forward_with_scalar()で一緒に流す
+// phiについて線形なので単位は何でもよいが、w0が効くように平均を引いてから入れるとよい
+// ライブラリのAPIだけで、設定ファイル(config.rs)やCLIからは使わない 温度と湿度はrepo::get_scalar_data_from_dir()で読む
+
+const META_FILENAME: &str = "model_scalar.toml";
+
+#[derive(Serialize, Deserialize)]
+struct MetaScalar {
+    row: usize,
+    col: usize,
+    margin: usize,
+    layers: usize,
+}
+
+#[derive(Clone)]
+pub struct ScalarModel {
+    row: usize,
+    col: usize,
+    margin: usize,
+    layers: usize,
+    streaming_weights: Vec<ScalarStreamingWeight>,
+    colliding_weights: Vec<ScalarCollidingWeight>,
+}
+
+// forward()の途中の場 backpropで使う
+pub struct ScalarForward {
+    input_field: ScalarInputField,
+    streamed_fields: Vec<ScalarStreamedField>,
+    collided_fields: Vec<ScalarCollidedField>,
+}
+
+impl ScalarModel {
+    pub fn new(row: usize, col: usize, margin: usize, layers: usize) -> ScalarModel {
+        if margin == 0 || layers == 0 || 2 * (margin + layers - 1) >= row.min(col) {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let streaming_weights = (0..layers).map(|k| ScalarStreamingWeight::new(row, col, margin + k)).collect();
+        let colliding_weights = (0..layers-1).map(|k| ScalarCollidingWeight::new(row, col, margin + k)).collect();
+        ScalarModel { row, col, margin, layers, streaming_weights, colliding_weights }
+    }
+
+    pub fn row(&self) -> usize { self.row }
+    pub fn col(&self) -> usize { self.col }
+    pub fn margin(&self) -> usize { self.margin }
+    pub fn layers(&self) -> usize { self.layers }
+    pub fn streaming_weights(&self) -> &[ScalarStreamingWeight] { &self.streaming_weights }
+    pub fn colliding_weights(&self) -> &[ScalarCollidingWeight] { &self.colliding_weights }
+    pub(crate) fn streaming_weights_mut(&mut self) -> &mut [ScalarStreamingWeight] { &mut self.streaming_weights }
+    pub(crate) fn colliding_weights_mut(&mut self) -> &mut [ScalarCollidingWeight] { &mut self.colliding_weights }
+
+    // 全層の拡散係数(格子単位)をそろえる 学習で層やセルごとに変わる
+    pub fn set_diffusivity(&mut self, diffusivity: f64) {
+        self.colliding_weights.iter_mut().for_each(|w| w.set_diffusivity(diffusivity));
+    }
+
+    // 出力のScalarStreamedFieldのmargin
+    pub fn output_margin(&self) -> usize {
+        self.margin + self.layers - 1
+    }
+
+    // windは同じ入力から流した風 層の数がこのモデル以上であること
+    pub fn forward(&self, phi: Array2<f64>, wind: &Forward) -> ScalarForward {
+        if wind.streamed_fields().len() < self.layers {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let (row, col) = (self.row, self.col);
+        let mut input_field = ScalarInputField::new(row, col);
+        input_field.set_phi(phi, wind.input_field().u_vert(), wind.input_field().u_hori());
+        let mut streamed_fields = Vec::with_capacity(self.layers);
+        let mut collided_fields = Vec::with_capacity(self.layers - 1);
+
+        let mut streamed_field = ScalarStreamedField::new(row, col, self.margin);
+        streamed_field.stream_from_input_field(&input_field, &self.streaming_weights[0]);
+        streamed_fields.push(streamed_field);
+        for k in 1..self.layers {
+            let mut collided_field = ScalarCollidedField::new(row, col, self.margin + k - 1);
+            collided_field.collide(&streamed_fields[k-1], &self.colliding_weights[k-1], &wind.streamed_fields()[k-1]);
+            let mut streamed_field = ScalarStreamedField::new(row, col, self.margin + k);
+            streamed_field.stream_from_collided_field(&collided_field, &self.streaming_weights[k]);
+            collided_fields.push(collided_field);
+            streamed_fields.push(streamed_field);
+        }
+
+        ScalarForward { input_field, streamed_fields, collided_fields }
+    }
+
+    // 全層の重みの変化分を計算してから、まとめて更新する 戻り値は更新前の損失 windはforward()に渡したもの
+    pub fn train_step(&mut self, eta: f64, forward: &ScalarForward, wind: &Forward, phi_ans: &Array2<f64>) -> f64 {
+        let last = self.layers - 1;
+        if last == 0 {
+            self.streaming_weights[0].propagate_from_output_with_input_field(eta, &forward.streamed_fields[0], &forward.input_field, phi_ans);
+        } else {
+            self.streaming_weights[last].propagate_from_output(eta, &forward.streamed_fields[last], &forward.collided_fields[last-1], phi_ans);
+        }
+        for k in (0..last).rev() {
+            let velocity = &wind.streamed_fields()[k];
+            let (streaming_weights_now, streaming_weights_next) = self.streaming_weights.split_at_mut(k + 1);
+            self.colliding_weights[k].propagate_from_streaming_weight(eta, &forward.streamed_fields[k], &streaming_weights_next[0], velocity);
+            if k == 0 {
+                streaming_weights_now[k].propagate_from_colliding_weight_with_input_field(eta, &forward.streamed_fields[k], &forward.input_field, &self.colliding_weights[k], velocity);
+            } else {
+                streaming_weights_now[k].propagate_from_colliding_weight(eta, &forward.streamed_fields[k], &forward.collided_fields[k-1], &self.colliding_weights[k], velocity);
+            }
+        }
+
+        self.streaming_weights.iter_mut().for_each(|w| w.update());
+        self.colliding_weights.iter_mut().for_each(|w| w.update());
+        scalar_loss(forward.output(), phi_ans)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let meta = MetaScalar { row: self.row, col: self.col, margin: self.margin, layers: self.layers };
+        fs::write(dir.join(META_FILENAME), toml::to_string(&meta).map_err(io::Error::other)?)?;
+        for (k, w) in self.streaming_weights.iter().enumerate() {
+            w.w0().to_owned().write_npy(fs::File::create(dir.join(format!("streaming_{}_w0.npy", k)))?).map_err(io::Error::other)?;
+            w.w1().to_owned().write_npy(fs::File::create(dir.join(format!("streaming_{}_w1.npy", k)))?).map_err(io::Error::other)?;
+        }
+        for (k, w) in self.colliding_weights.iter().enumerate() {
+            w.w1().to_owned().write_npy(fs::File::create(dir.join(format!("colliding_{}_w1.npy", k)))?).map_err(io::Error::other)?;
+            w.rate().to_owned().write_npy(fs::File::create(dir.join(format!("colliding_{}_rate.npy", k)))?).map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<ScalarModel> {
+        let dir = dir.as_ref();
+        let meta: MetaScalar = toml::from_str(&fs::read_to_string(dir.join(META_FILENAME))?).map_err(io::Error::other)?;
+        let mut model = ScalarModel::new(meta.row, meta.col, meta.margin, meta.layers);
+        let (row, col, q) = (meta.row, meta.col, D2Q5.q());
+        for (k, w) in model.streaming_weights.iter_mut().enumerate() {
+            w.set(read_npy(dir, &format!("streaming_{}_w0.npy", k), &[row, col, q])?, read_npy(dir, &format!("streaming_{}_w1.npy", k), &[row, col, q])?);
+        }
+        for (k, w) in model.colliding_weights.iter_mut().enumerate() {
+            w.set(read_npy(dir, &format!("colliding_{}_w1.npy", k), &[row, col, q])?, read_npy(dir, &format!("colliding_{}_rate.npy", k), &[row, col])?);
+        }
+        Ok(model)
+    }
+}
+
+impl ScalarForward {
+    // Model::forward_with_scalar()で作る用
+    pub(crate) fn new(input_field: ScalarInputField, streamed_fields: Vec<ScalarStreamedField>, collided_fields: Vec<ScalarCollidedField>) -> ScalarForward {
+        ScalarForward { input_field, streamed_fields, collided_fields }
+    }
+
+    pub fn output(&self) -> &ScalarStreamedField {
+        self.streamed_fields.last().unwrap()
+    }
+
+    pub fn streamed_fields(&self) -> &[ScalarStreamedField] {
+        &self.streamed_fields
+    }
+
+    pub fn collided_fields(&self) -> &[ScalarCollidedField] {
+        &self.collided_fields
+    }
+}
+
+// 損失 1/2 * Σ(phi - phi_ans)^2 marginの内側だけ足す
+pub fn scalar_loss(output: &ScalarStreamedField, phi_ans: &Array2<f64>) -> f64 {
+    let (margin, row, col) = (output.margin(), output.row(), output.col());
+    let interior = s![margin..row-margin, margin..col-margin];
+    (&output.phi().slice(interior) - &phi_ans.slice(interior)).mapv(|d| d * d).sum() / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use super::*;
+    use crate::model::Model;
+
+    fn field(row: usize, col: usize, f: impl Fn(usize, usize) -> f64) -> Array2<f64> {
+        Array2::from_shape_fn((row, col), |(r, c)| f(r, c))
+    }
+
+    fn wind(row: usize, col: usize, layers: usize) -> Forward {
+        let model = Model::new(row, col, 1, layers);
+        model.forward(field(row, col, |r, c| 0.03 * ((r + 2 * c) as f64).cos()), field(row, col, |r, c| 0.05 * ((r * c) as f64).sin()), field(row, col, |_, _| 1.0))
+    }
+
+    #[test]
+    fn test_scalar_model_train_step() {
+        let (row, col) = (8, 9);
+        let wind = wind(row, col, 3);
+        let mut model = ScalarModel::new(row, col, 1, 3);
+        model.set_diffusivity(0.05);
+        let phi = field(row, col, |r, c| ((r + c) as f64 * 0.7).sin());
+        let phi_ans = field(row, col, |r, c| ((r + c + 1) as f64 * 0.7).sin());
+        let mut losses = Vec::new();
+        for _ in 0..5 {
+            let forward = model.forward(phi.clone(), &wind);
+            losses.push(model.train_step(0.05, &forward, &wind, &phi_ans));
+        }
+        for k in 1..losses.len() {
+            assert!(losses[k] < losses[k-1], "{:?}", losses);
+        }
+    }
+
+    // train_step()の重みの変化分 -dL/dw を中心差分と比べる
+    #[test]
+    fn test_scalar_model_gradient() {
+        use crate::gradient_check::{check_scalar_gradient, ScalarParameter};
+        let (row, col) = (8, 9);
+        let wind = wind(row, col, 3);
+        let phi = field(row, col, |r, c| 1.0 + 0.3 * ((r * c) as f64).cos());
+        let phi_ans = field(row, col, |r, _| 0.1 * r as f64);
+        let mut model = ScalarModel::new(row, col, 1, 3);
+        model.set_diffusivity(0.1);
+
+        let mut parameters: Vec<ScalarParameter> = [(0, [3, 3, 1]), (1, [4, 5, 3]), (2, [4, 4, 2]), (0, [2, 6, 4])].into_iter()
+            .map(|(layer, index)| ScalarParameter::StreamingW0 { layer, index }).collect();
+        for (layer, index) in [(0, [3, 3, 0]), (1, [4, 5, 3])] {
+            parameters.push(ScalarParameter::CollidingW1 { layer, index });
+            parameters.push(ScalarParameter::Rate { layer, cell: [index[0], index[1]] });
+        }
+        for check in check_scalar_gradient(&model, |model| model.forward(phi.clone(), &wind), &wind, &phi_ans, &parameters, 0.000001) {
+            assert!(check.relative_error < 0.0001, "{}", check);
+        }
+    }
+
+    #[test]
+    fn test_scalar_model_save_and_load() {
+        let dir = env::temp_dir().join("lbm_rust_test_scalar_model_save_and_load");
+        let (row, col) = (7, 7);
+        let wind = wind(row, col, 2);
+        let mut model = ScalarModel::new(row, col, 1, 2);
+        let forward = model.forward(field(row, col, |r, _| r as f64), &wind);
+        model.train_step(0.1, &forward, &wind, &field(row, col, |_, c| c as f64));
+        model.save(&dir).unwrap();
+        let loaded = ScalarModel::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(2, loaded.layers());
+        assert_eq!(model.streaming_weights()[1].w0()[[3, 3, 4]], loaded.streaming_weights()[1].w0()[[3, 3, 4]]);
+        assert_eq!(model.colliding_weights()[0].w1()[[2, 4, 1]], loaded.colliding_weights()[0].w1()[[2, 4, 1]]);
+        assert_eq!(model.colliding_weights()[0].rate()[[2, 4]], loaded.colliding_weights()[0].rate()[[2, 4]]);
+    }
+
+    // 壊れたファイルや形の違うファイルはpanicせずにErr
+    #[test]
+    fn test_scalar_model_load_shape_mismatch() {
+        let dir = env::temp_dir().join("lbm_rust_test_scalar_model_load_shape_mismatch");
+        let model = ScalarModel::new(7, 7, 1, 2);
+        model.save(&dir).unwrap();
+        Array2::<f64>::zeros((7, 6)).write_npy(fs::File::create(dir.join("colliding_0_rate.npy")).unwrap()).unwrap();
+        let result = ScalarModel::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let message = result.err().unwrap().to_string();
+        assert!(message.contains("colliding_0_rate.npy"), "{}", message);
+    }
+}
@@ -0,0 +1,520 @@
+use std::{fmt, fs, io, path::Path};
+use ndarray::{ArrayD, IxDyn};
+
+// NetCDF-3 classic(CDF-1)と64bit offset(CDF-2)形式の読み込み
+// libnetcdfを使わず、ヘッダを自前でパースしてbig endianのデータを読む
+// https://docs.unidata.ucar.edu/netcdf-c/current/file_format_specifications.html
+
+const NC_DIMENSION: u32 = 0x0A;
+const NC_VARIABLE: u32 = 0x0B;
+const NC_ATTRIBUTE: u32 = 0x0C;
+const STREAMING: u32 = 0xFFFFFFFF;
+
+#[derive(Debug)]
+pub enum NetCdfError {
+    Io(io::Error),
+    Format(String),
+    VariableNotFound(String),
+}
+
+impl fmt::Display for NetCdfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetCdfError::Io(e) => write!(f, "io error: {}", e),
+            NetCdfError::Format(msg) => write!(f, "invalid netcdf file: {}", msg),
+            NetCdfError::VariableNotFound(name) => write!(f, "variable not found: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for NetCdfError {}
+
+impl From<io::Error> for NetCdfError {
+    fn from(e: io::Error) -> NetCdfError {
+        NetCdfError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NcType {
+    Byte,
+    Char,
+    Short,
+    Int,
+    Float,
+    Double,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    Text(String),
+    Numbers(Vec<f64>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Dimension {
+    pub name: String,
+    pub len: usize, // record次元のときはnumrecs
+    pub is_record: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Attribute {
+    pub name: String,
+    pub value: AttributeValue,
+}
+
+#[derive(Clone, Debug)]
+pub struct Variable {
+    pub name: String,
+    pub dim_ids: Vec<usize>,
+    pub attributes: Vec<Attribute>,
+    pub nc_type: NcType,
+    vsize: usize,
+    begin: usize,
+}
+
+pub struct NetCdfFile {
+    dimensions: Vec<Dimension>,
+    attributes: Vec<Attribute>,
+    variables: Vec<Variable>,
+    numrecs: usize,
+    record_size: usize,
+    bytes: Vec<u8>,
+}
+
+impl NcType {
+    fn from_code(code: u32) -> Result<NcType, NetCdfError> {
+        match code {
+            1 => Ok(NcType::Byte),
+            2 => Ok(NcType::Char),
+            3 => Ok(NcType::Short),
+            4 => Ok(NcType::Int),
+            5 => Ok(NcType::Float),
+            6 => Ok(NcType::Double),
+            _ => Err(NetCdfError::Format(format!("unknown nc_type {}", code))),
+        }
+    }
+
+    // _FillValueがないときの既定の埋め値(netcdf.hのNC_FILL_*) charは数値として扱わないのでNone
+    fn default_fill(&self) -> Option<f64> {
+        match self {
+            NcType::Byte => Some(-127.0),
+            NcType::Char => None,
+            NcType::Short => Some(-32767.0),
+            NcType::Int => Some(-2147483647.0),
+            NcType::Float => Some(9.9692099683868690e36_f32 as f64),
+            NcType::Double => Some(9.9692099683868690e36),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            NcType::Byte | NcType::Char => 1,
+            NcType::Short => 2,
+            NcType::Int | NcType::Float => 4,
+            NcType::Double => 8,
+        }
+    }
+
+    fn read(&self, bytes: &[u8]) -> f64 {
+        match self {
+            NcType::Byte => bytes[0] as i8 as f64,
+            NcType::Char => bytes[0] as f64,
+            NcType::Short => i16::from_be_bytes([bytes[0], bytes[1]]) as f64,
+            NcType::Int => i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            NcType::Float => f32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            NcType::Double => f64::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+}
+
+impl Variable {
+    pub fn attribute(&self, name: &str) -> Option<&AttributeValue> {
+        self.attributes.iter().find(|a| a.name == name).map(|a| &a.value)
+    }
+
+    fn number_attribute(&self, name: &str) -> Option<f64> {
+        self.number_attributes(name).first().copied()
+    }
+
+    fn number_attributes(&self, name: &str) -> &[f64] {
+        match self.attribute(name) {
+            Some(AttributeValue::Numbers(values)) => values,
+            _ => &[],
+        }
+    }
+}
+
+impl NetCdfFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<NetCdfFile, NetCdfError> {
+        NetCdfFile::from_bytes(fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<NetCdfFile, NetCdfError> {
+        let mut header = Header { bytes: &bytes, pos: 0, offset_size: 4 };
+        let magic = header.take(4)?;
+        if &magic[0..3] != b"CDF" {
+            return Err(NetCdfError::Format("magic number is not CDF".to_string()));
+        }
+        header.offset_size = match magic[3] {
+            1 => 4,
+            2 => 8,
+            v => return Err(NetCdfError::Format(format!("unsupported version {}", v))),
+        };
+        let numrecs = header.u32()?;
+
+        let mut dimensions = Vec::new();
+        for _ in 0..header.list_len(NC_DIMENSION)? {
+            let name = header.name()?;
+            let len = header.u32()? as usize;
+            dimensions.push(Dimension { name, len, is_record: len == 0 });
+        }
+        let attributes = header.attributes()?;
+        let mut variables = Vec::new();
+        for _ in 0..header.list_len(NC_VARIABLE)? {
+            let name = header.name()?;
+            let mut dim_ids = Vec::new();
+            for _ in 0..header.u32()? {
+                let dim_id = header.u32()? as usize;
+                if dim_id >= dimensions.len() {
+                    return Err(NetCdfError::Format(format!("variable {} refers to unknown dimension {}", name, dim_id)));
+                }
+                dim_ids.push(dim_id);
+            }
+            let attributes = header.attributes()?;
+            let nc_type = NcType::from_code(header.u32()?)?;
+            let vsize = header.u32()? as usize;
+            let begin = header.offset()?;
+            variables.push(Variable { name, dim_ids, attributes, nc_type, vsize, begin });
+        }
+
+        // numrecsがSTREAMINGのときはファイルサイズから数える
+        let is_record = |v: &Variable| v.dim_ids.first().map(|d| dimensions[*d].is_record).unwrap_or(false);
+        let record_vars: Vec<&Variable> = variables.iter().filter(|v| is_record(v)).collect();
+        // record変数が1つだけのときはパディングなしで詰めて置かれる
+        let record_size = match record_vars.as_slice() {
+            [var] => NetCdfFile::element_count(&dimensions, var, 1) * var.nc_type.size(),
+            vars => vars.iter().map(|v| v.vsize).sum(),
+        };
+        let numrecs = if numrecs == STREAMING {
+            match record_vars.iter().map(|v| v.begin).min() {
+                Some(begin) if record_size > 0 => (bytes.len().saturating_sub(begin)) / record_size,
+                _ => 0,
+            }
+        } else {
+            numrecs as usize
+        };
+        for dim in dimensions.iter_mut().filter(|d| d.is_record) {
+            dim.len = numrecs;
+        }
+
+        Ok(NetCdfFile { dimensions, attributes, variables, numrecs, record_size, bytes })
+    }
+
+    pub fn dimensions(&self) -> &[Dimension] {
+        &self.dimensions
+    }
+
+    pub fn attributes(&self) -> &[Attribute] {
+        &self.attributes
+    }
+
+    pub fn variables(&self) -> &[Variable] {
+        &self.variables
+    }
+
+    pub fn variable(&self, name: &str) -> Result<&Variable, NetCdfError> {
+        self.variables.iter().find(|v| v.name == name).ok_or_else(|| NetCdfError::VariableNotFound(name.to_string()))
+    }
+
+    // 変数の次元名
+    pub fn dimension_names(&self, name: &str) -> Result<Vec<String>, NetCdfError> {
+        let var = self.variable(name)?;
+        Ok(var.dim_ids.iter().map(|d| self.dimensions[*d].name.clone()).collect())
+    }
+
+    // 生の値をf64で読む scale_factor, add_offsetは適用しない
+    pub fn read_raw(&self, name: &str) -> Result<ArrayD<f64>, NetCdfError> {
+        let var = self.variable(name)?;
+        let shape: Vec<usize> = var.dim_ids.iter().map(|d| self.dimensions[*d].len).collect();
+        let is_record = var.dim_ids.first().map(|d| self.dimensions[*d].is_record).unwrap_or(false);
+        let type_size = var.nc_type.size();
+        let (records, per_record) = if is_record {
+            (self.numrecs, NetCdfFile::element_count(&self.dimensions, var, 1))
+        } else {
+            (1, NetCdfFile::element_count(&self.dimensions, var, 0))
+        };
+
+        let mut values = Vec::with_capacity(records * per_record);
+        for rec in 0..records {
+            let start = var.begin + rec * self.record_size;
+            let end = start + per_record * type_size;
+            if end > self.bytes.len() {
+                return Err(NetCdfError::Format(format!("data of variable {} is truncated", name)));
+            }
+            values.extend(self.bytes[start..end].chunks_exact(type_size).map(|b| var.nc_type.read(b)));
+        }
+        ArrayD::from_shape_vec(IxDyn(&shape), values).map_err(|e| NetCdfError::Format(e.to_string()))
+    }
+
+    // _FillValue(ないときは型ごとの既定値)とmissing_value(複数あればすべて)をNaNにし、scale_factor, add_offsetを適用した値
+    pub fn read(&self, name: &str) -> Result<ArrayD<f64>, NetCdfError> {
+        let var = self.variable(name)?;
+        let fill_value = var.number_attribute("_FillValue").or_else(|| var.nc_type.default_fill());
+        let missing_values = var.number_attributes("missing_value");
+        let scale_factor = var.number_attribute("scale_factor").unwrap_or(1.0);
+        let add_offset = var.number_attribute("add_offset").unwrap_or(0.0);
+        let mut arr = self.read_raw(name)?;
+        arr.mapv_inplace(|x| {
+            if Some(x) == fill_value || missing_values.contains(&x) {
+                f64::NAN
+            } else {
+                x * scale_factor + add_offset
+            }
+        });
+        Ok(arr)
+    }
+
+    fn element_count(dimensions: &[Dimension], var: &Variable, skip: usize) -> usize {
+        var.dim_ids.iter().skip(skip).map(|d| dimensions[*d].len).product()
+    }
+}
+
+struct Header<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    offset_size: usize,
+}
+
+impl<'a> Header<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], NetCdfError> {
+        if self.pos + n > self.bytes.len() {
+            return Err(NetCdfError::Format("header is truncated".to_string()));
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> Result<u32, NetCdfError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn offset(&mut self) -> Result<usize, NetCdfError> {
+        if self.offset_size == 4 {
+            return Ok(self.u32()? as usize);
+        }
+        let b = self.take(8)?;
+        Ok(u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as usize)
+    }
+
+    // 4バイト境界までのパディングを読み飛ばす
+    fn padded(&mut self, n: usize) -> Result<&'a [u8], NetCdfError> {
+        let slice = self.take(n)?;
+        self.take((4 - n % 4) % 4)?;
+        Ok(slice)
+    }
+
+    fn name(&mut self) -> Result<String, NetCdfError> {
+        let len = self.u32()? as usize;
+        let bytes = self.padded(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| NetCdfError::Format(e.to_string()))
+    }
+
+    // ABSENTは(0, 0)で表される
+    fn list_len(&mut self, tag: u32) -> Result<u32, NetCdfError> {
+        let found = self.u32()?;
+        let len = self.u32()?;
+        if found != tag && !(found == 0 && len == 0) {
+            return Err(NetCdfError::Format(format!("expected tag {:#x}, found {:#x}", tag, found)));
+        }
+        Ok(len)
+    }
+
+    fn attributes(&mut self) -> Result<Vec<Attribute>, NetCdfError> {
+        let mut attributes = Vec::new();
+        for _ in 0..self.list_len(NC_ATTRIBUTE)? {
+            let name = self.name()?;
+            let nc_type = NcType::from_code(self.u32()?)?;
+            let nelems = self.u32()? as usize;
+            let bytes = self.padded(nelems * nc_type.size())?;
+            let value = match nc_type {
+                NcType::Char => AttributeValue::Text(String::from_utf8_lossy(bytes).trim_end_matches('\0').to_string()),
+                _ => AttributeValue::Numbers(bytes.chunks_exact(nc_type.size()).map(|b| nc_type.read(b)).collect()),
+            };
+            attributes.push(Attribute { name, value });
+        }
+        Ok(attributes)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn push_u32(b: &mut Vec<u8>, x: u32) { b.extend_from_slice(&x.to_be_bytes()); }
+    fn push_name(b: &mut Vec<u8>, name: &str) {
+        push_u32(b, name.len() as u32);
+        b.extend_from_slice(name.as_bytes());
+        while !b.len().is_multiple_of(4) { b.push(0); }
+    }
+    fn push_text_att(b: &mut Vec<u8>, name: &str, text: &str) {
+        push_name(b, name);
+        push_u32(b, 2);
+        push_name(b, text);
+    }
+    fn push_short_att(b: &mut Vec<u8>, name: &str, x: i16) {
+        push_name(b, name);
+        push_u32(b, 3);
+        push_u32(b, 1);
+        b.extend_from_slice(&x.to_be_bytes());
+        b.extend_from_slice(&[0, 0]);
+    }
+    fn push_double_att(b: &mut Vec<u8>, name: &str, x: f64) {
+        push_name(b, name);
+        push_u32(b, 6);
+        push_u32(b, 1);
+        b.extend_from_slice(&x.to_be_bytes());
+    }
+
+    // テスト用にCDF-1のファイルを組み立てる
+    // time(record) x lat(2) x lon(3)のshort変数u10(scale_factor=0.5, add_offset=1, _FillValue=-1)と
+    // 座標変数time(hours since 2020-03-20 00:00:00), lat, lon(double)
+    pub(crate) fn sample_bytes() -> Vec<u8> {
+        // beginは後で埋めるので位置を覚えておく
+        let mut b = Vec::new();
+        b.extend_from_slice(b"CDF\x01");
+        push_u32(&mut b, 2);
+        push_u32(&mut b, NC_DIMENSION);
+        push_u32(&mut b, 3);
+        push_name(&mut b, "time"); push_u32(&mut b, 0);
+        push_name(&mut b, "lat"); push_u32(&mut b, 2);
+        push_name(&mut b, "lon"); push_u32(&mut b, 3);
+        push_u32(&mut b, 0); push_u32(&mut b, 0);
+        push_u32(&mut b, NC_VARIABLE);
+        push_u32(&mut b, 4);
+        let mut begins = Vec::new();
+        // lat
+        push_name(&mut b, "lat"); push_u32(&mut b, 1); push_u32(&mut b, 1);
+        push_u32(&mut b, 0); push_u32(&mut b, 0);
+        push_u32(&mut b, 6); push_u32(&mut b, 16); begins.push(b.len()); push_u32(&mut b, 0);
+        // lon
+        push_name(&mut b, "lon"); push_u32(&mut b, 1); push_u32(&mut b, 2);
+        push_u32(&mut b, 0); push_u32(&mut b, 0);
+        push_u32(&mut b, 6); push_u32(&mut b, 24); begins.push(b.len()); push_u32(&mut b, 0);
+        // time
+        push_name(&mut b, "time"); push_u32(&mut b, 1); push_u32(&mut b, 0);
+        push_u32(&mut b, NC_ATTRIBUTE); push_u32(&mut b, 1);
+        push_text_att(&mut b, "units", "hours since 2020-03-20 00:00:00");
+        push_u32(&mut b, 4); push_u32(&mut b, 4); begins.push(b.len()); push_u32(&mut b, 0);
+        // u10
+        push_name(&mut b, "u10"); push_u32(&mut b, 3); push_u32(&mut b, 0); push_u32(&mut b, 1); push_u32(&mut b, 2);
+        push_u32(&mut b, NC_ATTRIBUTE); push_u32(&mut b, 3);
+        push_double_att(&mut b, "scale_factor", 0.5);
+        push_double_att(&mut b, "add_offset", 1.0);
+        push_short_att(&mut b, "_FillValue", -1);
+        push_u32(&mut b, 3); push_u32(&mut b, 12); begins.push(b.len()); push_u32(&mut b, 0);
+
+        let set_begin = |b: &mut Vec<u8>, i: usize| {
+            let pos = begins[i];
+            let begin = (b.len() as u32).to_be_bytes();
+            b[pos..pos + 4].copy_from_slice(&begin);
+        };
+        set_begin(&mut b, 0);
+        for x in [35.0_f64, 34.95] { b.extend_from_slice(&x.to_be_bytes()); }
+        set_begin(&mut b, 1);
+        for x in [135.0_f64, 135.0625, 135.125] { b.extend_from_slice(&x.to_be_bytes()); }
+        // record部分 1レコード = time(4バイト) + u10(12バイト)
+        set_begin(&mut b, 2);
+        let time_begin = b.len();
+        let u10_begin = time_begin + 4;
+        let pos = begins[3];
+        b[pos..pos + 4].copy_from_slice(&(u10_begin as u32).to_be_bytes());
+        for (hour, u10) in [(3_i32, [0_i16, 2, 4, 6, -1, 10]), (6, [1, 3, 5, 7, 9, 11])] {
+            b.extend_from_slice(&hour.to_be_bytes());
+            for x in u10 { b.extend_from_slice(&x.to_be_bytes()); }
+        }
+        b
+    }
+
+    // time(record) x level(2) x lat(2) x lon(3)のdouble変数uと座標変数level(1000, 850hPa), lat, lon, time
+    // uの値は100 * 時刻 + 10 * level + lat * 3 + lon(添字)
+    pub(crate) fn sample_level_bytes() -> Vec<u8> {
+        let mut b = Vec::new();
+        b.extend_from_slice(b"CDF\x01");
+        push_u32(&mut b, 2);
+        push_u32(&mut b, NC_DIMENSION);
+        push_u32(&mut b, 4);
+        push_name(&mut b, "time"); push_u32(&mut b, 0);
+        push_name(&mut b, "level"); push_u32(&mut b, 2);
+        push_name(&mut b, "lat"); push_u32(&mut b, 2);
+        push_name(&mut b, "lon"); push_u32(&mut b, 3);
+        push_u32(&mut b, 0); push_u32(&mut b, 0);
+        push_u32(&mut b, NC_VARIABLE);
+        push_u32(&mut b, 5);
+        let mut begins = Vec::new();
+        for (name, dim, len) in [("level", 1, 2), ("lat", 2, 2), ("lon", 3, 3)] {
+            push_name(&mut b, name); push_u32(&mut b, 1); push_u32(&mut b, dim);
+            push_u32(&mut b, 0); push_u32(&mut b, 0);
+            push_u32(&mut b, 6); push_u32(&mut b, 8 * len); begins.push(b.len()); push_u32(&mut b, 0);
+        }
+        push_name(&mut b, "time"); push_u32(&mut b, 1); push_u32(&mut b, 0);
+        push_u32(&mut b, NC_ATTRIBUTE); push_u32(&mut b, 1);
+        push_text_att(&mut b, "units", "hours since 2020-03-20 00:00:00");
+        push_u32(&mut b, 4); push_u32(&mut b, 4); begins.push(b.len()); push_u32(&mut b, 0);
+        push_name(&mut b, "u"); push_u32(&mut b, 4); push_u32(&mut b, 0); push_u32(&mut b, 1); push_u32(&mut b, 2); push_u32(&mut b, 3);
+        push_u32(&mut b, 0); push_u32(&mut b, 0);
+        push_u32(&mut b, 6); push_u32(&mut b, 96); begins.push(b.len()); push_u32(&mut b, 0);
+
+        let set_begin = |b: &mut Vec<u8>, i: usize, offset: usize| {
+            let (pos, begin) = (begins[i], (b.len() + offset) as u32);
+            b[pos..pos + 4].copy_from_slice(&begin.to_be_bytes());
+        };
+        for (i, values) in [vec![1000.0_f64, 850.0], vec![35.0, 34.95], vec![135.0, 135.0625, 135.125]].iter().enumerate() {
+            set_begin(&mut b, i, 0);
+            for x in values { b.extend_from_slice(&x.to_be_bytes()); }
+        }
+        // record部分 1レコード = time(4バイト) + u(96バイト)
+        set_begin(&mut b, 3, 0);
+        set_begin(&mut b, 4, 4);
+        for hour in [3_i32, 6] {
+            b.extend_from_slice(&hour.to_be_bytes());
+            for k in 0..12 {
+                let x = 100.0 * hour as f64 + 10.0 * (k / 6) as f64 + (k % 6) as f64;
+                b.extend_from_slice(&x.to_be_bytes());
+            }
+        }
+        b
+    }
+
+    #[test]
+    fn test_netcdf_file_read() {
+        let file = NetCdfFile::from_bytes(sample_bytes()).unwrap();
+        assert_eq!(2, file.dimensions()[0].len);
+        assert_eq!(vec!["time", "lat", "lon"], file.dimension_names("u10").unwrap());
+        assert_eq!(vec![3.0, 6.0], file.read("time").unwrap().into_raw_vec());
+        assert_eq!(vec![135.0, 135.0625, 135.125], file.read("lon").unwrap().into_raw_vec());
+
+        let u10 = file.read("u10").unwrap();
+        assert_eq!(&[2, 2, 3], u10.shape());
+        assert_eq!(1.0, u10[[0, 0, 0]]);
+        assert_eq!(4.0, u10[[0, 1, 0]]);
+        assert!(u10[[0, 1, 1]].is_nan());
+        assert_eq!(6.5, u10[[1, 1, 2]]);
+        assert_eq!(-1.0, file.read_raw("u10").unwrap()[[0, 1, 1]]);
+        assert!(file.read("v10").is_err());
+    }
+
+    // _FillValueのない変数では、型ごとの既定の埋め値をNaNにする
+    #[test]
+    fn test_netcdf_file_read_default_fill() {
+        let mut b = sample_level_bytes();
+        let len = b.len();
+        b[len - 8..].copy_from_slice(&9.9692099683868690e36_f64.to_be_bytes());
+        let u = NetCdfFile::from_bytes(b).unwrap().read("u").unwrap();
+        assert!(u[[1, 1, 1, 2]].is_nan());
+        assert_eq!(614.0, u[[1, 1, 1, 1]]);
+    }
+}
@@ -1,64 +1,604 @@
-use std::{collections::HashMap, fs::File};
-use chrono::{DateTime, Utc};
-use ndarray::{Array2, Array};
+use std::{collections::HashMap, fs::File, io, path::Path};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use ndarray::{Array1, Array2, Array3, Array, ArrayD, Axis, Ix1, Ix2, s, stack};
 use ndarray_npy::ReadNpyExt;
 use std::env;
 use dotenv::dotenv;
+use crate::geo::GeoGrid;
+use crate::netcdf::{NetCdfError, NetCdfFile};
 
-#[derive(Hash, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub enum MeteorologicalType {
     UVert,
     UHori,
     Pressure,
+    Omega, // 鉛直p速度[Pa/s] 上昇流が負
+    Geopotential, // [m^2/s^2]
+    Temperature, // 地上気温[K]
+    Humidity, // 地上の相対湿度[%]
+}
+
+// 切り出す範囲 Indexは[row_start, row_end) x [col_start, col_end)、LatLonは両端を含む
+pub enum GridRange {
+    Index { row_start: usize, row_end: usize, col_start: usize, col_end: usize },
+    LatLon { lat_min: f64, lat_max: f64, lon_min: f64, lon_max: f64 },
+}
+
+// Decimationはstrideごとに間引き、BlockAverageはstride x strideのブロック平均(端数は捨てる)
+pub enum Coarsening {
+    None,
+    Decimation(usize),
+    BlockAverage(usize),
+}
+
+pub struct Region {
+    range: Option<GridRange>,
+    coarsening: Coarsening,
+}
+
+// NetCDFから読む変数名 uは東向き正、vは北向き正の風速
+// levelを指定すると、4次元(time, level, lat, lon)の変数からlevelの座標値が一致する面を取り出す
+pub struct NetCdfVariables {
+    pub u: String,
+    pub v: String,
+    pub pressure: String,
+    pub level: Option<f64>,
+}
+
+// 複数の気圧面をまとめて読むときの変数名 levelsは下から上(気圧の大きい順)に並べる[hPa]
+// wは鉛直p速度(omega)、geopotentialはジオポテンシャル
+pub struct NetCdfLevelVariables {
+    pub u: String,
+    pub v: String,
+    pub w: String,
+    pub geopotential: String,
+    pub levels: Vec<f64>,
+}
+
+// 複数の気圧面のデータ 配列は(level, row, col)で、levelはNetCdfLevelVariables::levelsの順
+pub struct MultiLevelData {
+    grid: GeoGrid,
+    levels: Vec<f64>,
+    data: HashMap<(DateTime<Utc>, MeteorologicalType), Array3<f64>>,
+}
+
+// 読み込んだデータとその格子
+pub struct MeteorologicalData {
+    grid: GeoGrid,
+    data: HashMap<(DateTime<Utc>, MeteorologicalType), Array2<f64>>,
+}
+
+impl GridRange {
+    // [row_start, row_end, col_start, col_end]に変換する 格子外ははみ出さないように丸める
+    fn to_index(&self, grid: &GeoGrid) -> [usize; 4] {
+        let [row_start, row_end, col_start, col_end] = match *self {
+            GridRange::Index { row_start, row_end, col_start, col_end } => [row_start, row_end, col_start, col_end],
+            GridRange::LatLon { lat_min, lat_max, lon_min, lon_max } => {
+                let (row_start, row_end) = grid.row_range(lat_min, lat_max);
+                let (col_start, col_end) = grid.col_range(lon_min, lon_max);
+                [row_start, row_end, col_start, col_end]
+            }
+        };
+        let row_end = row_end.min(grid.row());
+        let col_end = col_end.min(grid.col());
+        if row_start >= row_end || col_start >= col_end {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        [row_start, row_end, col_start, col_end]
+    }
+}
+
+impl Region {
+    pub fn new(range: Option<GridRange>, coarsening: Coarsening) -> Region {
+        if let Coarsening::Decimation(0) | Coarsening::BlockAverage(0) = coarsening {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        Region { range, coarsening }
+    }
+
+    // 元の格子がgridのとき、切り出し後の形 InputField::new(row, col)に渡す用
+    pub fn shape(&self, grid: &GeoGrid) -> (usize, usize) {
+        self.apply_to_grid(grid).shape()
+    }
+
+    // 切り出し後の格子
+    pub fn apply_to_grid(&self, grid: &GeoGrid) -> GeoGrid {
+        let [row_start, row_end, col_start, col_end] = self.index(grid);
+        let cropped = grid.crop(row_start, row_end, col_start, col_end);
+        match self.coarsening {
+            Coarsening::None => cropped,
+            Coarsening::Decimation(stride) => cropped.decimate(stride),
+            Coarsening::BlockAverage(stride) => {
+                if cropped.row() < stride || cropped.col() < stride {
+                    panic!("panicked at line {} in {}", line!(), file!());
+                }
+                cropped.block_average(stride)
+            }
+        }
+    }
+
+    pub fn apply(&self, arr: &Array2<f64>, grid: &GeoGrid) -> Array2<f64> {
+        if [grid.row(), grid.col()] != arr.shape() {
+            panic!("panicked at line {} in {}", line!(), file!());
+        }
+        let [row_start, row_end, col_start, col_end] = self.index(grid);
+        let cropped = arr.slice(s![row_start..row_end, col_start..col_end]);
+        match self.coarsening {
+            Coarsening::None => cropped.to_owned(),
+            Coarsening::Decimation(stride) => cropped.slice(s![..;stride, ..;stride]).to_owned(),
+            Coarsening::BlockAverage(stride) => {
+                let (row, col) = (cropped.nrows() / stride, cropped.ncols() / stride);
+                if row == 0 || col == 0 {
+                    panic!("panicked at line {} in {}", line!(), file!());
+                }
+                Array2::from_shape_fn((row, col), |(r, c)| {
+                    cropped.slice(s![r*stride..(r+1)*stride, c*stride..(c+1)*stride]).mean().unwrap()
+                })
+            }
+        }
+    }
+
+    fn index(&self, grid: &GeoGrid) -> [usize; 4] {
+        match &self.range {
+            Some(range) => range.to_index(grid),
+            None => [0, grid.row(), 0, grid.col()],
+        }
+    }
+}
+
+impl MeteorologicalData {
+    pub fn grid(&self) -> &GeoGrid {
+        &self.grid
+    }
+
+    pub fn get(&self, datetime: DateTime<Utc>, meteorological_type: MeteorologicalType) -> Option<&Array2<f64>> {
+        self.data.get(&(datetime, meteorological_type))
+    }
+
+    // npyのu_vert_はVGRD(北向き正)なので、lbmの(u_vert, u_hori)の向きに直して返す
+    pub fn lattice_velocity(&self, datetime: DateTime<Utc>) -> Option<(Array2<f64>, Array2<f64>)> {
+        let v = self.get(datetime, MeteorologicalType::UVert)?;
+        let u = self.get(datetime, MeteorologicalType::UHori)?;
+        Some(self.grid.to_lattice_velocity(u, v))
+    }
+
+    // 読み込んだ時刻(昇順)
+    pub fn datetimes(&self) -> Vec<DateTime<Utc>> {
+        let mut datetimes: Vec<DateTime<Utc>> = self.data.keys().map(|(datetime, _)| *datetime).collect();
+        datetimes.sort();
+        datetimes.dedup();
+        datetimes
+    }
+
+    pub fn into_map(self) -> HashMap<(DateTime<Utc>, MeteorologicalType), Array2<f64>> {
+        self.data
+    }
+}
+
+impl MultiLevelData {
+    pub fn grid(&self) -> &GeoGrid {
+        &self.grid
+    }
+
+    pub fn levels(&self) -> &[f64] {
+        &self.levels
+    }
+
+    // UVert, UHori, Omega, Geopotential
+    pub fn get(&self, datetime: DateTime<Utc>, meteorological_type: MeteorologicalType) -> Option<&Array3<f64>> {
+        self.data.get(&(datetime, meteorological_type))
+    }
+
+    // lbm3dの(u_level, u_vert, u_hori)の向きに直して返す u_levelは1秒あたりに進む気圧面の数(上向き正)
+    // 気圧面の間隔は平均で近似する(1000/925/850/700hPaなら約100hPa)
+    pub fn lattice_velocity(&self, datetime: DateTime<Utc>) -> Option<(Array3<f64>, Array3<f64>, Array3<f64>)> {
+        let omega = self.get(datetime, MeteorologicalType::Omega)?;
+        let v = self.get(datetime, MeteorologicalType::UVert)?;
+        let u = self.get(datetime, MeteorologicalType::UHori)?;
+        let (mut u_vert, mut u_hori) = (Array3::zeros(v.dim()), Array3::zeros(u.dim()));
+        for l in 0..self.levels.len() {
+            let (u_vert_l, u_hori_l) = self.grid.to_lattice_velocity(&u.index_axis(Axis(0), l).to_owned(), &v.index_axis(Axis(0), l).to_owned());
+            u_vert.index_axis_mut(Axis(0), l).assign(&u_vert_l);
+            u_hori.index_axis_mut(Axis(0), l).assign(&u_hori_l);
+        }
+        let u_level = match self.levels.as_slice() {
+            [bottom, .., top] => omega * (-1.0 / ((bottom - top) * 100.0 / (self.levels.len() - 1) as f64)),
+            _ => Array3::zeros(omega.dim()),
+        };
+        Some((u_level, u_vert, u_hori))
+    }
+
+    // 読み込んだ時刻(昇順)
+    pub fn datetimes(&self) -> Vec<DateTime<Utc>> {
+        let mut datetimes: Vec<DateTime<Utc>> = self.data.keys().map(|(datetime, _)| *datetime).collect();
+        datetimes.sort();
+        datetimes.dedup();
+        datetimes
+    }
 }
 
 pub fn get_meteorological_data(datetimes: Vec<DateTime<Utc>>) -> HashMap<(DateTime<Utc>, MeteorologicalType), Array2<f64>> {
+    get_meteorological_data_in_region(datetimes, &GeoGrid::msm(), &Region::new(None, Coarsening::None)).into_map()
+}
+
+// gridは読み込むファイルの格子 戻り値の格子は切り出し後のもの
+pub fn get_meteorological_data_in_region(datetimes: Vec<DateTime<Utc>>, grid: &GeoGrid, region: &Region) -> MeteorologicalData {
     dotenv().ok();
     let data_dir = env::var("DATA_DIR").unwrap();
-    let mut meteorological_data = HashMap::new();
+    get_meteorological_data_from_dir(&data_dir, datetimes, grid, region).unwrap()
+}
+
+// DATA_DIRの代わりにdata_dirから読む data_dirは/で終わること ファイルがないか読めないときはErr
+pub fn get_meteorological_data_from_dir(data_dir: &str, datetimes: Vec<DateTime<Utc>>, grid: &GeoGrid, region: &Region) -> io::Result<MeteorologicalData> {
+    let data_dir = data_dir.to_string();
+    let mut data = HashMap::new();
 
     for datetime in datetimes {
         let u_vert_filename = data_dir.clone() + "npy/u_vert_" + &datetime.format("%Y%m%d%H").to_string() + ".npy";
         let u_hori_filename = data_dir.clone() + "npy/u_hori_" + &datetime.format("%Y%m%d%H").to_string() + ".npy";
         let pressure_filename = data_dir.clone() + "npy/pressure_" + &datetime.format("%Y%m%d%H").to_string() + ".npy";
-        let reader = File::open(u_vert_filename).unwrap();
-        let u_vert_arr = Array2::<f64>::read_npy(reader).unwrap();
-        let reader = File::open(u_hori_filename).unwrap();
-        let u_hori_arr = Array2::<f64>::read_npy(reader).unwrap();
-        let reader = File::open(pressure_filename).unwrap();
-        let pressure_arr = Array2::<f64>::read_npy(reader).unwrap();
+        let u_vert_arr = read_npy(&u_vert_filename)?;
+        let u_hori_arr = read_npy(&u_hori_filename)?;
+        let pressure_arr = read_npy(&pressure_filename)?;
+
+        data.insert((datetime, MeteorologicalType::UVert), region.apply(&u_vert_arr, grid));
+        data.insert((datetime, MeteorologicalType::UHori), region.apply(&u_hori_arr, grid));
+        data.insert((datetime, MeteorologicalType::Pressure), region.apply(&pressure_arr, grid));
+    }
+
+    Ok(MeteorologicalData { grid: region.apply_to_grid(grid), data })
+}
+
+// エラーにはファイル名を付ける
+fn read_npy(filename: &str) -> io::Result<Array2<f64>> {
+    let reader = File::open(filename).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", filename, e)))?;
+    Array2::<f64>::read_npy(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", filename, e)))
+}
+
+// 温度と湿度のnpy(grib2npy.pyがtemperature_, humidity_として書き出したもの)を読む 風と別に読むのは、GRIBによっては入っていないため
+// meteorological_typesはTemperatureかHumidity(それ以外はInvalidInputのErr) ファイルがないか読めないときもErr
+// ScalarModel用のライブラリのAPIで、設定ファイルやCLIからは使わない
+pub fn get_scalar_data_from_dir(data_dir: &str, datetimes: Vec<DateTime<Utc>>, meteorological_types: &[MeteorologicalType], grid: &GeoGrid, region: &Region) -> io::Result<MeteorologicalData> {
+    let mut data = HashMap::new();
+
+    for datetime in datetimes {
+        for meteorological_type in meteorological_types {
+            let prefix = match meteorological_type {
+                MeteorologicalType::Temperature => "temperature_",
+                MeteorologicalType::Humidity => "humidity_",
+                other => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not a scalar (use Temperature or Humidity)", other))),
+            };
+            let filename = data_dir.to_string() + "npy/" + prefix + &datetime.format("%Y%m%d%H").to_string() + ".npy";
+            data.insert((datetime, *meteorological_type), region.apply(&read_npy(&filename)?, grid));
+        }
+    }
+
+    Ok(MeteorologicalData { grid: region.apply_to_grid(grid), data })
+}
+
+impl NetCdfLevelVariables {
+    // ERA5の気圧面データ
+    pub fn era5_pressure_levels() -> NetCdfLevelVariables {
+        NetCdfLevelVariables { u: "u".to_string(), v: "v".to_string(), w: "w".to_string(), geopotential: "z".to_string(), levels: vec![1000.0, 925.0, 850.0, 700.0] }
+    }
+}
+
+impl NetCdfVariables {
+    // ERA5の地上10m風と海面気圧
+    pub fn era5_surface() -> NetCdfVariables {
+        NetCdfVariables { u: "u10".to_string(), v: "v10".to_string(), pressure: "msl".to_string(), level: None }
+    }
+}
+
+// NetCDF-3のファイルから読む 次元は(time, lat, lon)か(time, level, lat, lon)を仮定する
+// npyと同じく、UVertには北向き正のv、UHoriには東向き正のuが入る 埋め値(_FillValue, missing_value)のセルはNaNになる
+pub fn get_meteorological_data_from_netcdf<P: AsRef<Path>>(path: P, datetimes: Vec<DateTime<Utc>>, variables: &NetCdfVariables, region: &Region) -> Result<MeteorologicalData, NetCdfError> {
+    let file = NetCdfFile::open(path)?;
+    let dim_names = file.dimension_names(&variables.u)?;
+    let (time_name, lat_name, lon_name) = match dim_names.as_slice() {
+        [time, lat, lon] | [time, _, lat, lon] => (time, lat, lon),
+        _ => return Err(NetCdfError::Format(format!("{} must have (time, lat, lon) or (time, level, lat, lon) dimensions", variables.u))),
+    };
+    let level_index = match (dim_names.as_slice(), variables.level) {
+        ([_, _, _], None) => None,
+        ([_, level_name, _, _], Some(level)) => Some(level_position(&file, level_name, level)?),
+        ([_, _, _], Some(_)) => return Err(NetCdfError::Format(format!("{} has no level dimension", variables.u))),
+        _ => return Err(NetCdfError::Format(format!("level is required to read {}", variables.u))),
+    };
+
+    let lat = coordinate(&file, lat_name)?;
+    let lon = coordinate(&file, lon_name)?;
+    let grid = GeoGrid::from_coordinates(lat, lon);
+    let times = netcdf_times(&file, time_name)?;
 
-        meteorological_data.insert((datetime, MeteorologicalType::UVert), u_vert_arr);
-        meteorological_data.insert((datetime, MeteorologicalType::UHori), u_hori_arr);
-        meteorological_data.insert((datetime, MeteorologicalType::Pressure), pressure_arr);
+    let u_arr = file.read(&variables.u)?;
+    let v_arr = file.read(&variables.v)?;
+    let pressure_arr = file.read(&variables.pressure)?;
+    let frame = |arr: &ArrayD<f64>, time_index: usize| -> Result<Array2<f64>, NetCdfError> {
+        let arr = arr.index_axis(Axis(0), time_index);
+        let arr = match level_index {
+            Some(level_index) => arr.index_axis_move(Axis(0), level_index),
+            None => arr,
+        };
+        arr.into_dimensionality::<Ix2>().map(|arr| arr.to_owned()).map_err(|e| NetCdfError::Format(e.to_string()))
+    };
+
+    let mut data = HashMap::new();
+    for datetime in datetimes {
+        let time_index = time_position(&times, datetime)?;
+        data.insert((datetime, MeteorologicalType::UVert), region.apply(&frame(&v_arr, time_index)?, &grid));
+        data.insert((datetime, MeteorologicalType::UHori), region.apply(&frame(&u_arr, time_index)?, &grid));
+        data.insert((datetime, MeteorologicalType::Pressure), region.apply(&frame(&pressure_arr, time_index)?, &grid));
     }
 
-    meteorological_data
+    Ok(MeteorologicalData { grid: region.apply_to_grid(&grid), data })
+}
+
+// NetCDF-3のファイルから複数の気圧面を読む 変数の次元は(time, level, lat, lon)を仮定する
+pub fn get_multilevel_data_from_netcdf<P: AsRef<Path>>(path: P, datetimes: Vec<DateTime<Utc>>, variables: &NetCdfLevelVariables, region: &Region) -> Result<MultiLevelData, NetCdfError> {
+    if variables.levels.is_empty() || variables.levels.windows(2).any(|pair| pair[0] <= pair[1]) {
+        return Err(NetCdfError::Format(format!("levels must be non-empty and in descending order (from the ground up): {:?}", variables.levels)));
+    }
+    let file = NetCdfFile::open(path)?;
+    let dim_names = file.dimension_names(&variables.u)?;
+    let (time_name, level_name, lat_name, lon_name) = match dim_names.as_slice() {
+        [time, level, lat, lon] => (time, level, lat, lon),
+        _ => return Err(NetCdfError::Format(format!("{} must have (time, level, lat, lon) dimensions", variables.u))),
+    };
+    let level_indices = variables.levels.iter()
+        .map(|level| level_position(&file, level_name, *level))
+        .collect::<Result<Vec<usize>, NetCdfError>>()?;
+
+    let lat = coordinate(&file, lat_name)?;
+    let lon = coordinate(&file, lon_name)?;
+    let grid = GeoGrid::from_coordinates(lat, lon);
+    let times = netcdf_times(&file, time_name)?;
+
+    let frames = |arr: &ArrayD<f64>, time_index: usize| -> Result<Array3<f64>, NetCdfError> {
+        let arr = arr.index_axis(Axis(0), time_index);
+        let planes = level_indices.iter()
+            .map(|&l| arr.index_axis(Axis(0), l).into_dimensionality::<Ix2>().map(|plane| region.apply(&plane.to_owned(), &grid)))
+            .collect::<Result<Vec<Array2<f64>>, _>>()
+            .map_err(|e| NetCdfError::Format(e.to_string()))?;
+        stack(Axis(0), &planes.iter().map(|plane| plane.view()).collect::<Vec<_>>()).map_err(|e| NetCdfError::Format(e.to_string()))
+    };
+    let arrays = [
+        (MeteorologicalType::UVert, file.read(&variables.v)?),
+        (MeteorologicalType::UHori, file.read(&variables.u)?),
+        (MeteorologicalType::Omega, file.read(&variables.w)?),
+        (MeteorologicalType::Geopotential, file.read(&variables.geopotential)?),
+    ];
+
+    let mut data = HashMap::new();
+    for datetime in datetimes {
+        let time_index = time_position(&times, datetime)?;
+        for (meteorological_type, arr) in &arrays {
+            data.insert((datetime, *meteorological_type), frames(arr, time_index)?);
+        }
+    }
+
+    Ok(MultiLevelData { grid: region.apply_to_grid(&grid), levels: variables.levels.clone(), data })
+}
+
+// 1次元の座標変数
+fn coordinate(file: &NetCdfFile, name: &str) -> Result<Array1<f64>, NetCdfError> {
+    let arr = file.read(name)?.into_dimensionality::<Ix1>().map_err(|e| NetCdfError::Format(format!("{}: {}", name, e)))?;
+    Ok(Array1::from(arr.to_vec()))
+}
+
+fn level_position(file: &NetCdfFile, level_name: &str, level: f64) -> Result<usize, NetCdfError> {
+    file.read(level_name)?.iter().position(|x| (x - level).abs() < 0.000001)
+        .ok_or_else(|| NetCdfError::Format(format!("level {} is not in {}", level, level_name)))
+}
+
+fn time_position(times: &[DateTime<Utc>], datetime: DateTime<Utc>) -> Result<usize, NetCdfError> {
+    times.iter().position(|t| *t == datetime)
+        .ok_or_else(|| NetCdfError::Format(format!("{} is not in the file", datetime)))
+}
+
+// units = "hours since 1900-01-01 00:00:00.0" のような時刻変数をDateTimeにする
+fn netcdf_times(file: &NetCdfFile, time_name: &str) -> Result<Vec<DateTime<Utc>>, NetCdfError> {
+    let invalid_units = || NetCdfError::Format(format!("{} must have units like \"hours since 1900-01-01 00:00:00\"", time_name));
+    let units = match file.variable(time_name)?.attribute("units") {
+        Some(crate::netcdf::AttributeValue::Text(units)) => units.clone(),
+        _ => return Err(invalid_units()),
+    };
+    let (unit, since) = units.split_once(" since ").ok_or_else(invalid_units)?;
+    let since = since.trim().trim_end_matches(" UTC").trim_end_matches('Z');
+    let origin = NaiveDateTime::parse_from_str(since, "%Y-%m-%d %H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(since, "%Y-%m-%dT%H:%M:%S%.f"))
+        .or_else(|_| NaiveDate::parse_from_str(since, "%Y-%m-%d").map(|d| d.and_hms_opt(0, 0, 0).unwrap()))
+        .map_err(|_| invalid_units())?;
+    let origin = Utc.from_utc_datetime(&origin);
+    let seconds = match unit.trim() {
+        "days" => 86400.0,
+        "hours" => 3600.0,
+        "minutes" => 60.0,
+        "seconds" => 1.0,
+        _ => return Err(invalid_units()),
+    };
+    Ok(file.read(time_name)?.iter()
+        .map(|t| origin + Duration::milliseconds((t * seconds * 1000.0).round() as i64))
+        .collect())
 }
 
 #[cfg(test)]
 mod tests {
     use chrono::TimeZone;
+    use ndarray::arr2;
+    use ndarray_npy::WriteNpyExt;
+    use crate::synthetic::{SyntheticFlow, write_synthetic_data};
 
     use super::*;
 
+    // DATA_DIRに人工データを書いて、実データと同じMSMの格子で読む
     #[test]
     fn test_get_meteorological_data() {
+        let data_dir = env::temp_dir().join("lbm_rust_test_get_meteorological_data");
+        let data_dir = data_dir.to_str().unwrap().to_string() + "/";
         let datetime1 = Utc.with_ymd_and_hms(2020, 3, 20, 3, 0, 0).unwrap();
-        let datetime2 = Utc.with_ymd_and_hms(2018, 8, 29, 0, 0, 0).unwrap();
+        let datetime2 = Utc.with_ymd_and_hms(2020, 3, 20, 6, 0, 0).unwrap();
         let datetimes = vec![datetime1, datetime2];
+        let flow = SyntheticFlow::TaylorGreen { u0: 3.0, nu: 0.0 };
+        write_synthetic_data(&data_dir, &flow, &GeoGrid::msm(), &datetimes).unwrap();
+        env::set_var("DATA_DIR", &data_dir);
         let meteorological_data = get_meteorological_data(datetimes);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+
+        let frame = flow.frame(GeoGrid::msm().row(), GeoGrid::msm().col(), 0.0);
+        // MSMの格子は北から並ぶので、北向き正のvはu_vertの符号を反転したもの
         assert_eq!(
-            -2.5597972869873047,
+            -frame.u_vert[[3, 4]],
             *meteorological_data.get(&(datetime1, MeteorologicalType::UVert)).unwrap().get((3, 4)).unwrap()
         );
         assert_eq!(
-            0.16127395629882812,
+            frame.u_hori[[10, 10]],
             *meteorological_data.get(&(datetime2, MeteorologicalType::UHori)).unwrap().get((10, 10)).unwrap()
         );
         assert_eq!(
-            101150.23193359375,
+            frame.pressure[[6, 9]],
             *meteorological_data.get(&(datetime2, MeteorologicalType::Pressure)).unwrap().get((6, 9)).unwrap()
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_region_index_range_and_decimation() {
+        let arr = Array::range(0., 30., 1.).into_shape((5, 6)).unwrap();
+        let grid = GeoGrid::new(47.6, 120.0, -0.05, 0.0625, 5, 6);
+        let region = Region::new(Some(GridRange::Index { row_start: 1, row_end: 5, col_start: 1, col_end: 6 }), Coarsening::Decimation(2));
+        let cropped = region.apply(&arr, &grid);
+        assert_eq!((2, 3), region.shape(&grid));
+        assert_eq!(arr2(&[[7., 9., 11.], [19., 21., 23.]]), cropped);
+    }
+
+    #[test]
+    fn test_region_block_average() {
+        let arr = Array::range(0., 30., 1.).into_shape((5, 6)).unwrap();
+        let grid = GeoGrid::new(47.6, 120.0, -0.05, 0.0625, 5, 6);
+        let region = Region::new(None, Coarsening::BlockAverage(2));
+        let cropped = region.apply(&arr, &grid);
+        assert_eq!((2, 3), region.shape(&grid));
+        assert_eq!(arr2(&[[3.5, 5.5, 7.5], [15.5, 17.5, 19.5]]), cropped);
+        assert!((region.apply_to_grid(&grid).lat()[1] - 47.475).abs() < 0.000001);
+    }
+
+    #[test]
+    fn test_region_lat_lon_range() {
+        // 47.5N~47.4N, 120.0625E~120.125E => 2~4行目, 1~2列目
+        let arr = Array::range(0., 30., 1.).into_shape((5, 6)).unwrap();
+        let grid = GeoGrid::new(47.6, 120.0, -0.05, 0.0625, 5, 6);
+        let region = Region::new(Some(GridRange::LatLon { lat_min: 47.4, lat_max: 47.5, lon_min: 120.0625, lon_max: 120.125 }), Coarsening::None);
+        assert_eq!((3, 2), region.shape(&grid));
+        assert_eq!(arr2(&[[13., 14.], [19., 20.], [25., 26.]]), region.apply(&arr, &grid));
+    }
+
+    #[test]
+    fn test_get_meteorological_data_from_netcdf() {
+        let path = env::temp_dir().join("lbm_rust_test_get_meteorological_data_from_netcdf.nc");
+        std::fs::write(&path, crate::netcdf::tests::sample_bytes()).unwrap();
+        let variables = NetCdfVariables { u: "u10".to_string(), v: "u10".to_string(), pressure: "u10".to_string(), level: None };
+        let region = Region::new(Some(GridRange::Index { row_start: 0, row_end: 2, col_start: 1, col_end: 3 }), Coarsening::None);
+        let datetime = Utc.with_ymd_and_hms(2020, 3, 20, 6, 0, 0).unwrap();
+        let datetime_filled = Utc.with_ymd_and_hms(2020, 3, 20, 3, 0, 0).unwrap();
+        let meteorological_data = get_meteorological_data_from_netcdf(&path, vec![datetime, datetime_filled], &variables, &region).unwrap();
+        // ファイルにない時刻はErr
+        let missing = get_meteorological_data_from_netcdf(&path, vec![Utc.with_ymd_and_hms(2020, 3, 20, 9, 0, 0).unwrap()], &variables, &region);
+        std::fs::remove_file(&path).unwrap();
+        assert!(missing.is_err());
+        assert!(get_meteorological_data_from_netcdf(&path, vec![datetime], &variables, &region).is_err());
+
+        assert_eq!((2, 2), meteorological_data.grid().shape());
+        assert_eq!(135.0625, meteorological_data.grid().lon()[0]);
+        assert_eq!(
+            &arr2(&[[2.5, 3.5], [5.5, 6.5]]),
+            meteorological_data.get(datetime, MeteorologicalType::UHori).unwrap()
+        );
+        // 北向き正のvは下向き正のu_vertに直すと符号が反転する
+        let (u_vert, _) = meteorological_data.lattice_velocity(datetime).unwrap();
+        assert_eq!(-2.5, u_vert[[0, 0]]);
+        // _FillValueのセルはNaN
+        let u_hori_filled = meteorological_data.get(datetime_filled, MeteorologicalType::UHori).unwrap();
+        assert!(u_hori_filled[[1, 0]].is_nan());
+        assert_eq!(2.0, u_hori_filled[[0, 0]]);
+    }
+
+    #[test]
+    fn test_get_multilevel_data_from_netcdf() {
+        let path = env::temp_dir().join("lbm_rust_test_get_multilevel_data_from_netcdf.nc");
+        std::fs::write(&path, crate::netcdf::tests::sample_level_bytes()).unwrap();
+        let variables = NetCdfLevelVariables { u: "u".to_string(), v: "u".to_string(), w: "u".to_string(), geopotential: "u".to_string(), levels: vec![1000.0, 850.0] };
+        let region = Region::new(Some(GridRange::Index { row_start: 0, row_end: 2, col_start: 1, col_end: 3 }), Coarsening::None);
+        let datetime = Utc.with_ymd_and_hms(2020, 3, 20, 6, 0, 0).unwrap();
+        let data = get_multilevel_data_from_netcdf(&path, vec![datetime], &variables, &region).unwrap();
+        // 気圧面が空か、下から順(降順)でないときはErr
+        for levels in [vec![], vec![850.0, 1000.0]] {
+            let variables = NetCdfLevelVariables { u: "u".to_string(), v: "u".to_string(), w: "u".to_string(), geopotential: "u".to_string(), levels };
+            assert!(matches!(get_multilevel_data_from_netcdf(&path, vec![datetime], &variables, &region), Err(NetCdfError::Format(_))));
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((2, 2), data.grid().shape());
+        assert_eq!(&[1000.0, 850.0], data.levels());
+        let u = data.get(datetime, MeteorologicalType::UHori).unwrap();
+        assert_eq!((2, 2, 2), u.dim());
+        assert_eq!(601.0, u[[0, 0, 0]]);
+        assert_eq!(615.0, u[[1, 1, 1]]);
+        // omegaは150hPaで1面なので、u_level = -omega / 15000 北向き正のvは下向き正のu_vertに直すと符号が反転する
+        let (u_level, u_vert, _) = data.lattice_velocity(datetime).unwrap();
+        assert!((u_level[[0, 0, 0]] + 601.0 / 15000.0).abs() < 0.000000001);
+        assert_eq!(-601.0, u_vert[[0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_get_meteorological_data_from_synthetic_dir() {
+        let data_dir = env::temp_dir().join("lbm_rust_test_get_meteorological_data_from_synthetic_dir");
+        let data_dir = data_dir.to_str().unwrap().to_string() + "/";
+        let grid = GeoGrid::new(35.0, 135.0, -0.05, 0.0625, 6, 8);
+        let datetime1 = Utc.with_ymd_and_hms(2020, 3, 20, 0, 0, 0).unwrap();
+        let datetime2 = Utc.with_ymd_and_hms(2020, 3, 20, 3, 0, 0).unwrap();
+        let flow = SyntheticFlow::Uniform { u_vert: 1.5, u_hori: -2.0 };
+        write_synthetic_data(&data_dir, &flow, &grid, &[datetime1, datetime2]).unwrap();
+
+        let region = Region::new(Some(GridRange::Index { row_start: 1, row_end: 5, col_start: 0, col_end: 8 }), Coarsening::BlockAverage(2));
+        let meteorological_data = get_meteorological_data_from_dir(&data_dir, vec![datetime1, datetime2], &grid, &region).unwrap();
+        // ファイルのない時刻はpanicせずにErr
+        let missing = get_meteorological_data_from_dir(&data_dir, vec![Utc.with_ymd_and_hms(2020, 3, 20, 6, 0, 0).unwrap()], &grid, &region);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+        assert!(missing.err().unwrap().to_string().contains("u_vert_2020032006.npy"));
+
+        assert_eq!((2, 4), meteorological_data.grid().shape());
+        // ファイルには北向き正で入っている
+        assert_eq!(-1.5, meteorological_data.get(datetime2, MeteorologicalType::UVert).unwrap()[[1, 3]]);
+        let (u_vert, u_hori) = meteorological_data.lattice_velocity(datetime2).unwrap();
+        assert_eq!(1.5, u_vert[[1, 3]]);
+        assert_eq!(-2.0, u_hori[[0, 0]]);
+        assert_eq!(101325.0, meteorological_data.get(datetime1, MeteorologicalType::Pressure).unwrap()[[0, 2]]);
+    }
+
+    #[test]
+    fn test_get_scalar_data_from_dir() {
+        let data_dir = env::temp_dir().join("lbm_rust_test_get_scalar_data_from_dir");
+        std::fs::create_dir_all(data_dir.join("npy")).unwrap();
+        let data_dir = data_dir.to_str().unwrap().to_string() + "/";
+        let grid = GeoGrid::new(35.0, 135.0, -0.05, 0.0625, 4, 4);
+        let datetime = Utc.with_ymd_and_hms(2020, 3, 20, 0, 0, 0).unwrap();
+        let temperature = Array2::from_shape_fn((4, 4), |(r, c)| 280.0 + r as f64 + 0.1 * c as f64);
+        temperature.write_npy(File::create(data_dir.clone() + "npy/temperature_2020032000.npy").unwrap()).unwrap();
+        Array2::<f64>::from_elem((4, 4), 60.0).write_npy(File::create(data_dir.clone() + "npy/humidity_2020032000.npy").unwrap()).unwrap();
+
+        let region = Region::new(Some(GridRange::Index { row_start: 1, row_end: 3, col_start: 0, col_end: 4 }), Coarsening::None);
+        let data = get_scalar_data_from_dir(&data_dir, vec![datetime], &[MeteorologicalType::Temperature, MeteorologicalType::Humidity], &grid, &region).unwrap();
+        // ファイルのない時刻はpanicせずにErr
+        let missing = get_scalar_data_from_dir(&data_dir, vec![datetime + Duration::hours(3)], &[MeteorologicalType::Temperature], &grid, &region);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+        assert!(missing.is_err());
+        // 風や気圧はスカラーとして読めない
+        let wind = get_scalar_data_from_dir(&data_dir, vec![datetime], &[MeteorologicalType::UVert], &grid, &region);
+        assert_eq!(io::ErrorKind::InvalidInput, wind.err().unwrap().kind());
+
+        assert_eq!((2, 4), data.grid().shape());
+        assert!((data.get(datetime, MeteorologicalType::Temperature).unwrap()[[0, 2]] - 281.2).abs() < 0.000000001);
+        assert_eq!(60.0, data.get(datetime, MeteorologicalType::Humidity).unwrap()[[1, 3]]);
+        assert!(data.get(datetime, MeteorologicalType::UVert).is_none());
+    }
+}
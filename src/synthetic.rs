@@ -0,0 +1,132 @@
+use std::{f64::consts::PI, fs::{self, File}, io, path::Path};
+use chrono::{DateTime, Utc};
+use ndarray::{Array2, Zip};
+use ndarray_npy::WriteNpyExt;
+use crate::geo::GeoGrid;
+
+// 実データなしで学習やテストを回すための人工データ
+// 位置はセル単位(r, c)、時間は最初の時刻からの経過時間[h]で、速度はu_vert(下向き正), u_hori(右向き正)で作る
+// ファイルに書くときはrepoのnpyと同じく、u_vert_に北向き正のv、u_hori_に東向き正のuを入れる
+
+const P0: f64 = 101325.0;
+const RHO_AIR: f64 = 1.2;
+
+pub enum SyntheticFlow {
+    // 一様な流れ
+    Uniform { u_vert: f64, u_hori: f64 },
+    // 領域全体を1周期とするTaylor–Green渦 nuは動粘性係数[セル^2/h]で、振幅はexp(-nu k^2 t)で減衰する
+    TaylorGreen { u0: f64, nu: f64 },
+    // 低気圧(ガウス分布の気圧の谷)が一定速度で動く 風は中心のまわりを反時計回り(北半球)に回る
+    // center:[セル]  velocity:[セル/h]  depth:中心の気圧の深さ[Pa]  radius:[セル]  max_wind:[m/s]
+    GaussianLow { center: (f64, f64), velocity: (f64, f64), depth: f64, radius: f64, max_wind: f64 },
+}
+
+pub struct SyntheticFrame {
+    pub u_vert: Array2<f64>,
+    pub u_hori: Array2<f64>,
+    pub pressure: Array2<f64>,
+}
+
+impl SyntheticFlow {
+    pub fn frame(&self, row: usize, col: usize, t: f64) -> SyntheticFrame {
+        let mut u_vert = Array2::<f64>::zeros((row, col));
+        let mut u_hori = Array2::<f64>::zeros((row, col));
+        let mut pressure = Array2::<f64>::from_elem((row, col), P0);
+
+        match *self {
+            SyntheticFlow::Uniform { u_vert: u_vert_0, u_hori: u_hori_0 } => {
+                u_vert.fill(u_vert_0);
+                u_hori.fill(u_hori_0);
+            }
+            SyntheticFlow::TaylorGreen { u0, nu } => {
+                let k_vert = 2.0 * PI / row as f64;
+                let k_hori = 2.0 * PI / col as f64;
+                let decay = (-nu * (k_vert * k_vert + k_hori * k_hori) * t).exp();
+                let ratio = k_hori / k_vert; // 発散が0になるようにu_vertの振幅を合わせる
+                Zip::indexed(&mut u_vert).and(&mut u_hori).and(&mut pressure).for_each(|(r, c), u_vert, u_hori, pressure| {
+                    let y = k_vert * r as f64;
+                    let x = k_hori * c as f64;
+                    *u_hori = u0 * decay * x.sin() * y.cos();
+                    *u_vert = -u0 * decay * ratio * x.cos() * y.sin();
+                    *pressure += RHO_AIR * u0 * u0 * decay * decay / 4.0 * ((2.0 * x).cos() + ratio * ratio * (2.0 * y).cos());
+                });
+            }
+            SyntheticFlow::GaussianLow { center, velocity, depth, radius, max_wind } => {
+                let r0 = center.0 + velocity.0 * t;
+                let c0 = center.1 + velocity.1 * t;
+                Zip::indexed(&mut u_vert).and(&mut u_hori).and(&mut pressure).for_each(|(r, c), u_vert, u_hori, pressure| {
+                    let dr = (r as f64 - r0) / radius;
+                    let dc = (c as f64 - c0) / radius;
+                    let d2 = dr * dr + dc * dc;
+                    *pressure -= depth * (-d2 / 2.0).exp();
+                    // 接線方向の風速は中心から半径radiusの位置で最大になる
+                    let swirl = max_wind * (0.5 * (1.0 - d2)).exp();
+                    *u_vert = -swirl * dc;
+                    *u_hori = swirl * dr;
+                });
+            }
+        }
+
+        SyntheticFrame { u_vert, u_hori, pressure }
+    }
+}
+
+// data_dir/npy/にdatetimesぶんのファイルを書き出す gridは書き出すデータの格子
+pub fn write_synthetic_data(data_dir: &str, flow: &SyntheticFlow, grid: &GeoGrid, datetimes: &[DateTime<Utc>]) -> io::Result<()> {
+    let npy_dir = Path::new(data_dir).join("npy");
+    fs::create_dir_all(&npy_dir)?;
+    let start = match datetimes.first() {
+        Some(start) => *start,
+        None => return Ok(()),
+    };
+
+    for datetime in datetimes {
+        let t = (*datetime - start).num_seconds() as f64 / 3600.0;
+        let frame = flow.frame(grid.row(), grid.col(), t);
+        let (u, v) = grid.to_meteorological_velocity(&frame.u_vert, &frame.u_hori);
+        let suffix = datetime.format("%Y%m%d%H").to_string() + ".npy";
+        let write = |prefix: &str, arr: &Array2<f64>| -> io::Result<()> {
+            let writer = File::create(npy_dir.join(prefix.to_string() + &suffix))?;
+            arr.write_npy(writer).map_err(|e| io::Error::other(e.to_string()))
+        };
+        write("u_vert_", &v)?;
+        write("u_hori_", &u)?;
+        write("pressure_", &frame.pressure)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synthetic_flow_taylor_green() {
+        let flow = SyntheticFlow::TaylorGreen { u0: 2.0, nu: 0.5 };
+        let frame = flow.frame(8, 16, 0.0);
+        assert!((frame.u_hori[[0, 4]] - 2.0).abs() < 0.000001);
+        assert!((frame.u_vert[[2, 0]] + 1.0).abs() < 0.000001);
+
+        // 中心差分で発散が0になっていること
+        for r in 1..7 {
+            for c in 1..15 {
+                let div = (frame.u_vert[[r+1, c]] - frame.u_vert[[r-1, c]]) / 2.0 + (frame.u_hori[[r, c+1]] - frame.u_hori[[r, c-1]]) / 2.0;
+                assert!(div.abs() < 0.2);
+            }
+        }
+
+        let later = flow.frame(8, 16, 1.0);
+        let decay = (-0.5 * ((2.0 * PI / 8.0).powi(2) + (2.0 * PI / 16.0).powi(2))).exp();
+        assert!((later.u_hori[[0, 4]] - 2.0 * decay).abs() < 0.000001);
+    }
+
+    #[test]
+    fn test_synthetic_flow_gaussian_low() {
+        let flow = SyntheticFlow::GaussianLow { center: (4.0, 4.0), velocity: (0.0, 1.0), depth: 2000.0, radius: 2.0, max_wind: 10.0 };
+        let frame = flow.frame(9, 12, 2.0);
+        assert!((frame.pressure[[4, 6]] - (P0 - 2000.0)).abs() < 0.000001);
+        // 中心の東側では北向き(u_vertが負)、北側では西向き(u_horiが負)
+        assert!((frame.u_vert[[4, 8]] + 10.0).abs() < 0.000001);
+        assert!((frame.u_hori[[2, 6]] + 10.0).abs() < 0.000001);
+    }
+}
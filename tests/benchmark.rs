@@ -0,0 +1,135 @@
+// 既定の重み(StreamingWeight::with_boundary()、CollidingWeight::new())のLBMが、解析解のある流れを正しく解くことを確かめる
+// 既定のBGKは緩和率0.5(τ = 2)なので、動粘性係数は格子単位でnu = (τ - 1/2) / 3 = 0.5
+// 格子を細かくするときは拡散スケーリング(幅をn倍にしたら、速度は1/n倍、ステップ数はn^2倍)にして、誤差が2次で減ることを見る
+
+use std::f64::consts::PI;
+use ndarray::{Array2, Zip};
+use lbm_rust::boundary::{EdgeCondition, Edges};
+use lbm_rust::synthetic::SyntheticFlow;
+use lbm_rust::{Boundary, CollidedField, CollidingWeight, InputField, StreamedField, StreamingWeight};
+
+const NU: f64 = 0.5;
+
+// 周期境界でstepsステップ流す 入力を平衡分布として最初にstreamするので、steps回のstreamの後の場を返す
+fn run(input_field: &InputField, streaming_weight: &StreamingWeight, colliding_weight: &CollidingWeight, steps: usize) -> StreamedField {
+    let (row, col) = (input_field.row(), input_field.col());
+    let mut streamed_field = StreamedField::new(row, col, 0);
+    streamed_field.stream_from_input_field(input_field, streaming_weight);
+    let mut collided_field = CollidedField::new(row, col, 0);
+    for _ in 1..steps {
+        collided_field.collide(&streamed_field, colliding_weight);
+        streamed_field.stream_from_collided_field(&collided_field, streaming_weight);
+    }
+    streamed_field
+}
+
+// 解析解との相対L2誤差 maskがtrueのセルだけ比べる
+fn relative_error(u: &Array2<f64>, u_ans: &Array2<f64>, mask: &Array2<bool>) -> f64 {
+    let (mut error, mut norm) = (0.0, 0.0);
+    Zip::from(u).and(u_ans).and(mask).for_each(|u, u_ans, mask| {
+        if *mask {
+            error += (u - u_ans).powi(2);
+            norm += u_ans.powi(2);
+        }
+    });
+    (error / norm).sqrt()
+}
+
+// 細かくするたびに誤差がratio倍以上小さくなること
+fn assert_converges(name: &str, errors: &[f64], ratio: f64) {
+    for pair in errors.windows(2) {
+        assert!(pair[0] / pair[1] > ratio, "{}: {:?}", name, errors);
+    }
+}
+
+// 上下(最初と最後の行)を障害物の壁にして、横向きの外力で駆動する 壁はhalf-way bounce-backなのでセルの中間にある
+// 定常解は u_hori(y) = F / (2 nu) * y * (h - y)  yは上の壁からの距離、hは流体の行数
+fn poiseuille(h: usize) -> f64 {
+    let (row, col) = (h + 2, 2);
+    let u_max = 0.4 / h as f64;
+    let force = 8.0 * NU * u_max / (h * h) as f64;
+    let y = |r: usize| r as f64 - 0.5;
+    let fluid = Array2::from_shape_fn((row, col), |(r, _)| r > 0 && r < row - 1);
+    let u_ans = Array2::from_shape_fn((row, col), |(r, _)| if fluid[[r, 0]] { force / (2.0 * NU) * y(r) * (h as f64 - y(r)) } else { 0.0 });
+
+    let mut input_field = InputField::new(row, col);
+    input_field.set(Array2::zeros((row, col)), u_ans.clone(), Array2::ones((row, col)));
+    let mut streaming_weight = StreamingWeight::with_boundary(row, col, 0, Boundary::Periodic);
+    streaming_weight.set_obstacle(fluid.mapv(|fluid| !fluid));
+    let mut colliding_weight = CollidingWeight::new(row, col, 0);
+    colliding_weight.set_force(Array2::zeros((row, col)), Array2::from_shape_fn((row, col), |(r, c)| if fluid[[r, c]] { force } else { 0.0 }));
+    let streamed_field = run(&input_field, &streaming_weight, &colliding_weight, 3 * h * h);
+
+    // Guoの外力では、流体の速度はstreamの後の速度に外力の半分を足したもの
+    let (_, u_hori) = colliding_weight.physical_velocity(&streamed_field);
+    relative_error(&u_hori, &u_ans, &fluid)
+}
+
+// 静止した流体の上の壁(最初の行)を急にuで動かす 壁はZou–Heの速度条件でセルの上にある
+// u_hori(y, t) = u y / h - 2u/π Σ (-1)^(n+1) / n sin(nπ y / h) exp(-nu n^2 π^2 t / h^2)  yは下の壁からの距離
+fn couette(h: usize) -> f64 {
+    let (row, col) = (h + 1, 2);
+    let u_wall = 0.4 / h as f64;
+    let steps = h * h / 5; // nu t / h^2 = 0.1
+    let y = |r: usize| (h - r) as f64 / h as f64;
+    let u_ans = Array2::from_shape_fn((row, col), |(r, _)| {
+        let decay = |n: f64| (-NU * n * n * PI * PI * steps as f64 / (h * h) as f64).exp();
+        let series: f64 = (1..200).map(|n| n as f64).map(|n| (if n % 2.0 == 1.0 { 1.0 } else { -1.0 }) / n * (n * PI * y(r)).sin() * decay(n)).sum();
+        u_wall * y(r) - 2.0 * u_wall / PI * series
+    });
+
+    let mut input_field = InputField::new(row, col);
+    input_field.set(Array2::zeros((row, col)), Array2::zeros((row, col)), Array2::ones((row, col)));
+    let mut streaming_weight = StreamingWeight::with_boundary(row, col, 0, Boundary::Periodic);
+    streaming_weight.set_edges(Edges {
+        north: Some(EdgeCondition::Velocity { u_vert: 0.0, u_hori: u_wall }),
+        south: Some(EdgeCondition::Velocity { u_vert: 0.0, u_hori: 0.0 }),
+        ..Edges::default()
+    });
+    let streamed_field = run(&input_field, &streaming_weight, &CollidingWeight::new(row, col, 0), steps);
+
+    let inner = Array2::from_shape_fn((row, col), |(r, _)| r > 0 && r < row - 1);
+    relative_error(&streamed_field.u_hori().to_owned(), &u_ans, &inner)
+}
+
+// 周期境界のn x nのTaylor–Green渦(synthetic::SyntheticFlow::TaylorGreen 時間はステップ、nuは格子単位)
+// 圧力の初期値もrho = 1 + 3 p(格子単位の圧力はcs^2 rho)で与える
+fn taylor_green(n: usize) -> f64 {
+    let u0 = 0.32 / n as f64;
+    let k = 2.0 * PI / n as f64;
+    let steps = n * n / 50; // nu k^2 t ≒ 0.4
+    let flow = SyntheticFlow::TaylorGreen { u0, nu: NU };
+    let (initial, answer) = (flow.frame(n, n, 0.0), flow.frame(n, n, steps as f64));
+    let rho = Array2::from_shape_fn((n, n), |(r, c)| 1.0 + 3.0 * u0 * u0 / 4.0 * ((2.0 * k * c as f64).cos() + (2.0 * k * r as f64).cos()));
+
+    let mut input_field = InputField::new(n, n);
+    input_field.set(initial.u_vert, initial.u_hori, rho);
+    let streamed_field = run(&input_field, &StreamingWeight::with_boundary(n, n, 0, Boundary::Periodic), &CollidingWeight::new(n, n, 0), steps);
+
+    let all = Array2::from_elem((n, n), true);
+    let error_vert = relative_error(&streamed_field.u_vert().to_owned(), &answer.u_vert, &all);
+    let error_hori = relative_error(&streamed_field.u_hori().to_owned(), &answer.u_hori, &all);
+    error_vert.max(error_hori)
+}
+
+#[test]
+fn test_poiseuille() {
+    // τ = 2のbounce-backは壁での滑りが大きいので、粗い格子では誤差が大きい
+    let errors: Vec<f64> = [6, 12, 24].into_iter().map(poiseuille).collect();
+    assert!(errors[2] < 0.05, "{:?}", errors);
+    assert_converges("poiseuille", &errors, 3.0);
+}
+
+#[test]
+fn test_couette() {
+    let errors: Vec<f64> = [10, 20, 40].into_iter().map(couette).collect();
+    assert!(errors[2] < 0.002, "{:?}", errors);
+    assert_converges("couette", &errors, 3.0);
+}
+
+#[test]
+fn test_taylor_green() {
+    let errors: Vec<f64> = [16, 32, 64].into_iter().map(taylor_green).collect();
+    assert!(errors[2] < 0.01, "{:?}", errors);
+    assert_converges("taylor-green", &errors, 3.0);
+}